@@ -0,0 +1,58 @@
+//! Compare signing messages with a reused signer and with a signer built for
+//! each message.
+//!
+//! cargo run --release --example sign_reuse
+
+use std::time::{Duration, Instant};
+
+use cfdkim::{DkimPrivateKey, SignerBuilder};
+use rsa::pkcs1::DecodeRsaPrivateKey;
+
+const MESSAGES: u32 = 200;
+
+fn builder(private_key: &DkimPrivateKey, logger: &slog::Logger) -> SignerBuilder<'static> {
+    SignerBuilder::new()
+        .with_signed_headers(&["From", "To", "Subject"])
+        .unwrap()
+        .with_private_key(private_key.clone())
+        .with_selector("s20")
+        .with_signing_domain("example.com")
+        .with_owned_logger(logger.clone())
+}
+
+/// Average duration of signing a message
+fn per_message(mut sign: impl FnMut(&mailparse::ParsedMail) -> String) -> Duration {
+    let raw_email =
+        "From: joe@example.com\r\nTo: bob@example.net\r\nSubject: Hello\r\n\r\nHello Bob\r\n";
+    let email = mailparse::parse_mail(raw_email.as_bytes()).unwrap();
+    // Warm up
+    sign(&email);
+
+    let start = Instant::now();
+    for _ in 0..MESSAGES {
+        sign(&email);
+    }
+    start.elapsed() / MESSAGES
+}
+
+fn main() {
+    let logger = slog::Logger::root(slog::Discard, slog::o!());
+    let rsa = rsa::RsaPrivateKey::read_pkcs1_pem_file("./test/keys/2022.private").unwrap();
+    let ed25519 = ed25519_dalek::SigningKey::from_bytes(&[7; 32]);
+
+    for (name, private_key) in [
+        ("RSA-2048", DkimPrivateKey::Rsa(rsa)),
+        ("Ed25519", DkimPrivateKey::Ed25519(ed25519)),
+    ] {
+        let signer = builder(&private_key, &logger).build().unwrap();
+        let reused = per_message(|email| signer.sign(email).unwrap());
+        let rebuilt = per_message(|email| {
+            let signer = builder(&private_key, &logger).build().unwrap();
+            signer.sign(email).unwrap()
+        });
+        println!(
+            "{}: {:?} per message with a reused signer, {:?} with a new signer",
+            name, reused, rebuilt
+        );
+    }
+}
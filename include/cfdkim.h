@@ -0,0 +1,52 @@
+/*
+ * C bindings of cfdkim, available when the library is built with the `capi`
+ * feature, with `cargo rustc --lib --release --features capi --crate-type
+ * cdylib`. Strings returned by the library must be released with
+ * dkim_string_free().
+ */
+
+#ifndef CFDKIM_H
+#define CFDKIM_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define DKIM_PASS 0
+#define DKIM_NEUTRAL 1
+#define DKIM_FAIL 2
+#define DKIM_ERR_INVALID_ARGUMENT -1
+#define DKIM_ERR_INTERNAL -2
+
+/*
+ * Sign a message with a RSA private key in PEM format. `signed_headers` is a
+ * colon-separated list of headers, for instance "From:Subject:To". On success
+ * returns DKIM_PASS and writes the DKIM-Signature header (without the
+ * trailing CRLF) to `header_out`, otherwise writes the error message.
+ */
+int dkim_sign(const uint8_t *message, size_t message_len,
+              const char *private_key_pem, const char *selector,
+              const char *signing_domain, const char *signed_headers,
+              char **header_out);
+
+/*
+ * Verify the signatures of a message for the domain of its From header.
+ * `key_record` is the content of the DKIM key TXT record; when NULL the key is
+ * retrieved from the DNS. Returns DKIM_PASS, DKIM_NEUTRAL or DKIM_FAIL and
+ * writes the result with its detail to `result_out`, or a negative code and
+ * the error message.
+ */
+int dkim_verify(const uint8_t *message, size_t message_len,
+                const char *key_record, char **result_out);
+
+/* Release a string returned by the library. */
+void dkim_string_free(char *value);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* CFDKIM_H */
@@ -0,0 +1,222 @@
+//! Author Domain Signing Practices (ADSP, RFC 5617): the practice published
+//! by an author domain at `_adsp._domainkey`, stating whether its messages
+//! are all signed. ADSP is historic, the lookup is provided to analyze
+//! archived messages and old mail flows.
+
+#[cfg(feature = "dns")]
+use slog::debug;
+#[cfg(feature = "dns")]
+use std::sync::Arc;
+
+use crate::parser;
+#[cfg(feature = "dns")]
+use crate::{dns, normalize_domain, DKIMError, DKIMResult, Verdict, DNS_NAMESPACE};
+
+/// Signing practice of an author domain (RFC 5617 section 4.2.1)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Practice {
+    /// Some messages of the domain may not be signed. Unknown values of the
+    /// `dkim=` tag mean the same.
+    Unknown,
+    /// All the messages of the domain are signed by the domain
+    All,
+    /// All the messages of the domain are signed by the domain, and the
+    /// others may be discarded
+    Discardable,
+}
+
+impl Practice {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Practice::Unknown => "unknown",
+            Practice::All => "all",
+            Practice::Discardable => "discardable",
+        }
+    }
+}
+
+/// Parse an ADSP record. Returns `None` if the record is invalid, which is
+/// the same as no record.
+pub fn parse_record(record: &str) -> Option<Practice> {
+    let (rest, tags) = parser::tag_list(record).ok()?;
+    if !rest.trim().is_empty() {
+        return None;
+    }
+    let tag = tags.iter().find(|tag| tag.name == "dkim")?;
+    Some(match tag.value.to_ascii_lowercase().as_str() {
+        "all" => Practice::All,
+        "discardable" => Practice::Discardable,
+        _ => Practice::Unknown,
+    })
+}
+
+/// Verdict of the ADSP check, as in the `dkim-adsp` method of the
+/// Authentication-Results header (RFC 5617 section 5.4)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum AdspVerdict {
+    /// The author domain publishes no practice
+    None,
+    /// The message has a valid signature of the author domain
+    Pass,
+    /// The message has no valid signature of the author domain, which may
+    /// not sign all its messages
+    Unknown,
+    /// The message has no valid signature of the author domain, which signs
+    /// all its messages
+    Fail,
+    /// The message has no valid signature of the author domain, which asks
+    /// to discard such messages
+    Discard,
+    /// The author domain doesn't exist
+    NxDomain,
+    /// A DNS error prevented the check
+    TempError,
+}
+
+impl AdspVerdict {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AdspVerdict::None => "none",
+            AdspVerdict::Pass => "pass",
+            AdspVerdict::Unknown => "unknown",
+            AdspVerdict::Fail => "fail",
+            AdspVerdict::Discard => "discard",
+            AdspVerdict::NxDomain => "nxdomain",
+            AdspVerdict::TempError => "temperror",
+        }
+    }
+}
+
+impl std::fmt::Display for AdspVerdict {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Retrieve the practice of `author_domain`. Returns `None` if it publishes
+/// no valid ADSP record, [DKIMError::DnsNxDomain] if the domain doesn't
+/// exist, or the DNS error if the lookup failed temporarily.
+#[cfg(feature = "dns")]
+pub async fn lookup_practice(
+    logger: &slog::Logger,
+    author_domain: &str,
+    resolver: Arc<dyn dns::Lookup>,
+) -> Result<Option<Practice>, DKIMError> {
+    let author_domain = normalize_domain(author_domain);
+    // The practice of a domain which doesn't exist is irrelevant
+    match resolver.lookup_txt(&author_domain).await {
+        Err(err @ DKIMError::DnsNxDomain { .. }) => return Err(err),
+        Err(err) if err.is_temporary() => return Err(err),
+        _ => {}
+    }
+
+    let name = format!("_adsp.{}.{}", DNS_NAMESPACE, author_domain);
+    let records = match resolver.lookup_txt(&name).await {
+        Ok(records) => records,
+        Err(err) if err.is_temporary() => return Err(err),
+        Err(_) => return Ok(None),
+    };
+    // Several records are ignored, like an invalid one
+    let practice = match records.as_slice() {
+        [record] => parse_record(record),
+        _ => None,
+    };
+    debug!(logger, "ADSP practice of {}: {:?}", author_domain, practice);
+    Ok(practice)
+}
+
+/// Check the results of the signatures of a message from `author_domain`
+/// against the practice of the domain. The message passes if any of its
+/// signatures is valid and from the author domain itself, not from one of
+/// its parent domains. `dkim_results` are the results of every signature,
+/// as returned by [crate::dmarc::DmarcDkimResults::results].
+#[cfg(feature = "dns")]
+pub async fn check(
+    logger: &slog::Logger,
+    author_domain: &str,
+    dkim_results: &[DKIMResult],
+    resolver: Arc<dyn dns::Lookup>,
+) -> AdspVerdict {
+    let author_domain_signed = dkim_results.iter().any(|result| {
+        result.verdict() == Verdict::Pass
+            && normalize_domain(&result.domain_used()) == normalize_domain(author_domain)
+    });
+    if author_domain_signed {
+        return AdspVerdict::Pass;
+    }
+    match lookup_practice(logger, author_domain, resolver).await {
+        Ok(None) => AdspVerdict::None,
+        Ok(Some(Practice::Unknown)) => AdspVerdict::Unknown,
+        Ok(Some(Practice::All)) => AdspVerdict::Fail,
+        Ok(Some(Practice::Discardable)) => AdspVerdict::Discard,
+        Err(DKIMError::DnsNxDomain { .. }) => AdspVerdict::NxDomain,
+        Err(_) => AdspVerdict::TempError,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_record() {
+        assert_eq!(parse_record("dkim=all"), Some(Practice::All));
+        assert_eq!(
+            parse_record(" dkim = Discardable ; x=y"),
+            Some(Practice::Discardable)
+        );
+        assert_eq!(parse_record("dkim=sometimes"), Some(Practice::Unknown));
+        assert_eq!(parse_record("x=y"), None);
+        assert_eq!(parse_record("dkim"), None);
+    }
+
+    #[cfg(feature = "dns")]
+    #[tokio::test]
+    async fn test_check() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let resolver: Arc<dyn dns::Lookup> = Arc::new(
+            crate::test_utils::MockResolver::new()
+                .with_record("example.com", "v=spf1 -all")
+                .with_record("_adsp._domainkey.example.com", "dkim=discardable")
+                .with_record("example.org", "v=spf1 -all"),
+        );
+        let check = |domain: &'static str, results: Vec<DKIMResult>| {
+            let resolver = Arc::clone(&resolver);
+            let logger = logger.clone();
+            async move { check(&logger, domain, &results, resolver).await }
+        };
+
+        let failed =
+            |domain: &str| DKIMResult::fail(DKIMError::SignatureDidNotVerify, domain.to_owned());
+        let passed = |domain: &str| {
+            DKIMResult::pass(
+                domain.to_owned(),
+                crate::canonicalization::Type::Relaxed,
+                crate::canonicalization::Type::Relaxed,
+            )
+        };
+        assert_eq!(
+            check("example.com", vec![failed("example.com")]).await,
+            AdspVerdict::Discard
+        );
+        assert_eq!(
+            check("example.org", vec![failed("example.org")]).await,
+            AdspVerdict::None
+        );
+        assert_eq!(check("example.net", vec![]).await, AdspVerdict::NxDomain);
+        // A valid signature of another domain doesn't count
+        assert_eq!(
+            check("example.com", vec![passed("mailer.example")]).await,
+            AdspVerdict::Discard
+        );
+        assert_eq!(
+            check(
+                "example.com",
+                vec![failed("example.com"), passed("Example.com")]
+            )
+            .await,
+            AdspVerdict::Pass
+        );
+    }
+}
@@ -0,0 +1,539 @@
+//! Authenticated Received Chain (ARC) support: <https://datatracker.ietf.org/doc/html/rfc8617>
+
+use mailparse::MailHeaderMap;
+
+use crate::{parser, signature_value, DKIMError, DKIMResult, Verdict};
+
+pub const ARC_SEAL: &str = "ARC-Seal";
+pub const ARC_MESSAGE_SIGNATURE: &str = "ARC-Message-Signature";
+pub const ARC_AUTHENTICATION_RESULTS: &str = "ARC-Authentication-Results";
+
+/// Maximum number of ARC sets in a chain
+/// <https://datatracker.ietf.org/doc/html/rfc8617#section-4.2.1>
+pub const MAX_INSTANCE: u32 = 50;
+
+/// Chain validation status, as found in the `cv=` tag of the ARC-Seal
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChainValidationStatus {
+    None,
+    Pass,
+    Fail,
+}
+
+impl std::fmt::Display for ChainValidationStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::None => write!(f, "none"),
+            Self::Pass => write!(f, "pass"),
+            Self::Fail => write!(f, "fail"),
+        }
+    }
+}
+
+/// Status of a DKIM verification, for instance of an ARC-Message-Signature:
+/// `pass` and `fail` map to the same status, `neutral` (no signature) to
+/// `none`
+impl From<&DKIMResult> for ChainValidationStatus {
+    fn from(result: &DKIMResult) -> Self {
+        match result.verdict() {
+            Verdict::Pass => Self::Pass,
+            Verdict::Neutral => Self::None,
+            _ => Self::Fail,
+        }
+    }
+}
+
+impl std::str::FromStr for ChainValidationStatus {
+    type Err = DKIMError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_ascii_lowercase().as_str() {
+            "none" => Ok(Self::None),
+            "pass" => Ok(Self::Pass),
+            "fail" => Ok(Self::Fail),
+            v => Err(DKIMError::SignatureSyntaxError {
+                tag: Some("cv"),
+                value: v.to_owned(),
+                source: None,
+            }),
+        }
+    }
+}
+
+fn parse_tags(value: &str) -> Result<Vec<parser::Tag>, DKIMError> {
+    Ok(parser::complete_tag_list(value)?)
+}
+
+/// Parse the instance (`i=` tag) of an ARC header value
+pub(crate) fn parse_instance(value: &str) -> Result<u32, DKIMError> {
+    instance_from_tags(&parse_tags(value)?)
+}
+
+fn instance_from_tags(tags: &[parser::Tag]) -> Result<u32, DKIMError> {
+    let tag = tags
+        .iter()
+        .find(|tag| tag.name == "i")
+        .ok_or(DKIMError::SignatureMissingRequiredTag("i"))?;
+    match tag.value.parse::<u32>() {
+        Ok(i) if (1..=MAX_INSTANCE).contains(&i) => Ok(i),
+        _ => Err(DKIMError::SignatureSyntaxError {
+            tag: Some("i"),
+            value: tag.value.clone(),
+            source: None,
+        }),
+    }
+}
+
+/// Returns the instance number to use for the next ARC set added to the
+/// email, based on the existing ARC-Seal headers.
+pub fn next_instance<'a>(email: &'a mailparse::ParsedMail<'a>) -> Result<u32, DKIMError> {
+    let mut highest = 0;
+    for h in email.headers.get_all_headers(ARC_SEAL) {
+        highest = highest.max(parse_instance(signature_value(h)?)?);
+    }
+    instance_after(highest)
+}
+
+/// The instance following the highest existing one
+fn instance_after(highest: u32) -> Result<u32, DKIMError> {
+    if highest >= MAX_INSTANCE {
+        return Err(DKIMError::UnacceptableSignatureHeader);
+    }
+    Ok(highest + 1)
+}
+
+/// State of the ARC chain of a message. Tracks the existing instances and
+/// the validation of their seals to compute the `cv=` value of the next seal.
+#[derive(Debug, Clone)]
+pub struct ArcChainState {
+    /// `cv=` of the existing seals, ordered by instance
+    seals: Vec<ChainValidationStatus>,
+    /// Whether the instances of the existing seals are valid and contiguous
+    well_formed: bool,
+    /// Validation outcome of the existing seals, ordered by instance
+    validated: Vec<Option<bool>>,
+    /// Validation outcome of the ARC-Message-Signature of the newest instance
+    message_signature: Option<ChainValidationStatus>,
+    /// Highest instance of the existing seals, 0 without seals
+    highest_instance: u32,
+}
+
+/// Inputs of the next ARC set: its instance, the `cv=` of its seal and its
+/// ARC-Authentication-Results header
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SealInputs {
+    pub instance: u32,
+    pub cv: ChainValidationStatus,
+    pub authentication_results: String,
+}
+
+impl ArcChainState {
+    /// Builds the chain state from the ARC-Seal headers of the email
+    pub fn from_email<'a>(email: &'a mailparse::ParsedMail<'a>) -> Result<Self, DKIMError> {
+        let mut seals = vec![];
+        for h in email.headers.get_all_headers(ARC_SEAL) {
+            let tags = parse_tags(signature_value(h)?)?;
+            let instance = instance_from_tags(&tags)?;
+            let cv = tags
+                .iter()
+                .find(|tag| tag.name == "cv")
+                .ok_or(DKIMError::SignatureMissingRequiredTag("cv"))?
+                .value
+                .parse::<ChainValidationStatus>()?;
+            seals.push((instance, cv));
+        }
+        seals.sort_by_key(|(instance, _)| *instance);
+
+        // https://datatracker.ietf.org/doc/html/rfc8617#section-5.2 Step 2:
+        // instances must be contiguous from 1, the first seal must have
+        // cv=none and the following ones cv=pass.
+        let well_formed = seals.iter().enumerate().all(|(idx, (instance, cv))| {
+            *instance as usize == idx + 1
+                && if idx == 0 {
+                    *cv == ChainValidationStatus::None
+                } else {
+                    *cv == ChainValidationStatus::Pass
+                }
+        });
+
+        Ok(Self {
+            validated: vec![None; seals.len()],
+            message_signature: None,
+            highest_instance: seals.last().map_or(0, |(instance, _)| *instance),
+            seals: seals.into_iter().map(|(_, cv)| cv).collect(),
+            well_formed,
+        })
+    }
+
+    /// Number of existing ARC sets
+    pub fn instances(&self) -> u32 {
+        self.seals.len() as u32
+    }
+
+    /// Instance number of the next ARC set, following the highest existing
+    /// instance as [next_instance]
+    pub fn next_instance(&self) -> Result<u32, DKIMError> {
+        instance_after(self.highest_instance)
+    }
+
+    /// Record the outcome of the validation of the seal for an existing
+    /// instance
+    pub fn record_seal_validation(&mut self, instance: u32, passed: bool) -> Result<(), DKIMError> {
+        let slot = (instance as usize)
+            .checked_sub(1)
+            .and_then(|idx| self.validated.get_mut(idx))
+            .ok_or_else(|| DKIMError::SignatureSyntaxError {
+                tag: Some("i"),
+                value: instance.to_string(),
+                source: None,
+            })?;
+        *slot = Some(passed);
+        Ok(())
+    }
+
+    /// Record the outcome of the validation of the ARC-Message-Signature of
+    /// the newest instance, which must pass for the chain to pass
+    pub fn record_message_signature_validation(&mut self, result: &DKIMResult) {
+        self.message_signature = Some(result.into());
+    }
+
+    /// Computes the `cv=` value of the next seal. The chain only passes if it
+    /// is well formed and all the existing seals and the newest
+    /// ARC-Message-Signature were validated successfully; a seal or message
+    /// signature that wasn't validated is considered failed.
+    pub fn next_cv(&self) -> ChainValidationStatus {
+        if self.seals.is_empty() {
+            return ChainValidationStatus::None;
+        }
+        let all_validated = self.validated.iter().all(|v| *v == Some(true));
+        let message_signature_passed = self.message_signature == Some(ChainValidationStatus::Pass);
+        if self.well_formed && all_validated && message_signature_passed {
+            ChainValidationStatus::Pass
+        } else {
+            ChainValidationStatus::Fail
+        }
+    }
+
+    /// Inputs of the next ARC set, from the validation outcomes recorded in
+    /// the chain state and the results of the local DKIM verifications. The
+    /// ARC-Authentication-Results header reports the DKIM results and the
+    /// chain validation (`arc=`).
+    pub fn seal_inputs(
+        &self,
+        authserv_id: &str,
+        dkim_results: &[DKIMResult],
+    ) -> Result<SealInputs, DKIMError> {
+        let instance = self.next_instance()?;
+        let cv = self.next_cv();
+        let authentication_results = dkim_results
+            .iter()
+            .fold(
+                ArcAuthenticationResultsBuilder::new()
+                    .with_instance(instance)
+                    .with_authserv_id(authserv_id),
+                |builder, result| builder.with_dkim_result(result),
+            )
+            .with_arc(cv)
+            .build()?;
+        Ok(SealInputs {
+            instance,
+            cv,
+            authentication_results,
+        })
+    }
+}
+
+/// Builder for the ARC-Authentication-Results header
+pub struct ArcAuthenticationResultsBuilder<'a> {
+    instance: Option<u32>,
+    authserv_id: Option<&'a str>,
+    results: Vec<String>,
+}
+
+impl<'a> ArcAuthenticationResultsBuilder<'a> {
+    /// New builder
+    pub fn new() -> Self {
+        Self {
+            instance: None,
+            authserv_id: None,
+            results: vec![],
+        }
+    }
+
+    /// Specify the instance of the ARC set. See [next_instance].
+    pub fn with_instance(mut self, value: u32) -> Self {
+        self.instance = Some(value);
+        self
+    }
+
+    /// Specify the instance of the ARC set from the existing ARC headers of
+    /// the email
+    pub fn with_instance_from<'b>(
+        self,
+        email: &'b mailparse::ParsedMail<'b>,
+    ) -> Result<Self, DKIMError> {
+        Ok(self.with_instance(next_instance(email)?))
+    }
+
+    /// Specify the authentication service identifier, usually the hostname
+    /// of the receiving server
+    pub fn with_authserv_id(mut self, value: &'a str) -> Self {
+        self.authserv_id = Some(value);
+        self
+    }
+
+    /// Add the result of the local DKIM verification
+    pub fn with_dkim_result(mut self, result: &DKIMResult) -> Self {
+        let mut out = format!("dkim={}", result.summary());
+        if let Some(err) = result.error() {
+            out += &format!(" ({})", err);
+        }
+        out += &format!(" header.d={}", result.domain_used());
+        self.results.push(out);
+        self
+    }
+
+    /// Add the result of the validation of the existing ARC chain
+    pub fn with_arc(mut self, cv: ChainValidationStatus) -> Self {
+        self.results.push(format!("arc={}", cv));
+        self
+    }
+
+    /// Add a SPF result, with the domain of the envelope sender
+    pub fn with_spf(mut self, result: &str, mailfrom: &str) -> Self {
+        self.results
+            .push(format!("spf={} smtp.mailfrom={}", result, mailfrom));
+        self
+    }
+
+    /// Add the result of a SPF check, see [crate::spf]
+    #[cfg(feature = "spf")]
+    pub fn with_spf_result(mut self, result: &crate::spf::SpfResult) -> Self {
+        self.results.push(format!(
+            "spf={} {}={}",
+            result,
+            result.identity().property(),
+            result.domain()
+        ));
+        self
+    }
+
+    /// Add a DMARC result, with the domain of the From header
+    pub fn with_dmarc(mut self, result: &str, header_from: &str) -> Self {
+        self.results
+            .push(format!("dmarc={} header.from={}", result, header_from));
+        self
+    }
+
+    /// Build the ARC-Authentication-Results header
+    /// Must be provided: instance and authserv_id.
+    pub fn build(self) -> Result<String, DKIMError> {
+        use DKIMError::BuilderError;
+
+        let instance = self
+            .instance
+            .ok_or(BuilderError("missing required instance"))?;
+        if !(1..=MAX_INSTANCE).contains(&instance) {
+            return Err(BuilderError("instance out of range"));
+        }
+        let authserv_id = self
+            .authserv_id
+            .ok_or(BuilderError("missing required authserv-id"))?;
+
+        let results = if self.results.is_empty() {
+            "none".to_owned()
+        } else {
+            self.results.join("; ")
+        };
+
+        Ok(format!(
+            "{}: i={}; {}; {}",
+            ARC_AUTHENTICATION_RESULTS, instance, authserv_id, results
+        ))
+    }
+}
+
+impl<'a> Default for ArcAuthenticationResultsBuilder<'a> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::canonicalization::Type;
+
+    #[test]
+    fn test_next_instance() {
+        let email = mailparse::parse_mail(b"From: a@example.com\r\n\r\nbody").unwrap();
+        assert_eq!(next_instance(&email).unwrap(), 1);
+
+        let email = mailparse::parse_mail(
+            b"ARC-Seal: i=2; cv=pass; a=rsa-sha256\r\nARC-Seal: i=1; cv=none\r\n\r\nbody",
+        )
+        .unwrap();
+        assert_eq!(next_instance(&email).unwrap(), 3);
+
+        let email = mailparse::parse_mail(b"ARC-Seal: i=51; cv=pass\r\n\r\nbody").unwrap();
+        assert!(next_instance(&email).is_err());
+    }
+
+    #[test]
+    fn test_chain_state_empty() {
+        let email = mailparse::parse_mail(b"From: a@example.com\r\n\r\nbody").unwrap();
+        let state = ArcChainState::from_email(&email).unwrap();
+        assert_eq!(state.next_instance().unwrap(), 1);
+        assert_eq!(state.next_cv(), ChainValidationStatus::None);
+    }
+
+    #[test]
+    fn test_chain_state() {
+        let email = mailparse::parse_mail(
+            b"ARC-Seal: i=2; cv=pass; a=rsa-sha256\r\nARC-Seal: i=1; cv=none\r\n\r\nbody",
+        )
+        .unwrap();
+        let mut state = ArcChainState::from_email(&email).unwrap();
+        assert_eq!(state.next_instance().unwrap(), 3);
+        // seals not validated yet
+        assert_eq!(state.next_cv(), ChainValidationStatus::Fail);
+
+        state.record_seal_validation(1, true).unwrap();
+        state.record_seal_validation(2, true).unwrap();
+        // message signature not validated
+        assert_eq!(state.next_cv(), ChainValidationStatus::Fail);
+
+        state.record_message_signature_validation(&DKIMResult::pass(
+            "example.com".to_owned(),
+            Type::Relaxed,
+            Type::Relaxed,
+        ));
+        assert_eq!(state.next_cv(), ChainValidationStatus::Pass);
+
+        state.record_seal_validation(2, false).unwrap();
+        assert_eq!(state.next_cv(), ChainValidationStatus::Fail);
+
+        assert!(state.record_seal_validation(3, true).is_err());
+    }
+
+    #[test]
+    fn test_chain_state_broken() {
+        // The next instance follows the highest one, whatever the gaps
+        let email =
+            mailparse::parse_mail(b"ARC-Seal: i=3; cv=pass\r\nARC-Seal: i=1; cv=none\r\n\r\nbody")
+                .unwrap();
+        let state = ArcChainState::from_email(&email).unwrap();
+        assert_eq!(
+            state.next_instance().unwrap(),
+            next_instance(&email).unwrap()
+        );
+        assert_eq!(state.next_instance().unwrap(), 4);
+
+        for raw in [
+            &b"ARC-Seal: i=2; cv=pass\r\n\r\nbody"[..],
+            &b"ARC-Seal: i=1; cv=pass\r\n\r\nbody"[..],
+            &b"ARC-Seal: i=2; cv=fail\r\nARC-Seal: i=1; cv=none\r\n\r\nbody"[..],
+        ] {
+            let email = mailparse::parse_mail(raw).unwrap();
+            let mut state = ArcChainState::from_email(&email).unwrap();
+            for i in 1..=state.instances() {
+                state.record_seal_validation(i, true).unwrap_or_default();
+            }
+            assert_eq!(state.next_cv(), ChainValidationStatus::Fail);
+        }
+    }
+
+    #[test]
+    fn test_seal_inputs() {
+        let pass = DKIMResult::pass("example.com".to_owned(), Type::Relaxed, Type::Relaxed);
+        let fail = DKIMResult::fail(DKIMError::BodyHashDidNotVerify, "example.org".to_owned());
+        assert_eq!(
+            ChainValidationStatus::from(&pass),
+            ChainValidationStatus::Pass
+        );
+        assert_eq!(
+            ChainValidationStatus::from(&DKIMResult::neutral("example.com".to_owned())),
+            ChainValidationStatus::None
+        );
+
+        let email = mailparse::parse_mail(b"From: a@example.com\r\n\r\nbody").unwrap();
+        let inputs = ArcChainState::from_email(&email)
+            .unwrap()
+            .seal_inputs("mx.example.org", std::slice::from_ref(&pass))
+            .unwrap();
+        assert_eq!(
+            inputs,
+            SealInputs {
+                instance: 1,
+                cv: ChainValidationStatus::None,
+                authentication_results: "ARC-Authentication-Results: i=1; mx.example.org; dkim=pass header.d=example.com; arc=none".to_owned(),
+            }
+        );
+
+        let email = mailparse::parse_mail(b"ARC-Seal: i=1; cv=none\r\n\r\nbody").unwrap();
+        let mut state = ArcChainState::from_email(&email).unwrap();
+        state.record_seal_validation(1, true).unwrap();
+        state.record_message_signature_validation(&pass);
+        let inputs = state
+            .seal_inputs("mx.example.org", &[pass, fail.clone()])
+            .unwrap();
+        assert_eq!(
+            (inputs.instance, inputs.cv),
+            (2, ChainValidationStatus::Pass)
+        );
+        assert_eq!(inputs.authentication_results, "ARC-Authentication-Results: i=2; mx.example.org; dkim=pass header.d=example.com; dkim=fail (body hash did not verify) header.d=example.org; arc=pass");
+
+        state.record_message_signature_validation(&fail);
+        assert_eq!(
+            state.seal_inputs("mx.example.org", &[]).unwrap().cv,
+            ChainValidationStatus::Fail
+        );
+    }
+
+    #[test]
+    fn test_arc_authentication_results_builder() {
+        let email = mailparse::parse_mail(b"ARC-Seal: i=1; cv=none\r\n\r\nbody").unwrap();
+        let dkim = DKIMResult::pass("example.com".to_owned(), Type::Relaxed, Type::Relaxed);
+
+        let header = ArcAuthenticationResultsBuilder::new()
+            .with_instance_from(&email)
+            .unwrap()
+            .with_authserv_id("mx.example.org")
+            .with_dkim_result(&dkim)
+            .with_spf("pass", "example.com")
+            .with_dmarc("pass", "example.com")
+            .build()
+            .unwrap();
+        assert_eq!(header, "ARC-Authentication-Results: i=2; mx.example.org; dkim=pass header.d=example.com; spf=pass smtp.mailfrom=example.com; dmarc=pass header.from=example.com");
+
+        let header = ArcAuthenticationResultsBuilder::new()
+            .with_instance(1)
+            .with_authserv_id("mx.example.org")
+            .with_dkim_result(&DKIMResult::fail(
+                DKIMError::BodyHashDidNotVerify,
+                "example.com".to_owned(),
+            ))
+            .build()
+            .unwrap();
+        assert_eq!(header, "ARC-Authentication-Results: i=1; mx.example.org; dkim=fail (body hash did not verify) header.d=example.com");
+    }
+
+    #[test]
+    fn test_arc_authentication_results_builder_missing() {
+        assert_eq!(
+            ArcAuthenticationResultsBuilder::new()
+                .with_authserv_id("mx")
+                .build()
+                .unwrap_err(),
+            DKIMError::BuilderError("missing required instance")
+        );
+        assert_eq!(
+            ArcAuthenticationResultsBuilder::new()
+                .with_instance(51)
+                .with_authserv_id("mx")
+                .build()
+                .unwrap_err(),
+            DKIMError::BuilderError("instance out of range")
+        );
+    }
+}
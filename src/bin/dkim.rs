@@ -0,0 +1,430 @@
+//! Command line tool to manage DKIM keys, sign messages and check the
+//! published key records
+
+use std::io::{Read, Write};
+use std::process::ExitCode;
+
+use base64::engine::general_purpose;
+use base64::Engine;
+use cfdkim::public_key::{self, PublicationIssue};
+use cfdkim::{dns, DkimPrivateKey, DkimPublicKey, SignerBuilder};
+use clap::{value_parser, Arg, ArgAction, ArgMatches, Command};
+use rsa::pkcs1::DecodeRsaPublicKey;
+use rsa::pkcs1::{DecodeRsaPrivateKey, EncodeRsaPrivateKey, LineEnding};
+use rsa::pkcs8::{DecodePrivateKey, DecodePublicKey};
+use rsa::rand_core::{OsRng, RngCore};
+
+/// Maximum length of a character-string of a TXT record
+const TXT_STRING_LEN: usize = 255;
+
+fn cli() -> Command {
+    Command::new("dkim")
+        .about("Manage DKIM keys and sign messages")
+        .subcommand_required(true)
+        .subcommand(
+            Command::new("keygen")
+                .about("Generate a key pair and print the DNS TXT record to publish")
+                .arg(
+                    Arg::new("type")
+                        .long("type")
+                        .value_parser(["ed25519", "rsa"])
+                        .default_value("rsa"),
+                )
+                .arg(
+                    Arg::new("bits")
+                        .long("bits")
+                        .help("Size of RSA keys")
+                        .value_parser(value_parser!(usize))
+                        .default_value("2048"),
+                )
+                .arg(Arg::new("selector").long("selector").required(true))
+                .arg(Arg::new("domain").long("domain").required(true))
+                .arg(
+                    Arg::new("out")
+                        .long("out")
+                        .help("File of the private key, <selector>.private by default"),
+                ),
+        )
+        .subcommand(
+            Command::new("sign")
+                .about("Sign a message and output it with its DKIM-Signature header")
+                .arg(
+                    Arg::new("key")
+                        .long("key")
+                        .required(true)
+                        .help("RSA key in PEM format or base64 encoded Ed25519 key"),
+                )
+                .arg(Arg::new("domain").long("domain").required(true))
+                .arg(Arg::new("selector").long("selector").required(true))
+                .arg(
+                    Arg::new("headers")
+                        .long("headers")
+                        .value_delimiter(',')
+                        .help("Signed headers, the recommended ones by default"),
+                )
+                .arg(
+                    Arg::new("header-only")
+                        .long("header-only")
+                        .action(ArgAction::SetTrue)
+                        .help("Only output the DKIM-Signature header"),
+                )
+                .arg(
+                    Arg::new("message")
+                        .help("File of the message, standard input by default or with -"),
+                ),
+        )
+        .subcommand(
+            Command::new("dns-check")
+                .about("Check the key record published for a selector")
+                .arg(Arg::new("domain").long("domain").required(true))
+                .arg(Arg::new("selector").long("selector").required(true))
+                .arg(Arg::new("expect").long("expect").help(
+                    "Key expected in the record: a key record, a public key in PEM format, \
+                     a base64 encoded Ed25519 public key or a RSA private key",
+                )),
+        )
+}
+
+fn main() -> ExitCode {
+    let matches = cli().get_matches();
+    let result = match matches.subcommand() {
+        Some(("keygen", matches)) => keygen(matches),
+        Some(("sign", matches)) => sign(matches),
+        Some(("dns-check", matches)) => dns_check(matches),
+        _ => unreachable!("a subcommand is required"),
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("error: {}", err);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn keygen(matches: &ArgMatches) -> Result<(), String> {
+    let selector = matches.get_one::<String>("selector").expect("required");
+    let domain = matches.get_one::<String>("domain").expect("required");
+    let out = matches
+        .get_one::<String>("out")
+        .cloned()
+        .unwrap_or_else(|| format!("{}.private", selector));
+
+    let (private_key, encoded) = match matches.get_one::<String>("type").map(String::as_str) {
+        Some("ed25519") => {
+            let mut secret_key = [0u8; 32];
+            OsRng.fill_bytes(&mut secret_key);
+            let encoded = general_purpose::STANDARD.encode(secret_key);
+            let signing_key = ed25519_dalek::SigningKey::from_bytes(&secret_key);
+            (DkimPrivateKey::Ed25519(signing_key), encoded)
+        }
+        _ => {
+            let bits = *matches.get_one::<usize>("bits").expect("defaulted");
+            if bits < 1024 {
+                return Err(format!("RSA keys must be at least 1024 bits, got {}", bits));
+            }
+            let private_key = rsa::RsaPrivateKey::new(&mut OsRng, bits)
+                .map_err(|err| format!("failed to generate key: {}", err))?;
+            let encoded = private_key
+                .to_pkcs1_pem(LineEnding::LF)
+                .map_err(|err| format!("failed to encode key: {}", err))?
+                .to_string();
+            (DkimPrivateKey::Rsa(private_key), encoded)
+        }
+    };
+
+    write_private_key(&out, &encoded).map_err(|err| format!("failed to write {}: {}", out, err))?;
+    eprintln!("private key written to {}", out);
+    println!(
+        "{}",
+        zone_record(
+            selector,
+            domain,
+            &private_key.to_public_key().to_key_record()
+        )
+    );
+    Ok(())
+}
+
+fn sign(matches: &ArgMatches) -> Result<(), String> {
+    let key_path = matches.get_one::<String>("key").expect("required");
+    let domain = matches.get_one::<String>("domain").expect("required");
+    let selector = matches.get_one::<String>("selector").expect("required");
+    let raw_email = read_message(matches.get_one::<String>("message").map(String::as_str))?;
+    // Messages with LF line endings are signed as they will be relayed over
+    // SMTP, with CRLF line endings
+    let crlf = raw_email.windows(2).any(|w| w == b"\r\n");
+    let relayed = if crlf {
+        raw_email.clone()
+    } else {
+        to_crlf(&raw_email)
+    };
+    let email = mailparse::parse_mail(&relayed)
+        .map_err(|err| format!("failed to parse message: {}", err))?;
+
+    let logger = slog::Logger::root(slog::Discard, slog::o!());
+    let mut builder = SignerBuilder::recommended();
+    if let Some(headers) = matches.get_many::<String>("headers") {
+        let headers: Vec<&String> = headers.collect();
+        builder = builder
+            .with_signed_headers(&headers)
+            .map_err(|err| err.to_string())?;
+    }
+    let signer = builder
+        .with_private_key(load_private_key(key_path)?)
+        .with_signing_domain(domain.as_str())
+        .with_selector(selector.as_str())
+        .with_logger(&logger)
+        .build()
+        .map_err(|err| err.to_string())?;
+    let header = signer.sign(&email).map_err(|err| err.to_string())?;
+
+    // Keep the line endings of the message
+    let (header, line_ending) = if crlf {
+        (header, "\r\n")
+    } else {
+        (header.replace("\r\n", "\n"), "\n")
+    };
+    let mut stdout = std::io::stdout().lock();
+    let written = if matches.get_flag("header-only") {
+        write!(stdout, "{}{}", header, line_ending)
+    } else {
+        write!(stdout, "{}{}", header, line_ending).and_then(|_| stdout.write_all(&raw_email))
+    };
+    written.map_err(|err| format!("failed to write message: {}", err))
+}
+
+fn dns_check(matches: &ArgMatches) -> Result<(), String> {
+    let domain = matches.get_one::<String>("domain").expect("required");
+    let selector = matches.get_one::<String>("selector").expect("required");
+    let expected = matches
+        .get_one::<String>("expect")
+        .map(|path| load_public_key(path))
+        .transpose()?;
+
+    let logger = slog::Logger::root(slog::Discard, slog::o!());
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .map_err(|err| format!("failed to start runtime: {}", err))?;
+    let check = runtime.block_on(async {
+        let resolver = trust_dns_resolver::TokioAsyncResolver::tokio_from_system_conf()
+            .map_err(|err| format!("failed to create DNS resolver: {}", err))?;
+        let resolver = dns::from_tokio_resolver(resolver);
+        let check = match &expected {
+            Some(expected) => {
+                public_key::check_published(&logger, resolver, domain, selector, expected).await
+            }
+            None => public_key::check_key_record(&logger, resolver, domain, selector).await,
+        };
+        check.map_err(|err| format!("failed to look up the key record: {}", err))
+    })?;
+
+    println!("{}", check.name);
+    for record in &check.records {
+        println!("  {}", record);
+    }
+    if check.is_ok() {
+        println!("ok");
+        return Ok(());
+    }
+    for issue in &check.issues {
+        println!("problem: {}", describe_issue(issue));
+    }
+    Err(format!("{} problem(s) found", check.issues.len()))
+}
+
+fn describe_issue(issue: &PublicationIssue) -> String {
+    match issue {
+        PublicationIssue::MissingRecord => "no key record is published".to_owned(),
+        PublicationIssue::InvalidRecord(err) => format!("invalid key record: {}", err),
+        PublicationIssue::WrongKey => "the published key isn't the expected one".to_owned(),
+        PublicationIssue::WeakKey(size) => {
+            format!(
+                "the RSA key has {} bits, at least 2048 are recommended",
+                size
+            )
+        }
+        PublicationIssue::Testing => "the testing flag (t=y) is set".to_owned(),
+        PublicationIssue::WrongServiceType(services) => {
+            format!("the service types (s={}) don't include email", services)
+        }
+        PublicationIssue::WrongHashAlgorithms(hashes) => {
+            format!("the hash algorithms (h={}) don't include sha256", hashes)
+        }
+        PublicationIssue::ExtraRecord(record) => format!("other key record: {}", record),
+    }
+}
+
+/// Load the expected public key of `dns-check`
+fn load_public_key(path: &str) -> Result<DkimPublicKey, String> {
+    let content =
+        std::fs::read_to_string(path).map_err(|err| format!("failed to read {}: {}", path, err))?;
+    parse_public_key(content.trim()).map_err(|err| format!("invalid key in {}: {}", path, err))
+}
+
+fn parse_public_key(content: &str) -> Result<DkimPublicKey, String> {
+    if content.contains("PRIVATE KEY-----") {
+        return parse_private_key(content).map(|key| key.to_public_key());
+    }
+    if content.starts_with("-----BEGIN") {
+        return rsa::RsaPublicKey::from_public_key_pem(content)
+            .or_else(|_| rsa::RsaPublicKey::from_pkcs1_pem(content))
+            .map(DkimPublicKey::Rsa)
+            .map_err(|err| err.to_string());
+    }
+    if content.contains("p=") {
+        // Key record, possibly in the zone file format printed by keygen
+        let record: String = if content.contains('"') {
+            content.split('"').skip(1).step_by(2).collect()
+        } else {
+            content.to_owned()
+        };
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        return public_key::parse_public_key(&logger, &record).map_err(|err| err.to_string());
+    }
+    let bytes = general_purpose::STANDARD
+        .decode(content)
+        .map_err(|err| err.to_string())?;
+    DkimPublicKey::try_from_bytes(&bytes, "ed25519").map_err(|err| err.to_string())
+}
+
+fn to_crlf(raw_email: &[u8]) -> Vec<u8> {
+    let mut converted = Vec::with_capacity(raw_email.len());
+    for byte in raw_email {
+        if *byte == b'\n' {
+            converted.push(b'\r');
+        }
+        converted.push(*byte);
+    }
+    converted
+}
+
+/// Read the message from a file, or from the standard input when the path is
+/// missing or `-`
+fn read_message(path: Option<&str>) -> Result<Vec<u8>, String> {
+    match path {
+        None | Some("-") => {
+            let mut raw_email = vec![];
+            std::io::stdin()
+                .read_to_end(&mut raw_email)
+                .map_err(|err| format!("failed to read message: {}", err))?;
+            Ok(raw_email)
+        }
+        Some(path) => {
+            std::fs::read(path).map_err(|err| format!("failed to read {}: {}", path, err))
+        }
+    }
+}
+
+/// Load a private key written by `keygen`: a RSA key in PEM format, PKCS#1 or
+/// PKCS#8, or a base64 encoded Ed25519 key
+fn load_private_key(path: &str) -> Result<DkimPrivateKey, String> {
+    let content =
+        std::fs::read_to_string(path).map_err(|err| format!("failed to read {}: {}", path, err))?;
+    parse_private_key(content.trim()).map_err(|err| format!("invalid key in {}: {}", path, err))
+}
+
+fn parse_private_key(content: &str) -> Result<DkimPrivateKey, String> {
+    if content.starts_with("-----BEGIN") {
+        return rsa::RsaPrivateKey::from_pkcs1_pem(content)
+            .or_else(|_| rsa::RsaPrivateKey::from_pkcs8_pem(content))
+            .map(DkimPrivateKey::Rsa)
+            .map_err(|err| err.to_string());
+    }
+    let secret_key: [u8; 32] = general_purpose::STANDARD
+        .decode(content)
+        .map_err(|err| err.to_string())?
+        .try_into()
+        .map_err(|_| "Ed25519 keys are 32 bytes long".to_owned())?;
+    Ok(DkimPrivateKey::Ed25519(
+        ed25519_dalek::SigningKey::from_bytes(&secret_key),
+    ))
+}
+
+/// Write the key to a new file, only readable by its owner
+fn write_private_key(path: &str, encoded: &str) -> std::io::Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(path)?;
+    writeln!(file, "{}", encoded.trim_end())
+}
+
+/// The key record as a zone file line, split in strings of up to 255
+/// characters
+fn zone_record(selector: &str, domain: &str, record: &str) -> String {
+    let strings: Vec<String> = record
+        .as_bytes()
+        .chunks(TXT_STRING_LEN)
+        .map(|chunk| format!("\"{}\"", String::from_utf8_lossy(chunk)))
+        .collect();
+    format!(
+        "{}._domainkey.{}.\tIN\tTXT\t( {} )",
+        selector,
+        domain,
+        strings.join("\n\t  ")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_zone_record() {
+        assert_eq!(
+            zone_record("s1", "example.com", "v=DKIM1; k=ed25519; p=key"),
+            "s1._domainkey.example.com.\tIN\tTXT\t( \"v=DKIM1; k=ed25519; p=key\" )"
+        );
+
+        let record = format!("v=DKIM1; k=rsa; p={}", "A".repeat(300));
+        let zone = zone_record("s1", "example.com", &record);
+        assert_eq!(zone.matches('"').count(), 4);
+        let strings: String = zone.split('"').skip(1).step_by(2).collect();
+        assert_eq!(strings, record);
+    }
+
+    #[test]
+    fn test_parse_private_key() {
+        let rsa = std::fs::read_to_string("./test/keys/2022.private").unwrap();
+        assert!(matches!(
+            parse_private_key(rsa.trim()),
+            Ok(DkimPrivateKey::Rsa(_))
+        ));
+        let ed25519 = std::fs::read_to_string("./test/keys/ed.private").unwrap();
+        assert!(matches!(
+            parse_private_key(ed25519.trim()),
+            Ok(DkimPrivateKey::Ed25519(_))
+        ));
+        assert!(parse_private_key("aGVsbG8=").is_err());
+    }
+
+    #[test]
+    fn test_parse_public_key() {
+        let zone = std::fs::read_to_string("./test/keys/2022.txt").unwrap();
+        let private_key = std::fs::read_to_string("./test/keys/2022.private").unwrap();
+        assert_eq!(
+            parse_public_key(zone.trim()),
+            parse_private_key(private_key.trim()).map(|key| key.to_public_key())
+        );
+        assert_eq!(
+            parse_public_key(private_key.trim()),
+            parse_private_key(private_key.trim()).map(|key| key.to_public_key())
+        );
+        let ed25519 = std::fs::read_to_string("./test/keys/ed.public").unwrap();
+        assert!(matches!(
+            parse_public_key(ed25519.trim()),
+            Ok(DkimPublicKey::Ed25519(_))
+        ));
+    }
+
+    #[test]
+    fn test_cli() {
+        cli().debug_assert();
+    }
+}
@@ -0,0 +1,201 @@
+//! Verification of many messages at once
+
+#[cfg(feature = "dns")]
+use futures::stream::{Stream, StreamExt};
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+#[cfg(feature = "dns")]
+use std::sync::Arc;
+
+#[cfg(feature = "dns")]
+use crate::{dns, verify_email_with_resolver};
+use crate::{header_from_domain, DKIMError, DKIMResult, ErrorSource};
+#[cfg(feature = "parallel")]
+use crate::{public_key, verify_email_with_key, Verdict};
+
+/// Names whose records are cached by [verify_stream], per record type
+#[cfg(feature = "dns")]
+const DNS_CACHE_CAPACITY: usize = 10_000;
+/// How long [verify_stream] caches the records, the stream may last longer
+/// than the records are valid
+#[cfg(feature = "dns")]
+const DNS_CACHE_TTL: std::time::Duration = std::time::Duration::from_secs(300);
+
+/// Verify a stream of raw messages, for instance coming from a queue, with up
+/// to `concurrency` messages verified at the same time. The DNS records are
+/// cached and shared between the messages of the stream. The results are
+/// yielded in the order of the messages.
+#[cfg(feature = "dns")]
+pub fn verify_stream<'a, S>(
+    logger: &'a slog::Logger,
+    resolver: Arc<dyn dns::Lookup>,
+    messages: S,
+    concurrency: usize,
+) -> impl Stream<Item = Result<DKIMResult, DKIMError>> + 'a
+where
+    S: Stream + 'a,
+    S::Item: AsRef<[u8]>,
+{
+    let resolver = dns::cached(resolver, DNS_CACHE_CAPACITY, DNS_CACHE_TTL);
+    messages
+        .map(move |raw_email| {
+            let resolver = Arc::clone(&resolver);
+            async move {
+                let email = parse_email(raw_email.as_ref())?;
+                let from_domain = header_from_domain(&email)?;
+                verify_email_with_resolver(logger, &from_domain, &email, resolver).await
+            }
+        })
+        .buffered(concurrency.max(1))
+}
+
+/// Verify archived messages with their already fetched key record (the
+/// content of the TXT DNS record of the signature selector), across CPU
+/// cores. Like [crate::verify_email_with_key], the signature of the domain in
+/// the From header is verified. The results are in the order of `messages`.
+#[cfg(feature = "parallel")]
+pub fn verify_all_parallel<M, K>(
+    logger: &slog::Logger,
+    messages: &[(M, K)],
+) -> Vec<Result<DKIMResult, DKIMError>>
+where
+    M: AsRef<[u8]> + Sync,
+    K: AsRef<str> + Sync,
+{
+    messages
+        .par_iter()
+        .map(|(raw_email, key_record)| verify(logger, raw_email.as_ref(), key_record.as_ref()))
+        .collect()
+}
+
+#[cfg(feature = "parallel")]
+fn verify(
+    logger: &slog::Logger,
+    raw_email: &[u8],
+    key_record: &str,
+) -> Result<DKIMResult, DKIMError> {
+    let email = parse_email(raw_email)?;
+    let from_domain = header_from_domain(&email)?;
+    let record = public_key::parse_key_record(logger, key_record)?;
+    let result = verify_email_with_key(logger, &from_domain, &email, record.key)?;
+    Ok(if result.verdict() == Verdict::Neutral {
+        result
+    } else {
+        result.with_key_record(record.testing, record.notes)
+    })
+}
+
+fn parse_email(raw_email: &[u8]) -> Result<mailparse::ParsedMail<'_>, DKIMError> {
+    mailparse::parse_mail(raw_email).map_err(|err| DKIMError::MalformedEmail {
+        reason: "invalid message",
+        source: Some(ErrorSource::new(err)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ed25519_email() -> String {
+        r#"DKIM-Signature: v=1; a=ed25519-sha256; c=relaxed/relaxed;
+ d=football.example.com; i=@football.example.com;
+ q=dns/txt; s=brisbane; t=1528637909; h=from : to :
+ subject : date : message-id : from : subject : date;
+ bh=2jUSOH9NhtVGCQWNr9BrIAPreKQjO6Sn7XIkfJVOzv8=;
+ b=/gCrinpcQOoIfuHNQIbq4pgh9kyIK3AQUdt9OdqQehSwhEIug4D11Bus
+ Fa3bT3FY5OsU7ZbnKELq+eXdp1Q1Dw==
+From: Joe SixPack <joe@football.example.com>
+To: Suzie Q <suzie@shopping.example.net>
+Subject: Is dinner ready?
+Date: Fri, 11 Jul 2003 21:00:37 -0700 (PDT)
+Message-ID: <20030712040037.46341.5F8J@football.example.com>
+
+Hi.
+
+We lost the game. Are you hungry yet?
+
+Joe."#
+            .replace('\n', "\r\n")
+    }
+
+    const ED25519_KEY_RECORD: &str =
+        "v=DKIM1; k=ed25519; p=11qYAYKxCrfVS/7TyWQHOg7hcvPapiMlrwIaaPcHURo=";
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn test_verify_all_parallel() {
+        let raw_email = ed25519_email();
+        let key_record = ED25519_KEY_RECORD;
+        let tampered = raw_email.replace("Joe.", "Bob.");
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+
+        let results = verify_all_parallel(
+            &logger,
+            &[
+                (raw_email.as_str(), key_record),
+                (tampered.as_str(), key_record),
+                (raw_email.as_str(), "v=DKIM1; k=foo; p=key"),
+            ],
+        );
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0].as_ref().unwrap().summary(), "pass");
+        assert_eq!(
+            results[1].as_ref().unwrap().error(),
+            Some(DKIMError::BodyHashDidNotVerify)
+        );
+        assert!(matches!(
+            results[2],
+            Err(DKIMError::InappropriateKeyAlgorithm { .. })
+        ));
+    }
+
+    #[cfg(feature = "dns")]
+    #[tokio::test]
+    async fn test_verify_stream() {
+        use futures::future::BoxFuture;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        struct TestResolver {
+            count: AtomicUsize,
+        }
+        impl dns::Lookup for TestResolver {
+            fn lookup_txt<'a>(
+                &'a self,
+                name: &'a str,
+            ) -> BoxFuture<'a, Result<Vec<String>, DKIMError>> {
+                self.count.fetch_add(1, Ordering::SeqCst);
+                assert_eq!(name, "brisbane._domainkey.football.example.com");
+                Box::pin(futures::future::ready(Ok(vec![
+                    ED25519_KEY_RECORD.to_owned()
+                ])))
+            }
+        }
+
+        let resolver = Arc::new(TestResolver {
+            count: AtomicUsize::new(0),
+        });
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let raw_email = ed25519_email();
+        let messages = vec![
+            raw_email.clone(),
+            "Subject: no From\r\n\r\n".to_owned(),
+            raw_email.clone(),
+            raw_email,
+        ];
+
+        let results: Vec<_> = verify_stream(
+            &logger,
+            resolver.clone(),
+            futures::stream::iter(messages),
+            2,
+        )
+        .collect()
+        .await;
+        assert_eq!(results.len(), 4);
+        assert_eq!(results[0].as_ref().unwrap().summary(), "pass");
+        assert!(matches!(results[1], Err(DKIMError::MalformedEmail { .. })));
+        assert_eq!(results[2].as_ref().unwrap().summary(), "pass");
+        assert_eq!(results[3].as_ref().unwrap().summary(), "pass");
+        assert!(resolver.count.load(Ordering::SeqCst) < 3);
+    }
+}
@@ -16,30 +16,18 @@ pub(crate) fn find(bytes: &[u8], search: &[u8]) -> Option<usize> {
         .position(|window| window == search)
 }
 
-pub(crate) fn replace(bytes: &mut [u8], from: char, to: char) {
-    for byte in bytes.iter_mut() {
-        if *byte == from as u8 {
-            *byte = to as u8;
-        }
-    }
-}
-
-pub(crate) fn replace_slice(source: &[u8], from: &[u8], to: &[u8]) -> Vec<u8> {
-    let mut result = source.to_vec();
-    let from_len = from.len();
-    let to_len = to.len();
-
-    let mut i = 0;
-    while i + from_len <= result.len() {
-        if result[i..].starts_with(from) {
-            result.splice(i..i + from_len, to.iter().cloned());
-            i += to_len;
-        } else {
-            i += 1;
-        }
+/// Decode base64, ignoring the folding whitespace (spaces, tabs and line
+/// breaks) found in real-world `p=` and `b=` values
+pub(crate) fn decode_base64(value: &str) -> Result<Vec<u8>, base64::DecodeError> {
+    use base64::Engine;
+
+    let is_fws = |c: char| c.is_ascii_whitespace();
+    if value.contains(is_fws) {
+        let value: String = value.chars().filter(|c| !is_fws(*c)).collect();
+        base64::engine::general_purpose::STANDARD.decode(value)
+    } else {
+        base64::engine::general_purpose::STANDARD.decode(value)
     }
-
-    result
 }
 
 #[cfg(test)]
@@ -54,9 +42,9 @@ mod tests {
     }
 
     #[test]
-    fn it_replace_slice() {
-        let source = "aba".as_bytes();
-        assert_eq!(replace_slice(source, &[97], &[99]), "cbc".as_bytes());
-        assert_eq!(replace_slice(source, &[97, 98], &[]), "a".as_bytes());
+    fn it_decode_base64() {
+        assert_eq!(decode_base64("aGVsbG8="), Ok(b"hello".to_vec()));
+        assert_eq!(decode_base64(" aGV\tsb\r\n G8= "), Ok(b"hello".to_vec()));
+        assert!(decode_base64("aGV*sbG8=").is_err());
     }
 }
@@ -1,158 +1,416 @@
 // Inspired from https://docs.rs/dkim/latest/src/dkim/canonicalization.rs.html
-use crate::bytes;
 
-#[derive(PartialEq, Clone, Debug)]
+#[derive(PartialEq, Eq, Hash, Clone, Debug)]
 pub enum Type {
     Simple,
     Relaxed,
 }
-impl std::string::ToString for Type {
-    fn to_string(&self) -> String {
+impl std::fmt::Display for Type {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Self::Simple => "simple".to_owned(),
-            Self::Relaxed => "relaxed".to_owned(),
+            Self::Simple => write!(f, "simple"),
+            Self::Relaxed => write!(f, "relaxed"),
         }
     }
 }
 
-/// Canonicalize body using the simple canonicalization algorithm.
+/// https://datatracker.ietf.org/doc/html/rfc6376#section-3.4.3
+/// Canonicalize body using the simple canonicalization algorithm. Empty lines
+/// at the end of the body are ignored and a CRLF is added to an empty body or
+/// a body without a trailing CRLF.
 ///
 /// The first argument **must** be the body of the mail.
 pub(crate) fn canonicalize_body_simple(mut body: &[u8]) -> Vec<u8> {
-    if body.is_empty() {
-        return b"\r\n".to_vec();
-    }
-
     while body.ends_with(b"\r\n\r\n") {
         body = &body[..body.len() - 2];
     }
 
-    body.to_vec()
+    let mut out = Vec::with_capacity(body.len() + 2);
+    out.extend_from_slice(body);
+    if !out.ends_with(b"\r\n") {
+        out.extend_from_slice(b"\r\n");
+    }
+    out
 }
 
-/// https://datatracker.ietf.org/doc/html/rfc6376#section-3.4.3
-/// Canonicalize body using the relaxed canonicalization algorithm.  
+/// https://datatracker.ietf.org/doc/html/rfc6376#section-3.4.4
+/// Canonicalize body using the relaxed canonicalization algorithm. An empty
+/// body, or one made only of empty lines, is canonicalized to nothing.
 ///
 /// The first argument **must** be the body of the mail.
 pub(crate) fn canonicalize_body_relaxed(body: &[u8]) -> Vec<u8> {
-    let mut body = body.to_vec();
     // See https://tools.ietf.org/html/rfc6376#section-3.4.4 for implementation details
+    let mut out = Vec::with_capacity(body.len() + 2);
+    let mut canonicalizer = BodyCanonicalizer::new(Type::Relaxed);
+    canonicalizer.update(body, &mut out);
+    canonicalizer.finish(&mut out);
+    out
+}
 
-    // Reduce all sequences of WSP within a line to a single SP character.
-    bytes::replace(&mut body, '\t', ' ');
-    let mut previous = false;
-    body.retain(|c| {
-        if *c == b' ' {
-            if previous {
-                false
-            } else {
-                previous = true;
-                true
+/// Incremental body canonicalization, producing the same output as
+/// [canonicalize_body_simple] and [canonicalize_body_relaxed] when the body
+/// is provided in chunks.
+pub(crate) struct BodyCanonicalizer {
+    canonicalization_type: Type,
+    /// Trailing CRLFs not emitted yet, they are dropped if they end the body
+    pending_crlfs: usize,
+    /// Trailing CR not emitted yet, waiting to know if it starts a CRLF
+    pending_cr: bool,
+    /// Trailing whitespace not emitted yet (relaxed only)
+    pending_space: bool,
+    /// Whether any byte was emitted
+    emitted: bool,
+    ends_with_crlf: bool,
+}
+
+impl BodyCanonicalizer {
+    pub(crate) fn new(canonicalization_type: Type) -> Self {
+        Self {
+            canonicalization_type,
+            pending_crlfs: 0,
+            pending_cr: false,
+            pending_space: false,
+            emitted: false,
+            ends_with_crlf: false,
+        }
+    }
+
+    fn emit(&mut self, bytes: &[u8], out: &mut Vec<u8>) {
+        out.extend_from_slice(bytes);
+        self.emitted = true;
+        self.ends_with_crlf = bytes.ends_with(b"\r\n");
+    }
+
+    fn flush_pending(&mut self, out: &mut Vec<u8>) {
+        for _ in 0..self.pending_crlfs {
+            self.emit(b"\r\n", out);
+        }
+        self.pending_crlfs = 0;
+        if self.pending_space {
+            self.emit(b" ", out);
+            self.pending_space = false;
+        }
+    }
+
+    /// Canonicalize a chunk of the body, appending the output to `out`
+    pub(crate) fn update(&mut self, chunk: &[u8], out: &mut Vec<u8>) {
+        let relaxed = self.canonicalization_type == Type::Relaxed;
+
+        let mut i = 0;
+        while i < chunk.len() {
+            // Emit the bytes that don't need canonicalization in bulk
+            if !self.pending_cr {
+                let len = find_special(&chunk[i..], relaxed);
+                if len > 0 {
+                    self.flush_pending(out);
+                    self.emit(&chunk[i..i + len], out);
+                    i += len;
+                    continue;
+                }
+            }
+
+            let byte = chunk[i];
+            i += 1;
+            let byte = if relaxed && byte == b'\t' { b' ' } else { byte };
+
+            if self.pending_cr {
+                self.pending_cr = false;
+                if byte == b'\n' {
+                    // Whitespace at the end of a line is ignored
+                    self.pending_space = false;
+                    self.pending_crlfs += 1;
+                    continue;
+                }
+                self.flush_pending(out);
+                self.emit(b"\r", out);
+            }
+
+            match byte {
+                b'\r' => self.pending_cr = true,
+                // Sequences of whitespace are reduced to a single space
+                b' ' if relaxed => self.pending_space = true,
+                _ => {
+                    self.flush_pending(out);
+                    self.emit(&[byte], out);
+                }
             }
-        } else {
-            previous = false;
-            true
         }
-    });
+    }
 
-    // Ignore all whitespace at the end of lines. Implementations MUST NOT remove the CRLF at the end of the line.
-    while let Some(idx) = bytes::find(&body, b" \r\n") {
-        body.remove(idx);
+    /// Canonicalize the end of the body, appending the output to `out`
+    pub(crate) fn finish(mut self, out: &mut Vec<u8>) {
+        if self.pending_cr || self.pending_space {
+            self.flush_pending(out);
+            if self.pending_cr {
+                self.emit(b"\r", out);
+            }
+        } else if self.pending_crlfs > 0 && self.emitted {
+            // Empty lines at the end of the body are ignored
+            self.emit(b"\r\n", out);
+        }
+
+        // A CRLF is added to a body without a trailing CRLF. In simple
+        // canonicalization an empty body is a single CRLF, in relaxed
+        // canonicalization it stays empty.
+        let empty = !self.emitted && self.canonicalization_type == Type::Relaxed;
+        if !self.ends_with_crlf && !empty {
+            self.emit(b"\r\n", out);
+        }
     }
+}
 
-    // Ignore all empty lines at the end of the message body. "Empty line" is defined in Section 3.4.3.
-    while body.ends_with(b"\r\n\r\n") {
-        body.remove(body.len() - 1);
-        body.remove(body.len() - 1);
+/// Convert the bare CR and LF line endings of a body to CRLF, as done by most
+/// MTAs when relaying it over SMTP
+pub(crate) fn normalize_line_endings(body: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(body.len() + body.len() / 32);
+    let mut bytes = body.iter().peekable();
+    while let Some(byte) = bytes.next() {
+        match byte {
+            b'\r' => {
+                bytes.next_if_eq(&&b'\n');
+                out.extend_from_slice(b"\r\n");
+            }
+            b'\n' => out.extend_from_slice(b"\r\n"),
+            _ => out.push(*byte),
+        }
     }
+    out
+}
 
-    // If the body is non-empty but does not end with a CRLF, a CRLF is added. (For email, this is only possible when using extensions to SMTP or non-SMTP transport mechanisms.)
-    if !body.is_empty() && !body.ends_with(b"\r\n") {
-        body.push(b'\r');
-        body.push(b'\n');
+/// Returns the number of bytes before the first byte that needs
+/// canonicalization: CR, and whitespace in relaxed mode
+#[cfg(feature = "simd")]
+fn find_special(bytes: &[u8], relaxed: bool) -> usize {
+    if relaxed {
+        memchr::memchr3(b'\r', b' ', b'\t', bytes)
+    } else {
+        memchr::memchr(b'\r', bytes)
     }
+    .unwrap_or(bytes.len())
+}
 
-    body
+#[cfg(not(feature = "simd"))]
+fn find_special(bytes: &[u8], relaxed: bool) -> usize {
+    bytes
+        .iter()
+        .position(|b| *b == b'\r' || (relaxed && (*b == b' ' || *b == b'\t')))
+        .unwrap_or(bytes.len())
 }
 
 // https://datatracker.ietf.org/doc/html/rfc6376#section-3.4.1
-pub(crate) fn canonicalize_header_simple(key: &str, value: &[u8]) -> Vec<u8> {
-    let mut out = Vec::new();
-    out.extend_from_slice(key.as_bytes());
+/// Appends the canonicalized header to `out`. The header name and value are
+/// raw bytes: the simple canonicalization doesn't change them.
+pub(crate) fn canonicalize_header_simple_into(key: &[u8], value: &[u8], out: &mut Vec<u8>) {
+    out.extend_from_slice(key);
     out.extend_from_slice(b": ");
     out.extend_from_slice(value);
     out.extend_from_slice(b"\r\n");
-
-    out
 }
 
 // https://datatracker.ietf.org/doc/html/rfc6376#section-3.4.2
-pub(crate) fn canonicalize_header_relaxed(key: &str, value: &[u8]) -> Vec<u8> {
-    let key = key.to_lowercase();
-    let key = key.trim_end();
-    let value = canonicalize_header_value_relaxed(value);
-
-    let mut out = Vec::new();
-    out.extend_from_slice(key.as_bytes());
-    out.extend_from_slice(b":");
-    out.extend_from_slice(&value);
-    out.extend_from_slice(b"\r\n");
+/// Appends the canonicalized header to `out`, in a single pass over the value
+/// without intermediate allocations. Only the ASCII letters of the name are
+/// lowercased, other bytes are kept as is.
+pub(crate) fn canonicalize_header_relaxed_into(key: &[u8], value: &[u8], out: &mut Vec<u8>) {
+    // Convert the header field name to lowercase and remove the whitespace
+    // before the colon
+    out.extend(key.trim_ascii_end().iter().map(u8::to_ascii_lowercase));
+    out.push(b':');
 
-    out
+    // Unfold the value, reduce all sequences of WSP to a single SP and remove
+    // the WSP at the beginning and end of the value
+    let mut pending_space = false;
+    let mut empty = true;
+    let mut i = 0;
+    while i < value.len() {
+        if value[i..].starts_with(b"\r\n") {
+            i += 2;
+            continue;
+        }
+        match value[i] {
+            b' ' | b'\t' => pending_space = true,
+            c => {
+                if pending_space && !empty {
+                    out.push(b' ');
+                }
+                out.push(c);
+                pending_space = false;
+                empty = false;
+            }
+        }
+        i += 1;
+    }
+
+    out.extend_from_slice(b"\r\n");
 }
 
-fn canonicalize_header_value_relaxed(value: &[u8]) -> Vec<u8> {
-    let mut value = value.to_vec();
-    bytes::replace(&mut value, '\t', ' ');
-    value = bytes::replace_slice(&value, b"\r\n", b"");
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Straightforward implementation of the relaxed body canonicalization,
+    /// used as a reference
+    fn reference_canonicalize_body_relaxed(body: &[u8]) -> Vec<u8> {
+        let mut body = body.to_vec();
+        // See https://tools.ietf.org/html/rfc6376#section-3.4.4 for implementation details
 
-    while value.ends_with(b" ") {
-        value.remove(value.len() - 1);
-    }
-    while value.starts_with(b" ") {
-        value.remove(0);
-    }
-    let mut previous = false;
-    value.retain(|c| {
-        if *c == b' ' {
-            if previous {
-                false
+        // Reduce all sequences of WSP within a line to a single SP character.
+        for c in body.iter_mut().filter(|c| **c == b'\t') {
+            *c = b' ';
+        }
+        let mut previous = false;
+        body.retain(|c| {
+            if *c == b' ' {
+                if previous {
+                    false
+                } else {
+                    previous = true;
+                    true
+                }
             } else {
-                previous = true;
+                previous = false;
                 true
             }
-        } else {
-            previous = false;
-            true
+        });
+
+        // Ignore all whitespace at the end of lines. Implementations MUST NOT remove the CRLF at the end of the line.
+        while let Some(idx) = crate::bytes::find(&body, b" \r\n") {
+            body.remove(idx);
         }
-    });
 
-    value
-}
+        // Ignore all empty lines at the end of the message body. "Empty line" is defined in Section 3.4.3.
+        while body.ends_with(b"\r\n\r\n") {
+            body.remove(body.len() - 1);
+            body.remove(body.len() - 1);
+        }
+        if body == b"\r\n" {
+            body.clear();
+        }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+        // If the body is non-empty but does not end with a CRLF, a CRLF is added. (For email, this is only possible when using extensions to SMTP or non-SMTP transport mechanisms.)
+        if !body.is_empty() && !body.ends_with(b"\r\n") {
+            body.push(b'\r');
+            body.push(b'\n');
+        }
+
+        body
+    }
+
+    fn canonicalize_header_relaxed(key: &[u8], value: &[u8]) -> Vec<u8> {
+        let mut out = vec![];
+        canonicalize_header_relaxed_into(key, value, &mut out);
+        out
+    }
 
     #[test]
     fn test_canonicalize_header_relaxed() {
         assert_eq!(
-            canonicalize_header_relaxed("SUBJect", b" AbC\r\n"),
+            canonicalize_header_relaxed(b"SUBJect", b" AbC\r\n"),
             b"subject:AbC\r\n"
         );
         assert_eq!(
-            canonicalize_header_relaxed("Subject \t", b"\t Your Name\t \r\n"),
+            canonicalize_header_relaxed(b"Subject \t", b"\t Your Name\t \r\n"),
             b"subject:Your Name\r\n"
         );
         assert_eq!(
-            canonicalize_header_relaxed("Subject \t", b"\t Kimi \t \r\n No \t\r\n Na Wa\r\n"),
+            canonicalize_header_relaxed(b"Subject \t", b"\t Kimi \t \r\n No \t\r\n Na Wa\r\n"),
             b"subject:Kimi No Na Wa\r\n"
         );
+        // Non-ASCII bytes are neither lowercased nor replaced
+        assert_eq!(
+            canonicalize_header_relaxed(b"X-Caf\xC9 ", b" caf\xE9\r\n"),
+            b"x-caf\xC9:caf\xE9\r\n"
+        );
+    }
+
+    #[test]
+    fn test_canonicalize_header_relaxed_into() {
+        let mut out = b"to:a\r\n".to_vec();
+        canonicalize_header_relaxed_into(b"Subject", b"\r\n\t\r\n  x\r\n\t y  \r\n", &mut out);
+        assert_eq!(out, b"to:a\r\nsubject:x y\r\n");
+    }
+
+    #[test]
+    fn test_canonicalize_body_simple() {
+        assert_eq!(canonicalize_body_simple(b""), b"\r\n");
+        assert_eq!(canonicalize_body_simple(b"\r\n\r\n\r\n"), b"\r\n");
+        assert_eq!(canonicalize_body_simple(b"hey"), b"hey\r\n");
+        assert_eq!(canonicalize_body_simple(b"hey \r\n\r\n"), b"hey \r\n");
     }
 
     #[test]
     fn test_canonicalize_body_relaxed() {
-        assert_eq!(canonicalize_body_relaxed(b"\r\n"), b"\r\n");
+        // Empty lines at the end of the body are ignored, even when the body
+        // is only made of them
+        assert_eq!(canonicalize_body_relaxed(b"\r\n"), b"");
+        assert_eq!(canonicalize_body_relaxed(b" \r\n\t\r\n"), b"");
         assert_eq!(canonicalize_body_relaxed(b"hey        \r\n"), b"hey\r\n");
+        assert_eq!(canonicalize_body_relaxed(b"hey"), b"hey\r\n");
+    }
+
+    #[test]
+    fn test_canonicalize_body_relaxed_matches_reference() {
+        for body in [
+            &b"hey \t you\r\n\r\n\r\n"[..],
+            b"a \r\n\tb\r\n",
+            b"",
+            b"\r\n\r\n",
+        ] {
+            assert_eq!(
+                canonicalize_body_relaxed(body),
+                reference_canonicalize_body_relaxed(body)
+            );
+        }
+    }
+
+    #[test]
+    fn test_normalize_line_endings() {
+        assert_eq!(normalize_line_endings(b""), b"");
+        assert_eq!(
+            normalize_line_endings(b"a\nb\r\nc\rd\n\n"),
+            b"a\r\nb\r\nc\r\nd\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn test_body_canonicalizer() {
+        let bodies: &[&[u8]] = &[
+            b"",
+            b"\r\n",
+            b"\r\n\r\n",
+            b"hey",
+            b"hey        \r\n",
+            b"hey \t you\r\n\r\n\r\n",
+            b"a\r\n \r\n\r\n",
+            b"a\r\n ",
+            b"a \rb\r\r\n",
+            b"a\r",
+            b" \r\n\t\r\n",
+            b"\r\n \r\n",
+            b"\r\n\r\na\t\tb \r\n  c\r\n\r\n",
+            b"Content\n.hi\n.hello..",
+        ];
+        for body in bodies {
+            for chunk_size in 1..=body.len().max(1) {
+                for (canonicalization_type, expected) in [
+                    (Type::Simple, canonicalize_body_simple(body)),
+                    (Type::Relaxed, reference_canonicalize_body_relaxed(body)),
+                ] {
+                    let mut canonicalizer = BodyCanonicalizer::new(canonicalization_type);
+                    let mut out = vec![];
+                    for chunk in body.chunks(chunk_size) {
+                        canonicalizer.update(chunk, &mut out);
+                    }
+                    canonicalizer.finish(&mut out);
+                    assert_eq!(
+                        out,
+                        expected,
+                        "body {:?} in chunks of {}",
+                        String::from_utf8_lossy(body),
+                        chunk_size
+                    );
+                }
+            }
+        }
     }
 }
@@ -0,0 +1,399 @@
+//! C bindings, to call the signer and the verifier from C MTAs or from other
+//! languages through their FFI. The C declarations are in `include/cfdkim.h`.
+//!
+//! Strings returned by the functions are allocated by the library and must be
+//! released with [dkim_string_free].
+//!
+//! The crate is only built as a Rust library by default, the shared library
+//! is built with:
+//!
+//! ```sh
+//! cargo rustc --lib --release --features capi --crate-type cdylib
+//! ```
+
+use std::ffi::{c_char, c_int, CStr, CString};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::slice;
+
+use crate::{
+    header_from_domain, public_key, verify_email_with_key, DKIMError, DKIMResult, DkimPrivateKey,
+    ErrorSource, SignerBuilder, Verdict,
+};
+
+/// The verification passed, or the signing succeeded
+pub const DKIM_PASS: c_int = 0;
+/// The message has no signature to verify
+pub const DKIM_NEUTRAL: c_int = 1;
+/// The verification failed
+pub const DKIM_FAIL: c_int = 2;
+/// The arguments are invalid, for instance a null pointer or a string which
+/// isn't UTF-8
+pub const DKIM_ERR_INVALID_ARGUMENT: c_int = -1;
+/// The message or the key couldn't be processed
+pub const DKIM_ERR_INTERNAL: c_int = -2;
+
+/// Sign a message and return the `DKIM-Signature` header, without the
+/// trailing CRLF, in `header_out`.
+///
+/// `private_key_pem` is a RSA private key in PEM format (PKCS#1 or PKCS#8) and
+/// `signed_headers` a colon-separated list of the headers to sign, for
+/// instance `From:Subject:To`.
+///
+/// Returns [DKIM_PASS] on success. Otherwise a negative code is returned and,
+/// if possible, the error message is written to `header_out`.
+///
+/// # Safety
+///
+/// `message` must point to `message_len` readable bytes, the other strings
+/// must be null-terminated and `header_out` must be valid for writes. The
+/// string written to `header_out` must be released with [dkim_string_free].
+#[no_mangle]
+pub unsafe extern "C" fn dkim_sign(
+    message: *const u8,
+    message_len: usize,
+    private_key_pem: *const c_char,
+    selector: *const c_char,
+    signing_domain: *const c_char,
+    signed_headers: *const c_char,
+    header_out: *mut *mut c_char,
+) -> c_int {
+    guard(|| {
+        sign_c(
+            message,
+            message_len,
+            private_key_pem,
+            selector,
+            signing_domain,
+            signed_headers,
+            header_out,
+        )
+    })
+}
+
+unsafe fn sign_c(
+    message: *const u8,
+    message_len: usize,
+    private_key_pem: *const c_char,
+    selector: *const c_char,
+    signing_domain: *const c_char,
+    signed_headers: *const c_char,
+    header_out: *mut *mut c_char,
+) -> c_int {
+    if header_out.is_null() {
+        return DKIM_ERR_INVALID_ARGUMENT;
+    }
+    *header_out = ptr::null_mut();
+
+    let (Some(message), Some(private_key_pem), Some(selector), Some(signing_domain)) = (
+        bytes_arg(message, message_len),
+        str_arg(private_key_pem),
+        str_arg(selector),
+        str_arg(signing_domain),
+    ) else {
+        return DKIM_ERR_INVALID_ARGUMENT;
+    };
+    let Some(signed_headers) = str_arg(signed_headers) else {
+        return DKIM_ERR_INVALID_ARGUMENT;
+    };
+
+    let header = sign(
+        message,
+        private_key_pem,
+        selector,
+        signing_domain,
+        signed_headers,
+    );
+    match header {
+        Ok(header) => {
+            *header_out = to_c_string(header);
+            DKIM_PASS
+        }
+        Err(err) => {
+            *header_out = to_c_string(err.to_string());
+            DKIM_ERR_INTERNAL
+        }
+    }
+}
+
+/// Verify the signatures of a message for the domain of its From header.
+///
+/// `key_record` is the DKIM key record (the content of the DNS TXT record,
+/// for instance `v=DKIM1; k=rsa; p=...`). When it's null and the `dns`
+/// feature is enabled, the key is retrieved from the DNS using the system
+/// configuration.
+///
+/// Returns [DKIM_PASS], [DKIM_NEUTRAL] or [DKIM_FAIL] and writes the result
+/// with its detail (for instance `fail (signature did not verify)`) to
+/// `result_out`. Otherwise a negative code is returned and, if possible, the
+/// error message is written to `result_out`.
+///
+/// # Safety
+///
+/// `message` must point to `message_len` readable bytes, `key_record` must be
+/// null or null-terminated and `result_out` must be valid for writes. The
+/// string written to `result_out` must be released with [dkim_string_free].
+#[no_mangle]
+pub unsafe extern "C" fn dkim_verify(
+    message: *const u8,
+    message_len: usize,
+    key_record: *const c_char,
+    result_out: *mut *mut c_char,
+) -> c_int {
+    guard(|| verify_c(message, message_len, key_record, result_out))
+}
+
+unsafe fn verify_c(
+    message: *const u8,
+    message_len: usize,
+    key_record: *const c_char,
+    result_out: *mut *mut c_char,
+) -> c_int {
+    if result_out.is_null() {
+        return DKIM_ERR_INVALID_ARGUMENT;
+    }
+    *result_out = ptr::null_mut();
+
+    let Some(message) = bytes_arg(message, message_len) else {
+        return DKIM_ERR_INVALID_ARGUMENT;
+    };
+    let key_record = if key_record.is_null() {
+        None
+    } else {
+        match str_arg(key_record) {
+            Some(key_record) => Some(key_record),
+            None => return DKIM_ERR_INVALID_ARGUMENT,
+        }
+    };
+
+    match verify(message, key_record) {
+        Ok(result) => {
+            *result_out = to_c_string(result.to_string());
+            match result.verdict() {
+                Verdict::Pass => DKIM_PASS,
+                Verdict::Neutral => DKIM_NEUTRAL,
+                _ => DKIM_FAIL,
+            }
+        }
+        Err(err) => {
+            *result_out = to_c_string(err.to_string());
+            DKIM_ERR_INTERNAL
+        }
+    }
+}
+
+/// Release a string returned by the library. Does nothing if `value` is null.
+///
+/// # Safety
+///
+/// `value` must be null or a string returned by the library, which wasn't
+/// released yet.
+#[no_mangle]
+pub unsafe extern "C" fn dkim_string_free(value: *mut c_char) {
+    guard(|| {
+        if !value.is_null() {
+            drop(CString::from_raw(value));
+        }
+        DKIM_PASS
+    });
+}
+
+/// Run the body of an exported function, unwinding across the FFI boundary
+/// is undefined behavior so a panic is reported as [DKIM_ERR_INTERNAL]
+fn guard(body: impl FnOnce() -> c_int) -> c_int {
+    panic::catch_unwind(AssertUnwindSafe(body)).unwrap_or(DKIM_ERR_INTERNAL)
+}
+
+fn sign(
+    message: &[u8],
+    private_key_pem: &str,
+    selector: &str,
+    signing_domain: &str,
+    signed_headers: &str,
+) -> Result<String, DKIMError> {
+    let private_key = DkimPrivateKey::rsa_from_pem(private_key_pem)?;
+    let email = mailparse::parse_mail(message).map_err(|err| DKIMError::MalformedEmail {
+        reason: "invalid message",
+        source: Some(ErrorSource::new(err)),
+    })?;
+    let signed_headers: Vec<&str> = signed_headers.split(':').map(str::trim).collect();
+
+    let logger = slog::Logger::root(slog::Discard, slog::o!());
+    let signer = SignerBuilder::new()
+        .with_signed_headers(&signed_headers)?
+        .with_private_key(private_key)
+        .with_selector(selector)
+        .with_signing_domain(signing_domain)
+        .with_logger(&logger)
+        .build()?;
+    signer.sign(&email)
+}
+
+fn verify(message: &[u8], key_record: Option<&str>) -> Result<DKIMResult, DKIMError> {
+    let email = mailparse::parse_mail(message).map_err(|err| DKIMError::MalformedEmail {
+        reason: "invalid message",
+        source: Some(ErrorSource::new(err)),
+    })?;
+    let from_domain = header_from_domain(&email)?;
+    let logger = slog::Logger::root(slog::Discard, slog::o!());
+
+    match key_record {
+        Some(key_record) => {
+            let public_key = public_key::parse_public_key(&logger, key_record)?;
+            verify_email_with_key(&logger, &from_domain, &email, public_key)
+        }
+        #[cfg(feature = "dns")]
+        None => tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .map_err(|err| {
+                DKIMError::UnknownInternalError(format!("failed to create runtime: {}", err))
+            })?
+            .block_on(crate::verify_email(&logger, &from_domain, &email)),
+        #[cfg(not(feature = "dns"))]
+        None => Err(DKIMError::UnsupportedLookup {
+            record_type: "TXT",
+            name: from_domain,
+        }),
+    }
+}
+
+unsafe fn bytes_arg<'a>(value: *const u8, len: usize) -> Option<&'a [u8]> {
+    if value.is_null() {
+        return None;
+    }
+    Some(slice::from_raw_parts(value, len))
+}
+
+unsafe fn str_arg<'a>(value: *const c_char) -> Option<&'a str> {
+    if value.is_null() {
+        return None;
+    }
+    CStr::from_ptr(value).to_str().ok()
+}
+
+fn to_c_string(value: String) -> *mut c_char {
+    // Interior null bytes can't be represented, they're dropped
+    let value = CString::new(value).unwrap_or_else(|err| {
+        let mut bytes = err.into_vec();
+        bytes.retain(|b| *b != 0);
+        CString::new(bytes).expect("null bytes were removed")
+    });
+    value.into_raw()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EMAIL: &[u8] = b"Subject: subject\r\nFrom: Joe <joe@example.com>\r\n\r\nHello Alice\r\n";
+
+    unsafe fn take_string(value: *mut c_char) -> String {
+        let owned = CStr::from_ptr(value).to_str().unwrap().to_owned();
+        dkim_string_free(value);
+        owned
+    }
+
+    #[test]
+    fn test_sign_verify() {
+        let private_key = CString::new(std::fs::read("./test/keys/2022.private").unwrap()).unwrap();
+        let key_record = CString::new(
+            std::fs::read_to_string("./test/keys/2022.txt")
+                .unwrap()
+                .split('"')
+                .skip(1)
+                .step_by(2)
+                .collect::<String>(),
+        )
+        .unwrap();
+        let selector = CString::new("2022").unwrap();
+        let domain = CString::new("example.com").unwrap();
+        let headers = CString::new("From:Subject").unwrap();
+
+        unsafe {
+            let mut header = ptr::null_mut();
+            let code = dkim_sign(
+                EMAIL.as_ptr(),
+                EMAIL.len(),
+                private_key.as_ptr(),
+                selector.as_ptr(),
+                domain.as_ptr(),
+                headers.as_ptr(),
+                &mut header,
+            );
+            assert_eq!(code, DKIM_PASS);
+            let header = take_string(header);
+            assert!(header.starts_with("DKIM-Signature: v=1; a=rsa-sha256;"));
+
+            let signed = [header.as_bytes(), b"\r\n", EMAIL].concat();
+            let mut result = ptr::null_mut();
+            let code = dkim_verify(
+                signed.as_ptr(),
+                signed.len(),
+                key_record.as_ptr(),
+                &mut result,
+            );
+            assert_eq!(take_string(result), "pass");
+            assert_eq!(code, DKIM_PASS);
+
+            let code = dkim_verify(
+                EMAIL.as_ptr(),
+                EMAIL.len(),
+                key_record.as_ptr(),
+                &mut result,
+            );
+            assert_eq!(code, DKIM_NEUTRAL);
+            dkim_string_free(result);
+
+            let tampered = [header.as_bytes(), b"\r\n", EMAIL, b"PS\r\n"].concat();
+            let code = dkim_verify(
+                tampered.as_ptr(),
+                tampered.len(),
+                key_record.as_ptr(),
+                &mut result,
+            );
+            assert_eq!(code, DKIM_FAIL);
+            assert!(take_string(result).starts_with("fail ("));
+        }
+    }
+
+    #[test]
+    fn test_invalid_arguments() {
+        unsafe {
+            let mut out = ptr::null_mut();
+            let code = dkim_sign(
+                EMAIL.as_ptr(),
+                EMAIL.len(),
+                ptr::null(),
+                ptr::null(),
+                ptr::null(),
+                ptr::null(),
+                &mut out,
+            );
+            assert_eq!(code, DKIM_ERR_INVALID_ARGUMENT);
+            assert!(out.is_null());
+
+            let invalid_key = CString::new("not a key").unwrap();
+            let value = CString::new("value").unwrap();
+            let code = dkim_sign(
+                EMAIL.as_ptr(),
+                EMAIL.len(),
+                invalid_key.as_ptr(),
+                value.as_ptr(),
+                value.as_ptr(),
+                value.as_ptr(),
+                &mut out,
+            );
+            assert_eq!(code, DKIM_ERR_INTERNAL);
+            assert!(take_string(out).contains("invalid private key"));
+
+            dkim_string_free(ptr::null_mut());
+        }
+    }
+
+    #[test]
+    fn test_guard() {
+        assert_eq!(guard(|| DKIM_NEUTRAL), DKIM_NEUTRAL);
+        assert_eq!(guard(|| panic!("unexpected")), DKIM_ERR_INTERNAL);
+    }
+}
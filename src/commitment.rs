@@ -0,0 +1,100 @@
+//! Canonical commitments to DKIM-verified messages, for instance to commit to
+//! the journal of a zero-knowledge proof: the proof then binds to the exact
+//! verified message without disclosing it.
+
+use sha2::{Digest, Sha256};
+
+/// Domain separation prefix of the commitment input
+const TAG: &[u8] = b"cfdkim-commitment-v1";
+
+/// Commitment to a message verified by a DKIM signature, available with
+/// [crate::DKIMResult::commitment] after a successful verification.
+///
+/// The [Commitment::digest] is the SHA-256 of `cfdkim-commitment-v1`
+/// followed by the signing domain (normalized to lowercase ASCII), the
+/// selector (lowercase), the hash of the signed headers and the decoded body
+/// hash (`bh=`), each prefixed with its length as a big-endian `u32`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Commitment {
+    domain: String,
+    selector: String,
+    header_hash: Vec<u8>,
+    body_hash: Vec<u8>,
+}
+
+impl Commitment {
+    /// New commitment, the hashes are the raw digests
+    pub fn new(domain: &str, selector: &str, header_hash: Vec<u8>, body_hash: Vec<u8>) -> Self {
+        Self {
+            domain: crate::normalize_domain(domain),
+            selector: selector.to_lowercase(),
+            header_hash,
+            body_hash,
+        }
+    }
+
+    pub fn domain(&self) -> &str {
+        &self.domain
+    }
+
+    pub fn selector(&self) -> &str {
+        &self.selector
+    }
+
+    /// Hash of the canonicalized signed headers and signature, as signed
+    pub fn header_hash(&self) -> &[u8] {
+        &self.header_hash
+    }
+
+    /// Hash of the canonicalized body
+    pub fn body_hash(&self) -> &[u8] {
+        &self.body_hash
+    }
+
+    /// The commitment digest
+    pub fn digest(&self) -> [u8; 32] {
+        let mut hasher = Sha256::new();
+        hasher.update(TAG);
+        for field in [
+            self.domain.as_bytes(),
+            self.selector.as_bytes(),
+            &self.header_hash,
+            &self.body_hash,
+        ] {
+            hasher.update((field.len() as u32).to_be_bytes());
+            hasher.update(field);
+        }
+        hasher.finalize().into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_digest() {
+        let commitment = Commitment::new("Example.COM", "Sel", vec![1, 2], vec![3]);
+        assert_eq!(commitment.domain(), "example.com");
+        assert_eq!(commitment.selector(), "sel");
+        assert_eq!(
+            commitment.digest(),
+            Commitment::new("example.com", "sel", vec![1, 2], vec![3]).digest()
+        );
+
+        // The fields are delimited
+        assert_ne!(
+            commitment.digest(),
+            Commitment::new("example.com", "sel", vec![1], vec![2, 3]).digest()
+        );
+
+        let mut input = TAG.to_vec();
+        input.extend_from_slice(
+            b"\0\0\0\x0bexample.com\0\0\0\x03sel\0\0\0\x02\x01\x02\0\0\0\x01\x03",
+        );
+        assert_eq!(
+            commitment.digest(),
+            <[u8; 32]>::from(Sha256::digest(&input))
+        );
+    }
+}
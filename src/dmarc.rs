@@ -0,0 +1,155 @@
+//! DKIM results in the form DMARC evaluators consume (RFC 7489 section 4.2).
+//!
+//! DMARC passes when any signature verifies with a `d=` domain aligned with
+//! the From domain, so every signature of the message is verified, whatever
+//! its signing domain, and the outcome of each one is listed.
+
+#[cfg(feature = "dns")]
+use std::sync::Arc;
+
+#[cfg(feature = "dns")]
+use crate::{dns, verify_signatures_with_resolver};
+use crate::{
+    verify_signatures_with_key, DKIMResult, DkimPublicKey, SignatureScope, Verdict,
+    VerificationPolicy,
+};
+
+/// Results of the signatures of a message, one per signature attributable to
+/// a signing domain
+pub struct DmarcDkimResults {
+    results: Vec<DKIMResult>,
+}
+
+impl DmarcDkimResults {
+    /// Results of the signatures, in the order of their headers. Signatures
+    /// without a `d=` tag can't be attributed to a domain and are left out.
+    pub fn results(&self) -> &[DKIMResult] {
+        &self.results
+    }
+
+    /// The signing domain (`d=` tag) and verdict of each signature, as listed
+    /// in DMARC aggregate reports
+    pub fn pairs(&self) -> Vec<(String, Verdict)> {
+        self.results
+            .iter()
+            .map(|result| (result.domain_used(), result.verdict()))
+            .collect()
+    }
+
+    /// The signing domains of the passing signatures, without duplicates: the
+    /// DKIM identifiers to check for alignment with the From domain
+    pub fn passing_domains(&self) -> Vec<String> {
+        let mut domains: Vec<String> = vec![];
+        for result in &self.results {
+            let domain = result.domain_used();
+            if result.verdict() == Verdict::Pass && !domains.contains(&domain) {
+                domains.push(domain);
+            }
+        }
+        domains
+    }
+}
+
+/// Verify every signature of the email with a provided public key, for a
+/// DMARC evaluation. Unlike [crate::verify_email_with_key_and_policy], the
+/// signatures of all domains are verified and the requirements on the
+/// number of passing signatures of the policy don't apply.
+pub fn verify_for_dmarc_with_key(
+    logger: &slog::Logger,
+    email: &mailparse::ParsedMail,
+    public_key: &DkimPublicKey,
+    policy: &VerificationPolicy,
+) -> DmarcDkimResults {
+    DmarcDkimResults {
+        results: verify_signatures_with_key(
+            logger,
+            SignatureScope::All,
+            email,
+            public_key,
+            policy,
+            None,
+        ),
+    }
+}
+
+/// Verify every signature of the email providing an existing resolver, for a
+/// DMARC evaluation. Unlike [crate::verify_email_with_resolver_and_policy],
+/// the signatures of all domains are verified and the requirements on the
+/// number of passing signatures of the policy don't apply.
+#[cfg(feature = "dns")]
+pub async fn verify_for_dmarc_with_resolver(
+    logger: &slog::Logger,
+    email: &mailparse::ParsedMail<'_>,
+    resolver: Arc<dyn dns::Lookup>,
+    policy: &VerificationPolicy,
+) -> DmarcDkimResults {
+    DmarcDkimResults {
+        results: verify_signatures_with_resolver(
+            logger,
+            SignatureScope::All,
+            email,
+            resolver,
+            policy,
+            None,
+        )
+        .await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{
+        ed25519_private_key, rsa_private_key, sign_message, ED25519_SELECTOR, RSA_SELECTOR,
+        SAMPLE_MESSAGE,
+    };
+    use crate::SignerBuilder;
+
+    #[test]
+    fn test_verify_for_dmarc_with_key() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        // Signed by the From domain, by a third party, and by the From
+        // domain with another key
+        let signed = sign_message(SAMPLE_MESSAGE, rsa_private_key(), RSA_SELECTOR);
+        let third_party = SignerBuilder::new()
+            .with_signed_headers(&["From", "Subject"])
+            .unwrap()
+            .with_private_key(rsa_private_key())
+            .with_selector("esp")
+            .with_signing_domain("ESP.example.net")
+            .with_logger(&logger)
+            .build()
+            .unwrap();
+        let email = mailparse::parse_mail(signed.as_bytes()).unwrap();
+        let header = third_party.sign(&email).unwrap();
+        let signed = format!("{}\r\n{}", header, signed);
+        let signed = sign_message(&signed, ed25519_private_key(), ED25519_SELECTOR);
+        let signed = format!(
+            "DKIM-Signature: v=1; a=rsa-sha256; s=x; b=; bh=\r\n{}",
+            signed
+        );
+
+        let email = mailparse::parse_mail(signed.as_bytes()).unwrap();
+        let public_key = rsa_private_key().to_public_key();
+        let results =
+            verify_for_dmarc_with_key(&logger, &email, &public_key, &VerificationPolicy::new());
+
+        // The signature without d= isn't listed, the Ed25519 signature fails
+        // with the RSA key
+        assert_eq!(
+            results.pairs(),
+            vec![
+                ("example.com".to_owned(), Verdict::Fail),
+                ("esp.example.net".to_owned(), Verdict::Pass),
+                ("example.com".to_owned(), Verdict::Pass),
+            ]
+        );
+        assert_eq!(
+            results.passing_domains(),
+            vec!["esp.example.net", "example.com"]
+        );
+        assert_eq!(results.results()[0].selector(), Some(ED25519_SELECTOR));
+        assert_eq!(results.results()[1].signature_index(), Some(2));
+        assert!(results.results()[1].commitment().is_some());
+    }
+}
@@ -1,21 +1,123 @@
-use crate::DKIMError;
+use crate::{DKIMError, ErrorSource, VerificationObserver};
 use futures::future::BoxFuture;
-use std::sync::Arc;
+use lru_cache::LruCache;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+#[cfg(feature = "spf")]
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use trust_dns_resolver::config::{NameServerConfig, ResolverConfig, ResolverOpts};
 use trust_dns_resolver::error::{ResolveError, ResolveErrorKind};
+use trust_dns_resolver::proto::error::ProtoErrorKind;
+use trust_dns_resolver::proto::op::{Message, Query, ResponseCode};
+use trust_dns_resolver::proto::rr::{Name, RData, RecordType};
 use trust_dns_resolver::TokioAsyncResolver;
 
 /// A trait for entities that perform DNS resolution.
 pub trait Lookup: Sync + Send {
     fn lookup_txt<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<Vec<String>, DKIMError>>;
+
+    /// Returns the IPv4 addresses of `name`, empty if it has none. Used by
+    /// the SPF checks: lookups only retrieving keys don't need to implement
+    /// it, by default it fails with the permanent
+    /// [DKIMError::UnsupportedLookup].
+    #[cfg(feature = "spf")]
+    fn lookup_a<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<Vec<Ipv4Addr>, DKIMError>> {
+        unsupported("A", name)
+    }
+
+    /// Returns the IPv6 addresses of `name`, empty if it has none, see
+    /// [Lookup::lookup_a]
+    #[cfg(feature = "spf")]
+    fn lookup_aaaa<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<Vec<Ipv6Addr>, DKIMError>> {
+        unsupported("AAAA", name)
+    }
+
+    /// Returns the mail exchangers of `name` by order of preference, empty if
+    /// it has none, see [Lookup::lookup_a]
+    #[cfg(feature = "spf")]
+    fn lookup_mx<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<Vec<String>, DKIMError>> {
+        unsupported("MX", name)
+    }
 }
 
-fn to_lookup_error(err: ResolveError) -> DKIMError {
+#[cfg(feature = "spf")]
+fn unsupported<'a, T: Send + 'a>(
+    record_type: &'static str,
+    name: &str,
+) -> BoxFuture<'a, Result<T, DKIMError>> {
+    Box::pin(futures::future::ready(Err(DKIMError::UnsupportedLookup {
+        record_type,
+        name: name.to_owned(),
+    })))
+}
+
+fn to_lookup_error(err: ResolveError, name: &str) -> DKIMError {
     match err.kind() {
-        ResolveErrorKind::NoRecordsFound { .. } => DKIMError::NoKeyForSignature,
-        _ => DKIMError::KeyUnavailable(format!("failed to query DNS: {}", err)),
+        ResolveErrorKind::NoRecordsFound { response_code, .. } => {
+            response_code_error(*response_code, name)
+        }
+        ResolveErrorKind::Timeout => DKIMError::DnsTimeout {
+            name: name.to_owned(),
+        },
+        ResolveErrorKind::Proto(err) if matches!(err.kind(), ProtoErrorKind::Timeout) => {
+            DKIMError::DnsTimeout {
+                name: name.to_owned(),
+            }
+        }
+        _ => DKIMError::KeyUnavailable {
+            name: name.to_owned(),
+            reason: "DNS query failed".to_owned(),
+            source: Some(ErrorSource::new(err)),
+        },
+    }
+}
+
+/// Error of a response without records
+fn response_code_error(code: ResponseCode, name: &str) -> DKIMError {
+    match code {
+        ResponseCode::NoError => DKIMError::DnsNoTxtRecord {
+            name: name.to_owned(),
+        },
+        ResponseCode::NXDomain => DKIMError::DnsNxDomain {
+            name: name.to_owned(),
+        },
+        ResponseCode::ServFail => DKIMError::DnsServFail {
+            name: name.to_owned(),
+        },
+        code => DKIMError::KeyUnavailable {
+            name: name.to_owned(),
+            reason: format!("DNS response code {}", code),
+            source: None,
+        },
     }
 }
 
+/// Records of a query which failed: none if the name exists without records
+/// of the queried type
+#[cfg(feature = "spf")]
+fn empty_if_no_records<T>(err: ResolveError, name: &str) -> Result<Vec<T>, DKIMError> {
+    match err.kind() {
+        ResolveErrorKind::NoRecordsFound {
+            response_code: ResponseCode::NoError,
+            ..
+        } => Ok(vec![]),
+        _ => Err(to_lookup_error(err, name)),
+    }
+}
+
+/// Mail exchangers, by order of preference
+#[cfg(feature = "spf")]
+fn sort_exchanges(mut exchanges: Vec<(u16, &Name)>) -> Vec<String> {
+    exchanges.sort_by_key(|(preference, _)| *preference);
+    exchanges
+        .into_iter()
+        .map(|(_, exchange)| normalize_name(&exchange.to_ascii()))
+        .collect()
+}
+
 // Technically we should be able to implemement Lookup for TokioAsyncResolver
 // directly but it's failing for some reason.
 struct TokioAsyncResolverWrapper {
@@ -27,7 +129,7 @@ impl Lookup for TokioAsyncResolverWrapper {
             self.inner
                 .txt_lookup(name)
                 .await
-                .map_err(to_lookup_error)?
+                .map_err(|err| to_lookup_error(err, name))?
                 .into_iter()
                 .map(|txt| {
                     Ok(txt
@@ -38,8 +140,836 @@ impl Lookup for TokioAsyncResolverWrapper {
                 .collect()
         })
     }
+
+    #[cfg(feature = "spf")]
+    fn lookup_a<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<Vec<Ipv4Addr>, DKIMError>> {
+        Box::pin(async move {
+            match self.inner.ipv4_lookup(name).await {
+                Ok(lookup) => Ok(lookup.into_iter().map(|a| a.0).collect()),
+                Err(err) => empty_if_no_records(err, name),
+            }
+        })
+    }
+
+    #[cfg(feature = "spf")]
+    fn lookup_aaaa<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<Vec<Ipv6Addr>, DKIMError>> {
+        Box::pin(async move {
+            match self.inner.ipv6_lookup(name).await {
+                Ok(lookup) => Ok(lookup.into_iter().map(|aaaa| aaaa.0).collect()),
+                Err(err) => empty_if_no_records(err, name),
+            }
+        })
+    }
+
+    #[cfg(feature = "spf")]
+    fn lookup_mx<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<Vec<String>, DKIMError>> {
+        Box::pin(async move {
+            match self.inner.mx_lookup(name).await {
+                Ok(lookup) => Ok(sort_exchanges(
+                    lookup
+                        .iter()
+                        .map(|mx| (mx.preference(), mx.exchange()))
+                        .collect(),
+                )),
+                Err(err) => empty_if_no_records(err, name),
+            }
+        })
+    }
 }
 
 pub fn from_tokio_resolver(resolver: TokioAsyncResolver) -> Arc<dyn Lookup> {
     Arc::new(TokioAsyncResolverWrapper { inner: resolver })
 }
+
+/// Protocol used to query nameservers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    Udp,
+    Tcp,
+}
+
+/// Lookup querying `nameservers` directly, without reading the system
+/// configuration (`/etc/resolv.conf`), for instance in containers
+pub fn from_nameservers(nameservers: &[SocketAddr], protocol: Protocol) -> Arc<dyn Lookup> {
+    nameservers
+        .iter()
+        .fold(ResolverBuilder::new(), |builder, addr| {
+            builder.with_nameserver(*addr, protocol)
+        })
+        .resolver()
+}
+
+/// Lookup querying `nameservers` over DNS-over-TLS (RFC 7858), their
+/// certificate being verified for `tls_name` against the webpki roots, for
+/// instance `&["1.1.1.1:853".parse()?]` and `cloudflare-dns.com`
+#[cfg(feature = "dns-over-tls")]
+pub fn from_tls_nameservers(nameservers: &[SocketAddr], tls_name: &str) -> Arc<dyn Lookup> {
+    nameservers
+        .iter()
+        .fold(ResolverBuilder::new(), |builder, addr| {
+            builder.with_tls_nameserver(*addr, tls_name)
+        })
+        .resolver()
+}
+
+/// Builder for the built-in resolver, querying explicit nameservers instead
+/// of the ones of the system configuration. Search domains are disabled: the
+/// key records are always queried by their full name.
+pub struct ResolverBuilder {
+    nameservers: Vec<NameServerConfig>,
+    opts: ResolverOpts,
+}
+
+impl ResolverBuilder {
+    /// New builder, without nameservers and with EDNS disabled
+    pub fn new() -> Self {
+        Self {
+            nameservers: vec![],
+            opts: ResolverOpts::default(),
+        }
+    }
+
+    /// Add a nameserver
+    pub fn with_nameserver(mut self, addr: SocketAddr, protocol: Protocol) -> Self {
+        let protocol = match protocol {
+            Protocol::Udp => trust_dns_resolver::config::Protocol::Udp,
+            Protocol::Tcp => trust_dns_resolver::config::Protocol::Tcp,
+        };
+        self.nameservers.push(NameServerConfig::new(addr, protocol));
+        self
+    }
+
+    /// Add a DNS-over-TLS nameserver, see [from_tls_nameservers]
+    #[cfg(feature = "dns-over-tls")]
+    pub fn with_tls_nameserver(mut self, addr: SocketAddr, tls_name: &str) -> Self {
+        self.nameservers.push(NameServerConfig {
+            tls_dns_name: Some(tls_name.to_owned()),
+            ..NameServerConfig::new(addr, trust_dns_resolver::config::Protocol::Tls)
+        });
+        self
+    }
+
+    /// Enable EDNS(0), allowing responses larger than 512 bytes over UDP, for
+    /// instance records with several 2048-bit keys
+    pub fn with_edns(mut self, enabled: bool) -> Self {
+        self.opts.edns0 = enabled;
+        self
+    }
+
+    /// Specify the timeout of each query, 5 seconds by default
+    pub fn with_timeout(mut self, value: Duration) -> Self {
+        self.opts.timeout = value;
+        self
+    }
+
+    /// Specify the number of attempts of each query, 2 by default
+    pub fn with_attempts(mut self, value: usize) -> Self {
+        self.opts.attempts = value;
+        self
+    }
+
+    /// Build the resolver. At least one nameserver must be provided.
+    pub fn build(self) -> Result<Arc<dyn Lookup>, DKIMError> {
+        if self.nameservers.is_empty() {
+            return Err(DKIMError::BuilderError("missing required nameservers"));
+        }
+        Ok(self.resolver())
+    }
+
+    fn resolver(mut self) -> Arc<dyn Lookup> {
+        self.opts.ndots = 0;
+        let config = ResolverConfig::from_parts(None, vec![], self.nameservers);
+        from_tokio_resolver(TokioAsyncResolver::tokio(config, self.opts))
+    }
+}
+
+impl Default for ResolverBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Transport exchanging DNS messages in wire format (RFC 1035) with a
+/// nameserver, for instance over a socket provided by the platform
+pub trait Transport: Sync + Send {
+    /// Sends the query and returns the response. A truncated response (TC
+    /// bit set) is reported as a temporary error, so a transport over UDP
+    /// should retry the query over TCP itself.
+    fn exchange<'a>(&'a self, query: Vec<u8>) -> BoxFuture<'a, Result<Vec<u8>, DKIMError>>;
+}
+
+struct TransportLookup {
+    transport: Arc<dyn Transport>,
+    next_id: AtomicU16,
+}
+impl TransportLookup {
+    fn query(&self, name: &str, record_type: RecordType) -> Result<(u16, Vec<u8>), DKIMError> {
+        let dns_name = Name::from_ascii(name).map_err(|err| DKIMError::KeyUnavailable {
+            name: name.to_owned(),
+            reason: "invalid DNS name".to_owned(),
+            source: Some(ErrorSource::new(err)),
+        })?;
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let mut message = Message::new();
+        message
+            .set_id(id)
+            .set_recursion_desired(true)
+            .add_query(Query::query(dns_name, record_type));
+        let query = message.to_vec().map_err(|err| DKIMError::KeyUnavailable {
+            name: name.to_owned(),
+            reason: "failed to encode the DNS query".to_owned(),
+            source: Some(ErrorSource::new(err)),
+        })?;
+        Ok((id, query))
+    }
+
+    /// Sends the query and returns the successful response
+    async fn exchange(&self, name: &str, record_type: RecordType) -> Result<Message, DKIMError> {
+        let (id, query) = self.query(name, record_type)?;
+        let response = self.transport.exchange(query).await?;
+        let response = Message::from_vec(&response).map_err(|err| DKIMError::KeyUnavailable {
+            name: name.to_owned(),
+            reason: "failed to decode the DNS response".to_owned(),
+            source: Some(ErrorSource::new(err)),
+        })?;
+        if response.id() != id {
+            return Err(DKIMError::KeyUnavailable {
+                name: name.to_owned(),
+                reason: "DNS response doesn't match the query".to_owned(),
+                source: None,
+            });
+        }
+        if response.truncated() {
+            return Err(DKIMError::KeyUnavailable {
+                name: name.to_owned(),
+                reason: "truncated DNS response".to_owned(),
+                source: None,
+            });
+        }
+        if response.response_code() != ResponseCode::NoError {
+            return Err(response_code_error(response.response_code(), name));
+        }
+        Ok(response)
+    }
+}
+impl Lookup for TransportLookup {
+    fn lookup_txt<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<Vec<String>, DKIMError>> {
+        Box::pin(async move {
+            let response = self.exchange(name, RecordType::TXT).await?;
+            let records: Vec<String> = response
+                .answers()
+                .iter()
+                .filter_map(|record| match record.data() {
+                    Some(RData::TXT(txt)) => Some(
+                        txt.iter()
+                            .map(|data| String::from_utf8_lossy(data))
+                            .collect(),
+                    ),
+                    _ => None,
+                })
+                .collect();
+            if records.is_empty() {
+                return Err(DKIMError::DnsNoTxtRecord {
+                    name: name.to_owned(),
+                });
+            }
+            Ok(records)
+        })
+    }
+
+    #[cfg(feature = "spf")]
+    fn lookup_a<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<Vec<Ipv4Addr>, DKIMError>> {
+        Box::pin(async move {
+            let response = self.exchange(name, RecordType::A).await?;
+            Ok(response
+                .answers()
+                .iter()
+                .filter_map(|record| match record.data() {
+                    Some(RData::A(a)) => Some(a.0),
+                    _ => None,
+                })
+                .collect())
+        })
+    }
+
+    #[cfg(feature = "spf")]
+    fn lookup_aaaa<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<Vec<Ipv6Addr>, DKIMError>> {
+        Box::pin(async move {
+            let response = self.exchange(name, RecordType::AAAA).await?;
+            Ok(response
+                .answers()
+                .iter()
+                .filter_map(|record| match record.data() {
+                    Some(RData::AAAA(aaaa)) => Some(aaaa.0),
+                    _ => None,
+                })
+                .collect())
+        })
+    }
+
+    #[cfg(feature = "spf")]
+    fn lookup_mx<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<Vec<String>, DKIMError>> {
+        Box::pin(async move {
+            let response = self.exchange(name, RecordType::MX).await?;
+            Ok(sort_exchanges(
+                response
+                    .answers()
+                    .iter()
+                    .filter_map(|record| match record.data() {
+                        Some(RData::MX(mx)) => Some((mx.preference(), mx.exchange())),
+                        _ => None,
+                    })
+                    .collect(),
+            ))
+        })
+    }
+}
+
+/// Lookup sending its queries over `transport`
+pub fn from_transport(transport: Arc<dyn Transport>) -> Arc<dyn Lookup> {
+    Arc::new(TransportLookup {
+        transport,
+        next_id: AtomicU16::new(1),
+    })
+}
+
+/// Lookup caching the records returned by another lookup, for instance to
+/// share the keys between the messages of a batch. Temporary errors are not
+/// cached.
+struct CachedLookup {
+    inner: Arc<dyn Lookup>,
+    ttl: Duration,
+    cache: Cache<Vec<String>>,
+    #[cfg(feature = "spf")]
+    a: Cache<Vec<Ipv4Addr>>,
+    #[cfg(feature = "spf")]
+    aaaa: Cache<Vec<Ipv6Addr>>,
+    #[cfg(feature = "spf")]
+    mx: Cache<Vec<String>>,
+}
+
+/// Results of the queries of one record type and when they were cached, by
+/// name
+type Cache<T> = Mutex<LruCache<String, (Instant, Result<T, DKIMError>)>>;
+
+/// Returns the cached result of the query of `name` if it's more recent than
+/// `ttl`, or runs it with `lookup` and caches its result unless the error is
+/// temporary
+async fn cached_lookup<'a, T: Clone>(
+    cache: &Cache<T>,
+    ttl: Duration,
+    name: &str,
+    lookup: impl FnOnce() -> BoxFuture<'a, Result<T, DKIMError>>,
+) -> Result<T, DKIMError> {
+    if let Some((time, res)) = cache.lock().unwrap().get_mut(name) {
+        if time.elapsed() < ttl {
+            return res.clone();
+        }
+    }
+    let res = lookup().await;
+    if !matches!(&res, Err(err) if err.is_temporary()) {
+        cache
+            .lock()
+            .unwrap()
+            .insert(name.to_owned(), (Instant::now(), res.clone()));
+    }
+    res
+}
+
+impl Lookup for CachedLookup {
+    fn lookup_txt<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<Vec<String>, DKIMError>> {
+        Box::pin(cached_lookup(&self.cache, self.ttl, name, || {
+            self.inner.lookup_txt(name)
+        }))
+    }
+
+    #[cfg(feature = "spf")]
+    fn lookup_a<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<Vec<Ipv4Addr>, DKIMError>> {
+        Box::pin(cached_lookup(&self.a, self.ttl, name, || {
+            self.inner.lookup_a(name)
+        }))
+    }
+
+    #[cfg(feature = "spf")]
+    fn lookup_aaaa<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<Vec<Ipv6Addr>, DKIMError>> {
+        Box::pin(cached_lookup(&self.aaaa, self.ttl, name, || {
+            self.inner.lookup_aaaa(name)
+        }))
+    }
+
+    #[cfg(feature = "spf")]
+    fn lookup_mx<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<Vec<String>, DKIMError>> {
+        Box::pin(cached_lookup(&self.mx, self.ttl, name, || {
+            self.inner.lookup_mx(name)
+        }))
+    }
+}
+
+/// Wrap a lookup to cache its records in memory, for up to `ttl`. At most
+/// `capacity` names are cached per record type, the least recently used one
+/// is evicted first.
+pub fn cached(lookup: Arc<dyn Lookup>, capacity: usize, ttl: Duration) -> Arc<dyn Lookup> {
+    Arc::new(CachedLookup {
+        inner: lookup,
+        ttl,
+        cache: Mutex::new(LruCache::new(capacity)),
+        #[cfg(feature = "spf")]
+        a: Mutex::new(LruCache::new(capacity)),
+        #[cfg(feature = "spf")]
+        aaaa: Mutex::new(LruCache::new(capacity)),
+        #[cfg(feature = "spf")]
+        mx: Mutex::new(LruCache::new(capacity)),
+    })
+}
+
+/// Lookup limiting the number of queries per signing domain, see
+/// [rate_limited]
+struct RateLimitedLookup {
+    inner: Arc<dyn Lookup>,
+    max_queries: usize,
+    period: Duration,
+    windows: Mutex<RateWindows>,
+}
+
+struct RateWindows {
+    // Start of the current period and number of queries, per domain
+    domains: HashMap<String, (Instant, usize)>,
+    last_sweep: Instant,
+}
+
+impl RateLimitedLookup {
+    fn acquire(&self, domain: &str) -> bool {
+        let now = Instant::now();
+        let mut windows = self.windows.lock().unwrap();
+        // Forget the domains whose period ended, at most once per period, so
+        // the map doesn't grow with every domain ever seen
+        if now.duration_since(windows.last_sweep) >= self.period {
+            let period = self.period;
+            windows
+                .domains
+                .retain(|_, (start, _)| now.duration_since(*start) < period);
+            windows.last_sweep = now;
+        }
+        let (start, count) = windows.domains.entry(domain.to_owned()).or_insert((now, 0));
+        if now.duration_since(*start) >= self.period {
+            *start = now;
+            *count = 0;
+        }
+        *count += 1;
+        *count <= self.max_queries
+    }
+
+    /// Count a query of `name` toward the limit of its domain
+    fn check(&self, name: &str) -> Result<(), DKIMError> {
+        // The domain of a key record is the part after `_domainkey`
+        let domain = match name.split_once("._domainkey.") {
+            Some((_, domain)) => domain,
+            None => name,
+        };
+        if !self.acquire(&domain.to_ascii_lowercase()) {
+            return Err(DKIMError::KeyUnavailable {
+                name: name.to_owned(),
+                reason: format!("DNS rate limit exceeded for {}", domain),
+                source: None,
+            });
+        }
+        Ok(())
+    }
+}
+impl Lookup for RateLimitedLookup {
+    fn lookup_txt<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<Vec<String>, DKIMError>> {
+        Box::pin(async move {
+            self.check(name)?;
+            self.inner.lookup_txt(name).await
+        })
+    }
+
+    #[cfg(feature = "spf")]
+    fn lookup_a<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<Vec<Ipv4Addr>, DKIMError>> {
+        Box::pin(async move {
+            self.check(name)?;
+            self.inner.lookup_a(name).await
+        })
+    }
+
+    #[cfg(feature = "spf")]
+    fn lookup_aaaa<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<Vec<Ipv6Addr>, DKIMError>> {
+        Box::pin(async move {
+            self.check(name)?;
+            self.inner.lookup_aaaa(name).await
+        })
+    }
+
+    #[cfg(feature = "spf")]
+    fn lookup_mx<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<Vec<String>, DKIMError>> {
+        Box::pin(async move {
+            self.check(name)?;
+            self.inner.lookup_mx(name).await
+        })
+    }
+}
+
+/// Wrap a lookup to allow at most `max_queries` queries per signing domain
+/// during each `period`, so a flood of messages for one domain doesn't turn
+/// into a query storm toward its nameservers. Queries over the limit fail
+/// with a temporary error. Combine with [cached] so that only cache misses
+/// count toward the limit.
+pub fn rate_limited(
+    lookup: Arc<dyn Lookup>,
+    max_queries: usize,
+    period: Duration,
+) -> Arc<dyn Lookup> {
+    Arc::new(RateLimitedLookup {
+        inner: lookup,
+        max_queries,
+        period,
+        windows: Mutex::new(RateWindows {
+            domains: HashMap::new(),
+            last_sweep: Instant::now(),
+        }),
+    })
+}
+
+struct ObservedLookup {
+    inner: Arc<dyn Lookup>,
+    observer: Arc<dyn VerificationObserver>,
+}
+impl ObservedLookup {
+    /// Run the query of `name`, reporting its latency
+    async fn observe<T>(
+        &self,
+        name: &str,
+        lookup: BoxFuture<'_, Result<T, DKIMError>>,
+    ) -> Result<T, DKIMError> {
+        let start = Instant::now();
+        let res = lookup.await;
+        self.observer
+            .dns_lookup(name, start.elapsed(), res.as_ref().map(|_| ()));
+        res
+    }
+}
+impl Lookup for ObservedLookup {
+    fn lookup_txt<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<Vec<String>, DKIMError>> {
+        Box::pin(self.observe(name, self.inner.lookup_txt(name)))
+    }
+
+    #[cfg(feature = "spf")]
+    fn lookup_a<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<Vec<Ipv4Addr>, DKIMError>> {
+        Box::pin(self.observe(name, self.inner.lookup_a(name)))
+    }
+
+    #[cfg(feature = "spf")]
+    fn lookup_aaaa<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<Vec<Ipv6Addr>, DKIMError>> {
+        Box::pin(self.observe(name, self.inner.lookup_aaaa(name)))
+    }
+
+    #[cfg(feature = "spf")]
+    fn lookup_mx<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<Vec<String>, DKIMError>> {
+        Box::pin(self.observe(name, self.inner.lookup_mx(name)))
+    }
+}
+
+/// Wrap a lookup to report the latency of its queries to an observer
+pub(crate) fn observed(
+    lookup: Arc<dyn Lookup>,
+    observer: Arc<dyn VerificationObserver>,
+) -> Arc<dyn Lookup> {
+    Arc::new(ObservedLookup {
+        inner: lookup,
+        observer,
+    })
+}
+
+/// Key records captured at some point, or their history, to verify archived
+/// messages after the keys were rotated. See [crate::verify_email_as_of].
+#[derive(Debug, Clone, Default)]
+pub struct Snapshot {
+    records: Vec<SnapshotRecord>,
+}
+
+#[derive(Debug, Clone)]
+struct SnapshotRecord {
+    name: String,
+    txt: String,
+    published: Option<i64>,
+    removed: Option<i64>,
+}
+
+impl Snapshot {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a TXT record of `name`, for instance `s1._domainkey.example.com`
+    pub fn with_record(self, name: &str, txt: &str) -> Self {
+        self.with_record_between(name, txt, None, None)
+    }
+
+    /// Add a TXT record published from `published` until `removed`, as Unix
+    /// timestamps. A missing bound is unlimited.
+    pub fn with_record_between(
+        mut self,
+        name: &str,
+        txt: &str,
+        published: Option<i64>,
+        removed: Option<i64>,
+    ) -> Self {
+        self.records.push(SnapshotRecord {
+            name: normalize_name(name),
+            txt: txt.to_owned(),
+            published,
+            removed,
+        });
+        self
+    }
+
+    /// Lookup answering with the records published at `time`, as a Unix
+    /// timestamp. Names without records don't exist.
+    pub fn at(&self, time: i64) -> Arc<dyn Lookup> {
+        let mut records: HashMap<String, Vec<String>> = HashMap::new();
+        for record in &self.records {
+            let published = record.published.is_none_or(|published| published <= time);
+            let removed = record.removed.is_some_and(|removed| removed <= time);
+            if published && !removed {
+                records
+                    .entry(record.name.clone())
+                    .or_default()
+                    .push(record.txt.clone());
+            }
+        }
+        Arc::new(SnapshotLookup { records })
+    }
+}
+
+pub(crate) fn normalize_name(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+struct SnapshotLookup {
+    records: HashMap<String, Vec<String>>,
+}
+impl Lookup for SnapshotLookup {
+    fn lookup_txt<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<Vec<String>, DKIMError>> {
+        let res = self
+            .records
+            .get(&normalize_name(name))
+            .cloned()
+            .ok_or_else(|| DKIMError::DnsNxDomain {
+                name: name.to_owned(),
+            });
+        Box::pin(futures::future::ready(res))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+    use trust_dns_resolver::proto::rr::rdata::TXT;
+    use trust_dns_resolver::proto::rr::Record;
+
+    struct CountingLookup {
+        count: AtomicUsize,
+    }
+    impl Lookup for CountingLookup {
+        fn lookup_txt<'a>(
+            &'a self,
+            name: &'a str,
+        ) -> BoxFuture<'a, Result<Vec<String>, DKIMError>> {
+            self.count.fetch_add(1, Ordering::SeqCst);
+            Box::pin(async move {
+                match name {
+                    "timeout" => Err(DKIMError::DnsTimeout {
+                        name: name.to_owned(),
+                    }),
+                    _ => Ok(vec![name.to_owned()]),
+                }
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cached() {
+        let inner = Arc::new(CountingLookup {
+            count: AtomicUsize::new(0),
+        });
+        let lookup = cached(inner.clone(), 2, Duration::from_secs(3600));
+
+        for _ in 0..3 {
+            assert_eq!(lookup.lookup_txt("a").await, Ok(vec!["a".to_owned()]));
+            assert!(lookup.lookup_txt("timeout").await.is_err());
+        }
+        assert_eq!(inner.count.load(Ordering::SeqCst), 4);
+
+        // "a" is evicted by "b" and "c"
+        for name in ["b", "c", "a"] {
+            assert!(lookup.lookup_txt(name).await.is_ok());
+        }
+        assert_eq!(inner.count.load(Ordering::SeqCst), 7);
+
+        // Expired records are queried again
+        let inner = Arc::new(CountingLookup {
+            count: AtomicUsize::new(0),
+        });
+        let lookup = cached(inner.clone(), 2, Duration::ZERO);
+        assert!(lookup.lookup_txt("a").await.is_ok());
+        assert!(lookup.lookup_txt("a").await.is_ok());
+        assert_eq!(inner.count.load(Ordering::SeqCst), 2);
+    }
+
+    struct TestTransport;
+    impl Transport for TestTransport {
+        fn exchange<'a>(&'a self, query: Vec<u8>) -> BoxFuture<'a, Result<Vec<u8>, DKIMError>> {
+            let query = Message::from_vec(&query).unwrap();
+            let name = query.queries()[0].name().clone();
+            let mut response = Message::new();
+            response.set_id(query.id());
+            match name.to_ascii().as_str() {
+                "s1._domainkey.example.com." => {
+                    response.add_answer(Record::from_rdata(
+                        name,
+                        300,
+                        RData::TXT(TXT::new(vec!["v=DKIM1; p=".to_owned(), "key".to_owned()])),
+                    ));
+                }
+                "empty._domainkey.example.com." => {}
+                "truncated._domainkey.example.com." => {
+                    response.set_truncated(true);
+                    response.add_answer(Record::from_rdata(
+                        name,
+                        300,
+                        RData::TXT(TXT::new(vec!["v=DKIM1; p=".to_owned()])),
+                    ));
+                }
+                "error._domainkey.example.com." => {
+                    response.set_response_code(ResponseCode::ServFail);
+                }
+                _ => {
+                    response.set_response_code(ResponseCode::NXDomain);
+                }
+            }
+            Box::pin(futures::future::ready(Ok(response.to_vec().unwrap())))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rate_limited() {
+        let inner = Arc::new(CountingLookup {
+            count: AtomicUsize::new(0),
+        });
+        let lookup = rate_limited(inner.clone(), 2, Duration::from_secs(3600));
+
+        assert!(lookup.lookup_txt("s1._domainkey.example.com").await.is_ok());
+        assert!(lookup.lookup_txt("s2._domainkey.Example.com").await.is_ok());
+        let err = lookup
+            .lookup_txt("s3._domainkey.example.com")
+            .await
+            .unwrap_err();
+        assert!(err.is_temporary());
+        assert!(lookup.lookup_txt("s1._domainkey.example.org").await.is_ok());
+        assert_eq!(inner.count.load(Ordering::SeqCst), 3);
+
+        let lookup = rate_limited(inner.clone(), 1, Duration::ZERO);
+        assert!(lookup.lookup_txt("s1._domainkey.example.com").await.is_ok());
+        assert!(lookup.lookup_txt("s1._domainkey.example.com").await.is_ok());
+
+        // The window of a domain restarts once its period ended
+        let lookup = rate_limited(inner.clone(), 1, Duration::from_millis(20));
+        assert!(lookup.lookup_txt("s1._domainkey.example.com").await.is_ok());
+        assert!(lookup
+            .lookup_txt("s1._domainkey.example.com")
+            .await
+            .is_err());
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        assert!(lookup.lookup_txt("s1._domainkey.example.com").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_resolver_builder() {
+        assert_eq!(
+            ResolverBuilder::new().build().err(),
+            Some(DKIMError::BuilderError("missing required nameservers"))
+        );
+        assert!(ResolverBuilder::new()
+            .with_nameserver("127.0.0.1:53".parse().unwrap(), Protocol::Udp)
+            .with_nameserver("127.0.0.1:53".parse().unwrap(), Protocol::Tcp)
+            .with_edns(true)
+            .with_timeout(Duration::from_secs(1))
+            .with_attempts(1)
+            .build()
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_from_transport() {
+        let lookup = from_transport(Arc::new(TestTransport));
+
+        assert_eq!(
+            lookup.lookup_txt("s1._domainkey.example.com").await,
+            Ok(vec!["v=DKIM1; p=key".to_owned()])
+        );
+        assert_eq!(
+            lookup.lookup_txt("empty._domainkey.example.com").await,
+            Err(DKIMError::DnsNoTxtRecord {
+                name: "empty._domainkey.example.com".to_owned(),
+            })
+        );
+        assert_eq!(
+            lookup.lookup_txt("missing._domainkey.example.com").await,
+            Err(DKIMError::DnsNxDomain {
+                name: "missing._domainkey.example.com".to_owned(),
+            })
+        );
+        assert_eq!(
+            lookup.lookup_txt("error._domainkey.example.com").await,
+            Err(DKIMError::DnsServFail {
+                name: "error._domainkey.example.com".to_owned(),
+            })
+        );
+        let err = lookup
+            .lookup_txt("truncated._domainkey.example.com")
+            .await
+            .unwrap_err();
+        assert!(err.is_temporary());
+    }
+
+    #[tokio::test]
+    async fn test_snapshot() {
+        let snapshot = Snapshot::new()
+            .with_record("S1._domainkey.example.com.", "v=DKIM1; p=current")
+            .with_record_between(
+                "s2._domainkey.example.com",
+                "v=DKIM1; p=old",
+                None,
+                Some(100),
+            )
+            .with_record_between(
+                "s2._domainkey.example.com",
+                "v=DKIM1; p=new",
+                Some(100),
+                None,
+            );
+
+        let lookup = snapshot.at(50);
+        assert_eq!(
+            lookup.lookup_txt("s1._domainkey.example.com").await,
+            Ok(vec!["v=DKIM1; p=current".to_owned()])
+        );
+        assert_eq!(
+            lookup.lookup_txt("s2._domainkey.example.com").await,
+            Ok(vec!["v=DKIM1; p=old".to_owned()])
+        );
+        assert_eq!(
+            snapshot
+                .at(100)
+                .lookup_txt("s2._domainkey.example.com")
+                .await,
+            Ok(vec!["v=DKIM1; p=new".to_owned()])
+        );
+        let err = lookup
+            .lookup_txt("s3._domainkey.example.com")
+            .await
+            .unwrap_err();
+        assert!(err.is_key_missing());
+    }
+}
@@ -0,0 +1,422 @@
+//! Verification of the historic DomainKeys signatures
+//! (`DomainKey-Signature` header), as specified in
+//! <https://datatracker.ietf.org/doc/html/rfc4870>. DomainKeys is obsoleted
+//! by DKIM but old messages and some senders still carry these signatures.
+//!
+//! The canonicalization types of the results are not set, since DomainKeys
+//! uses its own (`simple` and `nofws`).
+
+use slog::debug;
+use std::collections::HashMap;
+#[cfg(feature = "dns")]
+use std::sync::Arc;
+
+use crate::canonicalization::canonicalize_header_simple_into;
+use crate::{
+    bytes, hash, parser, signature_value, DKIMError, DKIMResult, DkimPublicKey, ErrorSource,
+};
+#[cfg(feature = "dns")]
+use crate::{dns, public_key};
+
+pub const HEADER: &str = "DomainKey-Signature";
+const REQUIRED_TAGS: &[&str] = &["a", "b", "d", "s"];
+
+/// DomainKeys canonicalization
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Canonicalization {
+    /// The message is left unchanged except for the trailing empty lines
+    Simple,
+    /// All the whitespace is removed and the headers are unfolded
+    Nofws,
+}
+
+/// Parsed `DomainKey-Signature` header
+#[derive(Debug, Clone)]
+pub struct DomainKeysHeader {
+    tags: HashMap<String, String>,
+}
+
+impl DomainKeysHeader {
+    pub fn parse(value: &str) -> Result<Self, DKIMError> {
+        let tags = parser::complete_tag_list(value)?;
+        let tags: HashMap<String, String> =
+            tags.into_iter().map(|tag| (tag.name, tag.value)).collect();
+
+        for required in REQUIRED_TAGS {
+            if !tags.contains_key(*required) {
+                return Err(DKIMError::SignatureMissingRequiredTag(required));
+            }
+        }
+        let header = Self { tags };
+        if header.get_required_tag("a") != "rsa-sha1" {
+            return Err(DKIMError::UnsupportedHashAlgorithm {
+                algorithm: header.get_required_tag("a").to_owned(),
+            });
+        }
+        if let Some(query_method) = header.get_tag("q") {
+            if query_method != "dns" {
+                return Err(DKIMError::UnsupportedQueryMethod);
+            }
+        }
+        header.canonicalization()?;
+        Ok(header)
+    }
+
+    pub fn get_tag(&self, name: &str) -> Option<&str> {
+        self.tags.get(name).map(String::as_str)
+    }
+
+    fn get_required_tag(&self, name: &str) -> &str {
+        self.get_tag(name)
+            .expect("required tags are checked when parsing")
+    }
+
+    /// Signing domain (`d=` tag)
+    pub fn domain(&self) -> &str {
+        self.get_required_tag("d")
+    }
+
+    /// Selector (`s=` tag)
+    pub fn selector(&self) -> &str {
+        self.get_required_tag("s")
+    }
+
+    pub fn canonicalization(&self) -> Result<Canonicalization, DKIMError> {
+        match self.get_tag("c") {
+            None | Some("simple") => Ok(Canonicalization::Simple),
+            Some("nofws") => Ok(Canonicalization::Nofws),
+            Some(value) => Err(DKIMError::UnsupportedCanonicalizationType {
+                canonicalization: value.to_owned(),
+            }),
+        }
+    }
+}
+
+/// Verify the DomainKeys signatures of the email with the provided public
+/// key, published for `selector`. Only the signatures of `from_domain` are
+/// considered, and those of other selectors fail with
+/// [DKIMError::NoKeyForSignature].
+pub fn verify_email_with_key(
+    logger: &slog::Logger,
+    from_domain: &str,
+    email: &mailparse::ParsedMail,
+    selector: &str,
+    public_key: DkimPublicKey,
+) -> Result<DKIMResult, DKIMError> {
+    verify_signatures(logger, from_domain, email, |header| {
+        if header.selector().eq_ignore_ascii_case(selector) {
+            Ok(public_key.clone())
+        } else {
+            Err(DKIMError::NoKeyForSignature)
+        }
+    })
+}
+
+/// Verify the DomainKeys signatures of the email, retrieving the keys with
+/// the resolver. Only the signatures of `from_domain` are considered.
+#[cfg(feature = "dns")]
+pub async fn verify_email_with_resolver(
+    logger: &slog::Logger,
+    from_domain: &str,
+    email: &mailparse::ParsedMail<'_>,
+    resolver: Arc<dyn dns::Lookup>,
+) -> Result<DKIMResult, DKIMError> {
+    // Retrieve the keys first, the verification itself is synchronous
+    let mut keys = HashMap::new();
+    for (_, header) in signatures(email, from_domain) {
+        let header = match header {
+            Ok(header) => header,
+            Err(_) => continue,
+        };
+        let key = public_key::retrieve_public_key(
+            logger,
+            Arc::clone(&resolver),
+            header.domain().to_owned(),
+            header.selector().to_owned(),
+        )
+        .await;
+        keys.insert(header.selector().to_owned(), key);
+    }
+
+    verify_signatures(logger, from_domain, email, |header| {
+        keys.remove(header.selector())
+            .unwrap_or(Err(DKIMError::NoKeyForSignature))
+    })
+}
+
+/// Returns the index and parsed value of the signatures of `from_domain`
+fn signatures<'a>(
+    email: &'a mailparse::ParsedMail,
+    from_domain: &'a str,
+) -> impl Iterator<Item = (usize, Result<DomainKeysHeader, DKIMError>)> + 'a {
+    email
+        .headers
+        .iter()
+        .enumerate()
+        .filter(|(_, h)| h.get_key_raw().eq_ignore_ascii_case(HEADER.as_bytes()))
+        .map(|(i, h)| (i, signature_value(h).and_then(DomainKeysHeader::parse)))
+        .filter(move |(_, header)| match header {
+            Ok(header) => header.domain().eq_ignore_ascii_case(from_domain),
+            Err(_) => true,
+        })
+}
+
+fn verify_signatures<F>(
+    logger: &slog::Logger,
+    from_domain: &str,
+    email: &mailparse::ParsedMail,
+    mut get_key: F,
+) -> Result<DKIMResult, DKIMError>
+where
+    F: FnMut(&DomainKeysHeader) -> Result<DkimPublicKey, DKIMError>,
+{
+    let mut last_error = None;
+
+    for (index, header) in signatures(email, from_domain) {
+        let res = header.and_then(|header| {
+            let public_key = get_key(&header)?;
+            verify_signature(logger, email, index, &header, public_key)
+        });
+        match res {
+            Ok(()) => {
+                return Ok(DKIMResult::pass_without_canonicalization(
+                    from_domain.to_owned(),
+                ))
+            }
+            Err(err) => {
+                debug!(logger, "failed to verify: {}", err);
+                last_error = Some(err);
+            }
+        }
+    }
+
+    if let Some(err) = last_error {
+        Ok(DKIMResult::fail(err, from_domain.to_owned()))
+    } else {
+        Ok(DKIMResult::neutral(from_domain.to_owned()))
+    }
+}
+
+fn verify_signature(
+    logger: &slog::Logger,
+    email: &mailparse::ParsedMail,
+    index: usize,
+    header: &DomainKeysHeader,
+    public_key: DkimPublicKey,
+) -> Result<(), DKIMError> {
+    if !matches!(public_key, DkimPublicKey::Rsa(_)) {
+        return Err(DKIMError::InappropriateKeyAlgorithm {
+            key_type: public_key.key_type().to_owned(),
+        });
+    }
+    let signature = bytes::decode_base64(header.get_required_tag("b")).map_err(|err| {
+        DKIMError::SignatureSyntaxError {
+            tag: Some("b"),
+            value: header.get_required_tag("b").to_owned(),
+            source: Some(ErrorSource::new(err)),
+        }
+    })?;
+
+    let input = canonicalize(email, index, header)?;
+    debug!(logger, "DomainKeys data to hash: {:?}", input);
+    let hash = hash::hash_sha1(&input);
+
+    if crate::verify_signature(hash::HashAlgo::RsaSha1, &hash, &signature, &public_key)? {
+        Ok(())
+    } else {
+        Err(DKIMError::SignatureDidNotVerify)
+    }
+}
+
+/// Returns the signed data: the headers following the signature (restricted
+/// to the `h=` tag when present), an empty line and the body
+// https://datatracker.ietf.org/doc/html/rfc4870#section-3.4
+fn canonicalize(
+    email: &mailparse::ParsedMail,
+    index: usize,
+    header: &DomainKeysHeader,
+) -> Result<Vec<u8>, DKIMError> {
+    let canonicalization = header.canonicalization()?;
+    let signed_headers: Option<Vec<String>> = header
+        .get_tag("h")
+        .map(|value| value.split(':').map(|h| h.trim().to_lowercase()).collect());
+
+    let mut out = vec![];
+    for h in &email.headers[index + 1..] {
+        let key = h.get_key_raw();
+        if let Some(signed_headers) = &signed_headers {
+            if !signed_headers
+                .iter()
+                .any(|name| name.as_bytes().eq_ignore_ascii_case(key))
+            {
+                continue;
+            }
+        }
+        match canonicalization {
+            Canonicalization::Simple => {
+                canonicalize_header_simple_into(key, h.get_value_raw(), &mut out)
+            }
+            Canonicalization::Nofws => {
+                let mut line = vec![];
+                canonicalize_header_simple_into(key, h.get_value_raw(), &mut line);
+                out.extend(line.into_iter().filter(|b| !is_fws(*b)));
+                out.extend_from_slice(b"\r\n");
+            }
+        }
+    }
+    out.extend_from_slice(b"\r\n");
+
+    let body = bytes::get_all_after(email.raw_bytes, b"\r\n\r\n");
+    let mut lines: Vec<Vec<u8>> = body
+        .split_inclusive(|b| *b == b'\n')
+        .map(|line| {
+            let line = line.strip_suffix(b"\n").unwrap_or(line);
+            let line = line.strip_suffix(b"\r").unwrap_or(line);
+            match canonicalization {
+                Canonicalization::Simple => line.to_vec(),
+                Canonicalization::Nofws => line.iter().copied().filter(|b| !is_fws(*b)).collect(),
+            }
+        })
+        .collect();
+    // Trailing empty lines are ignored
+    while lines.last().is_some_and(|line| line.is_empty()) {
+        lines.pop();
+    }
+    for line in lines {
+        out.extend_from_slice(&line);
+        out.extend_from_slice(b"\r\n");
+    }
+
+    Ok(out)
+}
+
+fn is_fws(b: u8) -> bool {
+    matches!(b, b' ' | b'\t' | b'\r' | b'\n')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::engine::general_purpose;
+    use base64::Engine;
+    use rsa::pkcs1::DecodeRsaPrivateKey;
+    use rsa::Pkcs1v15Sign;
+    use sha1::Sha1;
+    use std::path::Path;
+
+    fn sign(raw_email: &str, tags: &str) -> String {
+        let private_key =
+            rsa::RsaPrivateKey::read_pkcs1_pem_file(Path::new("./test/keys/2022.private")).unwrap();
+        let unsigned = format!("{}: {}; b=\r\n{}", HEADER, tags, raw_email);
+        let email = mailparse::parse_mail(unsigned.as_bytes()).unwrap();
+        let header = DomainKeysHeader::parse(&format!("{}; b=", tags)).unwrap();
+        let input = canonicalize(&email, 0, &header).unwrap();
+        let signature = private_key
+            .sign(Pkcs1v15Sign::new::<Sha1>(), &hash::hash_sha1(input))
+            .unwrap();
+        format!(
+            "{}: {}; b={}\r\n{}",
+            HEADER,
+            tags,
+            general_purpose::STANDARD.encode(signature),
+            raw_email
+        )
+    }
+
+    fn public_key() -> DkimPublicKey {
+        let private_key =
+            rsa::RsaPrivateKey::read_pkcs1_pem_file(Path::new("./test/keys/2022.private")).unwrap();
+        DkimPublicKey::Rsa(private_key.to_public_key())
+    }
+
+    #[test]
+    fn test_parse_header() {
+        let header =
+            DomainKeysHeader::parse("a=rsa-sha1; q=dns; c=nofws; d=example.com; s=s1; b=abc")
+                .unwrap();
+        assert_eq!(header.domain(), "example.com");
+        assert_eq!(header.selector(), "s1");
+        assert_eq!(header.canonicalization(), Ok(Canonicalization::Nofws));
+
+        assert_eq!(
+            DomainKeysHeader::parse("a=rsa-sha1; d=example.com; b=abc").unwrap_err(),
+            DKIMError::SignatureMissingRequiredTag("s")
+        );
+        assert_eq!(
+            DomainKeysHeader::parse("a=rsa-sha256; d=example.com; s=s1; b=abc").unwrap_err(),
+            DKIMError::UnsupportedHashAlgorithm {
+                algorithm: "rsa-sha256".to_owned()
+            }
+        );
+    }
+
+    #[test]
+    fn test_canonicalize_nofws() {
+        let raw_email =
+            "DomainKey-Signature: a=rsa-sha1; c=nofws; d=example.com; s=s1; b=abc\r\nReceived: by relay\r\nFrom: Joe <joe@example.com>\r\nSubject: long\r\n  subject\r\n\r\nHello  \tAlice\r\n\r\n\r\n";
+        let email = mailparse::parse_mail(raw_email.as_bytes()).unwrap();
+        let header = DomainKeysHeader::parse(
+            "a=rsa-sha1; c=nofws; d=example.com; s=s1; h=from:subject; b=abc",
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(canonicalize(&email, 0, &header).unwrap()).unwrap(),
+            "From:Joe<joe@example.com>\r\nSubject:longsubject\r\n\r\nHelloAlice\r\n"
+        );
+    }
+
+    #[test]
+    fn test_verify_email_with_key() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let raw_email =
+            "From: Joe <joe@example.com>\r\nSubject: dinner\r\n\r\nAre you hungry  yet?\r\n";
+
+        for c in ["simple", "nofws"] {
+            let tags = format!("a=rsa-sha1; q=dns; c={}; d=example.com; s=s20", c);
+            let signed = sign(raw_email, &tags);
+            let email = mailparse::parse_mail(signed.as_bytes()).unwrap();
+            let result =
+                verify_email_with_key(&logger, "example.com", &email, "s20", public_key()).unwrap();
+            assert_eq!(result.to_string(), "pass");
+
+            let tampered = signed.replace("hungry", "angry");
+            let email = mailparse::parse_mail(tampered.as_bytes()).unwrap();
+            let result =
+                verify_email_with_key(&logger, "example.com", &email, "s20", public_key()).unwrap();
+            assert_eq!(result.to_string(), "fail (signature did not verify)");
+        }
+
+        // Whitespace changes are tolerated with nofws only
+        let signed = sign(raw_email, "a=rsa-sha1; c=nofws; d=example.com; s=s20")
+            .replace("hungry  yet", "hungry yet");
+        let email = mailparse::parse_mail(signed.as_bytes()).unwrap();
+        let result =
+            verify_email_with_key(&logger, "example.com", &email, "s20", public_key()).unwrap();
+        assert_eq!(result.to_string(), "pass");
+
+        let email = mailparse::parse_mail(raw_email.as_bytes()).unwrap();
+        let result =
+            verify_email_with_key(&logger, "example.com", &email, "s20", public_key()).unwrap();
+        assert_eq!(result.to_string(), "neutral");
+    }
+
+    #[test]
+    fn test_verify_email_with_key_selector() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let raw_email = "From: Joe <joe@example.com>\r\nSubject: dinner\r\n\r\nHello\r\n";
+        let signed = sign(raw_email, "a=rsa-sha1; d=example.com; s=s20");
+        let email = mailparse::parse_mail(signed.as_bytes()).unwrap();
+
+        // The key of another selector isn't used, even if it would verify
+        let result =
+            verify_email_with_key(&logger, "example.com", &email, "s21", public_key()).unwrap();
+        assert_eq!(result.error(), Some(DKIMError::NoKeyForSignature));
+
+        // Each signature is verified with the key of its selector
+        let signed = sign(&signed, "a=rsa-sha1; d=example.com; s=s21");
+        let email = mailparse::parse_mail(signed.as_bytes()).unwrap();
+        let result =
+            verify_email_with_key(&logger, "example.com", &email, "s20", public_key()).unwrap();
+        assert_eq!(result.to_string(), "pass");
+    }
+}
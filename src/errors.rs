@@ -1,76 +1,224 @@
+use std::fmt;
+use std::sync::Arc;
+
 /// DKIM error status
 pub enum Status {
     Permfail,
     Tempfail,
 }
 
-quick_error! {
-    #[derive(Debug, PartialEq, Clone)]
-    /// DKIM errors
-    pub enum DKIMError {
-        UnsupportedHashAlgorithm(value: String) {
-            display("unsupported hash algorithm: {}", value)
-        }
-        UnsupportedCanonicalizationType(value: String) {
-            display("unsupported canonicalization: {}", value)
-        }
-        SignatureSyntaxError(err: String) {
-            display("signature syntax error: {}", err)
-        }
-        SignatureMissingRequiredTag(name: &'static str) {
-            display("signature missing required tag ({})", name)
-        }
-        IncompatibleVersion {
-            display("incompatible version")
-        }
-        DomainMismatch {
-            display("domain mismatch")
-        }
-        FromFieldNotSigned {
-            display("From field not signed")
-        }
-        SignatureExpired {
-            display("signature expired")
-        }
-        UnacceptableSignatureHeader {
-            display("unacceptable signature header")
-        }
-        UnsupportedQueryMethod {
-            display("unsupported query method")
-        }
-        KeyUnavailable(err: String) {
-            display("key unavailable: {}", err)
-        }
-        UnknownInternalError(err: String) {
-            display("internal error: {}", err)
-        }
-        NoKeyForSignature {
-            display("no key for signature")
-        }
-        KeySyntaxError {
-            display("key syntax error")
-        }
-        KeyIncompatibleVersion {
-            display("key incompatible version")
-        }
-        InappropriateKeyAlgorithm {
-            display("inappropriate key algorithm")
-        }
-        SignatureDidNotVerify {
-            display("signature did not verify")
-        }
-        BodyHashDidNotVerify {
-            display("body hash did not verify")
-        }
-        MalformedBody {
-            display("malformed email body")
-        }
-        FailedToSign(err: String) {
-            display("failed sign: {}", err)
-        }
-        BuilderError(err: &'static str) {
-            display("failed to build object: {}", err)
-        }
+/// DKIM errors. Variants carry the context of the error as fields, for
+/// instance to localize the messages; new variants may be added.
+///
+/// The variants wrapping the error of another library, such as
+/// `SignatureSyntaxError`, `KeyUnavailable` or `MalformedEmail`, keep it as
+/// their `source`. The signing domain and selector of a signature that failed
+/// are those of its [crate::DKIMResult].
+#[derive(Debug, PartialEq, Clone, thiserror::Error)]
+#[non_exhaustive]
+pub enum DKIMError {
+    #[error("unsupported hash algorithm: {algorithm}")]
+    UnsupportedHashAlgorithm { algorithm: String },
+    #[error("unsupported canonicalization: {canonicalization}")]
+    UnsupportedCanonicalizationType { canonicalization: String },
+    /// The value of a tag of the signature is invalid. Without `tag`, the
+    /// value is the whole signature.
+    #[error("signature syntax error: {}", invalid_value(*tag, value, source.as_ref()))]
+    SignatureSyntaxError {
+        tag: Option<&'static str>,
+        value: String,
+        #[source]
+        source: Option<ErrorSource>,
+    },
+    /// The tag list of a signature doesn't parse: `fragment` is the malformed
+    /// text, up to the end of its tag-spec, at byte `offset` of the tag list
+    #[error("signature syntax error: invalid tag list at byte {offset}: {fragment:?}")]
+    TagListSyntaxError { offset: usize, fragment: String },
+    #[error("signature missing required tag ({0})")]
+    SignatureMissingRequiredTag(&'static str),
+    #[error("incompatible version")]
+    IncompatibleVersion,
+    /// The domain of the identity (`i=` tag) isn't the signing domain (`d=`
+    /// tag) or one of its subdomains
+    #[error("domain mismatch: {identity} isn't in {domain}")]
+    DomainMismatch { domain: String, identity: String },
+    #[error("From field not signed")]
+    FromFieldNotSigned,
+    /// The verification time is past the expiration (`x=` tag), both Unix
+    /// timestamps
+    #[error("signature expired")]
+    SignatureExpired { expiration: i64, now: i64 },
+    /// The expiration (`x=` tag) isn't after the signature timestamp (`t=`
+    /// tag)
+    #[error("signature syntax error: x={expiration} isn't after t={timestamp}")]
+    ExpirationBeforeTimestamp { expiration: i64, timestamp: i64 },
+    /// The signature is older than the maximum age, in seconds
+    #[error("signature too old")]
+    SignatureTooOld { age: i64, max_age: i64 },
+    /// The signature timestamp is further in the future than the maximum
+    /// skew, in seconds
+    #[error("signature timestamp in the future")]
+    SignatureTimestampInFuture { skew: i64, max_skew: i64 },
+    /// A header required by the policy isn't in the `h=` tag
+    #[error("{header} header not signed")]
+    RequiredHeaderNotSigned { header: String },
+    #[error("unacceptable signature header")]
+    UnacceptableSignatureHeader,
+    #[error("unsupported query method")]
+    UnsupportedQueryMethod,
+    /// The key record at the DNS name `name` couldn't be retrieved
+    #[error("key unavailable for {name}: {}", with_source(reason, source.as_ref()))]
+    KeyUnavailable {
+        name: String,
+        reason: String,
+        #[source]
+        source: Option<ErrorSource>,
+    },
+    #[error("internal error: {0}")]
+    UnknownInternalError(String),
+    #[error("no key for signature")]
+    NoKeyForSignature,
+    #[error("no key for signature: {name} doesn't exist")]
+    DnsNxDomain { name: String },
+    #[error("no key for signature: no TXT record at {name}")]
+    DnsNoTxtRecord { name: String },
+    #[error("key unavailable: DNS query for {name} timed out")]
+    DnsTimeout { name: String },
+    #[error("key unavailable: DNS server failure for {name}")]
+    DnsServFail { name: String },
+    #[error("multiple key records at {name}")]
+    DnsMultipleRecords { name: String },
+    /// The resolver doesn't implement the lookups of a record type, for
+    /// instance the A and MX lookups of the SPF checks
+    #[error("unsupported DNS lookup: {record_type} records of {name}")]
+    UnsupportedLookup {
+        record_type: &'static str,
+        name: String,
+    },
+    /// The key record or its public key (`p=` tag) doesn't parse
+    #[error("key syntax error: {reason}")]
+    KeySyntaxError {
+        reason: String,
+        #[source]
+        source: Option<ErrorSource>,
+    },
+    #[error("key incompatible version")]
+    KeyIncompatibleVersion,
+    /// The key type (`k=` tag of the record) is unknown or can't verify the
+    /// signature
+    #[error("inappropriate key algorithm: {key_type}")]
+    InappropriateKeyAlgorithm { key_type: String },
+    /// The key doesn't have the length required by its type, in bytes
+    #[error("invalid key length: {actual} bytes instead of {expected}")]
+    InvalidKeyLength { expected: usize, actual: usize },
+    #[error("signature did not verify")]
+    SignatureDidNotVerify,
+    #[error("body hash did not verify")]
+    BodyHashDidNotVerify,
+    /// The body hashes provided ahead of time have none for the
+    /// canonicalization, digest and length of the signature
+    #[error("missing precomputed body hash")]
+    MissingBodyHash,
+    /// Fewer signatures passed than required, or none with the required
+    /// algorithm (`passing` is then 0 and `required` 1)
+    #[error("required signatures missing: {}", missing_signatures(*passing, *required, algorithm.as_deref()))]
+    RequiredSignaturesMissing {
+        passing: usize,
+        required: usize,
+        algorithm: Option<String>,
+    },
+    #[error("malformed email body")]
+    MalformedBody,
+    /// The signed headers are longer than the memory limit of the policy, in
+    /// bytes
+    #[error("memory limit exceeded: signed headers longer than {limit} bytes")]
+    MemoryLimitExceeded { limit: usize },
+    #[error("malformed email: {}", with_source(reason, source.as_ref()))]
+    MalformedEmail {
+        reason: &'static str,
+        #[source]
+        source: Option<ErrorSource>,
+    },
+    /// The private key is malformed, or its passphrase is wrong
+    #[error("invalid private key: {}", with_source(reason, source.as_ref()))]
+    InvalidPrivateKey {
+        reason: &'static str,
+        #[source]
+        source: Option<ErrorSource>,
+    },
+    #[error("failed to sign: {}", with_source(reason, source.as_ref()))]
+    FailedToSign {
+        reason: &'static str,
+        #[source]
+        source: Option<ErrorSource>,
+    },
+    #[error("failed to build object: {0}")]
+    BuilderError(&'static str),
+    #[error("no signer for {domain}")]
+    NoSignerForDomain { domain: String },
+    /// The key published for the selector isn't the public key of the
+    /// signer's private key
+    #[error("key published for {selector} in {domain} doesn't match the private key")]
+    PublishedKeyMismatch { domain: String, selector: String },
+}
+
+fn invalid_value(tag: Option<&str>, value: &str, source: Option<&ErrorSource>) -> String {
+    let message = match tag {
+        Some(tag) => format!("invalid {}= value {:?}", tag, value),
+        None => format!("invalid value {:?}", value),
+    };
+    with_source(&message, source)
+}
+
+/// The message followed by the description of the underlying error, if any
+fn with_source(message: &str, source: Option<&ErrorSource>) -> String {
+    match source {
+        Some(source) => format!("{}: {}", message, source),
+        None => message.to_owned(),
+    }
+}
+
+fn missing_signatures(passing: usize, required: usize, algorithm: Option<&str>) -> String {
+    match algorithm {
+        Some(algorithm) => format!("no passing {} signature", algorithm),
+        None => format!("{} passing signatures out of {}", passing, required),
+    }
+}
+
+/// Error underlying a [DKIMError], returned by its `source()`: the error of
+/// the RSA, Ed25519, base64, DNS or email parsing library. The message of the
+/// [DKIMError] already describes it.
+#[derive(Debug, Clone)]
+pub struct ErrorSource(Arc<dyn std::error::Error + Send + Sync>);
+
+impl ErrorSource {
+    pub fn new(err: impl std::error::Error + Send + Sync + 'static) -> Self {
+        Self(Arc::new(err))
+    }
+
+    /// The underlying error, to downcast it to its type
+    pub fn get(&self) -> &(dyn std::error::Error + Send + Sync + 'static) {
+        self.0.as_ref()
+    }
+}
+
+impl fmt::Display for ErrorSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl std::error::Error for ErrorSource {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.0.source()
+    }
+}
+
+/// Errors are compared by their description, the underlying errors generally
+/// don't implement `PartialEq`
+impl PartialEq for ErrorSource {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0) || self.0.to_string() == other.0.to_string()
     }
 }
 
@@ -78,25 +226,269 @@ impl DKIMError {
     pub fn status(self) -> Status {
         use DKIMError::*;
         match self {
-            SignatureSyntaxError(_)
+            SignatureSyntaxError { .. }
+            | TagListSyntaxError { .. }
             | SignatureMissingRequiredTag(_)
             | IncompatibleVersion
-            | DomainMismatch
+            | DomainMismatch { .. }
             | FromFieldNotSigned
-            | SignatureExpired
+            | SignatureExpired { .. }
+            | ExpirationBeforeTimestamp { .. }
+            | SignatureTooOld { .. }
+            | SignatureTimestampInFuture { .. }
+            | RequiredHeaderNotSigned { .. }
             | UnacceptableSignatureHeader
             | UnsupportedQueryMethod
             | NoKeyForSignature
-            | KeySyntaxError
+            | DnsNxDomain { .. }
+            | DnsNoTxtRecord { .. }
+            | DnsMultipleRecords { .. }
+            | UnsupportedLookup { .. }
+            | KeySyntaxError { .. }
             | KeyIncompatibleVersion
-            | InappropriateKeyAlgorithm
+            | InappropriateKeyAlgorithm { .. }
+            | InvalidKeyLength { .. }
             | SignatureDidNotVerify
             | BodyHashDidNotVerify
+            | MissingBodyHash
+            | RequiredSignaturesMissing { .. }
             | MalformedBody
-            | UnsupportedCanonicalizationType(_)
-            | UnsupportedHashAlgorithm(_) => Status::Permfail,
-            KeyUnavailable(_) | UnknownInternalError(_) => Status::Tempfail,
-            BuilderError(_) | FailedToSign(_) => unreachable!(),
+            | MemoryLimitExceeded { .. }
+            | MalformedEmail { .. }
+            | InvalidPrivateKey { .. }
+            | PublishedKeyMismatch { .. }
+            | NoSignerForDomain { .. }
+            | UnsupportedCanonicalizationType { .. }
+            | UnsupportedHashAlgorithm { .. } => Status::Permfail,
+            KeyUnavailable { .. }
+            | DnsTimeout { .. }
+            | DnsServFail { .. }
+            | UnknownInternalError(_) => Status::Tempfail,
+            BuilderError(_) | FailedToSign { .. } => unreachable!(),
+        }
+    }
+
+    /// Returns whether the error is temporary (for instance a DNS timeout or
+    /// SERVFAIL) and verification may succeed if retried later. Calling MTAs
+    /// typically map temporary errors to a 4xx SMTP response and permanent
+    /// ones to a 5xx.
+    pub fn is_temporary(&self) -> bool {
+        use DKIMError::*;
+        matches!(
+            self,
+            KeyUnavailable { .. }
+                | DnsTimeout { .. }
+                | DnsServFail { .. }
+                | UnknownInternalError(_)
+        )
+    }
+
+    /// Returns whether the error means that no key is published for the
+    /// selector: the name doesn't exist or has no key record
+    pub fn is_key_missing(&self) -> bool {
+        use DKIMError::*;
+        matches!(
+            self,
+            NoKeyForSignature | DnsNxDomain { .. } | DnsNoTxtRecord { .. }
+        )
+    }
+
+    /// Returns the DNS name whose query failed, for the DNS errors and
+    /// [DKIMError::KeyUnavailable]
+    pub fn dns_name(&self) -> Option<&str> {
+        use DKIMError::*;
+        match self {
+            DnsNxDomain { name }
+            | DnsNoTxtRecord { name }
+            | DnsTimeout { name }
+            | DnsServFail { name }
+            | DnsMultipleRecords { name }
+            | KeyUnavailable { name, .. } => Some(name),
+            _ => None,
+        }
+    }
+
+    /// Returns a stable machine-readable code for the error. Unlike the
+    /// `Display` output, codes are not changed between releases and are
+    /// suitable for logs and metrics.
+    pub fn code(&self) -> &'static str {
+        use DKIMError::*;
+        match self {
+            UnsupportedHashAlgorithm { .. } => "DKIM_UNSUPPORTED_HASH_ALGORITHM",
+            UnsupportedCanonicalizationType { .. } => "DKIM_UNSUPPORTED_CANONICALIZATION",
+            SignatureSyntaxError { .. } | TagListSyntaxError { .. } => "DKIM_SIG_SYNTAX",
+            SignatureMissingRequiredTag(_) => "DKIM_SIG_MISSING_TAG",
+            IncompatibleVersion => "DKIM_SIG_INCOMPATIBLE_VERSION",
+            DomainMismatch { .. } => "DKIM_SIG_DOMAIN_MISMATCH",
+            FromFieldNotSigned => "DKIM_SIG_FROM_NOT_SIGNED",
+            SignatureExpired { .. } => "DKIM_SIG_EXPIRED",
+            ExpirationBeforeTimestamp { .. } => "DKIM_SIG_SYNTAX",
+            SignatureTooOld { .. } => "DKIM_SIG_TOO_OLD",
+            SignatureTimestampInFuture { .. } => "DKIM_SIG_FUTURE_TIMESTAMP",
+            RequiredHeaderNotSigned { .. } => "DKIM_SIG_HEADER_NOT_SIGNED",
+            UnacceptableSignatureHeader => "DKIM_SIG_UNACCEPTABLE",
+            UnsupportedQueryMethod => "DKIM_SIG_UNSUPPORTED_QUERY_METHOD",
+            KeyUnavailable { .. } => "DKIM_KEY_UNAVAILABLE",
+            UnknownInternalError(_) => "DKIM_INTERNAL_ERROR",
+            NoKeyForSignature => "DKIM_KEY_NOT_FOUND",
+            DnsNxDomain { .. } => "DKIM_DNS_NXDOMAIN",
+            DnsNoTxtRecord { .. } => "DKIM_DNS_NO_TXT_RECORD",
+            DnsTimeout { .. } => "DKIM_DNS_TIMEOUT",
+            DnsServFail { .. } => "DKIM_DNS_SERVFAIL",
+            DnsMultipleRecords { .. } => "DKIM_DNS_MULTIPLE_RECORDS",
+            UnsupportedLookup { .. } => "DKIM_DNS_UNSUPPORTED_LOOKUP",
+            KeySyntaxError { .. } => "DKIM_KEY_SYNTAX",
+            KeyIncompatibleVersion => "DKIM_KEY_INCOMPATIBLE_VERSION",
+            InappropriateKeyAlgorithm { .. } => "DKIM_KEY_INAPPROPRIATE_ALGORITHM",
+            InvalidKeyLength { .. } => "DKIM_KEY_INVALID_LENGTH",
+            SignatureDidNotVerify => "DKIM_SIG_DID_NOT_VERIFY",
+            BodyHashDidNotVerify => "DKIM_BODY_HASH_DID_NOT_VERIFY",
+            MissingBodyHash => "DKIM_BODY_HASH_MISSING",
+            RequiredSignaturesMissing { .. } => "DKIM_REQUIRED_SIGNATURES_MISSING",
+            MalformedBody => "DKIM_MALFORMED_BODY",
+            MemoryLimitExceeded { .. } => "DKIM_MEMORY_LIMIT_EXCEEDED",
+            MalformedEmail { .. } => "DKIM_MALFORMED_EMAIL",
+            InvalidPrivateKey { .. } => "DKIM_INVALID_PRIVATE_KEY",
+            FailedToSign { .. } => "DKIM_SIGN_FAILED",
+            BuilderError(_) => "DKIM_BUILDER_ERROR",
+            NoSignerForDomain { .. } => "DKIM_NO_SIGNER",
+            PublishedKeyMismatch { .. } => "DKIM_PUBLISHED_KEY_MISMATCH",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_temporary() {
+        assert!(DKIMError::KeyUnavailable {
+            name: "s._domainkey.example.com".to_owned(),
+            reason: "timeout".to_owned(),
+            source: None,
+        }
+        .is_temporary());
+        assert!(DKIMError::UnknownInternalError("oops".to_owned()).is_temporary());
+        assert!(!DKIMError::SignatureDidNotVerify.is_temporary());
+        assert!(!DKIMError::SignatureSyntaxError {
+            tag: Some("l"),
+            value: "bad".to_owned(),
+            source: None,
+        }
+        .is_temporary());
+        assert!(!DKIMError::NoKeyForSignature.is_temporary());
+        assert!(DKIMError::DnsTimeout {
+            name: "s._domainkey.example.com".to_owned(),
+        }
+        .is_temporary());
+        assert!(DKIMError::DnsServFail {
+            name: "s._domainkey.example.com".to_owned(),
+        }
+        .is_temporary());
+        assert!(!DKIMError::DnsNxDomain {
+            name: "s._domainkey.example.com".to_owned(),
+        }
+        .is_temporary());
+        assert!(!DKIMError::BuilderError("missing").is_temporary());
+        assert!(matches!(
+            DKIMError::NoSignerForDomain {
+                domain: "example.net".to_owned()
+            }
+            .status(),
+            Status::Permfail
+        ));
+    }
+
+    #[test]
+    fn test_dns_errors() {
+        let err = DKIMError::DnsNxDomain {
+            name: "s._domainkey.example.com".to_owned(),
+        };
+        assert!(err.is_key_missing());
+        assert_eq!(err.dns_name(), Some("s._domainkey.example.com"));
+        assert_eq!(err.code(), "DKIM_DNS_NXDOMAIN");
+        assert!(DKIMError::DnsNoTxtRecord {
+            name: "s".to_owned(),
+        }
+        .is_key_missing());
+        assert!(!DKIMError::DnsTimeout {
+            name: "s".to_owned(),
         }
+        .is_key_missing());
+        assert_eq!(DKIMError::NoKeyForSignature.dns_name(), None);
+    }
+
+    #[test]
+    fn test_source() {
+        use std::error::Error;
+
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let err = crate::public_key::parse_public_key(&logger, "v=DKIM1; p=!!!").unwrap_err();
+        assert!(matches!(err, DKIMError::KeySyntaxError { .. }));
+        // A malformed key won't parse if retried
+        assert!(!err.is_temporary());
+        let source = err.source().unwrap();
+        assert!(err.to_string().ends_with(&source.to_string()));
+        let DKIMError::KeySyntaxError {
+            source: Some(source),
+            ..
+        } = &err
+        else {
+            unreachable!()
+        };
+        assert!(source.get().downcast_ref::<base64::DecodeError>().is_some());
+
+        assert!(DKIMError::SignatureDidNotVerify.source().is_none());
+    }
+
+    #[test]
+    fn test_display() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let err = crate::hash::parse_length("-1").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "signature syntax error: invalid l= value \"-1\": invalid digit found in string"
+        );
+        let err = DKIMError::KeyUnavailable {
+            name: "s._domainkey.example.com".to_owned(),
+            reason: "DNS rate limit exceeded".to_owned(),
+            source: None,
+        };
+        assert_eq!(
+            err.to_string(),
+            "key unavailable for s._domainkey.example.com: DNS rate limit exceeded"
+        );
+        assert_eq!(err.dns_name(), Some("s._domainkey.example.com"));
+        let err = crate::public_key::parse_public_key(&logger, "p=AAAA").unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("key syntax error: failed to parse public key: "));
+    }
+
+    #[test]
+    fn test_code() {
+        assert_eq!(
+            DKIMError::SignatureSyntaxError {
+                tag: Some("l"),
+                value: "bad".to_owned(),
+                source: None,
+            }
+            .code(),
+            "DKIM_SIG_SYNTAX"
+        );
+        assert_eq!(
+            DKIMError::KeyUnavailable {
+                name: "s._domainkey.example.com".to_owned(),
+                reason: "timeout".to_owned(),
+                source: None,
+            }
+            .code(),
+            "DKIM_KEY_UNAVAILABLE"
+        );
+        assert_eq!(
+            DKIMError::BodyHashDidNotVerify.code(),
+            "DKIM_BODY_HASH_DID_NOT_VERIFY"
+        );
     }
 }
@@ -0,0 +1,496 @@
+//! Step-by-step textual reports of the verification of a message, for support
+//! engineers investigating a failure without reading the debug logs.
+//!
+//! Each signature is checked like the verifier does: the tags are parsed, the
+//! key is retrieved, the hashes are computed and compared with the expected
+//! ones and the signature is checked. The report ends with the verdict.
+
+use base64::engine::general_purpose;
+use base64::Engine;
+use mailparse::MailHeaderMap;
+use std::fmt;
+#[cfg(feature = "dns")]
+use std::sync::Arc;
+
+use crate::mailing_list::{self, ListModification};
+use crate::mime::{self, BodyChange};
+use crate::{
+    compute_signature_hashes, decode_signature, hash, header_from_domain, normalize_domain,
+    signature_value, validate_header, verify_signature, DKIMError, DKIMHeader, DKIMResult,
+    DkimPublicKey, HeaderSelection, HEADER,
+};
+#[cfg(feature = "dns")]
+use crate::{dns, public_key};
+
+/// Explain the verification of a message, retrieving the keys with the
+/// resolver
+#[cfg(feature = "dns")]
+pub async fn explain(
+    logger: &slog::Logger,
+    email: &mailparse::ParsedMail<'_>,
+    resolver: Arc<dyn dns::Lookup>,
+) -> String {
+    let mut report = Report::new(email);
+    for (index, header) in email
+        .headers
+        .get_all_headers(HEADER)
+        .into_iter()
+        .enumerate()
+    {
+        let Some(dkim_header) = report.start_signature(index, header) else {
+            continue;
+        };
+        let domain = dkim_header.get_required_tag("d");
+        let selector = dkim_header.get_required_tag("s");
+        let name = format!(
+            "{}.{}.{}",
+            selector,
+            crate::DNS_NAMESPACE,
+            normalize_domain(domain)
+        );
+        let record = public_key::retrieve_key_record(
+            logger,
+            Arc::clone(&resolver),
+            domain.to_owned(),
+            selector.to_owned(),
+        )
+        .await;
+        let key = match record {
+            Ok(record) => {
+                let testing = if record.testing { ", testing" } else { "" };
+                report.step(
+                    "key",
+                    format_args!("{} ({}{})", name, describe_key(&record.key), testing),
+                );
+                Ok(record.key)
+            }
+            Err(err) => {
+                report.step("key", format_args!("{} unavailable", name));
+                Err(err)
+            }
+        };
+        report.check_signature(logger, &dkim_header, email, key.as_ref());
+    }
+    report.finish()
+}
+
+/// Explain the verification of a message with the provided public key
+pub fn explain_with_key(
+    logger: &slog::Logger,
+    email: &mailparse::ParsedMail<'_>,
+    public_key: &DkimPublicKey,
+) -> String {
+    let mut report = Report::new(email);
+    for (index, header) in email
+        .headers
+        .get_all_headers(HEADER)
+        .into_iter()
+        .enumerate()
+    {
+        let Some(dkim_header) = report.start_signature(index, header) else {
+            continue;
+        };
+        report.step(
+            "key",
+            format_args!("provided ({})", describe_key(public_key)),
+        );
+        report.check_signature(logger, &dkim_header, email, Ok(public_key));
+    }
+    report.finish()
+}
+
+struct Report {
+    out: String,
+    from_domain: Result<String, DKIMError>,
+    signatures: usize,
+    passed: bool,
+    last_error: Option<DKIMError>,
+}
+
+impl Report {
+    fn new(email: &mailparse::ParsedMail) -> Self {
+        let mut report = Self {
+            out: String::new(),
+            from_domain: header_from_domain(email),
+            signatures: 0,
+            passed: false,
+            last_error: None,
+        };
+        let from_domain = match &report.from_domain {
+            Ok(domain) => domain.clone(),
+            Err(err) => format!("unknown ({})", err),
+        };
+        report.line(format_args!("From domain: {}", from_domain));
+        report
+    }
+
+    fn line(&mut self, text: fmt::Arguments) {
+        self.out.push_str(&text.to_string());
+        self.out.push('\n');
+    }
+
+    fn step(&mut self, name: &str, detail: fmt::Arguments) {
+        self.line(format_args!("  {}: {}", name, detail));
+    }
+
+    /// Record the outcome of a signature of the From domain
+    fn outcome(&mut self, result: Result<(), DKIMError>) {
+        match result {
+            Ok(()) => {
+                self.step("result", format_args!("pass"));
+                self.passed = true;
+            }
+            Err(err) => {
+                self.step("result", format_args!("fail ({})", err));
+                self.last_error = Some(err);
+            }
+        }
+    }
+
+    /// Parse a signature, returns `None` if it's invalid or isn't used for the
+    /// verdict
+    fn start_signature<'a>(
+        &mut self,
+        index: usize,
+        header: &'a mailparse::MailHeader<'a>,
+    ) -> Option<DKIMHeader<'a>> {
+        self.signatures += 1;
+        self.line(format_args!("Signature #{}", index + 1));
+
+        let dkim_header = match signature_value(header).and_then(validate_header) {
+            Ok(dkim_header) => dkim_header,
+            Err(err) => {
+                self.step("tags", format_args!("invalid ({})", err));
+                self.outcome(Err(err));
+                return None;
+            }
+        };
+        let tags: Vec<String> = dkim_header
+            .tags
+            .values()
+            .filter(|tag| tag.name != "b" && tag.name != "bh")
+            .map(|tag| format!("{}={}", tag.name, tag.value))
+            .collect();
+        self.step("tags", format_args!("{}", tags.join("; ")));
+
+        let domain = dkim_header.get_required_tag("d");
+        match &self.from_domain {
+            Ok(from_domain) if normalize_domain(domain) == normalize_domain(from_domain) => {
+                Some(dkim_header)
+            }
+            _ => {
+                self.step(
+                    "skipped",
+                    format_args!(
+                        "the signing domain {} isn't the From domain, the signature isn't used for the verdict",
+                        domain
+                    ),
+                );
+                None
+            }
+        }
+    }
+
+    fn check_signature(
+        &mut self,
+        logger: &slog::Logger,
+        dkim_header: &DKIMHeader,
+        email: &mailparse::ParsedMail,
+        public_key: Result<&DkimPublicKey, &DKIMError>,
+    ) {
+        let result = public_key
+            .map_err(Clone::clone)
+            .and_then(|public_key| self.check_hashes(logger, dkim_header, email, public_key));
+        self.outcome(result);
+    }
+
+    fn check_hashes(
+        &mut self,
+        logger: &slog::Logger,
+        dkim_header: &DKIMHeader,
+        email: &mailparse::ParsedMail,
+        public_key: &DkimPublicKey,
+    ) -> Result<(), DKIMError> {
+        // The hashes are computed as by the verifier
+        let hashes = compute_signature_hashes(logger, dkim_header, email, None, None)?;
+        self.step(
+            "canonicalization",
+            format_args!(
+                "{} for the headers, {} for the body",
+                hashes.header_canonicalization_type, hashes.body_canonicalization_type
+            ),
+        );
+
+        let length = dkim_header.get_tag("l");
+        let computed_body_hash = &hashes.body_hash;
+        let expected_body_hash = dkim_header.get_required_tag("bh");
+        let matches = computed_body_hash == expected_body_hash;
+        self.step(
+            "body hash",
+            format_args!(
+                "computed {}, expected {}{}: {}",
+                computed_body_hash,
+                expected_body_hash,
+                length
+                    .map(|length| format!(" over the first {} bytes", length))
+                    .unwrap_or_default(),
+                if matches { "match" } else { "MISMATCH" }
+            ),
+        );
+        if !matches {
+            self.describe_body_change(dkim_header, email);
+            self.describe_list_modifications(logger, dkim_header, email, public_key);
+            return Err(DKIMError::BodyHashDidNotVerify);
+        }
+
+        let signed_headers: Vec<String> = hash::select_signed_headers(
+            dkim_header.get_required_tag("h"),
+            email,
+            HeaderSelection::BottomUp,
+        )
+        .into_iter()
+        .map(|selected| match selected.index {
+            Some(index) => format!("{} (header #{})", selected.name, index + 1),
+            None => format!("{} (absent)", selected.name),
+        })
+        .collect();
+        self.step(
+            "signed headers",
+            format_args!("{}", signed_headers.join(", ")),
+        );
+
+        self.step(
+            "header hash",
+            format_args!("{}", general_purpose::STANDARD.encode(&hashes.headers_hash)),
+        );
+
+        let signature = decode_signature(dkim_header)?;
+        let verified = verify_signature(
+            hashes.hash_algo,
+            &hashes.headers_hash,
+            &signature,
+            public_key,
+        )?;
+        self.step(
+            "signature",
+            format_args!(
+                "{}",
+                if verified {
+                    "verified"
+                } else {
+                    "DID NOT VERIFY"
+                }
+            ),
+        );
+        if !verified {
+            self.describe_list_modifications(logger, dkim_header, email, public_key);
+            return Err(DKIMError::SignatureDidNotVerify);
+        }
+        Ok(())
+    }
+
+    fn describe_body_change(&mut self, dkim_header: &DKIMHeader, email: &mailparse::ParsedMail) {
+        let Ok(diagnostics) = mime::diagnose_body(dkim_header, email) else {
+            return;
+        };
+        for part in &diagnostics.parts {
+            self.step(
+                "body part",
+                format_args!(
+                    "{} {} at {}..{}, hash {}",
+                    part.path, part.content_type, part.range.start, part.range.end, part.hash
+                ),
+            );
+        }
+        match diagnostics.change {
+            BodyChange::AddedPart(path) => {
+                self.step("body change", format_args!("part {} was added", path))
+            }
+            BodyChange::Appended(offset) => self.step(
+                "body change",
+                format_args!("content was appended at {}", offset),
+            ),
+            BodyChange::Unchanged | BodyChange::Unknown => self.step(
+                "body change",
+                format_args!("unknown, compare the part hashes with the sent message"),
+            ),
+        }
+    }
+
+    fn describe_list_modifications(
+        &mut self,
+        logger: &slog::Logger,
+        dkim_header: &DKIMHeader,
+        email: &mailparse::ParsedMail,
+        public_key: &DkimPublicKey,
+    ) {
+        let Ok(modifications) =
+            mailing_list::diagnose_list_modifications(logger, dkim_header, email, public_key)
+        else {
+            return;
+        };
+        let Some(likely) = modifications.first() else {
+            return;
+        };
+        let describe = |modification: &ListModification| match modification {
+            ListModification::Footer(offset) => {
+                format!("a mailing list footer appended at {}", offset)
+            }
+            ListModification::FooterPart(path) => {
+                format!("a mailing list footer added as part {}", path)
+            }
+            ListModification::TransferEncoding(encoding) => {
+                format!("the body re-encoded as {}", encoding)
+            }
+            ListModification::SubjectTag(tag) => format!("the {} tag added to the Subject", tag),
+        };
+        self.step("likely cause", format_args!("{}", describe(likely)));
+        for modification in &modifications[1..] {
+            self.step("also", format_args!("{}", describe(modification)));
+        }
+    }
+
+    fn finish(mut self) -> String {
+        let domain = self.from_domain.clone().unwrap_or_default();
+        let verdict = if self.passed {
+            "pass".to_owned()
+        } else if let Some(err) = self.last_error.take() {
+            DKIMResult::fail(err, domain).to_string()
+        } else {
+            DKIMResult::neutral(domain).to_string()
+        };
+        if self.signatures == 0 {
+            self.line(format_args!("No signature"));
+        }
+        self.line(format_args!("Verdict: {}", verdict));
+        self.out
+    }
+}
+
+fn describe_key(key: &DkimPublicKey) -> String {
+    match key {
+        DkimPublicKey::Rsa(_) => format!("{}-bit rsa", key.key_size()),
+        DkimPublicKey::Ed25519(_) => "ed25519".to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DkimPrivateKey, SignerBuilder};
+    use rsa::pkcs1::DecodeRsaPrivateKey;
+    use std::path::Path;
+
+    fn signed_email() -> String {
+        let private_key =
+            rsa::RsaPrivateKey::read_pkcs1_pem_file(Path::new("./test/keys/2022.private")).unwrap();
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let signer = SignerBuilder::new()
+            .with_signed_headers(&["From", "Subject", "To"])
+            .unwrap()
+            .with_private_key(DkimPrivateKey::Rsa(private_key))
+            .with_selector("2022")
+            .with_logger(&logger)
+            .with_signing_domain("example.com")
+            .build()
+            .unwrap();
+        let raw_email = "Subject: subject\r\nFrom: joe@example.com\r\n\r\nHello Alice\r\n";
+        let email = mailparse::parse_mail(raw_email.as_bytes()).unwrap();
+        format!("{}\r\n{}", signer.sign(&email).unwrap(), raw_email)
+    }
+
+    fn public_key() -> DkimPublicKey {
+        let record: String = std::fs::read_to_string("./test/keys/2022.txt")
+            .unwrap()
+            .split('"')
+            .skip(1)
+            .step_by(2)
+            .collect();
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        crate::public_key::parse_public_key(&logger, &record).unwrap()
+    }
+
+    #[test]
+    fn test_explain_pass() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let raw_email = signed_email();
+        let email = mailparse::parse_mail(raw_email.as_bytes()).unwrap();
+
+        let report = explain_with_key(&logger, &email, &public_key());
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(lines[0], "From domain: example.com");
+        assert_eq!(lines[1], "Signature #1");
+        assert!(lines[2].starts_with("  tags: v=1; a=rsa-sha256; d=example.com; s=2022;"));
+        assert_eq!(lines[3], "  key: provided (2048-bit rsa)");
+        assert_eq!(
+            lines[4],
+            "  canonicalization: simple for the headers, simple for the body"
+        );
+        assert!(lines[5].starts_with("  body hash: computed "));
+        assert!(lines[5].ends_with(": match"));
+        assert_eq!(
+            lines[6],
+            "  signed headers: from (header #3), subject (header #2), to (absent)"
+        );
+        assert_eq!(lines[8], "  signature: verified");
+        assert_eq!(lines[9], "  result: pass");
+        assert_eq!(lines[10], "Verdict: pass");
+    }
+
+    #[test]
+    fn test_explain_fail() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let raw_email = signed_email().replace("Hello Alice", "Hello Mallory");
+        let email = mailparse::parse_mail(raw_email.as_bytes()).unwrap();
+
+        let report = explain_with_key(&logger, &email, &public_key());
+        assert!(report.contains(": MISMATCH\n  body part: 1 text/plain at 0..15, hash "));
+        assert!(report.contains(
+            "  body change: unknown, compare the part hashes with the sent message\n  result: fail (body hash did not verify)\n"
+        ));
+        assert!(report.ends_with("Verdict: fail (body hash did not verify)\n"));
+
+        let email = mailparse::parse_mail(b"From: joe@example.com\r\n\r\nHello\r\n").unwrap();
+        assert_eq!(
+            explain_with_key(&logger, &email, &public_key()),
+            "From domain: example.com\nNo signature\nVerdict: neutral\n"
+        );
+    }
+
+    #[test]
+    fn test_explain_list_modification() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let raw_email = signed_email().replace("Subject: subject", "Subject: [list] subject");
+        let email = mailparse::parse_mail(raw_email.as_bytes()).unwrap();
+
+        let report = explain_with_key(&logger, &email, &public_key());
+        assert!(report.contains(
+            "  signature: DID NOT VERIFY\n  likely cause: the [list] tag added to the Subject\n"
+        ));
+    }
+
+    #[cfg(feature = "dns")]
+    #[tokio::test]
+    async fn test_explain_dns() {
+        struct Records;
+        impl dns::Lookup for Records {
+            fn lookup_txt<'a>(
+                &'a self,
+                name: &'a str,
+            ) -> futures::future::BoxFuture<'a, Result<Vec<String>, DKIMError>> {
+                assert_eq!(name, "2022._domainkey.example.com");
+                let record = std::fs::read_to_string("./test/keys/2022.txt").unwrap();
+                let record = record.split('"').skip(1).step_by(2).collect();
+                Box::pin(futures::future::ready(Ok(vec![record])))
+            }
+        }
+
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let raw_email = signed_email();
+        let email = mailparse::parse_mail(raw_email.as_bytes()).unwrap();
+
+        let report = explain(&logger, &email, Arc::new(Records)).await;
+        assert!(report.contains("\n  key: 2022._domainkey.example.com (2048-bit rsa, testing)\n"));
+        assert!(report.ends_with("Verdict: pass\n"));
+    }
+}
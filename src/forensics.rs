@@ -0,0 +1,224 @@
+//! Forensics using the copied header fields of a signature, the `z=` tag.
+//! Comparing them with the received headers tells which signed headers were
+//! altered in transit.
+//! See <https://datatracker.ietf.org/doc/html/rfc6376#section-3.5>
+
+use crate::hash::{select_signed_headers, HeaderSelection};
+use crate::{decode_quoted_printable, encode_quoted_printable, DKIMError, DKIMHeader, ErrorSource};
+
+/// Header field copied in the `z=` tag
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CopiedHeader {
+    pub name: String,
+    pub value: String,
+}
+
+/// How a copied header compares to the received message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HeaderChange {
+    Unchanged,
+    /// The header was modified, the received value is provided
+    Altered(String),
+    /// The header isn't in the received message anymore
+    Removed,
+}
+
+/// Comparison of a copied header with the received message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HeaderDiff {
+    pub name: String,
+    /// Value when the message was signed
+    pub signed: String,
+    pub change: HeaderChange,
+}
+
+/// Decode the value of a `z=` tag: `|`-separated header fields, in
+/// dkim-quoted-printable
+pub fn decode_copied_headers(value: &str) -> Result<Vec<CopiedHeader>, DKIMError> {
+    value
+        .split('|')
+        .filter(|field| !field.trim().is_empty())
+        .map(|field| {
+            let field = String::from_utf8(decode_quoted_printable(field)?).map_err(|err| {
+                DKIMError::SignatureSyntaxError {
+                    tag: Some("z"),
+                    value: String::from_utf8_lossy(err.as_bytes()).into_owned(),
+                    source: Some(ErrorSource::new(err)),
+                }
+            })?;
+            let (name, value) =
+                field
+                    .split_once(':')
+                    .ok_or_else(|| DKIMError::SignatureSyntaxError {
+                        tag: Some("z"),
+                        value: field.clone(),
+                        source: None,
+                    })?;
+            Ok(CopiedHeader {
+                name: name.trim().to_owned(),
+                value: value.to_owned(),
+            })
+        })
+        .collect()
+}
+
+/// Encode headers as the value of a `z=` tag, the counterpart of
+/// [decode_copied_headers]
+pub fn encode_copied_headers(headers: &[CopiedHeader]) -> String {
+    headers
+        .iter()
+        .map(|header| {
+            format!(
+                "{}:{}",
+                header.name,
+                encode_quoted_printable(header.value.as_bytes())
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("|")
+}
+
+/// Compare the headers copied in the `z=` tag of the signature with the
+/// received headers. When a header occurs several times, the instances are
+/// matched from the bottom, like the signed headers. Whitespace differences
+/// are ignored.
+pub fn diff_copied_headers(
+    dkim_header: &DKIMHeader,
+    email: &mailparse::ParsedMail,
+) -> Result<Vec<HeaderDiff>, DKIMError> {
+    let copied = dkim_header
+        .get_tag("z")
+        .ok_or(DKIMError::SignatureMissingRequiredTag("z"))?;
+    let copied = decode_copied_headers(copied)?;
+
+    let names: Vec<&str> = copied.iter().map(|header| header.name.as_str()).collect();
+    let received = select_signed_headers(&names.join(":"), email, HeaderSelection::BottomUp);
+
+    Ok(copied
+        .into_iter()
+        .zip(received)
+        .map(|(copied, received)| {
+            let change = match received.value {
+                None => HeaderChange::Removed,
+                Some(value) => {
+                    let value = String::from_utf8_lossy(value);
+                    if normalize(&value) == normalize(&copied.value) {
+                        HeaderChange::Unchanged
+                    } else {
+                        HeaderChange::Altered(value.trim().to_owned())
+                    }
+                }
+            };
+            HeaderDiff {
+                name: copied.name,
+                signed: copied.value,
+                change,
+            }
+        })
+        .collect())
+}
+
+/// Unfold and reduce whitespace, to compare values
+fn normalize(value: &str) -> String {
+    value.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::validate_header;
+
+    #[test]
+    fn test_decode_copied_headers() {
+        assert_eq!(
+            decode_copied_headers("From:foo@eng.example.net|To:joe@example.com|\r\n Subject:demo=20run|Date:July=205,=202005=203:44:08=20PM=20-0700")
+                .unwrap(),
+            vec![
+                CopiedHeader {
+                    name: "From".to_owned(),
+                    value: "foo@eng.example.net".to_owned()
+                },
+                CopiedHeader {
+                    name: "To".to_owned(),
+                    value: "joe@example.com".to_owned()
+                },
+                CopiedHeader {
+                    name: "Subject".to_owned(),
+                    value: "demo run".to_owned()
+                },
+                CopiedHeader {
+                    name: "Date".to_owned(),
+                    value: "July 5, 2005 3:44:08 PM -0700".to_owned()
+                },
+            ]
+        );
+        assert_eq!(
+            decode_copied_headers("Subject:Gr=C3=BC=C3=9Fe").unwrap()[0].value,
+            "Grüße"
+        );
+        assert!(decode_copied_headers("Subject:a=2").is_err());
+        assert!(decode_copied_headers("Subject").is_err());
+    }
+
+    #[test]
+    fn test_encode_copied_headers() {
+        let headers = vec![
+            CopiedHeader {
+                name: "From".to_owned(),
+                value: "foo@eng.example.net".to_owned(),
+            },
+            CopiedHeader {
+                name: "Subject".to_owned(),
+                value: " a|b; Grüße".to_owned(),
+            },
+        ];
+        let encoded = encode_copied_headers(&headers);
+        assert_eq!(
+            encoded,
+            "From:foo@eng.example.net|Subject:=20a=7Cb=3B=20Gr=C3=BC=C3=9Fe"
+        );
+        assert_eq!(decode_copied_headers(&encoded).unwrap(), headers);
+    }
+
+    #[test]
+    fn test_diff_copied_headers() {
+        let dkim_header = validate_header(
+            "v=1; a=rsa-sha256; d=example.net; s=brisbane; h=from:to:subject:cc; \
+             z=From:foo@example.net|To:joe@example.com|Subject:demo=20run|Cc:bob@example.com; \
+             bh=MTIzNDU2Nzg5MDEyMzQ1Njc4OTAxMjM0NTY3ODkwMTI=; b=dzdVyOfAKCdLXdJOc9G2q8LoXSlEniSbav+yuU4zGeeruD00lszZVoG4ZHRNiYzR",
+        )
+        .unwrap();
+        let email = mailparse::parse_mail(
+            b"From: foo@example.net\r\nTo:  joe@example.com\r\nSubject: [list] demo run\r\n\r\nbody",
+        )
+        .unwrap();
+
+        let diff = diff_copied_headers(&dkim_header, &email).unwrap();
+        let changes: Vec<_> = diff
+            .iter()
+            .map(|diff| (diff.name.as_str(), &diff.change))
+            .collect();
+        assert_eq!(
+            changes,
+            vec![
+                ("From", &HeaderChange::Unchanged),
+                ("To", &HeaderChange::Unchanged),
+                (
+                    "Subject",
+                    &HeaderChange::Altered("[list] demo run".to_owned())
+                ),
+                ("Cc", &HeaderChange::Removed),
+            ]
+        );
+        assert_eq!(diff[2].signed, "demo run");
+
+        let without_z = validate_header(
+            "v=1; a=rsa-sha256; d=example.net; s=brisbane; h=from; bh=hash; b=hash",
+        )
+        .unwrap();
+        assert_eq!(
+            diff_copied_headers(&without_z, &email).unwrap_err(),
+            DKIMError::SignatureMissingRequiredTag("z")
+        );
+    }
+}
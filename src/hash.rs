@@ -1,29 +1,188 @@
+use std::collections::hash_map::RandomState;
 use std::collections::HashMap;
+use std::hash::BuildHasher;
+use std::sync::{Mutex, PoisonError};
 
 use base64::engine::general_purpose;
 use base64::Engine;
+use lru_cache::LruCache;
 use slog::debug;
 
 use crate::canonicalization::{
-    self, canonicalize_body_relaxed, canonicalize_body_simple, canonicalize_header_relaxed,
-    canonicalize_header_simple,
+    self, canonicalize_body_relaxed, canonicalize_body_simple, canonicalize_header_relaxed_into,
+    canonicalize_header_simple_into,
 };
 use crate::header::HEADER;
-use crate::{bytes, DKIMError, DKIMHeader};
+use crate::{bytes, DKIMError, DKIMHeader, ErrorSource};
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum HashAlgo {
     RsaSha1,
     RsaSha256,
     Ed25519Sha256,
 }
 
+impl HashAlgo {
+    fn digest(&self) -> Digest {
+        match self {
+            HashAlgo::RsaSha1 => Digest::Sha1,
+            HashAlgo::RsaSha256 | HashAlgo::Ed25519Sha256 => Digest::Sha256,
+        }
+    }
+}
+
+/// Digest of a [HashAlgo]. The body hash only depends on the digest, so
+/// rsa-sha256 and ed25519-sha256 signatures share it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Digest {
+    Sha1,
+    Sha256,
+}
+
+/// Body hashes computed ahead of time, for instance by an edge proxy during
+/// SMTP DATA, to verify signatures without processing the body again.
+#[derive(Debug, Clone, Default)]
+pub struct BodyHashes {
+    hashes: HashMap<(canonicalization::Type, Digest, Option<usize>), String>,
+}
+
+impl BodyHashes {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add the base64 encoded hash of the body for the given canonicalization,
+    /// algorithm and body length limit (`l=` tag). The hash is used for the
+    /// signatures of all the algorithms with the same digest.
+    pub fn insert(
+        &mut self,
+        canonicalization_type: canonicalization::Type,
+        hash_algo: HashAlgo,
+        length: Option<usize>,
+        hash: String,
+    ) {
+        self.hashes
+            .insert((canonicalization_type, hash_algo.digest(), length), hash);
+    }
+
+    pub(crate) fn get(
+        &self,
+        canonicalization_type: canonicalization::Type,
+        hash_algo: HashAlgo,
+        length: Option<&str>,
+    ) -> Result<String, DKIMError> {
+        let length = length.map(parse_length).transpose()?;
+        self.hashes
+            .get(&(canonicalization_type, hash_algo.digest(), length))
+            .cloned()
+            .ok_or(DKIMError::MissingBodyHash)
+    }
+}
+
+/// Key of a [BodyHashCache] entry: the length of the body and its digest
+/// with the first hasher of the cache
+type BodyHashKey = (canonicalization::Type, Digest, usize, u64);
+
+/// Cache of body hashes, for senders signing the same body many times with
+/// different recipients or headers, such as campaigns. Bodies are identified
+/// by their length and a fast digest of their content, so that only this
+/// digest is computed for a body in the cache, instead of its
+/// canonicalization and hash. Share it between signers with an `Arc`, see
+/// [crate::SignerBuilder::with_body_hash_cache]. Once the capacity is
+/// reached, the least recently used entry is evicted.
+#[derive(Debug)]
+pub struct BodyHashCache {
+    /// Body hashes by key, with the digest of the body with the second
+    /// hasher, checked on a hit
+    entries: Mutex<LruCache<BodyHashKey, (u64, String)>>,
+    /// Hashers with random keys, so that colliding bodies can't be crafted
+    hashers: (RandomState, RandomState),
+}
+
+impl BodyHashCache {
+    /// New cache of at most `capacity` body hashes
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            entries: Mutex::new(LruCache::new(capacity)),
+            hashers: (RandomState::new(), RandomState::new()),
+        }
+    }
+
+    /// Number of body hashes in the cache
+    pub fn len(&self) -> usize {
+        self.entries().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the hash of `body`, as [compute_body_hash] without length
+    /// limit. The body is only canonicalized and hashed if it isn't in the
+    /// cache.
+    pub(crate) fn get_or_compute(
+        &self,
+        body: &[u8],
+        canonicalization_type: canonicalization::Type,
+        hash_algo: HashAlgo,
+    ) -> String {
+        let key = (
+            canonicalization_type.clone(),
+            hash_algo.digest(),
+            body.len(),
+            self.hashers.0.hash_one(body),
+        );
+        let check = self.hashers.1.hash_one(body);
+        if let Some((entry_check, hash)) = self.entries().get_mut(&key) {
+            if *entry_check == check {
+                return hash.clone();
+            }
+        }
+
+        let hash = compute_body_hash(body, canonicalization_type, hash_algo, None);
+        self.entries().insert(key, (check, hash.clone()));
+        hash
+    }
+
+    fn entries(&self) -> std::sync::MutexGuard<'_, LruCache<BodyHashKey, (u64, String)>> {
+        self.entries.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+pub(crate) fn parse_length(value: &str) -> Result<usize, DKIMError> {
+    value
+        .parse::<usize>()
+        .map_err(|err| DKIMError::SignatureSyntaxError {
+            tag: Some("l"),
+            value: value.to_owned(),
+            source: Some(ErrorSource::new(err)),
+        })
+}
+
 /// Get the body part of an email
 fn get_body<'a>(email: &'a mailparse::ParsedMail<'a>) -> Result<Vec<u8>, DKIMError> {
     Ok(bytes::get_all_after(email.raw_bytes, b"\r\n\r\n").to_vec())
 }
 
-fn hash_sha1<T: AsRef<[u8]>>(data: T) -> Vec<u8> {
+/// Get the body part of an email with CRLF line endings, whatever the line
+/// endings of the message
+pub(crate) fn get_normalized_body(email: &mailparse::ParsedMail) -> Vec<u8> {
+    let raw = email.raw_bytes;
+    let separator = raw
+        .windows(2)
+        .enumerate()
+        .find_map(|(i, window)| match window {
+            b"\n\n" => Some(i + 2),
+            b"\n\r" if raw[i + 2..].starts_with(b"\n") => Some(i + 3),
+            _ => None,
+        });
+    match separator {
+        Some(start) => canonicalization::normalize_line_endings(&raw[start..]),
+        None => vec![],
+    }
+}
+
+pub(crate) fn hash_sha1<T: AsRef<[u8]>>(data: T) -> Vec<u8> {
     use sha1::{Digest, Sha1};
 
     let mut hasher = Sha1::new();
@@ -39,25 +198,23 @@ fn hash_sha256<T: AsRef<[u8]>>(data: T) -> Vec<u8> {
     hasher.finalize().to_vec()
 }
 
-/// Returns the hash of message's body
+/// Returns the base64 encoded hash of a message's body, as found in the `bh=`
+/// tag. `body` is the raw body of the message, after the empty line
+/// separating it from the headers, and `length` is the body length limit
+/// (`l=` tag) if any.
 /// https://datatracker.ietf.org/doc/html/rfc6376#section-3.7
-pub(crate) fn compute_body_hash<'a>(
+pub fn compute_body_hash(
+    body: &[u8],
     canonicalization_type: canonicalization::Type,
-    length: Option<String>,
     hash_algo: HashAlgo,
-    email: &'a mailparse::ParsedMail<'a>,
-) -> Result<String, DKIMError> {
-    let body = get_body(email)?;
-
+    length: Option<usize>,
+) -> String {
     let mut canonicalized_body = if canonicalization_type == canonicalization::Type::Simple {
-        canonicalize_body_simple(&body)
+        canonicalize_body_simple(body)
     } else {
-        canonicalize_body_relaxed(&body)
+        canonicalize_body_relaxed(body)
     };
     if let Some(length) = length {
-        let length = length
-            .parse::<usize>()
-            .map_err(|err| DKIMError::SignatureSyntaxError(format!("invalid length: {}", err)))?;
         canonicalized_body.truncate(length);
     };
 
@@ -66,78 +223,349 @@ pub(crate) fn compute_body_hash<'a>(
         HashAlgo::RsaSha256 => hash_sha256(&canonicalized_body),
         HashAlgo::Ed25519Sha256 => hash_sha256(&canonicalized_body),
     };
-    Ok(general_purpose::STANDARD.encode(hash))
+    general_purpose::STANDARD.encode(hash)
 }
 
-fn select_headers<'a>(
-    dkim_header: &str,
+/// Returns the offset in the raw body at which the content covered by the
+/// body length limit (`l=` tag) ends: the canonicalized body up to this
+/// offset is `length` bytes long. The offset is moved after the LF of a CRLF
+/// split by the limit.
+pub(crate) fn signed_body_end(
+    body: &[u8],
+    canonicalization_type: canonicalization::Type,
+    length: usize,
+) -> usize {
+    let relaxed = canonicalization_type == canonicalization::Type::Relaxed;
+    let canonicalized = if relaxed {
+        canonicalize_body_relaxed(body)
+    } else {
+        canonicalize_body_simple(body)
+    };
+
+    // Apart from the CRLF added at its end, the canonicalized body is the raw
+    // body without the ignored whitespace and empty lines: its bytes come
+    // from the first raw bytes matching them.
+    let mut raw = body.iter().enumerate();
+    let mut end = 0;
+    for &byte in canonicalized.iter().take(length) {
+        let matching = raw.find(|&(_, &raw_byte)| {
+            raw_byte == byte || relaxed && byte == b' ' && raw_byte == b'\t'
+        });
+        match matching {
+            Some((index, _)) => end = index + 1,
+            None => return body.len(),
+        }
+    }
+    if body[..end].ends_with(b"\r") && body[end..].starts_with(b"\n") {
+        end += 1;
+    }
+    end
+}
+
+enum Hasher {
+    Sha1(sha1::Sha1),
+    Sha256(sha2::Sha256),
+}
+
+/// Streaming body hasher: canonicalizes and hashes the body as it is written,
+/// without holding it in memory. Produces the same hash as
+/// [compute_body_hash].
+pub struct BodyHasher {
+    canonicalizer: canonicalization::BodyCanonicalizer,
+    hasher: Hasher,
+    /// Remaining number of canonicalized bytes to hash (`l=` tag)
+    remaining: Option<usize>,
+    scratch: Vec<u8>,
+}
+
+impl BodyHasher {
+    /// New hasher. The bytes written must be the raw body of the message,
+    /// after the empty line separating it from the headers.
+    pub fn new(
+        canonicalization_type: canonicalization::Type,
+        hash_algo: HashAlgo,
+        length: Option<usize>,
+    ) -> Self {
+        use sha1::Digest;
+
+        let hasher = match hash_algo {
+            HashAlgo::RsaSha1 => Hasher::Sha1(sha1::Sha1::new()),
+            HashAlgo::RsaSha256 | HashAlgo::Ed25519Sha256 => Hasher::Sha256(sha2::Sha256::new()),
+        };
+        Self {
+            canonicalizer: canonicalization::BodyCanonicalizer::new(canonicalization_type),
+            hasher,
+            remaining: length,
+            scratch: vec![],
+        }
+    }
+
+    /// Add a chunk of the body
+    pub fn update(&mut self, chunk: &[u8]) {
+        self.canonicalizer.update(chunk, &mut self.scratch);
+        hash_canonicalized(&mut self.hasher, &mut self.remaining, &self.scratch);
+        self.scratch.clear();
+    }
+
+    /// Returns the base64 encoded hash of the body, as found in the `bh=` tag
+    pub fn finish(self) -> String {
+        use sha1::Digest;
+
+        let Self {
+            canonicalizer,
+            mut hasher,
+            mut remaining,
+            mut scratch,
+        } = self;
+        canonicalizer.finish(&mut scratch);
+        hash_canonicalized(&mut hasher, &mut remaining, &scratch);
+
+        let hash = match hasher {
+            Hasher::Sha1(hasher) => hasher.finalize().to_vec(),
+            Hasher::Sha256(hasher) => hasher.finalize().to_vec(),
+        };
+        general_purpose::STANDARD.encode(hash)
+    }
+}
+
+fn hash_canonicalized(hasher: &mut Hasher, remaining: &mut Option<usize>, data: &[u8]) {
+    use sha1::Digest;
+
+    let mut data = data;
+    if let Some(remaining) = remaining.as_mut() {
+        data = &data[..data.len().min(*remaining)];
+        *remaining -= data.len();
+    }
+    match hasher {
+        Hasher::Sha1(hasher) => hasher.update(data),
+        Hasher::Sha256(hasher) => hasher.update(data),
+    }
+}
+
+impl std::io::Write for BodyHasher {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(feature = "async")]
+impl futures::io::AsyncWrite for BodyHasher {
+    fn poll_write(
+        self: std::pin::Pin<&mut Self>,
+        _cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        self.get_mut().update(buf);
+        std::task::Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(
+        self: std::pin::Pin<&mut Self>,
+        _cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        std::task::Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(
+        self: std::pin::Pin<&mut Self>,
+        _cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        std::task::Poll::Ready(Ok(()))
+    }
+}
+
+/// Size of the chunks of the body canonicalized at once in bounded memory mode
+const BODY_CHUNK_SIZE: usize = 4096;
+
+/// Returns the hash of the email's body, canonicalized and hashed in chunks
+/// without copying it
+pub(crate) fn compute_email_body_hash_bounded(
+    canonicalization_type: canonicalization::Type,
+    length: Option<&str>,
+    hash_algo: HashAlgo,
+    email: &mailparse::ParsedMail,
+) -> Result<String, DKIMError> {
+    let length = length.map(parse_length).transpose()?;
+    let mut hasher = BodyHasher::new(canonicalization_type, hash_algo, length);
+    for chunk in bytes::get_all_after(email.raw_bytes, b"\r\n\r\n").chunks(BODY_CHUNK_SIZE) {
+        hasher.update(chunk);
+    }
+    Ok(hasher.finish())
+}
+
+/// Returns the hash of the email's body
+pub(crate) fn compute_email_body_hash<'a>(
+    canonicalization_type: canonicalization::Type,
+    length: Option<&str>,
+    hash_algo: HashAlgo,
     email: &'a mailparse::ParsedMail<'a>,
-) -> Result<Vec<(String, &'a [u8])>, DKIMError> {
-    let mut signed_headers = vec![];
+) -> Result<String, DKIMError> {
+    let body = get_body(email)?;
+    let length = length.map(parse_length).transpose()?;
+    Ok(compute_body_hash(
+        &body,
+        canonicalization_type,
+        hash_algo,
+        length,
+    ))
+}
+
+/// Order in which the instances of a header listed several times in `h=` are
+/// selected
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HeaderSelection {
+    /// From the bottom of the header block upward, as specified in
+    /// <https://datatracker.ietf.org/doc/html/rfc6376#section-5.4.2>. Used by
+    /// the signer and the verifier.
+    #[default]
+    BottomUp,
+    /// From the top of the header block downward. Not conformant, only useful
+    /// to diagnose signers getting the order wrong.
+    TopDown,
+}
+
+/// Header instance selected for a name listed in `h=`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SelectedHeader<'a> {
+    /// Name as listed in `h=`, lowercase
+    pub name: String,
+    /// Index of the instance in the headers of the email, `None` when the name
+    /// is listed more times than the header occurs: the absent header is
+    /// signed as a null string, so adding the header later breaks the
+    /// signature
+    pub index: Option<usize>,
+    /// Raw value of the instance
+    pub value: Option<&'a [u8]>,
+}
 
-    let email_headers = &email.headers;
-    let num_headers = email_headers.len();
-    let mut last_index: HashMap<String, usize> = HashMap::new();
+/// Select the header instances signed by a colon-separated list of header
+/// names, the value of the `h=` tag. Each instance is consumed at most once.
+pub fn select_signed_headers<'a>(
+    signed_headers: &str,
+    email: &'a mailparse::ParsedMail<'a>,
+    order: HeaderSelection,
+) -> Vec<SelectedHeader<'a>> {
+    // Number of instances already consumed per name
+    let mut consumed: HashMap<String, usize> = HashMap::new();
 
-    'outer: for name in dkim_header
+    signed_headers
         .split(':')
-        .map(|h| h.trim().to_ascii_lowercase())
-    {
-        let index = last_index.get(&name).unwrap_or(&num_headers);
-        for header in email_headers
-            .iter()
-            .enumerate()
-            .rev()
-            .skip(num_headers - index)
-        {
-            if header.1.get_key_ref().eq_ignore_ascii_case(&name) {
-                signed_headers.push((header.1.get_key(), header.1.get_value_raw()));
-                last_index.insert(name, header.0);
-                continue 'outer;
+        .map(|name| name.trim().to_ascii_lowercase())
+        .map(|name| {
+            let count = consumed.entry(name.clone()).or_default();
+            let mut instances =
+                email.headers.iter().enumerate().filter(|(_, header)| {
+                    header.get_key_raw().eq_ignore_ascii_case(name.as_bytes())
+                });
+            let instance = match order {
+                HeaderSelection::BottomUp => instances.nth_back(*count),
+                HeaderSelection::TopDown => instances.nth(*count),
+            };
+            *count += 1;
+
+            SelectedHeader {
+                name,
+                index: instance.map(|(index, _)| index),
+                value: instance.map(|(_, header)| header.get_value_raw()),
             }
-        }
+        })
+        .collect()
+}
 
-        last_index.insert(name, 0);
-    }
+/// Raw name and value of a header
+type HeaderField<'a> = (&'a [u8], &'a [u8]);
+
+fn select_headers<'a>(
+    dkim_header: &str,
+    email: &'a mailparse::ParsedMail<'a>,
+) -> Result<Vec<HeaderField<'a>>, DKIMError> {
+    Ok(
+        select_signed_headers(dkim_header, email, HeaderSelection::BottomUp)
+            .into_iter()
+            .filter_map(|selected| {
+                let header = &email.headers[selected.index?];
+                Some((header.get_key_raw(), header.get_value_raw()))
+            })
+            .collect(),
+    )
+}
 
-    Ok(signed_headers)
+/// Returns the hash of the headers of a message as signed by a DKIM signature:
+/// the headers listed in `headers` (the `h=` tag), selected bottom-up, followed
+/// by the signature header `dkim_header` without the value of its `b=` tag,
+/// canonicalized with `canonicalization_type` and hashed with `hash_algo`.
+/// The hash is the input of [crate::verify_signature].
+/// https://datatracker.ietf.org/doc/html/rfc6376#section-3.7
+pub fn compute_headers_hash<'a, 'b>(
+    logger: &slog::Logger,
+    canonicalization_type: canonicalization::Type,
+    headers: &'b str,
+    hash_algo: HashAlgo,
+    dkim_header: &'b DKIMHeader,
+    email: &'a mailparse::ParsedMail<'a>,
+) -> Result<Vec<u8>, DKIMError> {
+    compute_headers_hash_with_limit(
+        logger,
+        canonicalization_type,
+        headers,
+        hash_algo,
+        dkim_header,
+        email,
+        None,
+    )
 }
 
-pub(crate) fn compute_headers_hash<'a, 'b>(
+/// [compute_headers_hash] accumulating the canonicalized headers in a buffer
+/// of at most `limit` bytes, allocated once
+pub(crate) fn compute_headers_hash_with_limit<'a, 'b>(
     logger: &slog::Logger,
     canonicalization_type: canonicalization::Type,
     headers: &'b str,
     hash_algo: HashAlgo,
     dkim_header: &'b DKIMHeader,
     email: &'a mailparse::ParsedMail<'a>,
+    limit: Option<usize>,
 ) -> Result<Vec<u8>, DKIMError> {
-    let mut input = Vec::new();
+    let mut input = Vec::with_capacity(limit.unwrap_or_default());
+    // The canonicalization of a header is never longer than `name:value`
+    let reserve = |input: &Vec<u8>, len: usize| match limit {
+        Some(limit) if input.len() + len > limit => Err(DKIMError::MemoryLimitExceeded { limit }),
+        _ => Ok(()),
+    };
 
     // Add the headers defined in `h=` in the hash
     for (key, value) in select_headers(headers, email)? {
-        let canonicalized_value = if canonicalization_type == canonicalization::Type::Simple {
-            canonicalize_header_simple(&key, value)
+        reserve(&input, key.len() + value.len() + 3)?;
+        if canonicalization_type == canonicalization::Type::Simple {
+            canonicalize_header_simple_into(key, value, &mut input);
         } else {
-            canonicalize_header_relaxed(&key, value)
-        };
-        input.extend_from_slice(&canonicalized_value);
+            canonicalize_header_relaxed_into(key, value, &mut input);
+        }
     }
 
     // Add the DKIM-Signature header in the hash. Remove the value of the
     // signature (b) first.
     {
-        let sign = dkim_header.get_raw_tag("b").unwrap();
-        let value = dkim_header.raw_bytes.replace(&sign, "");
-        let mut canonicalized_value = if canonicalization_type == canonicalization::Type::Simple {
-            canonicalize_header_simple(HEADER, value.as_bytes())
+        let span = dkim_header.tag_value_span("b").unwrap();
+        let raw = dkim_header.raw_bytes.as_bytes();
+        let mut value = Vec::with_capacity(raw.len() - span.len());
+        value.extend_from_slice(&raw[..span.start]);
+        value.extend_from_slice(&raw[span.end..]);
+
+        reserve(&input, HEADER.len() + value.len() + 3)?;
+        if canonicalization_type == canonicalization::Type::Simple {
+            canonicalize_header_simple_into(HEADER.as_bytes(), &value, &mut input);
         } else {
-            canonicalize_header_relaxed(HEADER, value.as_bytes())
-        };
+            canonicalize_header_relaxed_into(HEADER.as_bytes(), &value, &mut input);
+        }
 
         // remove trailing "\r\n"
-        canonicalized_value.truncate(canonicalized_value.len() - 2);
-
-        input.extend_from_slice(&canonicalized_value);
+        input.truncate(input.len() - 2);
     }
     debug!(logger, "headers to hash: {:?}", input);
 
@@ -153,7 +581,7 @@ pub(crate) fn compute_headers_hash<'a, 'b>(
 mod tests {
     use super::*;
 
-    fn dkim_header() -> DKIMHeader {
+    fn dkim_header() -> DKIMHeader<'static> {
         crate::validate_header("v=1; a=rsa-sha256; q=dns/txt; c=relaxed/relaxed; s=smtp; d=test.com; t=1641506955; h=content-type:to: subject:date:from:mime-version:sender; bh=PU2XIErWsXvhvt1W96ntPWZ2VImjVZ3vBY2T/A+wA3A=; b=PIO0A014nyntOGKdTdtvCJor9ZxvP1M3hoLeEh8HqZ+RvAyEKdAc7VOg+/g/OTaZgsmw6U sZCoN0YNVp+2o9nkaeUslsVz3M4I55HcZnarxl+fhplIMcJ/3s0nIhXL51MfGPRqPbB7/M Gjg9/07/2vFoid6Kitg6Z+CfoD2wlSRa8xDfmeyA2cHpeVuGQhGxu7BXuU8kGbeM4+weit Ql3t9zalhikEPI5Pr7dzYFrgWNOEO6w6rQfG7niKON1BimjdbJlGanC7cO4UL361hhXT4X iXLnC9TG39xKFPT/+4nkHy8pp6YvWkD3wKlBjwkYNm0JvKGwTskCMDeTwxXhAg==").unwrap()
     }
 
@@ -174,18 +602,13 @@ Hello Alice
         let length = None;
         let hash_algo = HashAlgo::RsaSha1;
         assert_eq!(
-            compute_body_hash(
-                canonicalization_type.clone(),
-                length.clone(),
-                hash_algo,
-                &email
-            )
-            .unwrap(),
+            compute_email_body_hash(canonicalization_type.clone(), length, hash_algo, &email)
+                .unwrap(),
             "uoq1oCgLlTqpdDX/iUbLy7J1Wic="
         );
         let hash_algo = HashAlgo::RsaSha256;
         assert_eq!(
-            compute_body_hash(canonicalization_type, length, hash_algo, &email).unwrap(),
+            compute_email_body_hash(canonicalization_type, length, hash_algo, &email).unwrap(),
             "frcCV1k9oG9oKj3dpUqdJg1PxRT2RSN/XKdLCPjaYaY="
         )
     }
@@ -207,22 +630,80 @@ Hello Alice
         let length = None;
         let hash_algo = HashAlgo::RsaSha1;
         assert_eq!(
-            compute_body_hash(
-                canonicalization_type.clone(),
-                length.clone(),
-                hash_algo,
-                &email
-            )
-            .unwrap(),
+            compute_email_body_hash(canonicalization_type.clone(), length, hash_algo, &email)
+                .unwrap(),
             "2jmj7l5rSw0yVb/vlWAYkK/YBwk="
         );
         let hash_algo = HashAlgo::RsaSha256;
         assert_eq!(
-            compute_body_hash(canonicalization_type, length, hash_algo, &email).unwrap(),
+            compute_email_body_hash(canonicalization_type, length, hash_algo, &email).unwrap(),
             "47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU="
         )
     }
 
+    #[test]
+    fn test_signed_body_end() {
+        use canonicalization::Type::{Relaxed, Simple};
+
+        let body = b"Hello  Alice\r\n\r\nBye\r\n--\r\nFooter\r\n";
+        // "Hello Alice\r\n\r\nBye\r\n" in relaxed canonicalization
+        assert_eq!(signed_body_end(body, Relaxed, 20), 21);
+        assert_eq!(&body[21..], b"--\r\nFooter\r\n");
+        assert_eq!(signed_body_end(body, Simple, 21), 21);
+        assert_eq!(signed_body_end(body, Relaxed, 5), 5);
+        assert_eq!(signed_body_end(body, Relaxed, 0), 0);
+        assert_eq!(signed_body_end(body, Relaxed, 100), body.len());
+        assert_eq!(
+            compute_body_hash(body, Relaxed, HashAlgo::RsaSha256, Some(20)),
+            compute_body_hash(&body[..21], Relaxed, HashAlgo::RsaSha256, None)
+        );
+    }
+
+    #[test]
+    fn test_signed_body_end_trailing_whitespace() {
+        use canonicalization::Type::{Relaxed, Simple};
+
+        // "a\r\nb\r\n" in relaxed canonicalization, the space is ignored
+        let body = b"a \r\nb\r\n";
+        assert_eq!(signed_body_end(body, Relaxed, 1), 1);
+        assert_eq!(signed_body_end(body, Relaxed, 3), 4);
+        assert_eq!(canonicalize_body_relaxed(&body[..4]), b"a\r\n");
+        // The CRLF isn't split
+        assert_eq!(signed_body_end(body, Relaxed, 2), 4);
+        assert_eq!(signed_body_end(body, Relaxed, 4), 5);
+
+        assert_eq!(signed_body_end(body, Simple, 2), 2);
+        assert_eq!(signed_body_end(body, Simple, 3), 4);
+        assert_eq!(signed_body_end(body, Simple, 4), 4);
+        assert_eq!(signed_body_end(body, Simple, 5), 5);
+
+        // Whitespace sequences are reduced to their first byte
+        let body = b"a \t b\r\n";
+        assert_eq!(signed_body_end(body, Relaxed, 2), 2);
+        assert_eq!(signed_body_end(body, Relaxed, 3), 5);
+        assert_eq!(signed_body_end(body, Simple, 3), 3);
+    }
+
+    #[test]
+    fn test_signed_body_end_bare_cr() {
+        use canonicalization::Type::{Relaxed, Simple};
+
+        let body = b"a\rb\r\n";
+        for canonicalization_type in [Simple, Relaxed] {
+            assert_eq!(signed_body_end(body, canonicalization_type.clone(), 1), 1);
+            assert_eq!(signed_body_end(body, canonicalization_type.clone(), 2), 2);
+            assert_eq!(signed_body_end(body, canonicalization_type.clone(), 3), 3);
+            assert_eq!(signed_body_end(body, canonicalization_type, 4), 5);
+        }
+
+        // A trailing bare CR is followed by the added CRLF
+        let body = b"a \r";
+        assert_eq!(signed_body_end(body, Relaxed, 3), 3);
+        assert_eq!(signed_body_end(body, Relaxed, 4), 3);
+        assert_eq!(signed_body_end(body, Simple, 2), 2);
+        assert_eq!(signed_body_end(body, Simple, 5), 3);
+    }
+
     #[test]
     fn test_compute_body_hash_length() {
         let email = mailparse::parse_mail(
@@ -237,21 +718,16 @@ Hello Alice
         .unwrap();
 
         let canonicalization_type = canonicalization::Type::Relaxed;
-        let length = Some("3".to_owned());
+        let length = Some("3");
         let hash_algo = HashAlgo::RsaSha1;
         assert_eq!(
-            compute_body_hash(
-                canonicalization_type.clone(),
-                length.clone(),
-                hash_algo,
-                &email
-            )
-            .unwrap(),
+            compute_email_body_hash(canonicalization_type.clone(), length, hash_algo, &email)
+                .unwrap(),
             "2jmj7l5rSw0yVb/vlWAYkK/YBwk="
         );
         let hash_algo = HashAlgo::RsaSha256;
         assert_eq!(
-            compute_body_hash(canonicalization_type, length, hash_algo, &email).unwrap(),
+            compute_email_body_hash(canonicalization_type, length, hash_algo, &email).unwrap(),
             "47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU="
         )
     }
@@ -264,18 +740,13 @@ Hello Alice
         let length = None;
         let hash_algo = HashAlgo::RsaSha1;
         assert_eq!(
-            compute_body_hash(
-                canonicalization_type.clone(),
-                length.clone(),
-                hash_algo,
-                &email
-            )
-            .unwrap(),
+            compute_email_body_hash(canonicalization_type.clone(), length, hash_algo, &email)
+                .unwrap(),
             "uoq1oCgLlTqpdDX/iUbLy7J1Wic="
         );
         let hash_algo = HashAlgo::RsaSha256;
         assert_eq!(
-            compute_body_hash(canonicalization_type, length, hash_algo, &email).unwrap(),
+            compute_email_body_hash(canonicalization_type, length, hash_algo, &email).unwrap(),
             "frcCV1k9oG9oKj3dpUqdJg1PxRT2RSN/XKdLCPjaYaY="
         )
     }
@@ -288,18 +759,13 @@ Hello Alice
         let length = None;
         let hash_algo = HashAlgo::RsaSha1;
         assert_eq!(
-            compute_body_hash(
-                canonicalization_type.clone(),
-                length.clone(),
-                hash_algo,
-                &email
-            )
-            .unwrap(),
+            compute_email_body_hash(canonicalization_type.clone(), length, hash_algo, &email)
+                .unwrap(),
             "2jmj7l5rSw0yVb/vlWAYkK/YBwk="
         );
         let hash_algo = HashAlgo::RsaSha256;
         assert_eq!(
-            compute_body_hash(canonicalization_type, length, hash_algo, &email).unwrap(),
+            compute_email_body_hash(canonicalization_type, length, hash_algo, &email).unwrap(),
             "47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU="
         )
     }
@@ -404,6 +870,128 @@ Hello Alice
         )
     }
 
+    #[test]
+    fn test_compute_body_hash() {
+        // Body of the example in RFC 6376 Appendix A
+        let body = b"Hi.\r\n\r\nWe lost the game. Are you hungry yet?\r\n\r\nJoe.\r\n";
+        assert_eq!(
+            compute_body_hash(
+                body,
+                canonicalization::Type::Simple,
+                HashAlgo::RsaSha256,
+                None
+            ),
+            "2jUSOH9NhtVGCQWNr9BrIAPreKQjO6Sn7XIkfJVOzv8="
+        );
+        assert_eq!(
+            compute_body_hash(
+                b"",
+                canonicalization::Type::Relaxed,
+                HashAlgo::RsaSha1,
+                Some(3)
+            ),
+            "2jmj7l5rSw0yVb/vlWAYkK/YBwk="
+        );
+    }
+
+    #[test]
+    fn test_body_hasher() {
+        use std::io::Write;
+
+        let body = b"Hi.\r\n\r\nWe lost the game. Are you hungry yet?\r\n\r\nJoe.\r\n\r\n";
+        for (canonicalization_type, hash_algo, length) in [
+            (canonicalization::Type::Simple, HashAlgo::RsaSha256, None),
+            (canonicalization::Type::Relaxed, HashAlgo::RsaSha256, None),
+            (canonicalization::Type::Relaxed, HashAlgo::RsaSha1, Some(10)),
+            (
+                canonicalization::Type::Simple,
+                HashAlgo::Ed25519Sha256,
+                Some(1000),
+            ),
+        ] {
+            let mut hasher =
+                BodyHasher::new(canonicalization_type.clone(), hash_algo.clone(), length);
+            for chunk in body.chunks(7) {
+                hasher.write_all(chunk).unwrap();
+            }
+            assert_eq!(
+                hasher.finish(),
+                compute_body_hash(body, canonicalization_type, hash_algo, length)
+            );
+        }
+    }
+
+    #[test]
+    fn test_compute_email_body_hash_bounded() {
+        let body = "Hi.\r\n\r\nWe lost the game. Are you hungry yet?\r\n\r\nJoe.\r\n".repeat(500);
+        let raw_email = format!("From: joe@example.com\r\n\r\n{}", body);
+        let email = mailparse::parse_mail(raw_email.as_bytes()).unwrap();
+        for canonicalization_type in [
+            canonicalization::Type::Simple,
+            canonicalization::Type::Relaxed,
+        ] {
+            assert_eq!(
+                compute_email_body_hash_bounded(
+                    canonicalization_type.clone(),
+                    Some("5000"),
+                    HashAlgo::RsaSha256,
+                    &email
+                )
+                .unwrap(),
+                compute_email_body_hash(
+                    canonicalization_type,
+                    Some("5000"),
+                    HashAlgo::RsaSha256,
+                    &email
+                )
+                .unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn test_body_hashes() {
+        let mut body_hashes = BodyHashes::new();
+        body_hashes.insert(
+            canonicalization::Type::Relaxed,
+            HashAlgo::RsaSha256,
+            None,
+            "hash".to_owned(),
+        );
+        assert_eq!(
+            body_hashes
+                .get(canonicalization::Type::Relaxed, HashAlgo::RsaSha256, None)
+                .unwrap(),
+            "hash"
+        );
+        // Same digest
+        assert_eq!(
+            body_hashes
+                .get(
+                    canonicalization::Type::Relaxed,
+                    HashAlgo::Ed25519Sha256,
+                    None
+                )
+                .unwrap(),
+            "hash"
+        );
+        assert_eq!(
+            body_hashes.get(canonicalization::Type::Relaxed, HashAlgo::RsaSha1, None),
+            Err(DKIMError::MissingBodyHash)
+        );
+        let missing = body_hashes
+            .get(canonicalization::Type::Simple, HashAlgo::RsaSha256, None)
+            .unwrap_err();
+        assert!(!missing.is_temporary());
+        assert!(body_hashes
+            .get(
+                canonicalization::Type::Relaxed,
+                HashAlgo::RsaSha256,
+                Some("10")
+            )
+            .is_err());
+    }
+
     #[test]
     fn test_get_body() {
         let email =
@@ -426,9 +1014,9 @@ Hello Alice
         assert_eq!(
             result1,
             vec![
-                ("from".to_owned(), &b"baz"[..]),
-                ("subject".to_owned(), &b"boring"[..]),
-                ("from".to_owned(), &b"biz"[..]),
+                (&b"from"[..], &b"baz"[..]),
+                (&b"subject"[..], &b"boring"[..]),
+                (&b"from"[..], &b"biz"[..]),
             ]
         );
 
@@ -440,9 +1028,54 @@ Hello Alice
         assert_eq!(
             result2,
             vec![
-                ("From".to_owned(), &b"biz"[..]),
-                ("Subject".to_owned(), &b"Boring"[..]),
+                (&b"From"[..], &b"biz"[..]),
+                (&b"Subject"[..], &b"Boring"[..]),
             ]
         );
     }
+
+    #[test]
+    fn test_select_headers_non_ascii_name() {
+        // Names are compared and hashed as raw bytes: a Latin-1 name doesn't
+        // match its UTF-8 spelling, a UTF-8 name is kept as is
+        let email = mailparse::parse_mail(b"Caf\xE9: a\r\nCaf\xC3\xA9: b\r\n\r\ntest").unwrap();
+        assert_eq!(
+            select_headers("caf\u{e9}", &email).unwrap(),
+            vec![(&b"Caf\xC3\xA9"[..], &b"b"[..])]
+        );
+    }
+
+    #[test]
+    fn test_select_signed_headers_order() {
+        let email = mailparse::parse_mail(
+            b"Received: a\r\nSubject: one\r\nReceived: b\r\nReceived: c\r\n\r\ntest",
+        )
+        .unwrap();
+        let indexes = |order| -> Vec<Option<usize>> {
+            select_signed_headers("received:subject:received:Received:received", &email, order)
+                .into_iter()
+                .map(|selected| selected.index)
+                .collect()
+        };
+
+        assert_eq!(
+            indexes(HeaderSelection::BottomUp),
+            vec![Some(3), Some(1), Some(2), Some(0), None]
+        );
+        assert_eq!(
+            indexes(HeaderSelection::TopDown),
+            vec![Some(0), Some(1), Some(2), Some(3), None]
+        );
+
+        let selected = select_signed_headers("subject:subject", &email, HeaderSelection::BottomUp);
+        assert_eq!(selected[0].value, Some(&b"one"[..]));
+        assert_eq!(
+            selected[1],
+            SelectedHeader {
+                name: "subject".to_owned(),
+                index: None,
+                value: None,
+            }
+        );
+    }
 }
@@ -1,48 +1,355 @@
-use crate::{parser, DKIMError};
+use std::borrow::Cow;
+use std::ops::Range;
+
+use crate::{parser, DKIMError, ErrorSource};
 use indexmap::map::IndexMap;
 
 pub(crate) const HEADER: &str = "DKIM-Signature";
 pub(crate) const REQUIRED_TAGS: &[&str] = &["v", "a", "b", "bh", "d", "h", "s"];
+/// Tags defined by RFC 6376 section 3.5, the others are extensions which
+/// verifiers ignore
+pub(crate) const KNOWN_TAGS: &[&str] = &[
+    "v", "a", "b", "bh", "c", "d", "h", "i", "l", "q", "s", "t", "x", "z",
+];
 
+/// Parsed DKIM-Signature header. The tags borrow from the original header
+/// value, so verifying a message doesn't copy its signatures.
 #[derive(Debug, Clone)]
-pub struct DKIMHeader {
-    pub(crate) tags: IndexMap<String, parser::Tag>,
-    pub(crate) raw_bytes: String,
+pub struct DKIMHeader<'a> {
+    pub(crate) tags: IndexMap<Cow<'a, str>, parser::TagRef<'a>>,
+    pub(crate) raw_bytes: Cow<'a, str>,
 }
 
-impl DKIMHeader {
-    pub(crate) fn get_tag(&self, name: &str) -> Option<String> {
-        self.tags.get(name).map(|v| v.value.clone())
+impl<'a> DKIMHeader<'a> {
+    /// Value of the tag `name`, with its folding whitespace removed
+    pub fn get_tag(&self, name: &str) -> Option<&str> {
+        self.tags.get(name).map(|v| v.value.as_ref())
+    }
+
+    /// Names and values of the tags, in the order of the header. The tags
+    /// unknown to this crate are included.
+    pub fn tags(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.tags
+            .iter()
+            .map(|(name, tag)| (name.as_ref(), tag.value.as_ref()))
+    }
+
+    /// Names and values of the tags not defined by RFC 6376, such as the
+    /// tags of extensions, in the order of the header
+    pub fn unknown_tags(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.tags().filter(|(name, _)| !KNOWN_TAGS.contains(name))
     }
 
-    pub(crate) fn get_raw_tag(&self, name: &str) -> Option<String> {
-        self.tags.get(name).map(|v| v.raw_value.clone())
+    /// Generate the header value from the tags, all of them including the
+    /// unknown ones, in the given format. Parsing the generated value gives
+    /// back the same tags.
+    pub fn serialize(&self, format: &HeaderFormat) -> String {
+        serialize(self, format)
     }
 
-    pub fn get_required_tag(&self, name: &str) -> String {
+    /// Value of the header as signed, the spans are relative to it
+    pub fn raw_value(&self) -> &str {
+        &self.raw_bytes
+    }
+
+    /// Byte range of the tag `name` in [DKIMHeader::raw_value], from the start
+    /// of its name to the end of its value
+    pub fn tag_span(&self, name: &str) -> Option<Range<usize>> {
+        self.tags.get(name).map(|v| v.span.clone())
+    }
+
+    /// Byte range of the value of the tag `name` in [DKIMHeader::raw_value],
+    /// including its folding whitespace
+    pub fn tag_value_span(&self, name: &str) -> Option<Range<usize>> {
+        self.tags.get(name).map(|v| v.value_span.clone())
+    }
+
+    /// Identity of the signature (`i=` tag), decoded from
+    /// dkim-quoted-printable
+    pub(crate) fn identity(&self) -> Result<Option<String>, DKIMError> {
+        self.get_tag("i")
+            .map(|value| {
+                String::from_utf8(parser::decode_quoted_printable(value)?).map_err(|err| {
+                    DKIMError::SignatureSyntaxError {
+                        tag: Some("i"),
+                        value: value.to_owned(),
+                        source: Some(ErrorSource::new(err)),
+                    }
+                })
+            })
+            .transpose()
+    }
+
+    pub fn get_required_tag(&self, name: &str) -> &str {
         // Required tags are guaranteed by the parser to be present so it's safe
         // to assert and unwrap.
         debug_assert!(REQUIRED_TAGS.contains(&name));
-        self.tags.get(name).unwrap().value.clone()
+        self.tags.get(name).unwrap().value.as_ref()
     }
+
+    /// Copy the borrowed parts of the header to keep it beyond the lifetime
+    /// of the original header value
+    pub fn into_owned(self) -> DKIMHeader<'static> {
+        DKIMHeader {
+            tags: self
+                .tags
+                .into_iter()
+                .map(|(name, tag)| (Cow::Owned(name.into_owned()), tag.into_owned()))
+                .collect(),
+            raw_bytes: Cow::Owned(self.raw_bytes.into_owned()),
+        }
+    }
+}
+
+/// Tags starting on their own line and wrapped over several lines when the
+/// header is folded
+const WRAPPED_TAGS: &[&str] = &["h", "bh", "b"];
+
+/// Indentation of the continuation lines of a folded header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Indentation {
+    /// A single tab
+    Tab,
+    /// The given number of spaces, at least one
+    Spaces(usize),
+}
+
+impl Indentation {
+    fn to_str(self) -> String {
+        match self {
+            Indentation::Tab => "\t".to_owned(),
+            Indentation::Spaces(n) => " ".repeat(n.max(1)),
+        }
+    }
+}
+
+/// Folding of the generated header over several lines
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Folding {
+    pub(crate) width: usize,
+    pub(crate) indentation: Indentation,
+}
+
+/// Formatting of the generated DKIM-Signature header. The default format
+/// emits the tags on a single line in the order they are generated.
+#[derive(Debug, Clone)]
+pub struct HeaderFormat {
+    pub(crate) tag_order: Vec<String>,
+    pub(crate) folding: Option<Folding>,
+    pub(crate) trailing_semicolon: bool,
+    pub(crate) lowercase_header_names: bool,
+}
+
+impl Default for HeaderFormat {
+    fn default() -> Self {
+        Self {
+            tag_order: vec![],
+            folding: None,
+            trailing_semicolon: true,
+            lowercase_header_names: true,
+        }
+    }
+}
+
+impl HeaderFormat {
+    /// New default format
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Format used by OpenDKIM: folded at 75 characters with tabs, `bh=`
+    /// before `h=`, signed header names kept as given and no semicolon
+    /// after `b=`
+    pub fn opendkim() -> Self {
+        Self {
+            tag_order: to_strings(&["v", "a", "c", "d", "s", "t", "x", "l", "bh", "h", "b"]),
+            folding: Some(Folding {
+                width: 75,
+                indentation: Indentation::Tab,
+            }),
+            trailing_semicolon: false,
+            lowercase_header_names: false,
+        }
+    }
+
+    /// Format used by rspamd: folded at 78 characters with tabs, `h=` before
+    /// `bh=`, lowercase signed header names and no semicolon after `b=`
+    pub fn rspamd() -> Self {
+        Self {
+            tag_order: to_strings(&["v", "a", "c", "d", "s", "t", "x", "l", "h", "bh", "b"]),
+            folding: Some(Folding {
+                width: 78,
+                indentation: Indentation::Tab,
+            }),
+            trailing_semicolon: false,
+            lowercase_header_names: true,
+        }
+    }
+
+    /// Format which doesn't change across releases, unlike the default one:
+    /// every tag in a fixed order, folded at 78 characters with tabs, `h=`
+    /// before `bh=`, lowercase signed header names and a semicolon after
+    /// `b=`. See [crate::SignerBuilder::with_deterministic_output].
+    pub fn stable() -> Self {
+        Self {
+            tag_order: to_strings(&[
+                "v", "a", "c", "d", "i", "q", "s", "t", "x", "l", "z", "h", "bh", "b",
+            ]),
+            folding: Some(Folding {
+                width: 78,
+                indentation: Indentation::Tab,
+            }),
+            trailing_semicolon: true,
+            lowercase_header_names: true,
+        }
+    }
+
+    /// Emit the tags in the given order. Tags not listed keep their insertion
+    /// order after the listed ones, and `b=` is always last.
+    pub fn with_tag_order(mut self, order: &[&str]) -> Self {
+        self.tag_order = to_strings(order);
+        self
+    }
+
+    /// Fold the header so that lines, including the header name, don't
+    /// exceed `width` characters. Continuation lines start with
+    /// `indentation`. The `h=`, `bh=` and `b=` tags start on their own line.
+    pub fn with_folding(mut self, width: usize, indentation: Indentation) -> Self {
+        self.folding = Some(Folding { width, indentation });
+        self
+    }
+
+    /// Emit the header on a single line
+    pub fn without_folding(mut self) -> Self {
+        self.folding = None;
+        self
+    }
+}
+
+fn to_strings(values: &[&str]) -> Vec<String> {
+    values.iter().map(|v| v.to_string()).collect()
 }
 
 /// Generate the DKIM-Signature header from the tags
-fn serialize(header: DKIMHeader) -> String {
+fn serialize(header: &DKIMHeader, format: &HeaderFormat) -> String {
     let mut out = "".to_owned();
+    // Account for the header name preceding the value
+    let mut column = HEADER.len() + 2;
 
-    for (key, tag) in &header.tags {
-        out += &format!("{}={};", key, tag.value);
-        out += " ";
+    for (i, (key, tag)) in header.tags.iter().enumerate() {
+        let last = i + 1 == header.tags.len();
+        let terminator = if !last || format.trailing_semicolon {
+            ";"
+        } else {
+            ""
+        };
+        let wrapped = WRAPPED_TAGS.contains(&key.as_ref());
+
+        let folding = match format.folding {
+            Some(folding) => folding,
+            None => {
+                if i > 0 {
+                    out += " ";
+                }
+                out += &format!("{}={}{}", key, tag.value, terminator);
+                continue;
+            }
+        };
+        let indentation = folding.indentation.to_str();
+
+        if i > 0 {
+            let len = key.len() + 1 + tag.value.len() + terminator.len();
+            if wrapped || column + 1 + len > folding.width {
+                out += "\r\n";
+                out += &indentation;
+                column = indentation.len();
+            } else {
+                out += " ";
+                column += 1;
+            }
+        }
+        out += key;
+        out += "=";
+        column += key.len() + 1;
+
+        if !wrapped {
+            out += &tag.value;
+            column += tag.value.len();
+        } else {
+            // Signed header names are kept whole, base64 values can be split
+            // anywhere
+            let pieces: Vec<&str> = if key == "h" {
+                tag.value.split_inclusive(':').collect()
+            } else {
+                (0..tag.value.len()).map(|i| &tag.value[i..i + 1]).collect()
+            };
+            let mut line_empty = true;
+            for piece in pieces {
+                if !line_empty && column + piece.len() > folding.width {
+                    out += "\r\n";
+                    out += &indentation;
+                    column = indentation.len();
+                }
+                out += piece;
+                column += piece.len();
+                line_empty = false;
+            }
+        }
+        out += terminator;
+        column += terminator.len();
     }
 
-    out.pop(); // remove trailing whitespace
     out
 }
 
+/// Tags whose value is base64
+const BASE64_TAGS: &[&str] = &["b", "bh"];
+
+/// tag-name  =  ALPHA *ALNUMPUNC
+/// ALNUMPUNC =  ALPHA / DIGIT / "_"
+fn is_valid_tag_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphabetic())
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// tag-value =  [ tval *( 1*(WSP / FWS) tval ) ]
+/// VALCHAR   =  %x21-3A / %x3C-7E
+///
+/// The value is folded when the header is serialized, so it can't contain
+/// line breaks itself.
+fn is_valid_tag_value(value: &str) -> bool {
+    let is_wsp = |c: char| c == ' ' || c == '\t';
+    // UTF-8 is allowed by https://datatracker.ietf.org/doc/html/rfc8616#section-4
+    let is_valchar =
+        |c: char| ('!'..=':').contains(&c) || ('<'..='~').contains(&c) || !c.is_ascii();
+    !value.starts_with(is_wsp)
+        && !value.ends_with(is_wsp)
+        && value.chars().all(|c| is_valchar(c) || is_wsp(c))
+}
+
+/// base64string =  ALPHADIGITPS *([FWS] ALPHADIGITPS) [ [FWS] "=" [ [FWS] "=" ] ]
+fn is_valid_base64(value: &str) -> bool {
+    let data = value.trim_end_matches('=');
+    value.len() - data.len() <= 2
+        && data
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'+' || b == b'/')
+}
+
+/// field-name =  1*ftext
+/// ftext      =  %d33-57 / %d59-126
+fn is_valid_signed_headers(value: &str) -> bool {
+    value.is_empty()
+        || value
+            .split(':')
+            .all(|name| !name.is_empty() && name.bytes().all(|b| (33..=126).contains(&b)))
+}
+
 #[derive(Clone)]
 pub(crate) struct DKIMHeaderBuilder {
-    header: DKIMHeader,
+    header: DKIMHeader<'static>,
+    format: HeaderFormat,
     #[cfg(feature = "time")]
     time: Option<chrono::DateTime<chrono::offset::Utc>>,
 }
@@ -51,26 +358,34 @@ impl DKIMHeaderBuilder {
         DKIMHeaderBuilder {
             header: DKIMHeader {
                 tags: IndexMap::new(),
-                raw_bytes: "".to_owned(),
+                raw_bytes: Cow::Borrowed(""),
             },
+            format: HeaderFormat::default(),
             #[cfg(feature = "time")]
             time: None,
         }
     }
 
     pub(crate) fn add_tag(mut self, name: &str, value: &str) -> Self {
-        let tag = parser::Tag {
-            name: name.to_owned(),
-            value: value.to_owned(),
-            raw_value: value.to_owned(),
+        let tag = parser::TagRef {
+            name: Cow::Owned(name.to_owned()),
+            value: Cow::Owned(value.to_owned()),
+            raw_value: Cow::Owned(value.to_owned()),
+            // Set by `build` once the header is serialized
+            span: 0..0,
+            value_span: 0..0,
         };
-        self.header.tags.insert(name.to_owned(), tag);
+        self.header.tags.insert(Cow::Owned(name.to_owned()), tag);
 
         self
     }
 
-    pub(crate) fn set_signed_headers(self, headers: &[&str]) -> Self {
-        let headers: Vec<String> = headers.iter().map(|h| h.to_lowercase()).collect();
+    pub(crate) fn set_signed_headers<S: AsRef<str>>(self, headers: &[S]) -> Self {
+        let headers: Vec<String> = if self.format.lowercase_header_names {
+            headers.iter().map(|h| h.as_ref().to_lowercase()).collect()
+        } else {
+            headers.iter().map(|h| h.as_ref().to_owned()).collect()
+        };
         let value = headers.join(":");
         self.add_tag("h", &value)
     }
@@ -90,8 +405,54 @@ impl DKIMHeaderBuilder {
         self.add_tag("t", &time.timestamp().to_string())
     }
 
-    pub(crate) fn build(mut self) -> Result<DKIMHeader, DKIMError> {
-        self.header.raw_bytes = serialize(self.header.clone());
+    /// Format of the generated header. Must be set before adding the signed
+    /// headers.
+    pub(crate) fn set_format(mut self, format: HeaderFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Check the tags against the grammar of RFC 6376 section 3.2, so that
+    /// the generated header parses as it was built
+    pub(crate) fn validate(&self) -> Result<(), DKIMError> {
+        use DKIMError::BuilderError;
+
+        for (name, tag) in &self.header.tags {
+            let value = tag.value.as_ref();
+            if !is_valid_tag_name(name) {
+                return Err(BuilderError("invalid tag name"));
+            }
+            if !is_valid_tag_value(value) {
+                return Err(BuilderError("invalid character in tag value"));
+            }
+            if BASE64_TAGS.contains(&name.as_ref()) && !is_valid_base64(value) {
+                return Err(BuilderError("tag value isn't base64"));
+            }
+            if name == "h" && !is_valid_signed_headers(value) {
+                return Err(BuilderError("invalid signed header name"));
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn build(mut self) -> Result<DKIMHeader<'static>, DKIMError> {
+        self.validate()?;
+        let order = &self.format.tag_order;
+        let rank = |name: &str| {
+            let position = order.iter().position(|n| n == name);
+            (name == "b", position.unwrap_or(order.len()))
+        };
+        self.header.tags.sort_by(|a, _, b, _| rank(a).cmp(&rank(b)));
+        let raw_bytes = serialize(&self.header, &self.format);
+        let (_, parsed) = parser::tag_list_ref(&raw_bytes)
+            .map_err(|_| DKIMError::BuilderError("generated header doesn't parse"))?;
+        for parsed in parsed {
+            if let Some(tag) = self.header.tags.get_mut(parsed.name.as_ref()) {
+                tag.span = parsed.span;
+                tag.value_span = parsed.value_span;
+            }
+        }
+        self.header.raw_bytes = Cow::Owned(raw_bytes);
         Ok(self.header)
     }
 }
@@ -100,6 +461,27 @@ impl DKIMHeaderBuilder {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_dkim_header_unknown_tags() {
+        let value =
+            "v=1; a=rsa-sha256; d=example.net; s=brisbane; x_ext=a\r\n b; h=From; bh=hash; new=1; b=sig";
+        let header = crate::validate_header(value).unwrap();
+        assert_eq!(
+            header.unknown_tags().collect::<Vec<_>>(),
+            vec![("x_ext", "ab"), ("new", "1")]
+        );
+        assert_eq!(header.tags().count(), 9);
+
+        for format in [HeaderFormat::new(), HeaderFormat::opendkim()] {
+            let serialized = header.serialize(&format);
+            let reparsed = crate::validate_header(&serialized).unwrap();
+            assert_eq!(
+                reparsed.tags().collect::<Vec<_>>(),
+                header.tags().collect::<Vec<_>>()
+            );
+        }
+    }
+
     #[test]
     fn test_dkim_header_builder() {
         let header = DKIMHeaderBuilder::new()
@@ -108,6 +490,127 @@ mod tests {
             .build()
             .unwrap();
         assert_eq!(header.raw_bytes, "v=1; a=something;".to_owned());
+        assert_eq!(header.tag_span("a"), Some(5..16));
+        assert_eq!(header.tag_value_span("a"), Some(7..16));
+    }
+
+    #[test]
+    fn test_dkim_header_spans() {
+        let value = "v=1; a=rsa-sha256; d = example.net; s=brisbane; h=From; bh=; b=ab\r\n cd";
+        let header = crate::validate_header(value).unwrap();
+        assert_eq!(&value[header.tag_span("d").unwrap()], "d = example.net");
+        assert_eq!(&value[header.tag_value_span("d").unwrap()], "example.net");
+        assert_eq!(&value[header.tag_value_span("b").unwrap()], "ab\r\n cd");
+        assert_eq!(header.tag_value_span("bh"), Some(59..59));
+        assert_eq!(header.tag_span("x"), None);
+    }
+
+    #[test]
+    fn test_dkim_header_borrows_value() {
+        let value = "v=1; a=rsa-sha256; d=example.net; s=brisbane; h=From; bh=hash; b=ab\r\n cd";
+        let header = crate::validate_header(value).unwrap();
+        assert!(matches!(header.raw_bytes, Cow::Borrowed(_)));
+        assert!(matches!(
+            header.tags["d"].value,
+            Cow::Borrowed("example.net")
+        ));
+        assert_eq!(header.get_tag("b"), Some("abcd"));
+        assert_eq!(header.tags["b"].raw_value, "ab\r\n cd");
+
+        let header = header.into_owned();
+        assert!(matches!(header.raw_bytes, Cow::Owned(_)));
+        assert_eq!(header.get_required_tag("d"), "example.net");
+    }
+
+    #[test]
+    fn test_dkim_header_builder_tag_order() {
+        let header = DKIMHeaderBuilder::new()
+            .add_tag("b", "sig")
+            .add_tag("v", "1")
+            .add_tag("a", "rsa-sha256")
+            .add_tag("d", "example.com")
+            .set_format(HeaderFormat::new().with_tag_order(&["d", "b", "v"]))
+            .build()
+            .unwrap();
+        assert_eq!(
+            header.raw_bytes,
+            "d=example.com; v=1; a=rsa-sha256; b=sig;".to_owned()
+        );
+    }
+
+    #[test]
+    fn test_dkim_header_builder_opendkim_format() {
+        let header = DKIMHeaderBuilder::new()
+            .set_format(HeaderFormat::opendkim())
+            .add_tag("v", "1")
+            .add_tag("a", "rsa-sha256")
+            .add_tag("d", "example.com")
+            .add_tag("s", "mail")
+            .add_tag("c", "relaxed/simple")
+            .add_tag("bh", "47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=")
+            .set_signed_headers(&["From", "To", "Subject"])
+            .add_tag("t", "1514764800")
+            .add_tag("b", &"A".repeat(100))
+            .build()
+            .unwrap();
+        assert_eq!(
+            header.raw_bytes,
+            format!(
+                "v=1; a=rsa-sha256; c=relaxed/simple; d=example.com; s=mail;\r\n\
+                 \tt=1514764800;\r\n\
+                 \tbh=47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=;\r\n\
+                 \th=From:To:Subject;\r\n\
+                 \tb={}\r\n\
+                 \t{}",
+                "A".repeat(72),
+                "A".repeat(28)
+            )
+        );
+    }
+
+    #[test]
+    fn test_dkim_header_builder_rspamd_format() {
+        let header = DKIMHeaderBuilder::new()
+            .set_format(HeaderFormat::rspamd())
+            .add_tag("v", "1")
+            .add_tag("bh", "hash")
+            .set_signed_headers(&["From", "To"])
+            .add_tag("b", "sig")
+            .build()
+            .unwrap();
+        assert_eq!(
+            header.raw_bytes,
+            "v=1;\r\n\th=from:to;\r\n\tbh=hash;\r\n\tb=sig".to_owned()
+        );
+    }
+
+    #[test]
+    fn test_dkim_header_builder_folding() {
+        let header = DKIMHeaderBuilder::new()
+            .set_format(HeaderFormat::new().with_folding(40, Indentation::Spaces(2)))
+            .add_tag("v", "1")
+            .add_tag("a", "rsa-sha256")
+            .add_tag("d", "example.com")
+            .set_signed_headers(&["From", "To", "Subject", "Date"])
+            .add_tag("b", &"A".repeat(40))
+            .build()
+            .unwrap();
+        assert_eq!(
+            header.raw_bytes,
+            format!(
+                "v=1; a=rsa-sha256;\r\n  d=example.com;\r\n  h=from:to:subject:date;\r\n  b={}\r\n  {};",
+                "A".repeat(36),
+                "A".repeat(4)
+            )
+        );
+
+        let header = DKIMHeaderBuilder::new()
+            .set_format(HeaderFormat::opendkim().without_folding())
+            .add_tag("v", "1")
+            .add_tag("b", "sig")
+            .build()
+            .unwrap();
+        assert_eq!(header.raw_bytes, "v=1; b=sig".to_owned());
     }
 
     #[test]
@@ -123,6 +626,38 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_dkim_header_builder_validation() {
+        let build = |name: &str, value: &str| {
+            DKIMHeaderBuilder::new()
+                .add_tag("v", "1")
+                .add_tag(name, value)
+                .build()
+                .map(|header| header.raw_bytes.into_owned())
+        };
+        assert_eq!(build("s", "a b").unwrap(), "v=1; s=a b;");
+        assert_eq!(build("z_1", "").unwrap(), "v=1; z_1=;");
+        assert_eq!(build("bh", "YWJj").unwrap(), "v=1; bh=YWJj;");
+        assert_eq!(build("h", "From:X-1").unwrap(), "v=1; h=From:X-1;");
+
+        let invalid_name = Err(DKIMError::BuilderError("invalid tag name"));
+        assert_eq!(build("1a", "x"), invalid_name);
+        assert_eq!(build("a-b", "x"), invalid_name);
+        assert_eq!(build("", "x"), invalid_name);
+        let invalid_value = Err(DKIMError::BuilderError("invalid character in tag value"));
+        assert_eq!(build("s", "a;b"), invalid_value);
+        assert_eq!(build("s", "a\r\n b"), invalid_value);
+        assert_eq!(build("s", "a\x00"), invalid_value);
+        assert_eq!(build("s", " a"), invalid_value);
+        let invalid_base64 = Err(DKIMError::BuilderError("tag value isn't base64"));
+        assert_eq!(build("b", "YW*j"), invalid_base64);
+        assert_eq!(build("bh", "YW=j"), invalid_base64);
+        assert_eq!(build("b", "Y==="), invalid_base64);
+        let invalid_signed_headers = Err(DKIMError::BuilderError("invalid signed header name"));
+        assert_eq!(build("h", "From::To"), invalid_signed_headers);
+        assert_eq!(build("h", "From:X-Ä"), invalid_signed_headers);
+    }
+
     #[test]
     fn test_dkim_header_builder_time() {
         use chrono::TimeZone;
@@ -0,0 +1,222 @@
+//! Spans of the verification of each signature, emitted with the `tracing`
+//! crate when the `tracing` feature is enabled. Without it, they're no-ops.
+//!
+//! Each signature gets a `dkim_signature` span with its `d`, `s` and `a`
+//! tags. The durations of the DNS lookup, of the body and headers hashes and
+//! of the cryptographic verification are recorded on it, in microseconds,
+//! along with the `result` of the verification.
+
+use std::future::Future;
+#[cfg(feature = "tracing")]
+use std::time::Instant;
+
+use crate::DKIMError;
+
+/// Phase of the verification of a signature
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Phase {
+    #[cfg(feature = "dns")]
+    Dns,
+    BodyHash,
+    HeaderHash,
+    Crypto,
+}
+
+impl Phase {
+    #[cfg(feature = "tracing")]
+    fn field(self) -> &'static str {
+        match self {
+            #[cfg(feature = "dns")]
+            Phase::Dns => "dns_us",
+            Phase::BodyHash => "body_hash_us",
+            Phase::HeaderHash => "header_hash_us",
+            Phase::Crypto => "crypto_us",
+        }
+    }
+}
+
+/// Span of the verification of a signature
+pub(crate) struct SignatureSpan {
+    #[cfg(feature = "tracing")]
+    span: tracing::Span,
+}
+
+impl SignatureSpan {
+    #[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+    pub(crate) fn new(domain: &str, selector: &str, algorithm: &str) -> Self {
+        Self {
+            #[cfg(feature = "tracing")]
+            span: tracing::info_span!(
+                "dkim_signature",
+                d = domain,
+                s = selector,
+                a = algorithm,
+                dns_us = tracing::field::Empty,
+                body_hash_us = tracing::field::Empty,
+                header_hash_us = tracing::field::Empty,
+                crypto_us = tracing::field::Empty,
+                result = tracing::field::Empty,
+            ),
+        }
+    }
+
+    /// Run `future` inside the span
+    pub(crate) async fn instrument<F: Future>(&self, future: F) -> F::Output {
+        #[cfg(feature = "tracing")]
+        let future = tracing::Instrument::instrument(future, self.span.clone());
+        future.await
+    }
+
+    /// Record the outcome of the verification: `pass` or the code of the
+    /// error, see [DKIMError::code]
+    #[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+    pub(crate) fn record_result<T>(&self, result: &Result<T, DKIMError>) {
+        #[cfg(feature = "tracing")]
+        {
+            let result = match result {
+                Ok(_) => "pass",
+                Err(err) => err.code(),
+            };
+            self.span.record("result", result);
+        }
+    }
+}
+
+/// Run `f`, recording its duration on the current signature span
+#[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+pub(crate) fn timed<T>(phase: Phase, f: impl FnOnce() -> T) -> T {
+    #[cfg(feature = "tracing")]
+    let start = Instant::now();
+    let value = f();
+    #[cfg(feature = "tracing")]
+    record_duration(phase, start);
+    value
+}
+
+/// Await `future`, recording its duration on the current signature span
+#[cfg(feature = "dns")]
+#[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+pub(crate) async fn timed_async<F: Future>(phase: Phase, future: F) -> F::Output {
+    #[cfg(feature = "tracing")]
+    let start = Instant::now();
+    let value = future.await;
+    #[cfg(feature = "tracing")]
+    record_duration(phase, start);
+    value
+}
+
+#[cfg(feature = "tracing")]
+fn record_duration(phase: Phase, start: Instant) {
+    let micros = u64::try_from(start.elapsed().as_micros()).unwrap_or(u64::MAX);
+    tracing::Span::current().record(phase.field(), micros);
+}
+
+#[cfg(all(test, feature = "tracing"))]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+    use tracing::field::{Field, Visit};
+    use tracing::span::{Attributes, Id, Record};
+    use tracing::{Event, Metadata, Subscriber};
+    use tracing_core::span::Current;
+
+    type Fields = HashMap<String, String>;
+
+    /// Subscriber keeping the fields of the spans, on a single thread
+    #[derive(Clone, Default)]
+    struct Recorder {
+        spans: Arc<Mutex<Vec<(&'static Metadata<'static>, Fields)>>>,
+        entered: Arc<Mutex<Vec<Id>>>,
+    }
+
+    struct Visitor<'a>(&'a mut Fields);
+
+    impl Visit for Visitor<'_> {
+        fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+            self.0
+                .insert(field.name().to_owned(), format!("{:?}", value));
+        }
+
+        fn record_str(&mut self, field: &Field, value: &str) {
+            self.0.insert(field.name().to_owned(), value.to_owned());
+        }
+    }
+
+    impl Subscriber for Recorder {
+        fn enabled(&self, _: &Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, span: &Attributes<'_>) -> Id {
+            let mut spans = self.spans.lock().unwrap();
+            let mut fields = HashMap::new();
+            span.record(&mut Visitor(&mut fields));
+            spans.push((span.metadata(), fields));
+            Id::from_u64(spans.len() as u64)
+        }
+
+        fn record(&self, span: &Id, values: &Record<'_>) {
+            let mut spans = self.spans.lock().unwrap();
+            let (_, fields) = &mut spans[span.into_u64() as usize - 1];
+            values.record(&mut Visitor(fields));
+        }
+
+        fn record_follows_from(&self, _: &Id, _: &Id) {}
+
+        fn event(&self, _: &Event<'_>) {}
+
+        fn enter(&self, span: &Id) {
+            self.entered.lock().unwrap().push(span.clone());
+        }
+
+        fn exit(&self, _: &Id) {
+            self.entered.lock().unwrap().pop();
+        }
+
+        fn current_span(&self) -> Current {
+            match self.entered.lock().unwrap().last() {
+                Some(span) => {
+                    let (metadata, _) = self.spans.lock().unwrap()[span.into_u64() as usize - 1];
+                    Current::new(span.clone(), metadata)
+                }
+                None => Current::none(),
+            }
+        }
+    }
+
+    #[test]
+    fn test_signature_span() {
+        let recorder = Recorder::default();
+        tracing::subscriber::with_default(recorder.clone(), || {
+            let logger = slog::Logger::root(slog::Discard, slog::o!());
+            let raw_email = crate::test_utils::sign_message(
+                crate::test_utils::SAMPLE_MESSAGE,
+                crate::test_utils::rsa_private_key(),
+                crate::test_utils::RSA_SELECTOR,
+            );
+            let email = mailparse::parse_mail(raw_email.as_bytes()).unwrap();
+            let public_key = crate::test_utils::rsa_private_key().to_public_key();
+            let result = crate::verify_email_with_key(
+                &logger,
+                crate::test_utils::DOMAIN,
+                &email,
+                public_key,
+            )
+            .unwrap();
+            assert_eq!(result.summary(), "pass");
+        });
+
+        let spans = recorder.spans.lock().unwrap();
+        assert_eq!(spans.len(), 1);
+        let (_, span) = &spans[0];
+        assert_eq!(span["d"], "example.com");
+        assert_eq!(span["s"], "2022");
+        assert_eq!(span["a"], "rsa-sha256");
+        assert_eq!(span["result"], "pass");
+        for field in ["body_hash_us", "header_hash_us", "crypto_us"] {
+            assert!(span[field].parse::<u64>().is_ok(), "{}", field);
+        }
+        // The key was provided
+        assert!(!span.contains_key("dns_us"));
+    }
+}
@@ -0,0 +1,224 @@
+//! JavaScript bindings built with wasm-bindgen, for web tooling such as
+//! webmail debuggers or browser-based DKIM checkers.
+//!
+//! ```js
+//! const header = sign(message, privateKeyPem, "s1", "example.com", ["From", "Subject"]);
+//! const result = verifyWithRecords(message, {
+//!   "s1._domainkey.example.com": "v=DKIM1; k=rsa; p=...",
+//! });
+//! console.log(result.summary, result.detail);
+//! ```
+//!
+//! The WebAssembly module is built as a cdylib, then bound with the
+//! wasm-bindgen CLI:
+//!
+//! ```sh
+//! cargo rustc --lib --release --features js --target wasm32-unknown-unknown --crate-type cdylib
+//! wasm-bindgen --target web --out-dir pkg target/wasm32-unknown-unknown/release/cfdkim.wasm
+//! ```
+
+use std::borrow::Cow;
+
+use wasm_bindgen::prelude::*;
+
+use crate::{
+    author_result, header_from_domain, normalize_domain, public_key, verify_email_with_key,
+    verify_signatures_with_records, DKIMError, DKIMResult, DkimPrivateKey, ErrorSource,
+    SignatureScope, SignerBuilder, VerificationPolicy,
+};
+
+/// Result of a verification
+#[wasm_bindgen(getter_with_clone)]
+#[derive(Debug, Clone, PartialEq)]
+pub struct VerifyResult {
+    /// `pass`, `neutral` or `fail`
+    pub summary: String,
+    /// The summary followed by the reason of the failure, if any
+    pub detail: String,
+    pub domain: String,
+    /// Code of the failure, see [DKIMError::code]
+    #[wasm_bindgen(js_name = errorCode)]
+    pub error_code: Option<String>,
+    pub selector: Option<String>,
+    pub algorithm: Option<String>,
+    /// Size in bits of the key which verified the signature
+    #[wasm_bindgen(js_name = keySize)]
+    pub key_size: Option<usize>,
+}
+
+impl From<DKIMResult> for VerifyResult {
+    fn from(result: DKIMResult) -> Self {
+        Self {
+            summary: result.summary().to_owned(),
+            detail: result.to_string(),
+            domain: result.domain_used(),
+            error_code: result.error().map(|err| err.code().to_owned()),
+            selector: result.selector().map(str::to_owned),
+            algorithm: result.algorithm().map(str::to_owned),
+            key_size: result.key_size(),
+        }
+    }
+}
+
+/// Sign a message with a RSA private key in PEM format and return the
+/// `DKIM-Signature` header, without the trailing CRLF
+#[wasm_bindgen]
+pub fn sign(
+    message: &[u8],
+    private_key_pem: &str,
+    selector: &str,
+    signing_domain: &str,
+    signed_headers: Vec<String>,
+) -> Result<String, JsError> {
+    let email = parse_mail(message)?;
+    let logger = slog::Logger::root(slog::Discard, slog::o!());
+    let signer = SignerBuilder::new()
+        .with_signed_headers(&signed_headers)?
+        .with_private_key(DkimPrivateKey::rsa_from_pem(private_key_pem)?)
+        .with_selector(selector)
+        .with_signing_domain(signing_domain)
+        .with_logger(&logger)
+        .build()?;
+    Ok(signer.sign(&email)?)
+}
+
+/// Verify the signatures of a message for the domain of its From header with
+/// the provided key record, for instance `v=DKIM1; k=rsa; p=...`
+#[wasm_bindgen(js_name = verifyWithKey)]
+pub fn verify_with_key(message: &[u8], key_record: &str) -> Result<VerifyResult, JsError> {
+    let email = parse_mail(message)?;
+    let from_domain = header_from_domain(&email)?;
+    let logger = slog::Logger::root(slog::Discard, slog::o!());
+    let public_key = public_key::parse_public_key(&logger, key_record)?;
+    let result = verify_email_with_key(&logger, &from_domain, &email, public_key)?;
+    Ok(result.into())
+}
+
+/// Verify the signatures of a message for the domain of its From header.
+/// `records` is an object mapping the DNS names (for instance
+/// `s1._domainkey.example.com`) to the key records.
+#[wasm_bindgen(js_name = verifyWithRecords)]
+pub fn verify_with_records(
+    message: &[u8],
+    records: &js_sys::Object,
+) -> Result<VerifyResult, JsError> {
+    let email = parse_mail(message)?;
+    let result = verify_email_with_records(&email, |name| {
+        js_sys::Reflect::get(records, &JsValue::from_str(name))
+            .ok()
+            .and_then(|value| value.as_string())
+    })?;
+    Ok(result.into())
+}
+
+fn parse_mail(message: &[u8]) -> Result<mailparse::ParsedMail<'_>, DKIMError> {
+    mailparse::parse_mail(message).map_err(|err| DKIMError::MalformedEmail {
+        reason: "invalid message",
+        source: Some(ErrorSource::new(err)),
+    })
+}
+
+/// Verify the signatures of the domain of the From header, retrieving the key
+/// records with `lookup`, like [crate::verify_email_with_key] with the
+/// default policy
+fn verify_email_with_records<F>(
+    email: &mailparse::ParsedMail,
+    lookup: F,
+) -> Result<DKIMResult, DKIMError>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let from_domain = header_from_domain(email)?;
+    let logger = slog::Logger::root(slog::Discard, slog::o!());
+    let policy = VerificationPolicy::default();
+    let results = verify_signatures_with_records(
+        &logger,
+        SignatureScope::Author(&from_domain),
+        email,
+        &policy,
+        None,
+        |dkim_header| {
+            let name = format!(
+                "{}._domainkey.{}",
+                dkim_header.get_required_tag("s"),
+                normalize_domain(dkim_header.get_required_tag("d"))
+            );
+            let record = lookup(&name).ok_or_else(|| DKIMError::KeyUnavailable {
+                name: name.clone(),
+                reason: "no key record".to_owned(),
+                source: None,
+            })?;
+            public_key::parse_key_record(&logger, &record).map(Cow::Owned)
+        },
+    );
+    Ok(author_result(&policy, &from_domain, results))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EMAIL: &[u8] = b"Subject: subject\r\nFrom: Joe <joe@example.com>\r\n\r\nHello Alice\r\n";
+
+    fn key_record() -> String {
+        std::fs::read_to_string("./test/keys/2022.txt")
+            .unwrap()
+            .split('"')
+            .skip(1)
+            .step_by(2)
+            .collect()
+    }
+
+    #[test]
+    fn test_sign_verify() {
+        let private_key = std::fs::read_to_string("./test/keys/2022.private").unwrap();
+        let header = sign(
+            EMAIL,
+            &private_key,
+            "2022",
+            "example.com",
+            vec!["From".to_owned(), "Subject".to_owned()],
+        )
+        .unwrap();
+        let signed = [header.as_bytes(), b"\r\n", EMAIL].concat();
+
+        let result = verify_with_key(&signed, &key_record()).unwrap();
+        assert_eq!(result.summary, "pass");
+        assert_eq!(result.domain, "example.com");
+        assert_eq!(result.selector.as_deref(), Some("2022"));
+        assert_eq!(result.key_size, Some(2048));
+
+        let email = parse_mail(&signed).unwrap();
+        let result = verify_email_with_records(&email, |name| {
+            (name == "2022._domainkey.example.com").then(key_record)
+        })
+        .unwrap();
+        assert_eq!(result.summary(), "pass");
+        assert_eq!(result.signature_index(), Some(0));
+        // The flags of the record (t=y) are reported, as by the DNS
+        // verification
+        assert!(result.is_testing());
+
+        // As well as when the signature fails with the record
+        let tampered = String::from_utf8(signed.clone())
+            .unwrap()
+            .replace("Hello Alice", "Hello Mallory");
+        let email = parse_mail(tampered.as_bytes()).unwrap();
+        let result = verify_email_with_records(&email, |_| Some(key_record())).unwrap();
+        assert_eq!(result.summary(), "fail");
+        assert!(result.is_testing());
+
+        let email = parse_mail(&signed).unwrap();
+        let result = verify_email_with_records(&email, |_| None).unwrap();
+        assert_eq!(result.summary(), "fail");
+        assert_eq!(result.signature_index(), Some(0));
+        assert_eq!(
+            VerifyResult::from(result).error_code.as_deref(),
+            Some("DKIM_KEY_UNAVAILABLE")
+        );
+
+        let email = parse_mail(EMAIL).unwrap();
+        let result = verify_email_with_records(&email, |_| None).unwrap();
+        assert_eq!(result.summary(), "neutral");
+    }
+}
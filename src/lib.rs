@@ -1,10 +1,10 @@
 // Implementation of DKIM: https://datatracker.ietf.org/doc/html/rfc6376
 
-use base64::engine::general_purpose;
-use base64::Engine;
+use futures::FutureExt;
 use indexmap::map::IndexMap;
 use rsa::pkcs1;
 use rsa::pkcs1::EncodeRsaPublicKey;
+use rsa::traits::PublicKeyParts;
 use rsa::Pkcs1v15Sign;
 use rsa::RsaPrivateKey;
 use rsa::RsaPublicKey;
@@ -12,7 +12,10 @@ use sha1::Sha1;
 use sha2::Sha256;
 use slog::debug;
 use std::array::TryFromSliceError;
+use std::borrow::Cow;
 use std::collections::HashSet;
+use std::future::Future;
+use std::ops::Range;
 #[cfg(feature = "dns")]
 use std::sync::Arc;
 #[cfg(feature = "dns")]
@@ -20,36 +23,91 @@ use trust_dns_resolver::TokioAsyncResolver;
 
 use mailparse::MailHeaderMap;
 
-#[macro_use]
-extern crate quick_error;
-
+pub mod adsp;
+pub mod arc;
+#[cfg(any(feature = "dns", feature = "parallel"))]
+mod bulk;
 mod bytes;
 pub mod canonicalization;
+#[cfg(feature = "capi")]
+pub mod capi;
+mod commitment;
+pub mod dmarc;
 #[cfg(feature = "dns")]
 pub mod dns;
+#[cfg(feature = "domainkeys")]
+pub mod domainkeys;
 mod errors;
+pub mod explain;
+pub mod forensics;
 mod hash;
 pub mod header;
+mod instrument;
+#[cfg(feature = "js")]
+pub mod js;
+#[cfg(feature = "log")]
+mod logging;
+#[cfg(feature = "mailbox")]
+pub mod mailbox;
+pub mod mailing_list;
+pub mod mime;
+pub mod nested;
+mod observer;
 mod parser;
+mod policy;
 pub mod public_key;
+mod public_suffix;
+pub mod registry;
+pub mod report;
 mod result;
+pub mod rewrite;
+pub mod rotation;
 #[cfg(test)]
 mod roundtrip_test;
 mod sign;
-
-pub use errors::DKIMError;
+pub mod signing_policy;
+#[cfg(feature = "spf")]
+pub mod spf;
+#[cfg(feature = "async")]
+pub mod stream;
+#[cfg(any(test, feature = "test-utils"))]
+pub mod test_utils;
+#[cfg(any(test, feature = "test-vectors"))]
+pub mod test_vectors;
+#[cfg(feature = "tower")]
+pub mod tower;
+
+#[cfg(feature = "parallel")]
+pub use bulk::verify_all_parallel;
+#[cfg(feature = "dns")]
+pub use bulk::verify_stream;
+pub use commitment::Commitment;
+pub use errors::{DKIMError, ErrorSource};
+pub use hash::{
+    compute_body_hash, compute_headers_hash, select_signed_headers, BodyHashCache, BodyHasher,
+    BodyHashes, HashAlgo, HeaderSelection, SelectedHeader,
+};
 use header::{DKIMHeader, HEADER, REQUIRED_TAGS};
+pub use header::{HeaderFormat, Indentation};
+use instrument::{Phase, SignatureSpan};
+#[cfg(feature = "log")]
+pub use logging::log_logger;
+pub use observer::{SigningHooks, VerificationObserver};
 pub use parser::tag_list as parse_tag_list;
 pub use parser::Tag;
-pub use result::DKIMResult;
-pub use sign::{DKIMSigner, SignerBuilder};
-
+pub use parser::{decode_quoted_printable, encode_quoted_printable};
+pub use policy::{PolicyAction, SignatureSelection, VerificationPolicy};
+use public_key::KeyRecord;
+pub use result::{DKIMResult, Verdict};
 #[cfg(feature = "time")]
+pub use sign::RECOMMENDED_EXPIRY_DAYS;
+pub use sign::{DKIMSigner, SignerBuilder, OVERSIGNED_HEADERS, RECOMMENDED_SIGNED_HEADERS};
+
 const SIGN_EXPIRATION_DRIFT_MINS: i64 = 15;
 #[cfg(feature = "dns")]
 const DNS_NAMESPACE: &str = "_domainkey";
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum DkimPublicKey {
     Rsa(RsaPublicKey),
     Ed25519(ed25519_dalek::VerifyingKey),
@@ -66,6 +124,14 @@ impl DkimPublicKey {
         }
     }
 
+    /// Returns the size of the key in bits
+    pub fn key_size(&self) -> usize {
+        match self {
+            DkimPublicKey::Ed25519(_) => 256,
+            DkimPublicKey::Rsa(public_key) => public_key.size() * 8,
+        }
+    }
+
     /// Returns the key type
     pub fn key_type(&self) -> &'static str {
         match self {
@@ -74,33 +140,58 @@ impl DkimPublicKey {
         }
     }
 
+    /// Returns the DKIM key record to publish in the TXT record of the
+    /// selector, for instance `v=DKIM1; k=rsa; p=MIIBIjAN...`. RSA keys are
+    /// encoded as a SubjectPublicKeyInfo.
+    pub fn to_key_record(&self) -> String {
+        use base64::Engine;
+        use rsa::pkcs8::EncodePublicKey;
+
+        let bytes = match self {
+            DkimPublicKey::Ed25519(public_key) => public_key.as_bytes().to_vec(),
+            DkimPublicKey::Rsa(public_key) => public_key
+                .to_public_key_der()
+                .expect("RSA key serialization should not fail")
+                .into_vec(),
+        };
+        format!(
+            "v=DKIM1; k={}; p={}",
+            self.key_type(),
+            base64::engine::general_purpose::STANDARD.encode(bytes)
+        )
+    }
+
     /// Try to create a DkimPublicKey from bytes and key type
     pub fn try_from_bytes(bytes: &[u8], key_type: &str) -> Result<Self, DKIMError> {
         match key_type.to_lowercase().as_str() {
             "rsa" => Self::parse_rsa_key(bytes),
             "ed25519" => Self::parse_ed25519_key(bytes),
-            unsupported => Err(DKIMError::KeyUnavailable(format!(
-                "unsupported key type: {}",
-                unsupported
-            ))),
+            unsupported => Err(DKIMError::InappropriateKeyAlgorithm {
+                key_type: unsupported.to_owned(),
+            }),
         }
     }
 
     fn parse_rsa_key(bytes: &[u8]) -> Result<Self, DKIMError> {
         pkcs1::DecodeRsaPublicKey::from_pkcs1_der(bytes)
             .map(DkimPublicKey::Rsa)
-            .map_err(|err| DKIMError::KeyUnavailable(format!("failed to parse RSA key: {}", err)))
+            .map_err(|err| DKIMError::KeySyntaxError {
+                reason: format!("failed to parse RSA key: {}", err),
+                source: Some(ErrorSource::new(err)),
+            })
     }
 
     fn parse_ed25519_key(bytes: &[u8]) -> Result<Self, DKIMError> {
-        let key_bytes: [u8; 32] = bytes.try_into().map_err(|err| {
-            DKIMError::KeyUnavailable(format!("invalid Ed25519 key length: {}", err))
+        let key_bytes: [u8; 32] = bytes.try_into().map_err(|_| DKIMError::InvalidKeyLength {
+            expected: ed25519_dalek::PUBLIC_KEY_LENGTH,
+            actual: bytes.len(),
         })?;
 
         ed25519_dalek::VerifyingKey::from_bytes(&key_bytes)
             .map(DkimPublicKey::Ed25519)
-            .map_err(|err| {
-                DKIMError::KeyUnavailable(format!("failed to parse Ed25519 key: {}", err))
+            .map_err(|err| DKIMError::KeySyntaxError {
+                reason: format!("failed to parse Ed25519 key: {}", err),
+                source: Some(ErrorSource::new(err)),
             })
     }
 }
@@ -113,37 +204,207 @@ impl TryFrom<(&[u8], &str)> for DkimPublicKey {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum DkimPrivateKey {
     Rsa(RsaPrivateKey),
     Ed25519(ed25519_dalek::SigningKey),
 }
 
+impl DkimPrivateKey {
+    /// Derive the public key, for instance to generate the DNS record with
+    /// [DkimPublicKey::to_key_record] or verify the signatures locally
+    pub fn to_public_key(&self) -> DkimPublicKey {
+        match self {
+            DkimPrivateKey::Rsa(private_key) => DkimPublicKey::Rsa(private_key.to_public_key()),
+            DkimPrivateKey::Ed25519(signing_key) => {
+                DkimPublicKey::Ed25519(signing_key.verifying_key())
+            }
+        }
+    }
+
+    /// Parse a private key in PEM format (`ENCRYPTED PRIVATE KEY`), PKCS#8
+    /// encrypted with a passphrase (PBES2 with scrypt or PBKDF2). RSA and
+    /// Ed25519 keys are supported.
+    #[cfg(feature = "encrypted-keys")]
+    pub fn from_pkcs8_encrypted_pem(
+        pem: &str,
+        passphrase: impl AsRef<[u8]>,
+    ) -> Result<Self, DKIMError> {
+        let (label, document) =
+            pkcs8::Document::from_pem(pem.trim()).map_err(|err| DKIMError::InvalidPrivateKey {
+                reason: "invalid PEM",
+                source: Some(ErrorSource::new(err)),
+            })?;
+        if label != "ENCRYPTED PRIVATE KEY" {
+            return Err(DKIMError::InvalidPrivateKey {
+                reason: "not an ENCRYPTED PRIVATE KEY PEM",
+                source: None,
+            });
+        }
+        Self::from_pkcs8_encrypted_der(document.as_bytes(), passphrase)
+    }
+
+    /// Parse a private key in DER format, PKCS#8 encrypted with a passphrase,
+    /// see [Self::from_pkcs8_encrypted_pem]
+    #[cfg(feature = "encrypted-keys")]
+    pub fn from_pkcs8_encrypted_der(
+        der: &[u8],
+        passphrase: impl AsRef<[u8]>,
+    ) -> Result<Self, DKIMError> {
+        let info = pkcs8::EncryptedPrivateKeyInfo::try_from(der).map_err(|err| {
+            DKIMError::InvalidPrivateKey {
+                reason: "invalid encrypted key",
+                source: Some(ErrorSource::new(err)),
+            }
+        })?;
+        Self::from_encrypted_private_key_info(info, passphrase)
+    }
+
+    #[cfg(feature = "encrypted-keys")]
+    fn from_encrypted_private_key_info(
+        info: pkcs8::EncryptedPrivateKeyInfo,
+        passphrase: impl AsRef<[u8]>,
+    ) -> Result<Self, DKIMError> {
+        use pkcs8::DecodePrivateKey;
+
+        let invalid = |err: pkcs8::Error| DKIMError::InvalidPrivateKey {
+            reason: "invalid decrypted key",
+            source: Some(ErrorSource::new(err)),
+        };
+        // A wrong passphrase usually fails the decryption, else the decoding
+        let document = info
+            .decrypt(passphrase)
+            .map_err(|err| DKIMError::InvalidPrivateKey {
+                reason: "failed to decrypt",
+                source: Some(ErrorSource::new(err)),
+            })?;
+        let der = document.as_bytes();
+        let algorithm = pkcs8::PrivateKeyInfo::try_from(der)
+            .map_err(invalid)?
+            .algorithm
+            .oid;
+        if algorithm == pkcs8::ObjectIdentifier::new_unwrap("1.2.840.113549.1.1.1") {
+            RsaPrivateKey::from_pkcs8_der(der)
+                .map(DkimPrivateKey::Rsa)
+                .map_err(invalid)
+        } else if algorithm == ed25519_dalek::pkcs8::ALGORITHM_OID {
+            ed25519_dalek::SigningKey::from_pkcs8_der(der)
+                .map(DkimPrivateKey::Ed25519)
+                .map_err(invalid)
+        } else {
+            Err(DKIMError::InappropriateKeyAlgorithm {
+                key_type: algorithm.to_string(),
+            })
+        }
+    }
+
+    /// Parse a RSA private key in PEM format, PKCS#1 or PKCS#8
+    #[cfg(any(feature = "capi", feature = "js"))]
+    pub(crate) fn rsa_from_pem(pem: &str) -> Result<Self, DKIMError> {
+        use rsa::pkcs1::DecodeRsaPrivateKey;
+        use rsa::pkcs8::DecodePrivateKey;
+
+        RsaPrivateKey::from_pkcs1_pem(pem)
+            .or_else(|_| RsaPrivateKey::from_pkcs8_pem(pem))
+            .map(DkimPrivateKey::Rsa)
+            .map_err(|err| DKIMError::InvalidPrivateKey {
+                reason: "invalid RSA key",
+                source: Some(ErrorSource::new(err)),
+            })
+    }
+}
+
+/// Returns the lowercase domain of the first address in the From header, the
+/// domain whose signature is verified
+pub fn header_from_domain(email: &mailparse::ParsedMail) -> Result<String, DKIMError> {
+    header_from_address(email)?
+        .rsplit_once('@')
+        .map(|(_, domain)| domain.to_lowercase())
+        .ok_or_else(|| DKIMError::MalformedEmail {
+            reason: "missing From domain",
+            source: None,
+        })
+}
+
+/// Returns the first address in the From header
+pub(crate) fn header_from_address(email: &mailparse::ParsedMail) -> Result<String, DKIMError> {
+    let header =
+        email
+            .headers
+            .get_first_header("From")
+            .ok_or_else(|| DKIMError::MalformedEmail {
+                reason: "missing From header",
+                source: None,
+            })?;
+    let addresses =
+        mailparse::addrparse_header(header).map_err(|err| DKIMError::MalformedEmail {
+            reason: "invalid From header",
+            source: Some(ErrorSource::new(err)),
+        })?;
+    let address = match addresses.first() {
+        Some(mailparse::MailAddr::Single(info)) => Some(&info.addr),
+        Some(mailparse::MailAddr::Group(group)) => group.addrs.first().map(|info| &info.addr),
+        None => None,
+    };
+    address.cloned().ok_or_else(|| DKIMError::MalformedEmail {
+        reason: "missing From address",
+        source: None,
+    })
+}
+
+/// Normalize a domain for comparisons and DNS queries: U-labels are converted
+/// to A-labels, as allowed in `d=` and `i=` by
+/// <https://datatracker.ietf.org/doc/html/rfc8616#section-4>, and the domain
+/// is lowercased
+pub(crate) fn normalize_domain(domain: &str) -> String {
+    idna::domain_to_ascii(domain).unwrap_or_else(|_| domain.to_lowercase())
+}
+
+/// Value of a signature header. The value is hashed as is and may contain
+/// UTF-8, so it must not be converted lossily.
+pub(crate) fn signature_value<'a>(
+    header: &'a mailparse::MailHeader<'a>,
+) -> Result<&'a str, DKIMError> {
+    std::str::from_utf8(header.get_value_raw()).map_err(|err| DKIMError::SignatureSyntaxError {
+        tag: None,
+        value: String::from_utf8_lossy(header.get_value_raw()).into_owned(),
+        source: Some(ErrorSource::new(err)),
+    })
+}
+
 // https://datatracker.ietf.org/doc/html/rfc6376#section-6.1.1
-pub fn validate_header(value: &str) -> Result<DKIMHeader, DKIMError> {
-    let (_, tags) =
-        parser::tag_list(value).map_err(|err| DKIMError::SignatureSyntaxError(err.to_string()))?;
+pub fn validate_header(value: &str) -> Result<DKIMHeader<'_>, DKIMError> {
+    validate_header_with_policy(value, &VerificationPolicy::default())
+}
+
+/// Same as [validate_header] but the verification time used for the
+/// expiration check comes from the policy
+pub fn validate_header_with_policy<'a>(
+    value: &'a str,
+    policy: &VerificationPolicy,
+) -> Result<DKIMHeader<'a>, DKIMError> {
+    let tags = parser::complete_tag_list_ref(value)?;
 
     // Check presence of required tags
     {
-        let mut tag_names: HashSet<String> = HashSet::new();
+        let mut tag_names: HashSet<&str> = HashSet::new();
         for tag in &tags {
-            tag_names.insert(tag.name.clone());
+            tag_names.insert(&tag.name);
         }
         for required in REQUIRED_TAGS {
-            if tag_names.get(*required).is_none() {
+            if !tag_names.contains(*required) {
                 return Err(DKIMError::SignatureMissingRequiredTag(required));
             }
         }
     }
 
     let mut tags_map = IndexMap::new();
-    for tag in &tags {
-        tags_map.insert(tag.name.clone(), tag.clone());
+    for tag in tags {
+        tags_map.insert(tag.name.clone(), tag);
     }
     let header = DKIMHeader {
         tags: tags_map,
-        raw_bytes: value.to_owned(),
+        raw_bytes: Cow::Borrowed(value),
     };
     // FIXME: we could get the keys instead of generating tag_names ourselves
 
@@ -157,11 +418,17 @@ pub fn validate_header(value: &str) -> Result<DKIMHeader, DKIMError> {
 
     // Check that "d=" tag is the same as or a parent domain of the domain part
     // of the "i=" tag
-    if let Some(user) = header.get_tag("i") {
-        let signing_domain = header.get_required_tag("d");
-        // TODO: naive check, should switch to parsing the domains/email
-        if !user.ends_with(&signing_domain) {
-            return Err(DKIMError::DomainMismatch);
+    if let Some(user) = header.identity()? {
+        let signing_domain = normalize_domain(header.get_required_tag("d"));
+        let user_domain = normalize_domain(user.rsplit_once('@').map_or(&user, |(_, d)| d));
+        let is_subdomain = user_domain
+            .strip_suffix(&signing_domain)
+            .is_some_and(|prefix| prefix.is_empty() || prefix.ends_with('.'));
+        if !is_subdomain {
+            return Err(DKIMError::DomainMismatch {
+                domain: signing_domain,
+                identity: user_domain,
+            });
         }
     }
 
@@ -186,87 +453,176 @@ pub fn validate_header(value: &str) -> Result<DKIMHeader, DKIMError> {
     // "Signatures MAY be considered invalid if the verification time at the Verifier
     // is past the expiration date...The "x=" tag is not intended as an anti-replay
     // defense." Since the RFC explicitly makes this validation optional, not checking
-    // expiry when the "time" feature is disabled does not violate the specification.
-    #[cfg(feature = "time")]
-    if let Some(expiration) = header.get_tag("x") {
-        #[allow(deprecated)]
-        let mut expiration = chrono::NaiveDateTime::from_timestamp_opt(
-            expiration.parse::<i64>().unwrap_or_default(),
-            0,
-        )
-        .ok_or(DKIMError::SignatureExpired)?;
-        expiration += chrono::Duration::minutes(SIGN_EXPIRATION_DRIFT_MINS);
-        let now = chrono::Utc::now().naive_utc();
-        if now > expiration {
-            return Err(DKIMError::SignatureExpired);
+    // expiry when the verification time is unknown (the "time" feature is disabled and
+    // the policy doesn't provide it) does not violate the specification.
+    if let Some(expiration) = policy::signature_expiration(&header)? {
+        if let Some(now) = policy.now() {
+            if now > expiration.saturating_add(SIGN_EXPIRATION_DRIFT_MINS * 60) {
+                return Err(DKIMError::SignatureExpired { expiration, now });
+            }
+        }
+
+        // The "x=" tag MUST be greater than the "t=" tag if both are present
+        if let Some(timestamp) = policy::signature_timestamp(&header)? {
+            if expiration <= timestamp {
+                return Err(DKIMError::ExpirationBeforeTimestamp {
+                    expiration,
+                    timestamp,
+                });
+            }
         }
     }
 
     Ok(header)
 }
 
-// https://datatracker.ietf.org/doc/html/rfc6376#section-6.1.3 Step 4
-fn verify_signature(
+/// Verify the signature (the decoded `b=` tag) of the hash of the signed
+/// headers, as computed by [compute_headers_hash], with a public key. Returns
+/// whether the signature is valid; an error is only returned if the algorithm
+/// isn't supported by the key or the signature is malformed.
+/// This is the cryptographic layer of the verification, without any email
+/// parsing.
+/// https://datatracker.ietf.org/doc/html/rfc6376#section-6.1.3 Step 4
+pub fn verify_signature(
     hash_algo: hash::HashAlgo,
-    header_hash: Vec<u8>,
-    signature: Vec<u8>,
-    public_key: DkimPublicKey,
+    header_hash: &[u8],
+    signature: &[u8],
+    public_key: &DkimPublicKey,
 ) -> Result<bool, DKIMError> {
+    use base64::Engine;
+
     Ok(match public_key {
         DkimPublicKey::Rsa(public_key) => public_key
             .verify(
                 match hash_algo {
                     hash::HashAlgo::RsaSha1 => Pkcs1v15Sign::new::<Sha1>(),
                     hash::HashAlgo::RsaSha256 => Pkcs1v15Sign::new::<Sha256>(),
-                    hash => return Err(DKIMError::UnsupportedHashAlgorithm(format!("{:?}", hash))),
+                    hash => {
+                        return Err(DKIMError::UnsupportedHashAlgorithm {
+                            algorithm: format!("{:?}", hash),
+                        })
+                    }
                 },
-                &header_hash,
-                &signature,
+                header_hash,
+                signature,
             )
             .is_ok(),
         DkimPublicKey::Ed25519(public_key) => public_key
             .verify_strict(
-                &header_hash,
-                &ed25519_dalek::Signature::from_bytes((&signature as &[u8]).try_into().map_err(
-                    |err: TryFromSliceError| DKIMError::SignatureSyntaxError(err.to_string()),
+                header_hash,
+                &ed25519_dalek::Signature::from_bytes(signature.try_into().map_err(
+                    |err: TryFromSliceError| DKIMError::SignatureSyntaxError {
+                        tag: Some("b"),
+                        value: base64::engine::general_purpose::STANDARD.encode(signature),
+                        source: Some(ErrorSource::new(err)),
+                    },
                 )?),
             )
             .is_ok(),
     })
 }
 
-#[cfg(feature = "dns")]
-async fn verify_email_header<'a>(
-    logger: &'a slog::Logger,
-    resolver: Arc<dyn dns::Lookup>,
-    dkim_header: &'a DKIMHeader,
-    email: &'a mailparse::ParsedMail<'a>,
-) -> Result<(canonicalization::Type, canonicalization::Type), DKIMError> {
-    let public_key = public_key::retrieve_public_key(
-        logger,
-        Arc::clone(&resolver),
-        dkim_header.get_required_tag("d"),
-        dkim_header.get_required_tag("s"),
-    )
-    .await?;
+/// Outcome of the verification of a signature
+struct VerifiedSignature {
+    header_canonicalization_type: canonicalization::Type,
+    body_canonicalization_type: canonicalization::Type,
+    key_size: usize,
+    commitment: Commitment,
+    /// Range of the body not covered by the body length limit
+    unsigned_body: Option<Range<usize>>,
+}
+
+/// Hashes of a signature computed by the verifier
+pub(crate) struct SignatureHashes {
+    pub(crate) header_canonicalization_type: canonicalization::Type,
+    pub(crate) body_canonicalization_type: canonicalization::Type,
+    pub(crate) hash_algo: hash::HashAlgo,
+    /// Base64 encoded, to compare with the `bh=` tag
+    pub(crate) body_hash: String,
+    pub(crate) headers_hash: Vec<u8>,
+}
 
+/// Compute the body hash and the hash of the signed headers of a signature,
+/// see [verify_dkim_header]
+pub(crate) fn compute_signature_hashes(
+    logger: &slog::Logger,
+    dkim_header: &DKIMHeader,
+    email: &mailparse::ParsedMail,
+    body_hashes: Option<&BodyHashes>,
+    memory_limit: Option<usize>,
+) -> Result<SignatureHashes, DKIMError> {
     let (header_canonicalization_type, body_canonicalization_type) =
         parser::parse_canonicalization(dkim_header.get_tag("c"))?;
-    let hash_algo = parser::parse_hash_algo(&dkim_header.get_required_tag("a"))?;
-    let computed_body_hash = hash::compute_body_hash(
-        body_canonicalization_type.clone(),
-        dkim_header.get_tag("l"),
-        hash_algo.clone(),
-        email,
-    )?;
-    let computed_headers_hash = hash::compute_headers_hash(
-        logger,
-        header_canonicalization_type.clone(),
-        &dkim_header.get_required_tag("h"),
-        hash_algo.clone(),
-        dkim_header,
-        email,
-    )?;
+    let hash_algo = parser::parse_hash_algo(dkim_header.get_required_tag("a"))?;
+    let body_hash = instrument::timed(Phase::BodyHash, || match body_hashes {
+        Some(body_hashes) => body_hashes.get(
+            body_canonicalization_type.clone(),
+            hash_algo.clone(),
+            dkim_header.get_tag("l"),
+        ),
+        None if memory_limit.is_some() => hash::compute_email_body_hash_bounded(
+            body_canonicalization_type.clone(),
+            dkim_header.get_tag("l"),
+            hash_algo.clone(),
+            email,
+        ),
+        None => hash::compute_email_body_hash(
+            body_canonicalization_type.clone(),
+            dkim_header.get_tag("l"),
+            hash_algo.clone(),
+            email,
+        ),
+    })?;
+    let headers_hash = instrument::timed(Phase::HeaderHash, || {
+        hash::compute_headers_hash_with_limit(
+            logger,
+            header_canonicalization_type.clone(),
+            dkim_header.get_required_tag("h"),
+            hash_algo.clone(),
+            dkim_header,
+            email,
+            memory_limit,
+        )
+    })?;
+    Ok(SignatureHashes {
+        header_canonicalization_type,
+        body_canonicalization_type,
+        hash_algo,
+        body_hash,
+        headers_hash,
+    })
+}
+
+/// Decode the signature, the `b=` tag
+pub(crate) fn decode_signature(dkim_header: &DKIMHeader) -> Result<Vec<u8>, DKIMError> {
+    bytes::decode_base64(dkim_header.get_required_tag("b")).map_err(|err| {
+        DKIMError::SignatureSyntaxError {
+            tag: Some("b"),
+            value: dkim_header.get_required_tag("b").to_owned(),
+            source: Some(ErrorSource::new(err)),
+        }
+    })
+}
+
+/// Verify a single DKIM signature of the email with the provided public key.
+/// When body hashes are provided, the body of the email isn't processed. With
+/// a memory limit the hashes are computed with bounded memory, see
+/// [VerificationPolicy::with_memory_limit].
+fn verify_dkim_header<'a>(
+    logger: &'a slog::Logger,
+    dkim_header: &'a DKIMHeader<'a>,
+    email: &'a mailparse::ParsedMail<'a>,
+    public_key: &DkimPublicKey,
+    body_hashes: Option<&BodyHashes>,
+    memory_limit: Option<usize>,
+) -> Result<VerifiedSignature, DKIMError> {
+    let SignatureHashes {
+        header_canonicalization_type,
+        body_canonicalization_type,
+        hash_algo,
+        body_hash: computed_body_hash,
+        headers_hash: computed_headers_hash,
+    } = compute_signature_hashes(logger, dkim_header, email, body_hashes, memory_limit)?;
     debug!(logger, "body_hash {:?}", computed_body_hash);
 
     let header_body_hash = dkim_header.get_required_tag("bh");
@@ -274,16 +630,121 @@ async fn verify_email_header<'a>(
         return Err(DKIMError::BodyHashDidNotVerify);
     }
 
-    let signature = general_purpose::STANDARD
-        .decode(dkim_header.get_required_tag("b"))
-        .map_err(|err| {
-            DKIMError::SignatureSyntaxError(format!("failed to decode signature: {}", err))
-        })?;
-    if !verify_signature(hash_algo, computed_headers_hash, signature, public_key)? {
+    let key_size = public_key.key_size();
+    let signature = decode_signature(dkim_header)?;
+    let verified = instrument::timed(Phase::Crypto, || {
+        verify_signature(hash_algo, &computed_headers_hash, &signature, public_key)
+    })?;
+    if !verified {
         return Err(DKIMError::SignatureDidNotVerify);
     }
 
-    Ok((header_canonicalization_type, body_canonicalization_type))
+    let commitment = Commitment::new(
+        dkim_header.get_required_tag("d"),
+        dkim_header.get_required_tag("s"),
+        computed_headers_hash,
+        bytes::decode_base64(&computed_body_hash).map_err(|err| {
+            DKIMError::SignatureSyntaxError {
+                tag: Some("bh"),
+                value: computed_body_hash.clone(),
+                source: Some(ErrorSource::new(err)),
+            }
+        })?,
+    );
+    let unsigned_body = match (dkim_header.get_tag("l"), body_hashes) {
+        (Some(length), None) => {
+            let body = bytes::get_all_after(email.raw_bytes, b"\r\n\r\n");
+            let end = hash::signed_body_end(
+                body,
+                body_canonicalization_type.clone(),
+                hash::parse_length(length)?,
+            );
+            (end < body.len()).then_some(end..body.len())
+        }
+        _ => None,
+    };
+    Ok(VerifiedSignature {
+        header_canonicalization_type,
+        body_canonicalization_type,
+        key_size,
+        commitment,
+        unsigned_body,
+    })
+}
+
+/// Verify a single DKIM signature of the email with the provided public key,
+/// without selecting the signature nor applying a policy. The header should
+/// be validated first with [validate_header]. Returns a `pass` result or the
+/// reason of the failure.
+/// When body hashes are provided, the body of the email isn't processed.
+pub fn verify_email_header_with_key<'a>(
+    logger: &'a slog::Logger,
+    dkim_header: &'a DKIMHeader<'a>,
+    email: &'a mailparse::ParsedMail<'a>,
+    public_key: &DkimPublicKey,
+    body_hashes: Option<&BodyHashes>,
+) -> Result<DKIMResult, DKIMError> {
+    let verified = verify_dkim_header(logger, dkim_header, email, public_key, body_hashes, None)?;
+
+    Ok(DKIMResult::pass(
+        dkim_header.get_required_tag("d").to_owned(),
+        verified.header_canonicalization_type,
+        verified.body_canonicalization_type,
+    )
+    .with_signature(
+        dkim_header.get_required_tag("s"),
+        dkim_header.get_required_tag("a"),
+    )
+    .with_key_size(verified.key_size)
+    .with_commitment(verified.commitment)
+    .with_unsigned_body(verified.unsigned_body))
+}
+
+/// Verify the header signature of a DKIM signature given a trusted body hash
+/// (base64 encoded, as in the `bh=` tag), for instance one proven separately
+/// with [verify_body_hash]. The body of the email isn't processed, it may
+/// contain only the headers. The header should be validated first with
+/// [validate_header].
+pub fn verify_email_header_with_body_hash<'a>(
+    logger: &'a slog::Logger,
+    dkim_header: &'a DKIMHeader<'a>,
+    email: &'a mailparse::ParsedMail<'a>,
+    public_key: &DkimPublicKey,
+    body_hash: &str,
+) -> Result<DKIMResult, DKIMError> {
+    let (_, body_canonicalization_type) = parser::parse_canonicalization(dkim_header.get_tag("c"))?;
+    let hash_algo = parser::parse_hash_algo(dkim_header.get_required_tag("a"))?;
+    let length = dkim_header
+        .get_tag("l")
+        .map(hash::parse_length)
+        .transpose()?;
+
+    let mut body_hashes = BodyHashes::new();
+    body_hashes.insert(
+        body_canonicalization_type,
+        hash_algo,
+        length,
+        body_hash.to_owned(),
+    );
+    verify_email_header_with_key(logger, dkim_header, email, public_key, Some(&body_hashes))
+}
+
+/// Verify that `body`, the raw body of a message, matches the body hash of a
+/// DKIM signature. Together with [verify_email_header_with_body_hash] it
+/// splits the verification of a signature in two. Returns the body hash.
+pub fn verify_body_hash(dkim_header: &DKIMHeader, body: &[u8]) -> Result<String, DKIMError> {
+    let (_, body_canonicalization_type) = parser::parse_canonicalization(dkim_header.get_tag("c"))?;
+    let hash_algo = parser::parse_hash_algo(dkim_header.get_required_tag("a"))?;
+    let length = dkim_header
+        .get_tag("l")
+        .map(hash::parse_length)
+        .transpose()?;
+
+    let body_hash = compute_body_hash(body, body_canonicalization_type, hash_algo, length);
+    if body_hash != dkim_header.get_required_tag("bh") {
+        return Err(DKIMError::BodyHashDidNotVerify);
+    }
+    Ok(body_hash)
 }
 
 /// Run the DKIM verification on the email providing an existing resolver
@@ -294,48 +755,71 @@ pub async fn verify_email_with_resolver<'a>(
     email: &'a mailparse::ParsedMail<'a>,
     resolver: Arc<dyn dns::Lookup>,
 ) -> Result<DKIMResult, DKIMError> {
-    let mut last_error = None;
-
-    for h in email.headers.get_all_headers(HEADER) {
-        let value = String::from_utf8_lossy(h.get_value_raw());
-        debug!(logger, "checking signature {:?}", value);
+    verify_email_with_resolver_and_policy(
+        logger,
+        from_domain,
+        email,
+        resolver,
+        &VerificationPolicy::default(),
+    )
+    .await
+}
 
-        let dkim_header = match validate_header(&value) {
-            Ok(v) => v,
-            Err(err) => {
-                debug!(logger, "failed to verify: {}", err);
-                last_error = Some(err);
-                continue;
-            }
-        };
+/// Run the DKIM verification on the email providing an existing resolver and
+/// a verification policy
+#[cfg(feature = "dns")]
+pub async fn verify_email_with_resolver_and_policy<'a>(
+    logger: &slog::Logger,
+    from_domain: &str,
+    email: &'a mailparse::ParsedMail<'a>,
+    resolver: Arc<dyn dns::Lookup>,
+    policy: &VerificationPolicy,
+) -> Result<DKIMResult, DKIMError> {
+    verify_email_signatures(logger, from_domain, email, resolver, policy, None).await
+}
 
-        // Select the signature corresponding to the email sender
-        let signing_domain = dkim_header.get_required_tag("d");
-        if signing_domain.to_lowercase() != from_domain.to_lowercase() {
-            continue;
-        }
-
-        match verify_email_header(logger, Arc::clone(&resolver), &dkim_header, email).await {
-            Ok((header_canonicalization_type, body_canonicalization_type)) => {
-                return Ok(DKIMResult::pass(
-                    signing_domain,
-                    header_canonicalization_type,
-                    body_canonicalization_type,
-                ))
-            }
-            Err(err) => {
-                debug!(logger, "failed to verify: {}", err);
-                last_error = Some(err);
-                continue;
-            }
-        }
-    }
+/// Run the DKIM verification on the email providing an existing resolver and
+/// the hashes of the body computed ahead of time. The body of the email isn't
+/// processed, which allows verifying an email made only of its headers.
+#[cfg(feature = "dns")]
+pub async fn verify_email_with_body_hashes<'a>(
+    logger: &slog::Logger,
+    from_domain: &str,
+    email: &'a mailparse::ParsedMail<'a>,
+    resolver: Arc<dyn dns::Lookup>,
+    policy: &VerificationPolicy,
+    body_hashes: &BodyHashes,
+) -> Result<DKIMResult, DKIMError> {
+    verify_email_signatures(
+        logger,
+        from_domain,
+        email,
+        resolver,
+        policy,
+        Some(body_hashes),
+    )
+    .await
+}
 
-    if let Some(err) = last_error {
-        Ok(DKIMResult::fail(err, from_domain.to_owned()))
-    } else {
-        Ok(DKIMResult::neutral(from_domain.to_owned()))
-    }
+#[cfg(feature = "dns")]
+async fn verify_email_signatures<'a>(
+    logger: &slog::Logger,
+    from_domain: &str,
+    email: &'a mailparse::ParsedMail<'a>,
+    resolver: Arc<dyn dns::Lookup>,
+    policy: &VerificationPolicy,
+    body_hashes: Option<&BodyHashes>,
+) -> Result<DKIMResult, DKIMError> {
+    let results = verify_signatures_with_resolver(
+        logger,
+        SignatureScope::Author(from_domain),
+        email,
+        resolver,
+        policy,
+        body_hashes,
+    )
+    .await;
+    Ok(author_result(policy, from_domain, results))
 }
 
 /// Run the DKIM verification on the email
@@ -353,135 +837,540 @@ pub async fn verify_email<'a>(
     verify_email_with_resolver(logger, from_domain, email, resolver).await
 }
 
+/// Run the DKIM verification on an archived email as of `time`, a Unix
+/// timestamp: the keys are looked up in the snapshot as published at that
+/// time, and the expiration (`x=`) and timestamp (`t=`) of the signatures
+/// are evaluated against it.
+#[cfg(feature = "dns")]
+pub async fn verify_email_as_of<'a>(
+    logger: &slog::Logger,
+    from_domain: &str,
+    email: &'a mailparse::ParsedMail<'a>,
+    snapshot: &dns::Snapshot,
+    time: i64,
+) -> Result<DKIMResult, DKIMError> {
+    verify_email_with_resolver_and_policy(
+        logger,
+        from_domain,
+        email,
+        snapshot.at(time),
+        &VerificationPolicy::default().with_verification_time(time),
+    )
+    .await
+}
+
 pub fn verify_email_with_key<'a>(
     logger: &slog::Logger,
     from_domain: &str,
     email: &'a mailparse::ParsedMail<'a>,
     public_key: DkimPublicKey,
 ) -> Result<DKIMResult, DKIMError> {
-    let mut last_error = None;
-
-    for h in email.headers.get_all_headers(HEADER) {
-        let value = String::from_utf8_lossy(h.get_value_raw());
-        debug!(logger, "checking signature {:?}", value);
-
-        let dkim_header = match validate_header(&value) {
-            Ok(v) => v,
-            Err(err) => {
-                debug!(logger, "failed to verify: {}", err);
-                last_error = Some(err);
-                continue;
-            }
-        };
-
-        // select the signature corresponding to the email sender
-        let signing_domain = dkim_header.get_required_tag("d");
-        if signing_domain.to_lowercase() != from_domain.to_lowercase() {
-            // CHECK!
-            continue;
-        }
-
-        let (header_canon_type, body_canon_type) =
-            parser::parse_canonicalization(dkim_header.get_tag("c"))?;
-        let hash_algo = parser::parse_hash_algo(&dkim_header.get_required_tag("a"))?;
-
-        let computed_body_hash = hash::compute_body_hash(
-            body_canon_type.clone(),
-            dkim_header.get_tag("l"),
-            hash_algo.clone(),
-            email,
-        )?;
-
-        let computed_header_hash = hash::compute_headers_hash(
-            logger,
-            header_canon_type.clone(),
-            &dkim_header.get_required_tag("h"),
-            hash_algo.clone(),
-            &dkim_header,
-            email,
-        )?;
-
-        debug!(logger, "body_hash {:?}", computed_body_hash);
+    verify_email_with_key_and_policy(
+        logger,
+        from_domain,
+        email,
+        public_key,
+        &VerificationPolicy::default(),
+    )
+}
 
-        let header_body_hash = dkim_header.get_required_tag("bh");
+/// Run the DKIM verification on the email with a provided public key and a
+/// verification policy
+pub fn verify_email_with_key_and_policy<'a>(
+    logger: &slog::Logger,
+    from_domain: &str,
+    email: &'a mailparse::ParsedMail<'a>,
+    public_key: DkimPublicKey,
+    policy: &VerificationPolicy,
+) -> Result<DKIMResult, DKIMError> {
+    verify_email_signatures_with_key(logger, from_domain, email, public_key, policy, None)
+}
 
-        if header_body_hash != computed_body_hash {
-            return Err(DKIMError::BodyHashDidNotVerify);
-        }
+/// Run the DKIM verification on the email with a provided public key and the
+/// hashes of the body computed ahead of time. The body of the email isn't
+/// processed, which allows verifying an email made only of its headers.
+pub fn verify_email_with_key_and_body_hashes<'a>(
+    logger: &slog::Logger,
+    from_domain: &str,
+    email: &'a mailparse::ParsedMail<'a>,
+    public_key: DkimPublicKey,
+    policy: &VerificationPolicy,
+    body_hashes: &BodyHashes,
+) -> Result<DKIMResult, DKIMError> {
+    verify_email_signatures_with_key(
+        logger,
+        from_domain,
+        email,
+        public_key,
+        policy,
+        Some(body_hashes),
+    )
+}
 
-        let signature = general_purpose::STANDARD
-            .decode(dkim_header.get_required_tag("b"))
-            .map_err(|err| {
-                DKIMError::SignatureSyntaxError(format!("failed to decode signature: {}", err))
-            })?;
+/// Result of the verification when the policy requires all the signatures to
+/// be verified: the selected passing signature if the requirements are met
+fn passing_signatures_result(
+    policy: &VerificationPolicy,
+    from_domain: &str,
+    passed: Vec<DKIMResult>,
+) -> DKIMResult {
+    let algorithms: Vec<&str> = passed
+        .iter()
+        .filter_map(|result| result.algorithm())
+        .collect();
+    if let Err(err) = policy.check_passing_signatures(&algorithms) {
+        return DKIMResult::fail(err, from_domain.to_owned());
+    }
+    match policy.signature_selection() {
+        SignatureSelection::First => passed.into_iter().next().expect("a signature passed"),
+        SignatureSelection::Best => select_best_signature(passed),
+    }
+}
 
-        if !verify_signature(hash_algo, computed_header_hash, signature, public_key)? {
-            return Err(DKIMError::SignatureDidNotVerify);
+/// Select the best passing signature, see [SignatureSelection::Best]
+fn select_best_signature(passed: Vec<DKIMResult>) -> DKIMResult {
+    fn algorithm_strength(result: &DKIMResult) -> u8 {
+        match result.algorithm() {
+            Some("ed25519-sha256") => 2,
+            Some("rsa-sha256") => 1,
+            _ => 0,
         }
-
-        return Ok(DKIMResult::pass(
-            signing_domain,
-            header_canon_type,
-            body_canon_type,
-        ));
+    }
+    // The newest signature has the smallest age, signatures without
+    // timestamp come last
+    fn newness(result: &DKIMResult) -> i64 {
+        result.signature_age().map(|age| -age).unwrap_or(i64::MIN)
+    }
+    fn key_size(result: &DKIMResult) -> usize {
+        result.key_size().unwrap_or(0)
+    }
+    fn rank(result: &DKIMResult) -> (u8, i64, usize) {
+        (
+            algorithm_strength(result),
+            newness(result),
+            key_size(result),
+        )
     }
 
-    if let Some(err) = last_error {
-        Ok(DKIMResult::fail(err, from_domain.to_owned()))
-    } else {
-        Ok(DKIMResult::neutral(from_domain.to_owned()))
+    let mut best = 0;
+    for (i, result) in passed.iter().enumerate().skip(1) {
+        if rank(result) > rank(&passed[best]) {
+            best = i;
+        }
     }
+    let chosen = &passed[best];
+    // The runner-up is the best of the other signatures, the reason is the
+    // first criterion telling them apart
+    let runner_up = passed
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| *i != best)
+        .map(|(_, result)| result)
+        .max_by_key(|result| rank(result));
+    let reason = match runner_up {
+        None => "only passing signature".to_owned(),
+        Some(other) if algorithm_strength(chosen) != algorithm_strength(other) => format!(
+            "stronger algorithm ({})",
+            chosen.algorithm().unwrap_or_default()
+        ),
+        Some(other) if newness(chosen) != newness(other) => "newest signature".to_owned(),
+        Some(other) if key_size(chosen) != key_size(other) => {
+            format!("largest key ({} bits)", key_size(chosen))
+        }
+        Some(_) => "first of equivalent signatures".to_owned(),
+    };
+    passed
+        .into_iter()
+        .nth(best)
+        .expect("a signature passed")
+        .with_selection_reason(reason)
 }
 
-/// Run the DKIM verification on the email with a provided public key when DNS feature is disabled
-#[cfg(not(feature = "dns"))]
-pub fn verify_email<'a>(
+fn verify_email_signatures_with_key<'a>(
     logger: &slog::Logger,
     from_domain: &str,
     email: &'a mailparse::ParsedMail<'a>,
     public_key: DkimPublicKey,
+    policy: &VerificationPolicy,
+    body_hashes: Option<&BodyHashes>,
 ) -> Result<DKIMResult, DKIMError> {
-    verify_email_with_key(logger, from_domain, email, public_key)
+    let results = verify_signatures_with_key(
+        logger,
+        SignatureScope::Author(from_domain),
+        email,
+        &public_key,
+        policy,
+        body_hashes,
+    );
+    Ok(author_result(policy, from_domain, results))
 }
 
-#[cfg(test)]
-mod tests {
-    use pkcs1::DecodeRsaPublicKey;
-
-    use crate::dns::Lookup;
+/// Signatures of a message verified by [verify_signatures_with_key] and
+/// [verify_signatures_with_resolver]
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum SignatureScope<'a> {
+    /// The signatures of the author domain (From header) accepted by the
+    /// policy, up to the first one which passes unless the policy requires
+    /// several signatures. Failures are reported for the author domain.
+    Author(&'a str),
+    /// Every signature attributable to a signing domain, whatever the
+    /// domain, for instance for a DMARC evaluation
+    All,
+}
 
-    use super::*;
+impl SignatureScope<'_> {
+    /// Domain of the failure of a signature, when it has a signing domain
+    fn failure_domain(&self, signing_domain: Option<&str>) -> Option<String> {
+        match self {
+            SignatureScope::Author(from_domain) => Some((*from_domain).to_owned()),
+            SignatureScope::All => signing_domain
+                .filter(|domain| !domain.is_empty())
+                .map(normalize_domain),
+        }
+    }
 
-    struct MockResolver {}
-
-    impl Lookup for MockResolver {
-        fn lookup_txt<'a>(
-            &'a self,
-            name: &'a str,
-        ) -> futures::future::BoxFuture<'a, Result<Vec<String>, DKIMError>> {
-            match name {
-                "brisbane._domainkey.football.example.com" => {
-                    Box::pin(futures::future::ready(Ok(vec![
-                        "v=DKIM1; k=ed25519; p=11qYAYKxCrfVS/7TyWQHOg7hcvPapiMlrwIaaPcHURo="
-                            .to_string(),
-                    ])))
-                }
-                "newengland._domainkey.example.com" => Box::pin(futures::future::ready(Ok(vec![
-                    "v=DKIM1; p=MIGJAoGBALVI635dLK4cJJAH3Lx6upo3X/Lm1tQz3mezcWTA3BUBnyIsdnRf57aD5BtNmhPrYYDlWlzw3UgnKisIxktkk5+iMQMlFtAS10JB8L3YadXNJY+JBcbeSi5TgJe4WFzNgW95FWDAuSTRXSWZfA/8xjflbTLDx0euFZOM7C4T0GwLAgMBAAE=".to_string(),
-                ]))),
-                _ => {
-                    println!("asked to resolve: {}", name);
-                    todo!()
+    /// Domain of the passing signature of `signing_domain`
+    fn pass_domain(&self, signing_domain: &str) -> String {
+        match self {
+            SignatureScope::Author(_) => signing_domain.to_owned(),
+            SignatureScope::All => normalize_domain(signing_domain),
+        }
+    }
+
+    fn stops_at_pass(&self, policy: &VerificationPolicy) -> bool {
+        matches!(self, SignatureScope::Author(_)) && !policy.requires_several_signatures()
+    }
+}
+
+/// Signature of a message, validated and accepted by the policy
+struct SignatureToVerify<'a> {
+    index: usize,
+    value: &'a str,
+    dkim_header: DKIMHeader<'a>,
+    warnings: Vec<DKIMError>,
+}
+
+/// Parse the signature at `index` and check it against the policy. Returns
+/// the signature to verify, the result of its failure, or nothing if it's
+/// out of the scope.
+fn prepare_signature<'a>(
+    logger: &slog::Logger,
+    scope: SignatureScope,
+    index: usize,
+    header: &'a mailparse::MailHeader<'a>,
+    policy: &VerificationPolicy,
+) -> Option<Result<SignatureToVerify<'a>, DKIMResult>> {
+    let value = match signature_value(header) {
+        Ok(v) => v,
+        Err(err) => {
+            debug!(logger, "failed to verify: {}", err);
+            return Some(Err(DKIMResult::fail(err, scope.failure_domain(None)?)));
+        }
+    };
+    debug!(logger, "checking signature {:?}", value);
+
+    let dkim_header = match validate_header_with_policy(value, policy) {
+        Ok(v) => v,
+        Err(err) => {
+            debug!(logger, "failed to verify: {}", err);
+            // The tags of an invalid signature may still designate its domain
+            let tags = parser::tag_list_ref(value).map(|(_, tags)| tags);
+            let tag = |name: &str| {
+                tags.as_ref().ok().and_then(|tags| {
+                    tags.iter()
+                        .find(|tag| tag.name == name)
+                        .map(|tag| tag.value.to_string())
+                })
+            };
+            let result = DKIMResult::fail(err, scope.failure_domain(tag("d").as_deref())?);
+            let result = match scope {
+                SignatureScope::Author(_) => result,
+                SignatureScope::All => result
+                    .with_signature(&tag("s").unwrap_or_default(), &tag("a").unwrap_or_default()),
+            };
+            return Some(Err(result.with_signature_header(index, value)));
+        }
+    };
+
+    let signing_domain = dkim_header.get_required_tag("d");
+    if let SignatureScope::Author(from_domain) = scope {
+        // Select the signature corresponding to the email sender
+        if !policy.accepts_signing_domain(signing_domain, from_domain) {
+            return None;
+        }
+    }
+    let selector = dkim_header.get_required_tag("s");
+    let observer = policy.observer();
+    if let Some(observer) = observer {
+        observer.signature_started(signing_domain, selector);
+    }
+
+    let warnings = match policy.check(&dkim_header) {
+        Ok(v) => v,
+        Err(err) => {
+            debug!(logger, "rejected by policy: {}", err);
+            if let Some(observer) = observer {
+                observer.signature_failed(signing_domain, selector, &err);
+            }
+            return Some(Err(signature_failure(
+                scope,
+                err,
+                &dkim_header,
+                index,
+                value,
+            )));
+        }
+    };
+    Some(Ok(SignatureToVerify {
+        index,
+        value,
+        dkim_header,
+        warnings,
+    }))
+}
+
+fn signature_failure(
+    scope: SignatureScope,
+    err: DKIMError,
+    dkim_header: &DKIMHeader,
+    index: usize,
+    value: &str,
+) -> DKIMResult {
+    let domain = scope
+        .failure_domain(Some(dkim_header.get_required_tag("d")))
+        .expect("the signature has a signing domain");
+    DKIMResult::fail(err, domain)
+        .with_signature(
+            dkim_header.get_required_tag("s"),
+            dkim_header.get_required_tag("a"),
+        )
+        .with_signature_header(index, value)
+}
+
+impl SignatureToVerify<'_> {
+    /// Result of the signature, given the outcome of its verification with
+    /// the key record, if it could be retrieved
+    fn result(
+        self,
+        logger: &slog::Logger,
+        scope: SignatureScope,
+        email: &mailparse::ParsedMail,
+        policy: &VerificationPolicy,
+        record: Option<&KeyRecord>,
+        verified: Result<VerifiedSignature, DKIMError>,
+    ) -> DKIMResult {
+        let signing_domain = self.dkim_header.get_required_tag("d");
+        let selector = self.dkim_header.get_required_tag("s");
+        let observer = policy.observer();
+        let verified = match verified {
+            Ok(verified) => verified,
+            Err(err) => {
+                debug!(logger, "failed to verify: {}", err);
+                if let Some(observer) = observer {
+                    observer.signature_failed(signing_domain, selector, &err);
                 }
+                let result =
+                    signature_failure(scope, err, &self.dkim_header, self.index, self.value);
+                return match record {
+                    Some(record) => result.with_key_record(record.testing, record.notes.clone()),
+                    None => result,
+                };
             }
+        };
+        let record = record.expect("a verified signature has a key record");
+        if let Some(observer) = observer {
+            observer.signature_passed(signing_domain, selector);
         }
+
+        DKIMResult::pass(
+            scope.pass_domain(signing_domain),
+            verified.header_canonicalization_type,
+            verified.body_canonicalization_type,
+        )
+        .with_warnings(self.warnings)
+        .with_signature_age(policy.signature_age(&self.dkim_header))
+        .with_signature(selector, self.dkim_header.get_required_tag("a"))
+        .with_signature_header(self.index, self.value)
+        .with_key_size(verified.key_size)
+        .with_identity_match(policy.identity_match(&self.dkim_header, email))
+        .with_commitment(verified.commitment)
+        .with_unsigned_body(verified.unsigned_body)
+        .with_key_record(record.testing, record.notes.clone())
     }
+}
+
+/// Verify the signatures of `scope` with a provided public key. Returns the
+/// result of each signature, in the order of their headers.
+pub(crate) fn verify_signatures_with_key<'a>(
+    logger: &slog::Logger,
+    scope: SignatureScope,
+    email: &'a mailparse::ParsedMail<'a>,
+    public_key: &DkimPublicKey,
+    policy: &VerificationPolicy,
+    body_hashes: Option<&BodyHashes>,
+) -> Vec<DKIMResult> {
+    let record = KeyRecord::new(public_key.clone());
+    verify_signatures_with_records(logger, scope, email, policy, body_hashes, |_| {
+        Ok(Cow::Borrowed(&record))
+    })
+}
+
+/// Same as [verify_signatures_with_key], the key record of each signature
+/// being provided by `key_record`
+pub(crate) fn verify_signatures_with_records<'a, 'k>(
+    logger: &slog::Logger,
+    scope: SignatureScope,
+    email: &'a mailparse::ParsedMail<'a>,
+    policy: &VerificationPolicy,
+    body_hashes: Option<&BodyHashes>,
+    key_record: impl Fn(&DKIMHeader) -> Result<Cow<'k, KeyRecord>, DKIMError>,
+) -> Vec<DKIMResult> {
+    verify_signatures(logger, scope, email, policy, body_hashes, |header| {
+        std::future::ready(key_record(header))
+    })
+    .now_or_never()
+    .expect("the key records are provided synchronously")
+}
+
+/// Same as [verify_signatures_with_key], retrieving the keys with `resolver`
+#[cfg(feature = "dns")]
+pub(crate) async fn verify_signatures_with_resolver<'a>(
+    logger: &slog::Logger,
+    scope: SignatureScope<'_>,
+    email: &'a mailparse::ParsedMail<'a>,
+    resolver: Arc<dyn dns::Lookup>,
+    policy: &VerificationPolicy,
+    body_hashes: Option<&BodyHashes>,
+) -> Vec<DKIMResult> {
+    let resolver = match policy.observer() {
+        Some(observer) => dns::observed(resolver, Arc::clone(observer)),
+        None => resolver,
+    };
+    verify_signatures(logger, scope, email, policy, body_hashes, |header| {
+        let record = public_key::retrieve_key_record(
+            logger,
+            Arc::clone(&resolver),
+            header.get_required_tag("d").to_owned(),
+            header.get_required_tag("s").to_owned(),
+        );
+        async move {
+            instrument::timed_async(Phase::Dns, record)
+                .await
+                .map(Cow::Owned)
+        }
+    })
+    .await
+}
 
-    impl MockResolver {
-        fn new() -> Self {
-            MockResolver {}
+/// Verify the signatures of `scope`, the key record of each signature being
+/// retrieved by `key_record`. Returns the result of each signature, in the
+/// order of their headers.
+async fn verify_signatures<'a, 'k, F, R>(
+    logger: &slog::Logger,
+    scope: SignatureScope<'_>,
+    email: &'a mailparse::ParsedMail<'a>,
+    policy: &VerificationPolicy,
+    body_hashes: Option<&BodyHashes>,
+    key_record: F,
+) -> Vec<DKIMResult>
+where
+    F: Fn(&DKIMHeader) -> R,
+    R: Future<Output = Result<Cow<'k, KeyRecord>, DKIMError>>,
+{
+    let policy = policy.for_email(email);
+    let policy = policy.as_ref();
+    let mut results = vec![];
+
+    for (index, header) in email
+        .headers
+        .get_all_headers(HEADER)
+        .into_iter()
+        .enumerate()
+    {
+        let signature = match prepare_signature(logger, scope, index, header, policy) {
+            Some(Ok(signature)) => signature,
+            Some(Err(result)) => {
+                results.push(result);
+                continue;
+            }
+            None => continue,
+        };
+        let dkim_header = &signature.dkim_header;
+        let span = SignatureSpan::new(
+            dkim_header.get_required_tag("d"),
+            dkim_header.get_required_tag("s"),
+            dkim_header.get_required_tag("a"),
+        );
+        let (record, verified) = span
+            .instrument(async {
+                let record = match key_record(dkim_header).await {
+                    Ok(record) => record,
+                    Err(err) => return (None, Err(err)),
+                };
+                let verified = verify_dkim_header(
+                    logger,
+                    dkim_header,
+                    email,
+                    &record.key,
+                    body_hashes,
+                    policy.memory_limit(),
+                );
+                (Some(record), verified)
+            })
+            .await;
+        span.record_result(&verified);
+        let result = signature.result(logger, scope, email, policy, record.as_deref(), verified);
+        let done = result.verdict() == Verdict::Pass && scope.stops_at_pass(policy);
+        results.push(result);
+        if done {
+            break;
         }
     }
+    results
+}
+
+/// Result of the verification for the author domain, given the results of
+/// its signatures: the selected passing signature, or else the failure of the
+/// last signature
+pub(crate) fn author_result(
+    policy: &VerificationPolicy,
+    from_domain: &str,
+    mut results: Vec<DKIMResult>,
+) -> DKIMResult {
+    let passed: Vec<DKIMResult> = results
+        .iter()
+        .filter(|result| result.verdict() == Verdict::Pass)
+        .cloned()
+        .collect();
+    if !passed.is_empty() {
+        return passing_signatures_result(policy, from_domain, passed);
+    }
+    results
+        .pop()
+        .unwrap_or_else(|| DKIMResult::neutral(from_domain.to_owned()))
+}
+
+/// Run the DKIM verification on the email with a provided public key when DNS feature is disabled
+#[cfg(not(feature = "dns"))]
+pub fn verify_email<'a>(
+    logger: &slog::Logger,
+    from_domain: &str,
+    email: &'a mailparse::ParsedMail<'a>,
+    public_key: DkimPublicKey,
+) -> Result<DKIMResult, DKIMError> {
+    verify_email_with_key(logger, from_domain, email, public_key)
+}
+
+#[cfg(test)]
+mod tests {
+    use base64::engine::general_purpose;
+    use base64::Engine;
+    use pkcs1::DecodeRsaPublicKey;
+
+    use crate::dns::Lookup;
+    use crate::test_utils::MockResolver;
+
+    use super::*;
 
     #[test]
     fn test_validate_header() {
@@ -513,7 +1402,32 @@ b=dzdVyOfAKCdLXdJOc9G2q8LoXSlEniSbav+yuU4zGeeruD00lszZ
         "#;
         assert_eq!(
             validate_header(header).unwrap_err(),
-            DKIMError::DomainMismatch
+            DKIMError::DomainMismatch {
+                domain: "example.net".to_owned(),
+                identity: "hein.com".to_owned()
+            }
+        );
+    }
+
+    #[test]
+    fn test_validate_header_internationalized_domain() {
+        let header = |d: &str, i: &str| {
+            format!("v=1; a=rsa-sha256; d={d}; s=s; i={i}; h=from; bh=hash; b=hash")
+        };
+        validate_header(&header("bücher.example", "jöe@bücher.example")).unwrap();
+        validate_header(&header("bücher.example", "@eng.xn--bcher-kva.example")).unwrap();
+        // The identity is dkim-quoted-printable
+        validate_header(&header("example.com", "joe=40example=2Ecom")).unwrap();
+        assert!(matches!(
+            validate_header(&header("example.com", "joe@example=2")).unwrap_err(),
+            DKIMError::SignatureSyntaxError { .. }
+        ));
+        assert_eq!(
+            validate_header(&header("example.com", "@badexample.com")).unwrap_err(),
+            DKIMError::DomainMismatch {
+                domain: "example.com".to_owned(),
+                identity: "badexample.com".to_owned()
+            }
         );
     }
 
@@ -554,12 +1468,58 @@ b=dzdVyOfAKCdLXdJOc9G2q8LoXSlEniSbav+yuU4zGeeruD00lszZ
 
         let header = format!("v=1; a=rsa-sha256; d=example.net; s=brisbane; i=foo@example.net; h=From:B; bh=hash; b=hash; x={}", now.timestamp());
 
-        assert_eq!(
+        assert!(matches!(
             validate_header(&header).unwrap_err(),
-            DKIMError::SignatureExpired
+            DKIMError::SignatureExpired { .. }
+        ));
+    }
+
+    #[test]
+    fn test_validate_header_with_verification_time() {
+        let header = "v=1; a=rsa-sha256; d=example.net; s=brisbane; h=From:B; bh=hash; b=hash; t=1000000000; x=1000003600";
+
+        let policy = VerificationPolicy::new().with_verification_time(1000001000);
+        assert!(validate_header_with_policy(header, &policy).is_ok());
+
+        let policy = VerificationPolicy::new().with_verification_time(1000010000);
+        assert_eq!(
+            validate_header_with_policy(header, &policy).unwrap_err(),
+            DKIMError::SignatureExpired {
+                expiration: 1000003600,
+                now: 1000010000
+            }
+        );
+    }
+
+    #[test]
+    fn test_validate_header_expiration_before_timestamp() {
+        let header = "v=1; a=rsa-sha256; d=example.net; s=brisbane; h=From:B; bh=hash; b=hash; t=1000000000; x=999999999";
+        let policy = VerificationPolicy::new().with_verification_time(1000000000);
+        assert_eq!(
+            validate_header_with_policy(header, &policy).unwrap_err(),
+            DKIMError::ExpirationBeforeTimestamp {
+                expiration: 999999999,
+                timestamp: 1000000000
+            }
         );
     }
 
+    #[test]
+    fn test_validate_header_invalid_expiration() {
+        let header =
+            "v=1; a=rsa-sha256; d=example.net; s=brisbane; h=From:B; bh=hash; b=hash; x=soon";
+        let policy = VerificationPolicy::new().with_verification_time(1000000000);
+        assert!(matches!(
+            validate_header_with_policy(header, &policy).unwrap_err(),
+            DKIMError::SignatureSyntaxError { .. }
+        ));
+
+        // "t=" is only checked against "x="
+        let header =
+            "v=1; a=rsa-sha256; d=example.net; s=brisbane; h=From:B; bh=hash; b=hash; t=1000000000";
+        assert!(validate_header_with_policy(header, &policy).is_ok());
+    }
+
     #[tokio::test]
     async fn test_validate_email_header_ed25519() {
         let raw_email = r#"DKIM-Signature: v=1; a=ed25519-sha256; c=relaxed/relaxed;
@@ -591,25 +1551,20 @@ Joe."#
             .replace('\n', "\r\n");
 
         let email = mailparse::parse_mail(raw_email.as_bytes()).unwrap();
-        let h = email
-            .headers
-            .get_all_headers(HEADER)
-            .first()
-            .unwrap()
-            .get_value_raw();
-        let raw_header_dkim = String::from_utf8_lossy(h);
-
         let resolver: Arc<dyn Lookup> = Arc::new(MockResolver::new());
 
-        let dkim_verify_result = verify_email_header(
+        let results = verify_signatures_with_resolver(
             &slog::Logger::root(slog::Discard, slog::o!()),
-            Arc::clone(&resolver),
-            &validate_header(&raw_header_dkim).unwrap(),
+            SignatureScope::All,
             &email,
+            resolver,
+            &VerificationPolicy::default(),
+            None,
         )
         .await;
 
-        assert!(dkim_verify_result.is_ok());
+        assert_eq!(results.len(), 2);
+        assert!(results.iter().all(|r| r.verdict() == Verdict::Pass));
     }
 
     #[tokio::test]
@@ -642,37 +1597,255 @@ Joe.
 "#
             .replace('\n', "\r\n");
         let email = mailparse::parse_mail(raw_email.as_bytes()).unwrap();
-        let h = email
-            .headers
-            .get_all_headers(HEADER)
-            .first()
-            .unwrap()
-            .get_value_raw();
-        let raw_header_rsa = String::from_utf8_lossy(h);
-
         let resolver: Arc<dyn Lookup> = Arc::new(MockResolver::new());
 
-        let dkim_verify_result = verify_email_header(
+        let results = verify_signatures_with_resolver(
             &slog::Logger::root(slog::Discard, slog::o!()),
-            Arc::clone(&resolver),
-            &validate_header(&raw_header_rsa).unwrap(),
+            SignatureScope::All,
             &email,
+            resolver,
+            &VerificationPolicy::default(),
+            None,
         )
         .await;
 
-        assert!(dkim_verify_result.is_ok());
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].verdict(), Verdict::Pass);
+    }
+
+    #[tokio::test]
+    async fn test_failed_signature_key_record() {
+        use crate::test_utils::{
+            ed25519_private_key, sign_message, StaticResolver, DOMAIN, ED25519_SELECTOR,
+            SAMPLE_MESSAGE,
+        };
+
+        let signed = sign_message(SAMPLE_MESSAGE, ed25519_private_key(), ED25519_SELECTOR);
+        let tampered = signed.replace("Are you hungry yet?", "Are you thirsty yet?");
+        let email = mailparse::parse_mail(tampered.as_bytes()).unwrap();
+        let record = format!(
+            "{}; t=y; n=rotated on 2024-01-01",
+            ed25519_private_key().to_public_key().to_key_record()
+        );
+        let resolver = StaticResolver::new().with_record(
+            &format!("{}._domainkey.{}", ED25519_SELECTOR, DOMAIN),
+            &record,
+        );
+
+        let results = verify_signatures_with_resolver(
+            &slog::Logger::root(slog::Discard, slog::o!()),
+            SignatureScope::All,
+            &email,
+            Arc::new(resolver),
+            &VerificationPolicy::default(),
+            None,
+        )
+        .await;
+
+        // The flags and notes of the record are reported whatever the result
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].error(), Some(DKIMError::BodyHashDidNotVerify));
+        assert!(results[0].is_testing());
+        assert_eq!(results[0].key_notes(), Some("rotated on 2024-01-01"));
     }
 
     #[test]
     fn test_invalid_key_type() {
         let result = DkimPublicKey::try_from_bytes(&[0u8; 32], "invalid");
-        assert!(matches!(result, Err(DKIMError::KeyUnavailable(_))));
+        assert_eq!(
+            result.unwrap_err(),
+            DKIMError::InappropriateKeyAlgorithm {
+                key_type: "invalid".to_owned()
+            }
+        );
+
+        // A malformed key is a permanent failure
+        let err = DkimPublicKey::try_from_bytes(&[0u8; 32], "rsa").unwrap_err();
+        assert!(matches!(err, DKIMError::KeySyntaxError { .. }), "{}", err);
+        assert!(!err.is_temporary());
     }
 
     #[test]
     fn test_invalid_ed25519_key() {
         let result = DkimPublicKey::try_from_bytes(&[0u8; 31], "ed25519");
-        assert!(matches!(result, Err(DKIMError::KeyUnavailable(_))));
+        assert_eq!(
+            result,
+            Err(DKIMError::InvalidKeyLength {
+                expected: 32,
+                actual: 31
+            })
+        );
+    }
+
+    #[cfg(feature = "encrypted-keys")]
+    #[test]
+    fn test_pkcs8_encrypted_keys() {
+        use crate::test_utils::{ed25519_private_key, rsa_private_key};
+
+        // Encrypted with scrypt and PBKDF2 respectively
+        for (path, expected) in [
+            ("./test/keys/2022.encrypted", rsa_private_key()),
+            ("./test/keys/ed.encrypted", ed25519_private_key()),
+        ] {
+            let pem = std::fs::read_to_string(path).unwrap();
+            let private_key = DkimPrivateKey::from_pkcs8_encrypted_pem(&pem, "dkim-test").unwrap();
+            assert_eq!(
+                private_key.to_public_key().to_key_record(),
+                expected.to_public_key().to_key_record()
+            );
+
+            let err = DkimPrivateKey::from_pkcs8_encrypted_pem(&pem, "wrong").unwrap_err();
+            assert!(
+                matches!(err, DKIMError::InvalidPrivateKey { .. }),
+                "{}",
+                err
+            );
+            assert!(!err.is_temporary());
+        }
+
+        let pem = std::fs::read_to_string("./test/keys/2022.private").unwrap();
+        assert!(DkimPrivateKey::from_pkcs8_encrypted_pem(&pem, "dkim-test").is_err());
+    }
+
+    #[test]
+    fn test_to_public_key() {
+        use rsa::pkcs1::DecodeRsaPrivateKey;
+
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let private_key = DkimPrivateKey::Rsa(
+            RsaPrivateKey::read_pkcs1_pem_file(std::path::Path::new("./test/keys/2022.private"))
+                .unwrap(),
+        );
+        let record = private_key.to_public_key().to_key_record();
+        let published: String = std::fs::read_to_string("./test/keys/2022.txt")
+            .unwrap()
+            .split('"')
+            .skip(1)
+            .step_by(2)
+            .collect();
+        assert!(published.ends_with(record.split("; ").last().unwrap()));
+        assert_eq!(
+            public_key::parse_public_key(&logger, &record).unwrap(),
+            private_key.to_public_key()
+        );
+
+        let secret_key: [u8; 32] = general_purpose::STANDARD
+            .decode(std::fs::read("./test/keys/ed.private").unwrap())
+            .unwrap()
+            .try_into()
+            .unwrap();
+        let private_key =
+            DkimPrivateKey::Ed25519(ed25519_dalek::SigningKey::from_bytes(&secret_key));
+        assert_eq!(
+            private_key.to_public_key().to_key_record(),
+            format!(
+                "v=DKIM1; k=ed25519; p={}",
+                std::fs::read_to_string("./test/keys/ed.public").unwrap()
+            )
+        );
+    }
+
+    #[cfg(feature = "time")]
+    #[tokio::test]
+    async fn test_verify_email_as_of() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let secret_key: [u8; 32] = general_purpose::STANDARD
+            .decode(std::fs::read("./test/keys/ed.private").unwrap())
+            .unwrap()
+            .try_into()
+            .unwrap();
+        let private_key =
+            DkimPrivateKey::Ed25519(ed25519_dalek::SigningKey::from_bytes(&secret_key));
+        let record = private_key.to_public_key().to_key_record();
+        use chrono::TimeZone;
+        let signed_at = chrono::Utc.timestamp_opt(1_600_000_000, 0).unwrap();
+        let signer = SignerBuilder::new()
+            .with_signed_headers(&["From"])
+            .unwrap()
+            .with_private_key(private_key)
+            .with_selector("old")
+            .with_signing_domain("example.com")
+            .with_logger(&logger)
+            .with_time(signed_at)
+            .with_expiry(chrono::Duration::days(7))
+            .build()
+            .unwrap();
+        let raw_email = "From: joe@example.com\r\n\r\nHello\r\n";
+        let email = mailparse::parse_mail(raw_email.as_bytes()).unwrap();
+        let raw_email = format!("{}\r\n{}", signer.sign(&email).unwrap(), raw_email);
+        let email = mailparse::parse_mail(raw_email.as_bytes()).unwrap();
+
+        // The key was rotated a year after the message was received
+        let snapshot = dns::Snapshot::new().with_record_between(
+            "old._domainkey.example.com",
+            &record,
+            None,
+            Some(1_600_000_000 + 365 * 86400),
+        );
+        let verify = |time| verify_email_as_of(&logger, "example.com", &email, &snapshot, time);
+        assert_eq!(verify(1_600_000_100).await.unwrap().summary(), "pass");
+        // Expired
+        assert_eq!(
+            verify(1_600_000_000 + 8 * 86400).await.unwrap().summary(),
+            "fail"
+        );
+        // Key removed
+        assert_eq!(
+            verify(1_600_000_000 + 400 * 86400).await.unwrap().summary(),
+            "fail"
+        );
+    }
+
+    #[cfg(feature = "time")]
+    #[test]
+    fn test_verify_email_received_time() {
+        use chrono::TimeZone;
+        use rsa::pkcs1::DecodeRsaPrivateKey;
+
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let private_key = rsa::RsaPrivateKey::read_pkcs1_pem_file(std::path::Path::new(
+            "./test/keys/2022.private",
+        ))
+        .unwrap();
+        let public_key = DkimPublicKey::Rsa(private_key.to_public_key());
+        let signer = SignerBuilder::new()
+            .with_signed_headers(&["From"])
+            .unwrap()
+            .with_private_key(DkimPrivateKey::Rsa(private_key))
+            .with_selector("2022")
+            .with_signing_domain("example.com")
+            .with_logger(&logger)
+            .with_time(chrono::Utc.with_ymd_and_hms(2020, 9, 13, 12, 0, 0).unwrap())
+            .with_expiry(chrono::Duration::days(7))
+            .build()
+            .unwrap();
+        let raw_email = "From: joe@example.com\r\n\r\nHello\r\n";
+        let email = mailparse::parse_mail(raw_email.as_bytes()).unwrap();
+        let raw_email = format!(
+            "Received: from a.example.com by b.example.com; Sun, 13 Sep 2020 12:01:00 +0000\r\n{}\r\n{}",
+            signer.sign(&email).unwrap(),
+            raw_email
+        );
+        let email = mailparse::parse_mail(raw_email.as_bytes()).unwrap();
+
+        let verify = |policy: &VerificationPolicy| {
+            verify_email_with_key_and_policy(
+                &logger,
+                "example.com",
+                &email,
+                public_key.clone(),
+                policy,
+            )
+            .unwrap()
+        };
+        let result = verify(&VerificationPolicy::new());
+        assert_eq!(result.summary(), "fail");
+        assert!(matches!(
+            result.error(),
+            Some(DKIMError::SignatureExpired { .. })
+        ));
+        let policy = VerificationPolicy::new().with_received_time(true);
+        assert_eq!(verify(&policy).summary(), "pass");
     }
 
     #[test]
@@ -733,7 +1906,7 @@ Joe.
 
         let result = verify_email_with_key(&logger, "example.com", &email, public_key).unwrap();
 
-        assert_eq!(result.with_detail(), "pass");
+        assert_eq!(result.to_string(), "pass");
     }
 
     #[test]
@@ -770,6 +1943,595 @@ Joe."#
         let result =
             verify_email_with_key(&logger, "football.example.com", &email, public_key).unwrap();
 
-        assert_eq!(result.with_detail(), "pass");
+        assert_eq!(result.to_string(), "pass");
+    }
+
+    fn rsa_email() -> String {
+        r#"DKIM-Signature: a=rsa-sha256; bh=2jUSOH9NhtVGCQWNr9BrIAPreKQjO6Sn7XIkfJVOzv8=;
+ c=simple/simple; d=example.com;
+ h=Received:From:To:Subject:Date:Message-ID; i=joe@football.example.com;
+ s=newengland; t=1615825284; v=1;
+ b=Xh4Ujb2wv5x54gXtulCiy4C0e+plRm6pZ4owF+kICpYzs/8WkTVIDBrzhJP0DAYCpnL62T0G
+ k+0OH8pi/yqETVjKtKk+peMnNvKkut0GeWZMTze0bfq3/JUK3Ln3jTzzpXxrgVnvBxeY9EZIL4g
+ s4wwFRRKz/1bksZGSjD8uuSU=
+Received: from client1.football.example.com  [192.0.2.1]
+      by submitserver.example.com with SUBMISSION;
+      Fri, 11 Jul 2003 21:01:54 -0700 (PDT)
+From: Joe SixPack <joe@football.example.com>
+To: Suzie Q <suzie@shopping.example.net>
+Subject: Is dinner ready?
+Date: Fri, 11 Jul 2003 21:00:37 -0700 (PDT)
+Message-ID: <20030712040037.46341.5F8J@football.example.com>
+
+Hi.
+
+We lost the game. Are you hungry yet?
+
+Joe.
+"#
+        .replace('\n', "\r\n")
+    }
+
+    #[test]
+    fn test_header_from_domain() {
+        let email = mailparse::parse_mail(b"From: Joe <joe@Football.Example.com>\r\n\r\n").unwrap();
+        assert_eq!(header_from_domain(&email).unwrap(), "football.example.com");
+
+        let email = mailparse::parse_mail(b"To: joe@example.com\r\n\r\n").unwrap();
+        assert_eq!(
+            header_from_domain(&email).unwrap_err(),
+            DKIMError::MalformedEmail {
+                reason: "missing From header",
+                source: None,
+            }
+        );
+    }
+
+    fn rsa_public_key() -> DkimPublicKey {
+        let rsa_data = general_purpose::STANDARD
+            .decode("MIGJAoGBALVI635dLK4cJJAH3Lx6upo3X/Lm1tQz3mezcWTA3BUBnyIsdnRf57aD5BtNmhPrYYDlWlzw3UgnKisIxktkk5+iMQMlFtAS10JB8L3YadXNJY+JBcbeSi5TgJe4WFzNgW95FWDAuSTRXSWZfA/8xjflbTLDx0euFZOM7C4T0GwLAgMBAAE=")
+            .unwrap();
+        DkimPublicKey::try_from_bytes(&rsa_data, "rsa").unwrap()
+    }
+
+    #[test]
+    fn test_verify_email_with_max_signature_age() {
+        let raw_email = rsa_email();
+        let email = mailparse::parse_mail(raw_email.as_bytes()).unwrap();
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let max_age = std::time::Duration::from_secs(24 * 60 * 60);
+
+        let policy =
+            VerificationPolicy::new().with_max_signature_age(max_age, PolicyAction::Reject);
+        let result = verify_email_with_key_and_policy(
+            &logger,
+            "example.com",
+            &email,
+            rsa_public_key(),
+            &policy,
+        )
+        .unwrap();
+        assert_eq!(result.to_string(), "fail (signature too old)");
+        assert_eq!(result.verdict(), Verdict::Fail);
+        assert_eq!(result.reason_code(), Some("DKIM_SIG_TOO_OLD"));
+
+        let policy = VerificationPolicy::new().with_max_signature_age(max_age, PolicyAction::Flag);
+        let result = verify_email_with_key_and_policy(
+            &logger,
+            "example.com",
+            &email,
+            rsa_public_key(),
+            &policy,
+        )
+        .unwrap();
+        assert_eq!(result.to_string(), "pass");
+        assert_eq!(result.verdict(), Verdict::Pass);
+        assert_eq!(result.reason_code(), None);
+        assert!(matches!(
+            result.warnings(),
+            [DKIMError::SignatureTooOld { max_age: 86400, .. }]
+        ));
+        assert_eq!(result.warning_codes(), vec!["DKIM_SIG_TOO_OLD"]);
+    }
+
+    #[test]
+    fn test_verify_email_with_body_hashes() {
+        let raw_email = rsa_email();
+        // Only keep the headers, the body hash is provided separately
+        let (headers, _) = raw_email.split_once("\r\n\r\n").unwrap();
+        let headers = format!("{}\r\n\r\n", headers);
+        let email = mailparse::parse_mail(headers.as_bytes()).unwrap();
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+
+        let mut body_hashes = BodyHashes::new();
+        body_hashes.insert(
+            canonicalization::Type::Simple,
+            HashAlgo::RsaSha256,
+            None,
+            "2jUSOH9NhtVGCQWNr9BrIAPreKQjO6Sn7XIkfJVOzv8=".to_owned(),
+        );
+        let result = verify_email_with_key_and_body_hashes(
+            &logger,
+            "example.com",
+            &email,
+            rsa_public_key(),
+            &VerificationPolicy::default(),
+            &body_hashes,
+        )
+        .unwrap();
+        assert_eq!(result.to_string(), "pass");
+
+        let result =
+            verify_email_with_key(&logger, "example.com", &email, rsa_public_key()).unwrap();
+        assert_eq!(result.error(), Some(DKIMError::BodyHashDidNotVerify));
+    }
+
+    #[test]
+    fn test_verify_email_with_key_continues_after_failure() {
+        let raw_email = rsa_email();
+        let (signature, rest) = raw_email.split_once("\r\nReceived:").unwrap();
+        let altered = signature.replace("bh=2jUSOH9N", "bh=3jUSOH9N");
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+
+        // The failing signature is reported when no signature passes
+        let raw = format!("{}\r\nReceived:{}", altered, rest);
+        let email = mailparse::parse_mail(raw.as_bytes()).unwrap();
+        let result =
+            verify_email_with_key(&logger, "example.com", &email, rsa_public_key()).unwrap();
+        assert_eq!(result.error(), Some(DKIMError::BodyHashDidNotVerify));
+
+        // The next signature is tried after a failure
+        let raw = format!("{}\r\n{}\r\nReceived:{}", altered, signature, rest);
+        let email = mailparse::parse_mail(raw.as_bytes()).unwrap();
+        let result =
+            verify_email_with_key(&logger, "example.com", &email, rsa_public_key()).unwrap();
+        assert_eq!(result.summary(), "pass");
+        assert_eq!(result.signature_index(), Some(1));
+    }
+
+    #[test]
+    fn test_verify_email_with_required_signatures() {
+        let raw_email = rsa_email();
+        let (signature, rest) = raw_email.split_once("\r\nReceived:").unwrap();
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let verify = |raw: &str, policy: &VerificationPolicy| {
+            let email = mailparse::parse_mail(raw.as_bytes()).unwrap();
+            verify_email_with_key_and_policy(
+                &logger,
+                "example.com",
+                &email,
+                rsa_public_key(),
+                policy,
+            )
+            .unwrap()
+        };
+
+        let policy = VerificationPolicy::new().with_min_passing_signatures(2);
+        let result = verify(&raw_email, &policy);
+        assert_eq!(
+            result.to_string(),
+            "fail (required signatures missing: 1 passing signatures out of 2)"
+        );
+        let twice = format!("{}\r\n{}\r\nReceived:{}", signature, signature, rest);
+        let result = verify(&twice, &policy);
+        assert_eq!(result.summary(), "pass");
+        assert_eq!(result.signature_index(), Some(0));
+
+        let policy = VerificationPolicy::new()
+            .with_required_algorithm("rsa")
+            .with_required_algorithm("ed25519");
+        let result = verify(&twice, &policy);
+        assert_eq!(
+            result.error(),
+            Some(DKIMError::RequiredSignaturesMissing {
+                passing: 0,
+                required: 1,
+                algorithm: Some("ed25519".to_owned())
+            })
+        );
+    }
+
+    #[test]
+    fn test_select_best_signature() {
+        let pass = |algorithm: &str, age: Option<i64>, key_size: usize| {
+            DKIMResult::pass(
+                "example.com".to_owned(),
+                canonicalization::Type::Relaxed,
+                canonicalization::Type::Relaxed,
+            )
+            .with_signature("s", algorithm)
+            .with_signature_age(age)
+            .with_key_size(key_size)
+        };
+        let selected = |passed: Vec<DKIMResult>| {
+            let result = select_best_signature(passed);
+            (
+                result.algorithm().unwrap().to_owned(),
+                result.selection_reason().unwrap().to_owned(),
+            )
+        };
+
+        assert_eq!(
+            selected(vec![
+                pass("rsa-sha256", Some(10), 2048),
+                pass("ed25519-sha256", Some(20), 256)
+            ]),
+            (
+                "ed25519-sha256".to_owned(),
+                "stronger algorithm (ed25519-sha256)".to_owned()
+            )
+        );
+        let result = select_best_signature(vec![
+            pass("rsa-sha256", Some(20), 2048),
+            pass("rsa-sha1", Some(0), 4096),
+            pass("rsa-sha256", Some(10), 1024),
+        ]);
+        assert_eq!(result.key_size(), Some(1024));
+        assert_eq!(result.selection_reason(), Some("newest signature"));
+        let result = select_best_signature(vec![
+            pass("rsa-sha256", None, 1024),
+            pass("rsa-sha256", None, 2048),
+        ]);
+        assert_eq!(result.selection_reason(), Some("largest key (2048 bits)"));
+        assert_eq!(
+            selected(vec![pass("rsa-sha256", None, 1024)]).1,
+            "only passing signature"
+        );
+    }
+
+    #[test]
+    fn test_verify_email_with_best_signature() {
+        let raw_email = rsa_email();
+        let (signature, rest) = raw_email.split_once("\r\nReceived:").unwrap();
+        let twice = format!("{}\r\n{}\r\nReceived:{}", signature, signature, rest);
+        let email = mailparse::parse_mail(twice.as_bytes()).unwrap();
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+
+        let policy = VerificationPolicy::new().with_signature_selection(SignatureSelection::Best);
+        let result = verify_email_with_key_and_policy(
+            &logger,
+            "example.com",
+            &email,
+            rsa_public_key(),
+            &policy,
+        )
+        .unwrap();
+        assert_eq!(result.signature_index(), Some(0));
+        assert_eq!(
+            result.selection_reason(),
+            Some("first of equivalent signatures")
+        );
+    }
+
+    #[test]
+    fn test_verify_email_with_parent_domain() {
+        let raw_email = rsa_email();
+        let email = mailparse::parse_mail(raw_email.as_bytes()).unwrap();
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let verify = |from_domain: &str, policy: &VerificationPolicy| {
+            verify_email_with_key_and_policy(&logger, from_domain, &email, rsa_public_key(), policy)
+                .unwrap()
+                .summary()
+        };
+
+        let policy = VerificationPolicy::new();
+        assert_eq!(verify("news.example.com", &policy), "neutral");
+        let policy = policy.with_parent_domain_signatures(true);
+        assert_eq!(verify("news.example.com", &policy), "pass");
+        assert_eq!(verify("example.com", &policy), "pass");
+        assert_eq!(verify("badexample.com", &policy), "neutral");
+    }
+
+    #[test]
+    fn test_verify_email_identity_match() {
+        let raw_email = rsa_email();
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let verify = |raw: &str, policy: &VerificationPolicy| {
+            let email = mailparse::parse_mail(raw.as_bytes()).unwrap();
+            verify_email_with_key_and_policy(
+                &logger,
+                "example.com",
+                &email,
+                rsa_public_key(),
+                policy,
+            )
+            .unwrap()
+            .identity_matches_from()
+        };
+
+        let policy = VerificationPolicy::new();
+        assert_eq!(verify(&raw_email, &policy), None);
+        let policy = policy.with_identity_check(true);
+        // i=joe@football.example.com
+        assert_eq!(verify(&raw_email, &policy), Some(true));
+    }
+
+    #[test]
+    fn test_verify_email_header_with_body_hash() {
+        let raw_email = rsa_email();
+        let (headers, body) = raw_email.split_once("\r\n\r\n").unwrap();
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let email = mailparse::parse_mail(raw_email.as_bytes()).unwrap();
+        let header = email.headers.get_first_header(HEADER).unwrap();
+        let dkim_header = validate_header(signature_value(header).unwrap()).unwrap();
+
+        let body_hash = verify_body_hash(&dkim_header, body.as_bytes()).unwrap();
+        assert_eq!(
+            verify_body_hash(&dkim_header, b"Hello"),
+            Err(DKIMError::BodyHashDidNotVerify)
+        );
+
+        // The headers are verified without the body
+        let headers = format!("{}\r\n\r\n", headers);
+        let email = mailparse::parse_mail(headers.as_bytes()).unwrap();
+        let result = verify_email_header_with_body_hash(
+            &logger,
+            &dkim_header,
+            &email,
+            &rsa_public_key(),
+            &body_hash,
+        )
+        .unwrap();
+        assert_eq!(result.summary(), "pass");
+
+        assert!(matches!(
+            verify_email_header_with_body_hash(
+                &logger,
+                &dkim_header,
+                &email,
+                &rsa_public_key(),
+                "47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=",
+            ),
+            Err(DKIMError::BodyHashDidNotVerify)
+        ));
+    }
+
+    #[test]
+    fn test_verify_signature() {
+        use ed25519_dalek::Signer;
+
+        let secret_key: [u8; 32] = general_purpose::STANDARD
+            .decode(std::fs::read("./test/keys/ed.private").unwrap())
+            .unwrap()
+            .try_into()
+            .unwrap();
+        let signing_key = ed25519_dalek::SigningKey::from_bytes(&secret_key);
+        let public_key = DkimPublicKey::Ed25519(signing_key.verifying_key());
+        let header_hash = [42u8; 32];
+        let signature = signing_key.sign(&header_hash).to_bytes();
+
+        let verify = |hash: &[u8], signature: &[u8]| {
+            verify_signature(hash::HashAlgo::Ed25519Sha256, hash, signature, &public_key)
+        };
+        assert_eq!(verify(&header_hash, &signature), Ok(true));
+        assert_eq!(verify(&[0u8; 32], &signature), Ok(false));
+        assert!(matches!(
+            verify(&header_hash, &signature[1..]),
+            Err(DKIMError::SignatureSyntaxError { .. })
+        ));
+        assert!(matches!(
+            verify_signature(
+                hash::HashAlgo::Ed25519Sha256,
+                &header_hash,
+                &signature,
+                &rsa_public_key()
+            ),
+            Err(DKIMError::UnsupportedHashAlgorithm { .. })
+        ));
+    }
+
+    #[test]
+    fn test_compute_headers_hash() {
+        let raw_email = rsa_email();
+        let email = mailparse::parse_mail(raw_email.as_bytes()).unwrap();
+        let header = email.headers.get_first_header(HEADER).unwrap();
+        let dkim_header = validate_header(signature_value(header).unwrap()).unwrap();
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+
+        let header_hash = compute_headers_hash(
+            &logger,
+            canonicalization::Type::Simple,
+            dkim_header.get_required_tag("h"),
+            HashAlgo::RsaSha256,
+            &dkim_header,
+            &email,
+        )
+        .unwrap();
+        let signature = bytes::decode_base64(dkim_header.get_required_tag("b")).unwrap();
+        assert_eq!(
+            verify_signature(
+                HashAlgo::RsaSha256,
+                &header_hash,
+                &signature,
+                &rsa_public_key()
+            ),
+            Ok(true)
+        );
+    }
+
+    #[test]
+    fn test_verify_email_with_memory_limit() {
+        let raw_email = rsa_email();
+        let email = mailparse::parse_mail(raw_email.as_bytes()).unwrap();
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let verify = |policy: &VerificationPolicy| {
+            verify_email_with_key_and_policy(
+                &logger,
+                "example.com",
+                &email,
+                rsa_public_key(),
+                policy,
+            )
+            .unwrap()
+        };
+
+        let result = verify(&VerificationPolicy::new().with_memory_limit(4096));
+        assert_eq!(result.summary(), "pass");
+        let result = verify(&VerificationPolicy::new().with_memory_limit(256));
+        assert!(matches!(
+            result.error(),
+            Some(DKIMError::MemoryLimitExceeded { limit: 256 })
+        ));
+    }
+
+    #[test]
+    fn test_verify_email_commitment() {
+        let raw_email = rsa_email();
+        let email = mailparse::parse_mail(raw_email.as_bytes()).unwrap();
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let result =
+            verify_email_with_key(&logger, "example.com", &email, rsa_public_key()).unwrap();
+
+        let commitment = result.commitment().unwrap();
+        assert_eq!(commitment.domain(), "example.com");
+        assert_eq!(commitment.selector(), "newengland");
+        assert_eq!(
+            general_purpose::STANDARD.encode(commitment.body_hash()),
+            "2jUSOH9NhtVGCQWNr9BrIAPreKQjO6Sn7XIkfJVOzv8="
+        );
+        assert_eq!(commitment.header_hash().len(), 32);
+
+        let raw_email = raw_email.replace("Hi.", "Hello.");
+        let email = mailparse::parse_mail(raw_email.as_bytes()).unwrap();
+        let result =
+            verify_email_with_key(&logger, "example.com", &email, rsa_public_key()).unwrap();
+        assert!(result.commitment().is_none());
+    }
+
+    #[test]
+    fn test_verify_email_signature_age() {
+        let raw_email = rsa_email();
+        let email = mailparse::parse_mail(raw_email.as_bytes()).unwrap();
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+
+        let result =
+            verify_email_with_key(&logger, "example.com", &email, rsa_public_key()).unwrap();
+        let expected = chrono::Utc::now().timestamp() - 1615825284;
+        let age = result.signature_age().unwrap();
+        assert!((expected..expected + 5).contains(&age));
+    }
+
+    #[tokio::test]
+    async fn test_verify_email_with_observer() {
+        use std::sync::Mutex;
+
+        #[derive(Default)]
+        struct RecordingObserver {
+            events: Mutex<Vec<String>>,
+        }
+        impl VerificationObserver for RecordingObserver {
+            fn signature_started(&self, domain: &str, selector: &str) {
+                let event = format!("started {} {}", domain, selector);
+                self.events.lock().unwrap().push(event);
+            }
+            fn dns_lookup(
+                &self,
+                name: &str,
+                _latency: std::time::Duration,
+                result: Result<(), &DKIMError>,
+            ) {
+                let event = format!("lookup {} {}", name, result.is_ok());
+                self.events.lock().unwrap().push(event);
+            }
+            fn signature_passed(&self, domain: &str, selector: &str) {
+                let event = format!("passed {} {}", domain, selector);
+                self.events.lock().unwrap().push(event);
+            }
+        }
+
+        let raw_email = rsa_email();
+        let email = mailparse::parse_mail(raw_email.as_bytes()).unwrap();
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let observer = Arc::new(RecordingObserver::default());
+        let policy = VerificationPolicy::new().with_observer(observer.clone());
+
+        let result = verify_email_with_resolver_and_policy(
+            &logger,
+            "example.com",
+            &email,
+            Arc::new(MockResolver::new()),
+            &policy,
+        )
+        .await
+        .unwrap();
+        assert_eq!(result.summary(), "pass");
+        assert_eq!(
+            *observer.events.lock().unwrap(),
+            vec![
+                "started example.com newengland",
+                "lookup newengland._domainkey.example.com true",
+                "passed example.com newengland",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_verify_email_header_with_key() {
+        let raw_email = rsa_email();
+        let email = mailparse::parse_mail(raw_email.as_bytes()).unwrap();
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let value = String::from_utf8_lossy(
+            email
+                .headers
+                .get_first_header(HEADER)
+                .unwrap()
+                .get_value_raw(),
+        )
+        .into_owned();
+        let header = validate_header(&value).unwrap();
+
+        let result =
+            verify_email_header_with_key(&logger, &header, &email, &rsa_public_key(), None)
+                .unwrap();
+        assert_eq!(result.to_string(), "pass");
+        assert_eq!(result.domain_used(), "example.com");
+        assert_eq!(result.selector(), Some("newengland"));
+        assert_eq!(result.key_size(), Some(1024));
+
+        let tampered = raw_email.replace("Joe.", "Bob.");
+        let email = mailparse::parse_mail(tampered.as_bytes()).unwrap();
+        let result =
+            verify_email_header_with_key(&logger, &header, &email, &rsa_public_key(), None);
+        assert!(matches!(result, Err(DKIMError::BodyHashDidNotVerify)));
+    }
+
+    #[test]
+    fn test_verify_email_signature_header() {
+        let raw_email = rsa_email();
+        let raw_email = format!(
+            "DKIM-Signature: v=1; a=rsa-sha256; d=other.example; s=s1; h=From; bh=aGFzaA==; b=aGFzaA==\r\n{}",
+            raw_email
+        );
+        let email = mailparse::parse_mail(raw_email.as_bytes()).unwrap();
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+
+        let result =
+            verify_email_with_key(&logger, "example.com", &email, rsa_public_key()).unwrap();
+        assert_eq!(result.to_string(), "pass");
+        assert_eq!(result.signature_index(), Some(1));
+        assert!(result
+            .signature_header()
+            .unwrap()
+            .starts_with("a=rsa-sha256; bh=2jUSOH9NhtVGCQWNr9BrIAPreKQjO6Sn7XIkfJVOzv8=;"));
+    }
+
+    #[test]
+    fn test_verify_email_failure_of_last_signature() {
+        // A signature which parses but fails, followed by a malformed one
+        let raw_email = format!(
+            "DKIM-Signature: v=1; a=rsa-sha256; d=example.com; s=2022; h=From; bh=aGFzaA==; b=aGFzaA==\r\n\
+             DKIM-Signature: v=1; a\r\n{}",
+            crate::test_utils::SAMPLE_MESSAGE
+        );
+        let email = mailparse::parse_mail(raw_email.as_bytes()).unwrap();
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+
+        let result =
+            verify_email_with_key(&logger, "example.com", &email, rsa_public_key()).unwrap();
+        assert_eq!(result.verdict(), Verdict::Fail);
+        assert_eq!(result.reason_code(), Some("DKIM_SIG_SYNTAX"));
+        assert_eq!(result.signature_index(), Some(1));
+        assert_eq!(result.signature_header(), Some("v=1; a"));
+        assert_eq!(result.selector(), None);
     }
 }
@@ -0,0 +1,74 @@
+//! Forwarding of the log records to the `log` crate facade, for consumers
+//! using `env_logger` or another `log` implementation instead of slog.
+
+use slog::{Drain, Never, OwnedKVList, Record};
+
+struct LogDrain;
+
+impl Drain for LogDrain {
+    type Ok = ();
+    type Err = Never;
+
+    fn log(&self, record: &Record, _values: &OwnedKVList) -> Result<(), Never> {
+        log::log!(target: record.module(), to_log_level(record.level()), "{}", record.msg());
+        Ok(())
+    }
+
+    fn is_enabled(&self, level: slog::Level) -> bool {
+        to_log_level(level) <= log::max_level()
+    }
+}
+
+fn to_log_level(level: slog::Level) -> log::Level {
+    match level {
+        slog::Level::Critical | slog::Level::Error => log::Level::Error,
+        slog::Level::Warning => log::Level::Warn,
+        slog::Level::Info => log::Level::Info,
+        slog::Level::Debug => log::Level::Debug,
+        slog::Level::Trace => log::Level::Trace,
+    }
+}
+
+/// Returns a logger emitting the records with the `log` crate macros, to be
+/// passed to the functions of this crate
+pub fn log_logger() -> slog::Logger {
+    slog::Logger::root(LogDrain, slog::o!())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    struct CapturingLogger {
+        records: Mutex<Vec<String>>,
+    }
+    impl log::Log for CapturingLogger {
+        fn enabled(&self, _metadata: &log::Metadata) -> bool {
+            true
+        }
+        fn log(&self, record: &log::Record) {
+            let line = format!("{} {} {}", record.level(), record.target(), record.args());
+            self.records.lock().unwrap().push(line);
+        }
+        fn flush(&self) {}
+    }
+
+    static LOGGER: CapturingLogger = CapturingLogger {
+        records: Mutex::new(vec![]),
+    };
+
+    #[test]
+    fn test_log_logger() {
+        log::set_logger(&LOGGER).unwrap();
+        log::set_max_level(log::LevelFilter::Info);
+
+        let logger = log_logger();
+        slog::warn!(logger, "key syntax error: {}", "oops");
+        slog::debug!(logger, "filtered out");
+        assert_eq!(
+            *LOGGER.records.lock().unwrap(),
+            vec!["WARN cfdkim::logging::tests key syntax error: oops"]
+        );
+    }
+}
@@ -0,0 +1,225 @@
+//! Reading messages from mbox files and Maildir directories, for instance to
+//! audit the signatures of a mailbox. Messages are returned with CRLF line
+//! endings, as expected by the verifier.
+
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader};
+use std::path::{Path, PathBuf};
+
+#[cfg(feature = "dns")]
+use futures::stream::Stream;
+#[cfg(feature = "dns")]
+use std::sync::Arc;
+
+#[cfg(feature = "dns")]
+use crate::{dns, verify_stream, DKIMError, DKIMResult};
+
+/// Iterator over the messages of an mbox file. Both the mboxo and mboxrd
+/// variants are supported: `>From ` quoting is removed from the lines.
+pub struct Mbox<R> {
+    reader: R,
+    // Whether the separator line of the first message was read
+    started: bool,
+    done: bool,
+}
+
+impl Mbox<BufReader<File>> {
+    /// Open an mbox file
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Ok(Self::new(BufReader::new(File::open(path)?)))
+    }
+}
+
+impl<R: BufRead> Mbox<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            started: false,
+            done: false,
+        }
+    }
+
+    fn read_line(&mut self, line: &mut Vec<u8>) -> io::Result<bool> {
+        line.clear();
+        Ok(self.reader.read_until(b'\n', line)? > 0)
+    }
+}
+
+impl<R: BufRead> Iterator for Mbox<R> {
+    type Item = io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut line = vec![];
+
+        // Skip the content before the first separator
+        while !self.started && !self.done {
+            match self.read_line(&mut line) {
+                Ok(true) => self.started = line.starts_with(b"From "),
+                Ok(false) => self.done = true,
+                Err(err) => return Some(Err(err)),
+            }
+        }
+        if self.done {
+            return None;
+        }
+
+        let mut message = vec![];
+        let mut previous_empty = true;
+        loop {
+            match self.read_line(&mut line) {
+                Ok(true) => {}
+                Ok(false) => {
+                    self.done = true;
+                    break;
+                }
+                Err(err) => return Some(Err(err)),
+            }
+            let content = trim_line_ending(&line);
+            if previous_empty && content.starts_with(b"From ") {
+                break;
+            }
+            previous_empty = content.is_empty();
+
+            // mboxrd quoting: ">From " is stored as ">>From "
+            let quoted = content.iter().take_while(|b| **b == b'>').count();
+            let content = if quoted > 0 && content[quoted..].starts_with(b"From ") {
+                &content[1..]
+            } else {
+                content
+            };
+            message.extend_from_slice(content);
+            message.extend_from_slice(b"\r\n");
+        }
+
+        // The empty line preceding the next separator isn't part of the
+        // message
+        if message.ends_with(b"\r\n\r\n") {
+            message.truncate(message.len() - 2);
+        }
+        Some(Ok(message))
+    }
+}
+
+/// Iterator over the messages of a Maildir directory, in the `new` and `cur`
+/// subdirectories
+pub struct Maildir {
+    paths: std::vec::IntoIter<PathBuf>,
+}
+
+impl Maildir {
+    /// Open a Maildir directory
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let mut paths = vec![];
+        for subdir in ["new", "cur"] {
+            let dir = path.as_ref().join(subdir);
+            if !dir.is_dir() {
+                continue;
+            }
+            for entry in fs::read_dir(dir)? {
+                let entry = entry?;
+                if entry.file_type()?.is_file() {
+                    paths.push(entry.path());
+                }
+            }
+        }
+        paths.sort();
+        Ok(Self {
+            paths: paths.into_iter(),
+        })
+    }
+}
+
+impl Iterator for Maildir {
+    type Item = io::Result<(PathBuf, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let path = self.paths.next()?;
+        Some(fs::read(&path).map(|raw| (path, to_crlf(&raw))))
+    }
+}
+
+/// Verify the messages of a mailbox, see [crate::verify_stream]
+#[cfg(feature = "dns")]
+pub fn verify_messages<'a, I>(
+    logger: &'a slog::Logger,
+    resolver: Arc<dyn dns::Lookup>,
+    messages: I,
+    concurrency: usize,
+) -> impl Stream<Item = Result<DKIMResult, DKIMError>> + 'a
+where
+    I: IntoIterator<Item = Vec<u8>>,
+    I::IntoIter: 'a,
+{
+    verify_stream(
+        logger,
+        resolver,
+        futures::stream::iter(messages),
+        concurrency,
+    )
+}
+
+fn trim_line_ending(line: &[u8]) -> &[u8] {
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    line.strip_suffix(b"\r").unwrap_or(line)
+}
+
+/// Convert the line endings of a message to CRLF
+fn to_crlf(raw: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(raw.len() + raw.len() / 32);
+    for line in raw.split_inclusive(|b| *b == b'\n') {
+        out.extend_from_slice(trim_line_ending(line));
+        if line.ends_with(b"\n") {
+            out.extend_from_slice(b"\r\n");
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mbox() {
+        let mbox = b"From joe@example.com Fri Jul 11 21:00:37 2003\nFrom: joe@example.com\nSubject: one\n\nHello\n>From the start\n>>From quoted\n\nFrom suzie@example.net Fri Jul 11 21:01:37 2003\nFrom: suzie@example.net\n\nBye\n";
+        let messages: Vec<Vec<u8>> = Mbox::new(&mbox[..]).map(Result::unwrap).collect();
+        assert_eq!(
+            messages,
+            vec![
+                b"From: joe@example.com\r\nSubject: one\r\n\r\nHello\r\nFrom the start\r\n>From quoted\r\n".to_vec(),
+                b"From: suzie@example.net\r\n\r\nBye\r\n".to_vec(),
+            ]
+        );
+
+        assert_eq!(Mbox::new(&b""[..]).count(), 0);
+    }
+
+    #[test]
+    fn test_maildir() {
+        let dir = std::env::temp_dir().join(format!("cfdkim-maildir-{}", std::process::id()));
+        fs::create_dir_all(dir.join("new")).unwrap();
+        fs::create_dir_all(dir.join("cur")).unwrap();
+        fs::create_dir_all(dir.join("tmp")).unwrap();
+        fs::write(dir.join("new").join("2"), b"Subject: new\n\nbody\n").unwrap();
+        fs::write(
+            dir.join("cur").join("1:2,S"),
+            b"Subject: cur\r\n\r\nbody\r\n",
+        )
+        .unwrap();
+        fs::write(dir.join("tmp").join("3"), b"Subject: tmp\n\n").unwrap();
+
+        let messages: Vec<Vec<u8>> = Maildir::open(&dir)
+            .unwrap()
+            .map(|res| res.unwrap().1)
+            .collect();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(
+            messages,
+            vec![
+                b"Subject: cur\r\n\r\nbody\r\n".to_vec(),
+                b"Subject: new\r\n\r\nbody\r\n".to_vec(),
+            ]
+        );
+    }
+}
@@ -0,0 +1,277 @@
+//! Heuristics identifying the modifications commonly made by mailing lists,
+//! to triage signatures broken in transit.
+//!
+//! Each modification is undone in turn and the hashes are checked again: a
+//! modification is reported when undoing it makes the body hash match, or the
+//! signature verify for the Subject tag.
+
+use mailparse::MailHeaderMap;
+
+use crate::mime::{self, BodyChange};
+use crate::rewrite::header_fields;
+use crate::{
+    bytes, hash, parser, verify_signature, DKIMError, DKIMHeader, DkimPublicKey, ErrorSource,
+};
+
+/// Number of lines at the end of the body tried as a footer
+const MAX_FOOTER_LINES: usize = 50;
+
+/// Modification made by a mailing list
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ListModification {
+    /// A footer was appended to the body, starting at this offset of the body
+    Footer(usize),
+    /// A footer was added as the MIME part with this path, see
+    /// [crate::mime::MimePart]
+    FooterPart(String),
+    /// The body was re-encoded with this Content-Transfer-Encoding
+    TransferEncoding(String),
+    /// This tag, such as `[list]`, was added to the Subject
+    SubjectTag(String),
+}
+
+/// Test the common mailing list modifications against a signature which
+/// failed to verify. The modifications which explain the failure are
+/// returned, the most likely first; the list is empty when none does.
+pub fn diagnose_list_modifications(
+    logger: &slog::Logger,
+    dkim_header: &DKIMHeader,
+    email: &mailparse::ParsedMail,
+    public_key: &DkimPublicKey,
+) -> Result<Vec<ListModification>, DKIMError> {
+    let mut modifications = vec![];
+    if let Some(modification) = body_modification(dkim_header, email)? {
+        modifications.push(modification);
+    }
+    if let Some(tag) = subject_tag(logger, dkim_header, email, public_key)? {
+        modifications.push(ListModification::SubjectTag(tag));
+    }
+    Ok(modifications)
+}
+
+fn body_modification(
+    dkim_header: &DKIMHeader,
+    email: &mailparse::ParsedMail,
+) -> Result<Option<ListModification>, DKIMError> {
+    let (_, canonicalization) = parser::parse_canonicalization(dkim_header.get_tag("c"))?;
+    let hash_algo = parser::parse_hash_algo(dkim_header.get_required_tag("a"))?;
+    let length = dkim_header
+        .get_tag("l")
+        .map(hash::parse_length)
+        .transpose()?;
+    let expected = dkim_header.get_required_tag("bh");
+    let matches = |body: &[u8]| {
+        hash::compute_body_hash(body, canonicalization.clone(), hash_algo.clone(), length)
+            == expected
+    };
+
+    let body = bytes::get_all_after(email.raw_bytes, b"\r\n\r\n");
+    if matches(body) {
+        return Ok(None);
+    }
+
+    match mime::diagnose_body(dkim_header, email)?.change {
+        BodyChange::AddedPart(path) => return Ok(Some(ListModification::FooterPart(path))),
+        BodyChange::Appended(offset) => return Ok(Some(ListModification::Footer(offset))),
+        BodyChange::Unchanged | BodyChange::Unknown => {}
+    }
+
+    // Truncate the body at the start of each of its last lines
+    let footer = body
+        .iter()
+        .enumerate()
+        .rev()
+        .skip(1)
+        .filter(|(_, b)| **b == b'\n')
+        .map(|(index, _)| index + 1)
+        .take(MAX_FOOTER_LINES)
+        .find(|offset| matches(&body[..*offset]));
+    if let Some(offset) = footer {
+        return Ok(Some(ListModification::Footer(offset)));
+    }
+
+    // The body was signed before being converted to a 7bit encoding
+    if email.subparts.is_empty() {
+        if let Some(encoding) = email.headers.get_first_value("Content-Transfer-Encoding") {
+            let encoding = encoding.trim().to_lowercase();
+            if encoding == "base64" || encoding == "quoted-printable" {
+                let decoded = email
+                    .get_body_raw()
+                    .map_err(|err| DKIMError::MalformedEmail {
+                        reason: "invalid message",
+                        source: Some(ErrorSource::new(err)),
+                    })?;
+                if matches(&decoded) {
+                    return Ok(Some(ListModification::TransferEncoding(encoding)));
+                }
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Returns the tag of the Subject if the signature verifies without it
+fn subject_tag(
+    logger: &slog::Logger,
+    dkim_header: &DKIMHeader,
+    email: &mailparse::ParsedMail,
+    public_key: &DkimPublicKey,
+) -> Result<Option<String>, DKIMError> {
+    let raw = email.raw_bytes;
+    let (fields, _) = header_fields(raw);
+    // The last instance is the one signed
+    let Some(field) = fields
+        .iter()
+        .rev()
+        .find(|field| field.name.eq_ignore_ascii_case("subject"))
+    else {
+        return Ok(None);
+    };
+    let Some((tag, untagged)) = std::str::from_utf8(field.value)
+        .ok()
+        .and_then(remove_subject_tag)
+    else {
+        return Ok(None);
+    };
+
+    let name_end = field.range.end - field.value.len();
+    let mut rewritten = raw[..name_end].to_vec();
+    rewritten.extend_from_slice(untagged.as_bytes());
+    rewritten.extend_from_slice(&raw[field.range.end..]);
+    let rewritten = mailparse::parse_mail(&rewritten).map_err(|err| DKIMError::MalformedEmail {
+        reason: "invalid message",
+        source: Some(ErrorSource::new(err)),
+    })?;
+
+    let (canonicalization, _) = parser::parse_canonicalization(dkim_header.get_tag("c"))?;
+    let hash_algo = parser::parse_hash_algo(dkim_header.get_required_tag("a"))?;
+    let header_hash = hash::compute_headers_hash(
+        logger,
+        canonicalization,
+        dkim_header.get_required_tag("h"),
+        hash_algo.clone(),
+        dkim_header,
+        &rewritten,
+    )?;
+    let signature = bytes::decode_base64(dkim_header.get_required_tag("b")).map_err(|err| {
+        DKIMError::SignatureSyntaxError {
+            tag: Some("b"),
+            value: dkim_header.get_required_tag("b").to_owned(),
+            source: Some(ErrorSource::new(err)),
+        }
+    })?;
+    let verified = verify_signature(hash_algo, &header_hash, &signature, public_key)?;
+    Ok(verified.then_some(tag))
+}
+
+/// Split a Subject value into its list tag and the value without it. The tag
+/// is either at the start, or after the `Re:` of a reply.
+fn remove_subject_tag(value: &str) -> Option<(String, String)> {
+    let text = value.trim_start();
+    let (leading, text) = value.split_at(value.len() - text.len());
+    let (reply, text) = match text.get(..3) {
+        Some(prefix) if prefix.eq_ignore_ascii_case("re:") => {
+            let rest = text[3..].trim_start();
+            text.split_at(text.len() - rest.len())
+        }
+        _ => ("", text),
+    };
+    if !text.starts_with('[') {
+        return None;
+    }
+    let end = text.find(']')? + 1;
+    let rest = text[end..].trim_start_matches([' ', '\t']);
+    Some((
+        text[..end].to_owned(),
+        format!("{}{}{}", leading, reply, rest),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DkimPrivateKey, SignerBuilder};
+    use rsa::pkcs1::DecodeRsaPrivateKey;
+    use std::path::Path;
+
+    fn sign(raw_email: &str) -> String {
+        let private_key =
+            rsa::RsaPrivateKey::read_pkcs1_pem_file(Path::new("./test/keys/2022.private")).unwrap();
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let signer = SignerBuilder::new()
+            .with_signed_headers(&["From", "Subject"])
+            .unwrap()
+            .with_private_key(DkimPrivateKey::Rsa(private_key))
+            .with_selector("2022")
+            .with_logger(&logger)
+            .with_signing_domain("example.com")
+            .build()
+            .unwrap();
+        let email = mailparse::parse_mail(raw_email.as_bytes()).unwrap();
+        format!("{}\r\n{}", signer.sign(&email).unwrap(), raw_email)
+    }
+
+    fn diagnose(raw_email: &str) -> Vec<ListModification> {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let record: String = std::fs::read_to_string("./test/keys/2022.txt")
+            .unwrap()
+            .split('"')
+            .skip(1)
+            .step_by(2)
+            .collect();
+        let public_key = crate::public_key::parse_public_key(&logger, &record).unwrap();
+        let email = mailparse::parse_mail(raw_email.as_bytes()).unwrap();
+        let header = email.headers.get_first_header(crate::HEADER).unwrap();
+        let dkim_header = crate::signature_value(header)
+            .and_then(crate::validate_header)
+            .unwrap();
+        diagnose_list_modifications(&logger, &dkim_header, &email, &public_key).unwrap()
+    }
+
+    #[test]
+    fn test_remove_subject_tag() {
+        assert_eq!(
+            remove_subject_tag(" [list] Hello\r\n"),
+            Some(("[list]".to_owned(), " Hello\r\n".to_owned()))
+        );
+        assert_eq!(
+            remove_subject_tag(" Re: [list] Hello\r\n"),
+            Some(("[list]".to_owned(), " Re: Hello\r\n".to_owned()))
+        );
+        assert_eq!(remove_subject_tag(" Hello [list]\r\n"), None);
+    }
+
+    #[test]
+    fn test_diagnose_list_modifications() {
+        let raw_email = sign("Subject: Hello\r\nFrom: joe@example.com\r\n\r\nHello Alice\r\n");
+        assert_eq!(diagnose(&raw_email), vec![]);
+
+        let listed = raw_email
+            .replace("Subject: Hello", "Subject: [list] Hello")
+            .replace(
+                "Hello Alice\r\n",
+                "Hello Alice\r\n\r\n--\r\nTo unsubscribe, write to list-leave@example.org\r\n",
+            );
+        assert_eq!(
+            diagnose(&listed),
+            vec![
+                ListModification::Footer(15),
+                ListModification::SubjectTag("[list]".to_owned())
+            ]
+        );
+
+        let raw_email = sign(
+            "Subject: Hello\r\nFrom: joe@example.com\r\nContent-Type: text/plain; charset=utf-8\r\n\r\nHello Zoë\r\n",
+        )
+        .replace(
+            "charset=utf-8\r\n\r\nHello Zoë",
+            "charset=utf-8\r\nContent-Transfer-Encoding: quoted-printable\r\n\r\nHello Zo=C3=AB",
+        );
+        assert_eq!(
+            diagnose(&raw_email),
+            vec![ListModification::TransferEncoding(
+                "quoted-printable".to_owned()
+            )]
+        );
+    }
+}
@@ -0,0 +1,240 @@
+//! Localization of body hash failures in the MIME structure of a message.
+//!
+//! A signature only carries the hash of the whole body, so when it doesn't
+//! match the message is hashed again without each MIME part, and without the
+//! content following the closing delimiter, to find a part which was added in
+//! transit (a typical mailing list footer). The hashes of the individual parts
+//! are also reported so they can be compared with a copy of the message as it
+//! was sent, to find which part was modified (e.g. only the HTML alternative).
+
+use std::ops::Range;
+
+use crate::header::DKIMHeader;
+use crate::{bytes, hash, parser, DKIMError};
+
+/// A MIME part of the body of a message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MimePart {
+    /// Position of the part in the MIME tree, as in IMAP: `2.1` is the first
+    /// part of the second part. The body of a message which isn't multipart
+    /// is the part `1`.
+    pub path: String,
+    pub content_type: String,
+    /// Range of the part in the body, including its headers
+    pub range: Range<usize>,
+    /// Hash of the part with the body canonicalization and the hash algorithm
+    /// of the signature
+    pub hash: String,
+}
+
+/// Change of the body localized by [diagnose_body]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BodyChange {
+    /// The body hash matches
+    Unchanged,
+    /// The body hash matches without this part: it was added
+    AddedPart(String),
+    /// The body hash matches without the content from this offset of the
+    /// body: it was appended after the closing delimiter
+    Appended(usize),
+    /// The change couldn't be localized: any part may have been modified
+    Unknown,
+}
+
+/// Result of [diagnose_body]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BodyDiagnostics {
+    pub parts: Vec<MimePart>,
+    pub change: BodyChange,
+}
+
+// A part with the position of the delimiter line preceding it, if any
+struct Located {
+    part: MimePart,
+    delimiter: Option<usize>,
+}
+
+/// Hash the body of a message per MIME part to localize why its body hash
+/// doesn't match the one of a signature
+pub fn diagnose_body(
+    dkim_header: &DKIMHeader,
+    email: &mailparse::ParsedMail,
+) -> Result<BodyDiagnostics, DKIMError> {
+    let (_, canonicalization) = parser::parse_canonicalization(dkim_header.get_tag("c"))?;
+    let hash_algo = parser::parse_hash_algo(dkim_header.get_required_tag("a"))?;
+    let length = dkim_header
+        .get_tag("l")
+        .map(hash::parse_length)
+        .transpose()?;
+    let expected = dkim_header.get_required_tag("bh");
+    let hash = |body: &[u8]| {
+        hash::compute_body_hash(body, canonicalization.clone(), hash_algo.clone(), length)
+    };
+
+    let raw = email.raw_bytes;
+    let body_start = bytes::find(raw, b"\r\n\r\n").map_or(raw.len(), |index| index + 4);
+    let body = &raw[body_start..];
+
+    let mut located = vec![];
+    if email.subparts.is_empty() {
+        located.push(Located {
+            part: MimePart {
+                path: "1".to_owned(),
+                content_type: email.ctype.mimetype.clone(),
+                range: 0..body.len(),
+                hash: hash(body),
+            },
+            delimiter: None,
+        });
+    } else {
+        locate_parts(email, raw, body_start, "", &hash, &mut located);
+    }
+
+    let change = if hash(body) == expected {
+        BodyChange::Unchanged
+    } else if let Some(path) = located.iter().find_map(|located| {
+        let delimiter = located.delimiter?;
+        let mut without = body[..delimiter].to_vec();
+        without.extend_from_slice(&body[located.part.range.end..]);
+        (hash(&without) == expected).then(|| located.part.path.clone())
+    }) {
+        BodyChange::AddedPart(path)
+    } else {
+        match closing_delimiter_end(email, body) {
+            Some(end) if end < body.len() && hash(&body[..end]) == expected => {
+                BodyChange::Appended(end)
+            }
+            _ => BodyChange::Unknown,
+        }
+    };
+
+    Ok(BodyDiagnostics {
+        parts: located.into_iter().map(|located| located.part).collect(),
+        change,
+    })
+}
+
+/// Collect the subparts of `parent`, depth-first, with their position in the
+/// body
+fn locate_parts(
+    parent: &mailparse::ParsedMail,
+    raw: &[u8],
+    body_start: usize,
+    prefix: &str,
+    hash: &dyn Fn(&[u8]) -> String,
+    located: &mut Vec<Located>,
+) {
+    let delimiter = parent
+        .ctype
+        .params
+        .get("boundary")
+        .map(|boundary| format!("--{}", boundary));
+    for (index, subpart) in parent.subparts.iter().enumerate() {
+        // The parts are slices of the raw message
+        let Some(start) = (subpart.raw_bytes.as_ptr() as usize)
+            .checked_sub(raw.as_ptr() as usize)
+            .and_then(|offset| offset.checked_sub(body_start))
+        else {
+            continue;
+        };
+        let range = start..start + subpart.raw_bytes.len();
+        let body = &raw[body_start..];
+        let path = format!("{}{}", prefix, index + 1);
+        located.push(Located {
+            part: MimePart {
+                path: path.clone(),
+                content_type: subpart.ctype.mimetype.clone(),
+                range: range.clone(),
+                hash: hash(&body[range.clone()]),
+            },
+            delimiter: delimiter
+                .as_ref()
+                .and_then(|delimiter| rfind(&body[..range.start], delimiter.as_bytes())),
+        });
+        locate_parts(
+            subpart,
+            raw,
+            body_start,
+            &format!("{}.", path),
+            hash,
+            located,
+        );
+    }
+}
+
+/// Returns the offset of the end of the line of the closing delimiter of a
+/// multipart body
+fn closing_delimiter_end(email: &mailparse::ParsedMail, body: &[u8]) -> Option<usize> {
+    let boundary = email.ctype.params.get("boundary")?;
+    let delimiter = format!("--{}--", boundary);
+    let start = rfind(body, delimiter.as_bytes())?;
+    let end = start + delimiter.len();
+    Some(match bytes::find(&body[end..], b"\n") {
+        Some(index) => end + index + 1,
+        None => body.len(),
+    })
+}
+
+fn rfind(bytes: &[u8], search: &[u8]) -> Option<usize> {
+    bytes
+        .windows(search.len())
+        .rposition(|window| window == search)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::canonicalization::Type;
+    use crate::hash::HashAlgo;
+
+    const HEADERS: &str =
+        "From: joe@example.com\r\nContent-Type: multipart/alternative; boundary=\"b1\"\r\n\r\n";
+    const BODY: &str = "--b1\r\nContent-Type: text/plain\r\n\r\nHello\r\n--b1\r\nContent-Type: text/html\r\n\r\n<p>Hello</p>\r\n--b1--\r\n";
+
+    fn diagnose(body: &str) -> BodyDiagnostics {
+        let bh = hash::compute_body_hash(BODY.as_bytes(), Type::Relaxed, HashAlgo::RsaSha256, None);
+        let dkim_header = crate::validate_header(&format!(
+            "v=1; a=rsa-sha256; c=relaxed/relaxed; d=example.com; s=s; h=From; bh={}; b=x",
+            bh
+        ))
+        .unwrap()
+        .into_owned();
+        let raw = format!("{}{}", HEADERS, body);
+        let email = mailparse::parse_mail(raw.as_bytes()).unwrap();
+        diagnose_body(&dkim_header, &email).unwrap()
+    }
+
+    #[test]
+    fn test_diagnose_body() {
+        let original = diagnose(BODY);
+        assert_eq!(original.change, BodyChange::Unchanged);
+        let parts: Vec<(&str, &str)> = original
+            .parts
+            .iter()
+            .map(|part| (part.path.as_str(), part.content_type.as_str()))
+            .collect();
+        assert_eq!(parts, vec![("1", "text/plain"), ("2", "text/html")]);
+        assert_eq!(
+            &BODY[original.parts[0].range.clone()],
+            "Content-Type: text/plain\r\n\r\nHello\r\n"
+        );
+
+        let added = BODY.replace(
+            "--b1--",
+            "--b1\r\nContent-Type: text/plain\r\n\r\nUnsubscribe\r\n--b1--",
+        );
+        assert_eq!(
+            diagnose(&added).change,
+            BodyChange::AddedPart("3".to_owned())
+        );
+
+        let appended = format!("{}Unsubscribe\r\n", BODY);
+        assert_eq!(diagnose(&appended).change, BodyChange::Appended(BODY.len()));
+
+        // Only the hash of the modified part changes
+        let modified = diagnose(&BODY.replace("<p>Hello</p>", "<p>Hello!</p>"));
+        assert_eq!(modified.change, BodyChange::Unknown);
+        assert_eq!(modified.parts[0].hash, original.parts[0].hash);
+        assert_ne!(modified.parts[1].hash, original.parts[1].hash);
+    }
+}
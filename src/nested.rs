@@ -0,0 +1,291 @@
+//! Verification of the messages attached to a message as `message/rfc822`
+//! parts, typically emails forwarded as attachments.
+//!
+//! mailparse doesn't parse the content of `message/rfc822` parts, so each
+//! attached message is decoded and parsed in turn, and the messages attached
+//! to it are searched for as well.
+
+#[cfg(feature = "dns")]
+use std::sync::Arc;
+
+#[cfg(feature = "dns")]
+use crate::{dns, verify_email_with_resolver_and_policy};
+use crate::{
+    header_from_domain, verify_email_with_key_and_policy, DKIMError, DKIMResult, DkimPublicKey,
+    ErrorSource, VerificationPolicy,
+};
+
+const MESSAGE_MIMETYPE: &str = "message/rfc822";
+
+/// Maximum nesting level of the attached messages verified. A message
+/// attached deeper is reported as an error and isn't parsed, nor the
+/// messages attached to it.
+pub const MAX_DEPTH: usize = 10;
+
+/// Result of the verification of a message at one nesting level
+pub struct NestedResult {
+    /// Position of the `message/rfc822` part in the MIME tree, as in IMAP:
+    /// `2.1` is the first part of the message attached as the second part.
+    /// Empty for the outer message.
+    pub path: String,
+    /// Nesting level of the message, 0 for the outer message
+    pub depth: usize,
+    /// Result of the verification of the signature of the domain in the From
+    /// header of the message
+    pub result: Result<DKIMResult, DKIMError>,
+}
+
+/// An attached message, decoded, or the error of a message nested too deep
+struct Attached {
+    path: String,
+    depth: usize,
+    raw: Result<Vec<u8>, DKIMError>,
+}
+
+/// Verify the signatures of a message and of the messages attached to it,
+/// depth-first, with a provided public key. The outer message comes first.
+pub fn verify_nested_with_key(
+    logger: &slog::Logger,
+    email: &mailparse::ParsedMail,
+    public_key: DkimPublicKey,
+    policy: &VerificationPolicy,
+) -> Vec<NestedResult> {
+    let verify = |email: &mailparse::ParsedMail| {
+        let from_domain = header_from_domain(email)?;
+        verify_email_with_key_and_policy(logger, &from_domain, email, public_key.clone(), policy)
+    };
+
+    let mut results = vec![NestedResult {
+        path: String::new(),
+        depth: 0,
+        result: verify(email),
+    }];
+    for attached in attached_messages(email) {
+        let result = match &attached.raw {
+            Ok(raw) => parse_email(raw).and_then(|email| verify(&email)),
+            Err(err) => Err(err.clone()),
+        };
+        results.push(NestedResult {
+            result,
+            path: attached.path,
+            depth: attached.depth,
+        });
+    }
+    results
+}
+
+/// Verify the signatures of a message and of the messages attached to it,
+/// depth-first, providing an existing resolver. The outer message comes first.
+#[cfg(feature = "dns")]
+pub async fn verify_nested_with_resolver(
+    logger: &slog::Logger,
+    email: &mailparse::ParsedMail<'_>,
+    resolver: Arc<dyn dns::Lookup>,
+    policy: &VerificationPolicy,
+) -> Vec<NestedResult> {
+    async fn verify(
+        logger: &slog::Logger,
+        email: &mailparse::ParsedMail<'_>,
+        resolver: Arc<dyn dns::Lookup>,
+        policy: &VerificationPolicy,
+    ) -> Result<DKIMResult, DKIMError> {
+        let from_domain = header_from_domain(email)?;
+        verify_email_with_resolver_and_policy(logger, &from_domain, email, resolver, policy).await
+    }
+
+    let mut results = vec![NestedResult {
+        path: String::new(),
+        depth: 0,
+        result: verify(logger, email, Arc::clone(&resolver), policy).await,
+    }];
+    for attached in attached_messages(email) {
+        let email = match &attached.raw {
+            Ok(raw) => parse_email(raw),
+            Err(err) => Err(err.clone()),
+        };
+        let result = match email {
+            Ok(email) => verify(logger, &email, Arc::clone(&resolver), policy).await,
+            Err(err) => Err(err),
+        };
+        results.push(NestedResult {
+            path: attached.path,
+            depth: attached.depth,
+            result,
+        });
+    }
+    results
+}
+
+/// The messages attached to `email` at any depth, depth-first
+fn attached_messages(email: &mailparse::ParsedMail) -> Vec<Attached> {
+    let mut attached = vec![];
+    collect_attached(email, "", 1, &mut attached);
+    attached
+}
+
+fn collect_attached(
+    email: &mailparse::ParsedMail,
+    prefix: &str,
+    depth: usize,
+    attached: &mut Vec<Attached>,
+) {
+    // The body of a message which isn't multipart is its part `1`
+    let parts: Vec<(String, &mailparse::ParsedMail)> = if email.subparts.is_empty() {
+        vec![(format!("{}1", prefix), email)]
+    } else {
+        let mut parts = vec![];
+        collect_parts(email, prefix, &mut parts);
+        parts
+    };
+
+    for (path, part) in parts {
+        if part.ctype.mimetype != MESSAGE_MIMETYPE {
+            continue;
+        }
+        if depth > MAX_DEPTH {
+            attached.push(Attached {
+                path,
+                depth,
+                raw: Err(DKIMError::MalformedEmail {
+                    reason: "attached message nested too deeply",
+                    source: None,
+                }),
+            });
+            continue;
+        }
+        // Undo the Content-Transfer-Encoding of the part, if any
+        let Ok(raw) = part.get_body_raw() else {
+            continue;
+        };
+        let mut nested = vec![];
+        if let Ok(email) = mailparse::parse_mail(&raw) {
+            collect_attached(&email, &format!("{}.", path), depth + 1, &mut nested);
+        }
+        attached.push(Attached {
+            path,
+            depth,
+            raw: Ok(raw),
+        });
+        attached.append(&mut nested);
+    }
+}
+
+/// Collect the subparts of `parent` with their path, depth-first
+fn collect_parts<'a, 'b>(
+    parent: &'b mailparse::ParsedMail<'a>,
+    prefix: &str,
+    parts: &mut Vec<(String, &'b mailparse::ParsedMail<'a>)>,
+) {
+    for (index, subpart) in parent.subparts.iter().enumerate() {
+        let path = format!("{}{}", prefix, index + 1);
+        parts.push((path.clone(), subpart));
+        collect_parts(subpart, &format!("{}.", path), parts);
+    }
+}
+
+fn parse_email(raw_email: &[u8]) -> Result<mailparse::ParsedMail<'_>, DKIMError> {
+    mailparse::parse_mail(raw_email).map_err(|err| DKIMError::MalformedEmail {
+        reason: "invalid message",
+        source: Some(ErrorSource::new(err)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DkimPrivateKey, SignerBuilder};
+    use rsa::pkcs1::DecodeRsaPrivateKey;
+    use std::path::Path;
+
+    fn sign(raw_email: &str) -> String {
+        let private_key =
+            rsa::RsaPrivateKey::read_pkcs1_pem_file(Path::new("./test/keys/2022.private")).unwrap();
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let signer = SignerBuilder::new()
+            .with_signed_headers(&["From", "Subject"])
+            .unwrap()
+            .with_private_key(DkimPrivateKey::Rsa(private_key))
+            .with_selector("2022")
+            .with_logger(&logger)
+            .with_signing_domain("example.com")
+            .build()
+            .unwrap();
+        let email = mailparse::parse_mail(raw_email.as_bytes()).unwrap();
+        format!("{}\r\n{}", signer.sign(&email).unwrap(), raw_email)
+    }
+
+    fn forward(attached: &str, boundary: &str) -> String {
+        format!(
+            "From: bob@example.com\r\nSubject: Fwd\r\nContent-Type: multipart/mixed; boundary=\"{0}\"\r\n\r\n--{0}\r\nContent-Type: text/plain\r\n\r\nSee attached\r\n--{0}\r\nContent-Type: message/rfc822\r\n\r\n{1}\r\n--{0}--\r\n",
+            boundary, attached
+        )
+    }
+
+    fn verify_nested(raw_email: &str) -> Vec<NestedResult> {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let record: String = std::fs::read_to_string("./test/keys/2022.txt")
+            .unwrap()
+            .split('"')
+            .skip(1)
+            .step_by(2)
+            .collect();
+        let public_key = crate::public_key::parse_public_key(&logger, &record).unwrap();
+        let email = mailparse::parse_mail(raw_email.as_bytes()).unwrap();
+        verify_nested_with_key(&logger, &email, public_key, &VerificationPolicy::default())
+    }
+
+    fn verify(raw_email: &str) -> Vec<(String, usize, String)> {
+        verify_nested(raw_email)
+            .into_iter()
+            .map(|nested| {
+                (
+                    nested.path,
+                    nested.depth,
+                    nested.result.unwrap().summary().to_owned(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_verify_nested() {
+        let original = sign("From: joe@example.com\r\nSubject: Hello\r\n\r\nHello Alice\r\n");
+        let forwarded = sign(&forward(&original, "b1"));
+        assert_eq!(
+            verify(&forwarded),
+            vec![
+                ("".to_owned(), 0, "pass".to_owned()),
+                ("2".to_owned(), 1, "pass".to_owned()),
+            ]
+        );
+
+        // Forwarded twice, the innermost message was modified
+        let modified = original.replace("Hello Alice", "Hello Mallory");
+        let twice = forward(&forward(&modified, "b2"), "b1");
+        assert_eq!(
+            verify(&twice),
+            vec![
+                ("".to_owned(), 0, "neutral".to_owned()),
+                ("2".to_owned(), 1, "neutral".to_owned()),
+                ("2.2".to_owned(), 2, "fail".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_verify_nested_max_depth() {
+        let mut raw_email = sign("From: joe@example.com\r\nSubject: Hello\r\n\r\nHello Alice\r\n");
+        for level in 0..MAX_DEPTH + 2 {
+            raw_email = forward(&raw_email, &format!("b{}", level));
+        }
+
+        let results = verify_nested(&raw_email);
+        assert_eq!(results.len(), MAX_DEPTH + 2);
+        let last = results.last().unwrap();
+        assert_eq!(last.depth, MAX_DEPTH + 1);
+        assert!(matches!(last.result, Err(DKIMError::MalformedEmail { .. })));
+        assert!(results[..MAX_DEPTH + 1]
+            .iter()
+            .all(|nested| nested.result.is_ok()));
+    }
+}
@@ -0,0 +1,43 @@
+use std::fmt;
+use std::time::Duration;
+
+use crate::DKIMError;
+
+/// Callbacks invoked during verification, for instance to feed metrics to
+/// Prometheus or StatsD. All the methods do nothing by default.
+///
+/// Only the signatures matching the expected domain are reported; signatures
+/// which can't be parsed are not.
+pub trait VerificationObserver: Send + Sync {
+    /// The verification of a signature started
+    fn signature_started(&self, _domain: &str, _selector: &str) {}
+
+    /// A DNS lookup of `name` completed, successfully or not
+    fn dns_lookup(&self, _name: &str, _latency: Duration, _result: Result<(), &DKIMError>) {}
+
+    /// The signature verified successfully
+    fn signature_passed(&self, _domain: &str, _selector: &str) {}
+
+    /// The signature failed to verify
+    fn signature_failed(&self, _domain: &str, _selector: &str, _reason: &DKIMError) {}
+}
+
+/// Callbacks invoked while signing a message, for instance to audit what was
+/// signed or to time each phase. All the methods do nothing by default.
+pub trait SigningHooks: Send + Sync {
+    /// Called before the message is canonicalized and hashed
+    fn before_canonicalization(&self, _email: &mailparse::ParsedMail) {}
+
+    /// Called once the message is hashed, with the value of the signature
+    /// header that was signed (its `b=` tag is empty) and the resulting hash
+    fn after_hash(&self, _signed_header: &str, _hash: &[u8]) {}
+
+    /// Called with the generated `DKIM-Signature` header
+    fn after_signature(&self, _header: &str) {}
+}
+
+impl fmt::Debug for dyn VerificationObserver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("VerificationObserver")
+    }
+}
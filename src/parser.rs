@@ -1,14 +1,21 @@
+use std::borrow::Cow;
+use std::ops::Range;
+
 use crate::{canonicalization, hash, DKIMError};
 use nom::bytes::complete::tag;
+use nom::bytes::complete::take_while;
 use nom::bytes::complete::take_while1;
 use nom::character::complete::alpha1;
 use nom::combinator::opt;
+use nom::combinator::recognize;
 use nom::multi::fold_many0;
+use nom::multi::many0;
 use nom::sequence::delimited;
 use nom::sequence::pair;
 use nom::sequence::preceded;
 use nom::sequence::terminated;
 use nom::IResult;
+use nom::Offset;
 
 #[derive(Clone, Debug, PartialEq)]
 /// DKIM signature tag
@@ -21,41 +28,147 @@ pub struct Tag {
     pub raw_value: String,
 }
 
+/// DKIM signature tag borrowing from the parsed text when possible
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct TagRef<'a> {
+    pub(crate) name: Cow<'a, str>,
+    pub(crate) value: Cow<'a, str>,
+    pub(crate) raw_value: Cow<'a, str>,
+    /// Byte range of the tag in the tag list, from the start of its name to
+    /// the end of its value
+    pub(crate) span: Range<usize>,
+    /// Byte range of the raw value in the tag list
+    pub(crate) value_span: Range<usize>,
+}
+
+impl<'a> TagRef<'a> {
+    pub(crate) fn into_owned(self) -> TagRef<'static> {
+        TagRef {
+            name: Cow::Owned(self.name.into_owned()),
+            value: Cow::Owned(self.value.into_owned()),
+            raw_value: Cow::Owned(self.raw_value.into_owned()),
+            span: self.span,
+            value_span: self.value_span,
+        }
+    }
+}
+
+impl<'a> From<TagRef<'a>> for Tag {
+    fn from(tag: TagRef<'a>) -> Self {
+        Tag {
+            name: tag.name.into_owned(),
+            value: tag.value.into_owned(),
+            raw_value: tag.raw_value.into_owned(),
+        }
+    }
+}
+
+/// Syntax error of a tag list, returned as
+/// [DKIMError::TagListSyntaxError] for a signature which doesn't parse
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct TagListError {
+    /// Byte offset of the error in the tag list
+    pub offset: usize,
+    /// The malformed text, from the offset to the end of its tag-spec
+    pub fragment: String,
+}
+
+impl TagListError {
+    /// Error at the start of `rest`, the unparsed end of `input`
+    fn at(input: &str, rest: &str) -> Self {
+        let rest = rest.trim_start_matches([' ', '\t', '\r', '\n']);
+        let fragment = rest.split(';').next().unwrap_or_default().trim_end();
+        TagListError {
+            offset: input.offset(rest),
+            fragment: fragment.to_owned(),
+        }
+    }
+}
+
+impl From<TagListError> for DKIMError {
+    fn from(err: TagListError) -> Self {
+        DKIMError::TagListSyntaxError {
+            offset: err.offset,
+            fragment: err.fragment,
+        }
+    }
+}
+
 /// Main entrypoint of the parser. Parses the DKIM signature tag list
 /// as specified <https://datatracker.ietf.org/doc/html/rfc6376#section-3.6.1>.
 /// tag-list  =  tag-spec *( ";" tag-spec ) [ ";" ]
 pub fn tag_list(input: &str) -> IResult<&str, Vec<Tag>> {
+    let (input, tags) = tag_list_ref(input)?;
+    Ok((input, tags.into_iter().map(Tag::from).collect()))
+}
+
+/// Same as [tag_list] but the tags borrow from the input
+pub(crate) fn tag_list_ref(input: &str) -> IResult<&str, Vec<TagRef<'_>>> {
+    let original = input;
     let (input, start) = tag_spec(input)?;
 
-    terminated(
+    let (input, mut tags) = terminated(
         fold_many0(
             preceded(tag(";"), tag_spec),
             move || vec![start.clone()],
-            |mut acc: Vec<Tag>, item| {
+            |mut acc: Vec<TagRef>, item| {
                 acc.push(item);
                 acc
             },
         ),
         opt(tag(";")),
-    )(input)
+    )(input)?;
+
+    // The spans are relative to the input of each tag-spec, make them relative
+    // to the tag list. The name is never empty so it points into the input.
+    for tag in &mut tags {
+        let shift = original.offset(&tag.name) - tag.span.start;
+        tag.span = tag.span.start + shift..tag.span.end + shift;
+        tag.value_span = tag.value_span.start + shift..tag.value_span.end + shift;
+    }
+    Ok((input, tags))
+}
+
+/// Same as [tag_list_ref] but the whole input must be a tag list: the error
+/// locates the first tag-spec which doesn't parse
+pub(crate) fn complete_tag_list_ref(input: &str) -> Result<Vec<TagRef<'_>>, TagListError> {
+    match tag_list_ref(input) {
+        Ok((rest, tags)) if rest.trim_start_matches([' ', '\t', '\r', '\n']).is_empty() => Ok(tags),
+        Ok((rest, _)) => Err(TagListError::at(input, rest)),
+        Err(nom::Err::Error(err) | nom::Err::Failure(err)) => {
+            Err(TagListError::at(input, err.input))
+        }
+        Err(nom::Err::Incomplete(_)) => Err(TagListError::at(input, &input[input.len()..])),
+    }
+}
+
+/// Same as [complete_tag_list_ref] but the tags are owned
+pub(crate) fn complete_tag_list(input: &str) -> Result<Vec<Tag>, TagListError> {
+    Ok(complete_tag_list_ref(input)?
+        .into_iter()
+        .map(Tag::from)
+        .collect())
 }
 
 /// tag-spec  =  [FWS] tag-name [FWS] "=" [FWS] tag-value [FWS]
-fn tag_spec(input: &str) -> IResult<&str, Tag> {
+fn tag_spec(input: &str) -> IResult<&str, TagRef<'_>> {
+    let spec = input;
     let (input, name) = delimited(opt(fws), tag_name, opt(fws))(input)?;
     let (input, _) = tag("=")(input)?;
-
-    // Parse the twice to keep the original text
-    let value_input = input;
-    let (_, raw_value) = delimited(opt(fws), raw_tag_value, opt(fws))(value_input)?;
-    let (input, value) = delimited(opt(fws), tag_value, opt(fws))(value_input)?;
+    let (input, _) = opt(fws)(input)?;
+    let value_start = spec.offset(input);
+    let (input, raw_value) = raw_tag_value(input)?;
+    let (input, _) = opt(fws)(input)?;
+    let value_end = value_start + raw_value.len();
 
     Ok((
         input,
-        Tag {
-            name: name.to_owned(),
-            value,
-            raw_value,
+        TagRef {
+            name: Cow::Borrowed(name),
+            value: tag_value(raw_value),
+            raw_value: Cow::Borrowed(raw_value),
+            span: spec.offset(name)..value_end,
+            value_span: value_start..value_end,
         },
     ))
 }
@@ -63,40 +176,35 @@ fn tag_spec(input: &str) -> IResult<&str, Tag> {
 /// tag-name  =  ALPHA *ALNUMPUNC
 /// ALNUMPUNC =  ALPHA / DIGIT / "_"
 fn tag_name(input: &str) -> IResult<&str, &str> {
-    alpha1(input)
+    recognize(pair(
+        alpha1,
+        take_while(|c: char| c.is_ascii_alphanumeric() || c == '_'),
+    ))(input)
 }
 
-/// tag-value =  [ tval *( 1*(WSP / FWS) tval ) ]
-/// tval      =  1*VALCHAR
-/// VALCHAR   =  %x21-3A / %x3C-7E
-fn tag_value(input: &str) -> IResult<&str, String> {
-    let is_valchar = |c| ('!'..=':').contains(&c) || ('<'..='~').contains(&c);
-    match opt(take_while1(is_valchar))(input)? {
-        (input, Some(start)) => fold_many0(
-            preceded(fws, take_while1(is_valchar)),
-            || start.to_owned(),
-            |mut acc: String, item| {
-                acc += item;
-                acc
-            },
-        )(input),
-        (input, None) => Ok((input, "".to_string())),
+/// Value of the tag with the folding whitespace removed. Only allocates if the
+/// value contains whitespace.
+fn tag_value(raw_value: &str) -> Cow<'_, str> {
+    let is_fws = |c| c == ' ' || c == '\t' || c == '\r' || c == '\n';
+    if raw_value.contains(is_fws) {
+        Cow::Owned(raw_value.chars().filter(|c| !is_fws(*c)).collect())
+    } else {
+        Cow::Borrowed(raw_value)
     }
 }
 
-fn raw_tag_value(input: &str) -> IResult<&str, String> {
-    let is_valchar = |c| ('!'..=':').contains(&c) || ('<'..='~').contains(&c);
-    match opt(take_while1(is_valchar))(input)? {
-        (input, Some(start)) => fold_many0(
-            pair(fws, take_while1(is_valchar)),
-            || start.to_owned(),
-            |mut acc: String, item| {
-                acc += &(item.0.to_owned() + item.1);
-                acc
-            },
-        )(input),
-        (input, None) => Ok((input, "".to_string())),
-    }
+/// tag-value =  [ tval *( 1*(WSP / FWS) tval ) ]
+/// tval      =  1*VALCHAR
+/// VALCHAR   =  %x21-3A / %x3C-7E
+fn raw_tag_value(input: &str) -> IResult<&str, &str> {
+    // UTF-8 is allowed by https://datatracker.ietf.org/doc/html/rfc8616#section-4
+    let is_valchar =
+        |c: char| ('!'..=':').contains(&c) || ('<'..='~').contains(&c) || !c.is_ascii();
+    let (input, value) = opt(recognize(pair(
+        take_while1(is_valchar),
+        many0(pair(fws, take_while1(is_valchar))),
+    )))(input)?;
+    Ok((input, value.unwrap_or_default()))
 }
 
 /// FWS is folding whitespace.  It allows multiple lines separated by CRLF followed by at least one whitespace, to be joined.
@@ -104,33 +212,99 @@ fn fws(input: &str) -> IResult<&str, &str> {
     take_while1(|c| c == ' ' || c == '\t' || c == '\r' || c == '\n')(input)
 }
 
+/// Decode a dkim-quoted-printable value, as in the `z=` tag
+/// <https://datatracker.ietf.org/doc/html/rfc6376#section-2.11>: `=XX` is the
+/// byte XX in hexadecimal and the folding whitespace is removed. The decoded
+/// bytes may not be UTF-8.
+pub fn decode_quoted_printable(value: &str) -> Result<Vec<u8>, DKIMError> {
+    decode_qp(value, false)
+}
+
+/// Decode a qp-section, as in the `n=` tag of key records
+/// <https://datatracker.ietf.org/doc/html/rfc6376#section-3.6.1>: unlike
+/// dkim-quoted-printable, the whitespace is part of the value.
+pub(crate) fn decode_qp_section(value: &str) -> Result<Vec<u8>, DKIMError> {
+    decode_qp(value, true)
+}
+
+fn decode_qp(value: &str, keep_whitespace: bool) -> Result<Vec<u8>, DKIMError> {
+    let mut out = Vec::with_capacity(value.len());
+    let mut bytes = value
+        .bytes()
+        .filter(|b| keep_whitespace || !b.is_ascii_whitespace());
+    while let Some(b) = bytes.next() {
+        if b != b'=' {
+            out.push(b);
+            continue;
+        }
+        let decoded = match [bytes.next(), bytes.next()] {
+            [Some(high), Some(low)] => hex_digit(high)
+                .zip(hex_digit(low))
+                .map(|(high, low)| high << 4 | low),
+            _ => None,
+        };
+        out.push(decoded.ok_or_else(|| DKIMError::SignatureSyntaxError {
+            tag: None,
+            value: value.to_owned(),
+            source: None,
+        })?);
+    }
+    Ok(out)
+}
+
+fn hex_digit(b: u8) -> Option<u8> {
+    (b as char).to_digit(16).map(|digit| digit as u8)
+}
+
+/// Encode a value in dkim-quoted-printable, the counterpart of
+/// [decode_quoted_printable]. Only the dkim-safe-char are kept as is, the
+/// other bytes and `|`, which separates the headers of the `z=` tag, are
+/// encoded as `=XX`.
+pub fn encode_quoted_printable(value: &[u8]) -> String {
+    // dkim-safe-char =  %x21-3A / %x3C / %x3E-7E
+    let is_safe = |b: u8| matches!(b, 0x21..=0x3A | 0x3C | 0x3E..=0x7E) && b != b'|';
+    let mut out = String::with_capacity(value.len());
+    for &b in value {
+        if is_safe(b) {
+            out.push(b as char);
+        } else {
+            out += &format!("={:02X}", b);
+        }
+    }
+    out
+}
+
 pub(crate) fn parse_hash_algo(value: &str) -> Result<hash::HashAlgo, DKIMError> {
     use hash::HashAlgo;
     match value {
         "rsa-sha1" => Ok(HashAlgo::RsaSha1),
         "rsa-sha256" => Ok(HashAlgo::RsaSha256),
         "ed25519-sha256" => Ok(HashAlgo::Ed25519Sha256),
-        e => Err(DKIMError::UnsupportedHashAlgorithm(e.to_string())),
+        e => Err(DKIMError::UnsupportedHashAlgorithm {
+            algorithm: e.to_string(),
+        }),
     }
 }
 
 /// Parses the canonicalization value (passed in c=) and returns canonicalization
 /// for (Header, Body)
 pub(crate) fn parse_canonicalization(
-    value: Option<String>,
+    value: Option<&str>,
 ) -> Result<(canonicalization::Type, canonicalization::Type), DKIMError> {
     use canonicalization::Type::{Relaxed, Simple};
     if value.is_none() {
         return Ok((Simple, Simple));
     }
-    match value.unwrap().as_str() {
+    match value.unwrap() {
         "simple/simple" => Ok((Simple, Simple)),
         "relaxed/simple" => Ok((Relaxed, Simple)),
         "simple/relaxed" => Ok((Simple, Relaxed)),
         "relaxed/relaxed" => Ok((Relaxed, Relaxed)),
         "relaxed" => Ok((Relaxed, Simple)),
         "simple" => Ok((Simple, Simple)),
-        v => Err(DKIMError::UnsupportedCanonicalizationType(v.to_owned())),
+        v => Err(DKIMError::UnsupportedCanonicalizationType {
+            canonicalization: v.to_owned(),
+        }),
     }
 }
 
@@ -149,11 +323,11 @@ mod tests {
         use canonicalization::Type::{Relaxed, Simple};
 
         assert_eq!(
-            parse_canonicalization(Some("simple".to_string())).unwrap(),
+            parse_canonicalization(Some("simple")).unwrap(),
             (Simple, Simple)
         );
         assert_eq!(
-            parse_canonicalization(Some("relaxed".to_string())).unwrap(),
+            parse_canonicalization(Some("relaxed")).unwrap(),
             (Relaxed, Simple)
         );
     }
@@ -194,7 +368,7 @@ mod tests {
     #[test]
     fn test_tag_spec() {
         assert_eq!(
-            tag_spec("a=b").unwrap(),
+            tag_spec("a=b").map(|(i, t)| (i, Tag::from(t))).unwrap(),
             (
                 "",
                 Tag {
@@ -205,7 +379,9 @@ mod tests {
             )
         );
         assert_eq!(
-            tag_spec("a=b c d e f").unwrap(),
+            tag_spec("a=b c d e f")
+                .map(|(i, t)| (i, Tag::from(t)))
+                .unwrap(),
             (
                 "",
                 Tag {
@@ -215,6 +391,79 @@ mod tests {
                 }
             )
         );
+        assert_eq!(
+            tag_spec("d=bücher.example").unwrap().1.value,
+            "bücher.example"
+        );
+    }
+
+    #[test]
+    fn test_complete_tag_list_errors() {
+        let error = |input: &str| complete_tag_list(input).unwrap_err();
+        assert_eq!(complete_tag_list("a=b;\r\n c=d; ").unwrap().len(), 2);
+        assert_eq!(
+            error("v=1; a=rsa-sha256; d example.com; s=x"),
+            TagListError {
+                offset: 19,
+                fragment: "d example.com".to_owned()
+            }
+        );
+        assert_eq!(
+            error("  =1"),
+            TagListError {
+                offset: 2,
+                fragment: "=1".to_owned()
+            }
+        );
+        assert_eq!(
+            error(""),
+            TagListError {
+                offset: 0,
+                fragment: "".to_owned()
+            }
+        );
+
+        let err = DKIMError::from(error("v=1;;"));
+        assert_eq!(
+            err,
+            DKIMError::TagListSyntaxError {
+                offset: 4,
+                fragment: "".to_owned()
+            }
+        );
+        assert_eq!(
+            err.to_string(),
+            "signature syntax error: invalid tag list at byte 4: \"\""
+        );
+    }
+
+    #[test]
+    fn test_tag_name() {
+        assert_eq!(tag_name("x_ext2=1"), Ok(("=1", "x_ext2")));
+        assert!(tag_name("_x=1").is_err());
+        assert!(tag_name("2x=1").is_err());
+    }
+
+    #[test]
+    fn test_quoted_printable() {
+        assert_eq!(
+            decode_quoted_printable("July=205,\r\n =202005=3b=7C").unwrap(),
+            b"July 5, 2005;|"
+        );
+        assert_eq!(decode_quoted_printable("=FF").unwrap(), vec![0xFF]);
+        assert!(decode_quoted_printable("a=2").is_err());
+        assert!(decode_quoted_printable("a=+1").is_err());
+        assert_eq!(decode_qp_section("a b=3Bc").unwrap(), b"a b;c".to_vec());
+
+        assert_eq!(
+            encode_quoted_printable(b"Date: July 5;=|Gr\xC3\xBC\r\n"),
+            "Date:=20July=205=3B=3D=7CGr=C3=BC=0D=0A"
+        );
+        let value = "a b\tc=d;|\u{e9}".as_bytes();
+        assert_eq!(
+            decode_quoted_printable(&encode_quoted_printable(value)).unwrap(),
+            value
+        );
     }
 
     #[test]
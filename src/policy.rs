@@ -0,0 +1,586 @@
+use std::borrow::Cow;
+use std::sync::Arc;
+use std::time::Duration;
+
+use mailparse::MailHeaderMap;
+
+use crate::{DKIMError, DKIMHeader, ErrorSource, VerificationObserver};
+
+/// Action taken when a signature violates the verification policy
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PolicyAction {
+    /// The signature is rejected
+    Reject,
+    /// The signature is accepted and the violation is reported in
+    /// [crate::DKIMResult::warnings]
+    Flag,
+}
+
+/// Which signature of the From domain is reported when several pass
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SignatureSelection {
+    /// The first passing signature, in the order of the headers
+    #[default]
+    First,
+    /// All the signatures are verified and the best passing one is reported,
+    /// with the reason why it was chosen in
+    /// [crate::DKIMResult::selection_reason]. Signatures are compared by, in
+    /// order:
+    /// 1. the strength of their algorithm: `ed25519-sha256`, then
+    ///    `rsa-sha256`, then `rsa-sha1`
+    /// 2. their timestamp (`t=` tag), the newest being preferred
+    /// 3. the size of their key, the largest being preferred
+    ///
+    /// The first in the order of the headers is chosen among equivalent
+    /// signatures.
+    Best,
+}
+
+/// Policy applied to the signatures during verification. The default policy
+/// doesn't add any check on top of RFC 6376.
+#[derive(Debug, Clone, Default)]
+pub struct VerificationPolicy {
+    verification_time: Option<i64>,
+    received_time: bool,
+    max_signature_age: Option<(Duration, PolicyAction)>,
+    max_future_skew: Option<(Duration, PolicyAction)>,
+    required_signed_headers: Option<(Vec<String>, PolicyAction)>,
+    min_passing_signatures: usize,
+    required_algorithms: Vec<String>,
+    selection: SignatureSelection,
+    parent_domain_signatures: bool,
+    identity_check: bool,
+    memory_limit: Option<usize>,
+    observer: Option<Arc<dyn VerificationObserver>>,
+}
+
+impl VerificationPolicy {
+    /// New default policy
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Specify the verification time as a Unix timestamp, instead of the
+    /// current time. Required to check the `x=` and `t=` tags when the `time`
+    /// feature is disabled.
+    pub fn with_verification_time(mut self, value: i64) -> Self {
+        self.verification_time = Some(value);
+        self
+    }
+
+    /// Use the date of the topmost `Received` header, added when the message
+    /// was delivered, as the verification time, to verify messages long after
+    /// their delivery. The verification time of the policy, or the current
+    /// time, is used when the message has no `Received` header or its date
+    /// can't be parsed.
+    pub fn with_received_time(mut self, value: bool) -> Self {
+        self.received_time = value;
+        self
+    }
+
+    /// The policy to verify `email` with: the verification time is the date
+    /// of its delivery, see [Self::with_received_time]
+    pub(crate) fn for_email(&self, email: &mailparse::ParsedMail) -> Cow<'_, Self> {
+        if !self.received_time {
+            return Cow::Borrowed(self);
+        }
+        match received_time(email) {
+            Some(time) => Cow::Owned(self.clone().with_verification_time(time)),
+            None => Cow::Borrowed(self),
+        }
+    }
+
+    /// Specify the maximum age of a signature, based on its `t=` tag, to
+    /// mitigate replay attacks of old signed messages. Signatures without a
+    /// `t=` tag are not affected.
+    pub fn with_max_signature_age(mut self, value: Duration, action: PolicyAction) -> Self {
+        self.max_signature_age = Some((value, action));
+        self
+    }
+
+    /// Require the given headers, for instance Subject, To, Date and
+    /// Message-ID, to be signed (listed in the `h=` tag): a signature only
+    /// covering From can be replayed with any other content. Signatures not
+    /// covering them are rejected, or pass with a
+    /// [DKIMError::RequiredHeaderNotSigned] warning per unsigned header.
+    pub fn with_required_signed_headers<S: AsRef<str>>(
+        mut self,
+        headers: &[S],
+        action: PolicyAction,
+    ) -> Self {
+        let headers = headers.iter().map(|h| h.as_ref().to_owned()).collect();
+        self.required_signed_headers = Some((headers, action));
+        self
+    }
+
+    /// Specify how far in the future the `t=` tag of a signature can be,
+    /// to account for clock skew between the signer and the verifier. A
+    /// timestamp further in the future is a sign of a forged or broken signer.
+    pub fn with_max_future_skew(mut self, value: Duration, action: PolicyAction) -> Self {
+        self.max_future_skew = Some((value, action));
+        self
+    }
+
+    /// Require at least `value` signatures of the From domain to pass for the
+    /// message to pass, instead of stopping at the first passing signature
+    pub fn with_min_passing_signatures(mut self, value: usize) -> Self {
+        self.min_passing_signatures = value;
+        self
+    }
+
+    /// Require a signature using `algorithm` to pass, either a key type
+    /// (`ed25519`, `rsa`) or a signing algorithm (`ed25519-sha256`). Can be
+    /// specified several times, for instance to require both an Ed25519 and
+    /// a RSA signature during the transition from RSA to Ed25519.
+    pub fn with_required_algorithm(mut self, algorithm: &str) -> Self {
+        self.required_algorithms.push(algorithm.to_lowercase());
+        self
+    }
+
+    /// Specify which signature is reported when several pass, the first one
+    /// by default
+    pub fn with_signature_selection(mut self, value: SignatureSelection) -> Self {
+        self.selection = value;
+        self
+    }
+
+    pub(crate) fn signature_selection(&self) -> SignatureSelection {
+        self.selection
+    }
+
+    /// Accept the signatures whose `d=` is a parent domain of the From
+    /// domain (relaxed alignment), for instance `d=example.com` for mail from
+    /// `news.example.com`. By default `d=` must be the From domain. The
+    /// signing domain must not be a public suffix such as `com` or `co.uk`.
+    pub fn with_parent_domain_signatures(mut self, value: bool) -> Self {
+        self.parent_domain_signatures = value;
+        self
+    }
+
+    /// Whether a signature of `signing_domain` is verified for a message from
+    /// `from_domain`
+    pub(crate) fn accepts_signing_domain(&self, signing_domain: &str, from_domain: &str) -> bool {
+        let signing_domain = crate::normalize_domain(signing_domain);
+        let from_domain = crate::normalize_domain(from_domain);
+        if signing_domain == from_domain {
+            return true;
+        }
+        self.parent_domain_signatures
+            && !crate::public_suffix::is_public_suffix(&signing_domain)
+            && from_domain.ends_with(&format!(".{}", signing_domain))
+    }
+
+    /// Compare the identity of the passing signature (`i=` tag), when it has a
+    /// local-part, to the From address and report whether they match in
+    /// [crate::DKIMResult::identity_matches_from]. Local-parts are compared
+    /// exactly (RFC 5321 section 2.4) and domains case-insensitively.
+    pub fn with_identity_check(mut self, value: bool) -> Self {
+        self.identity_check = value;
+        self
+    }
+
+    /// Whether the identity of the signature matches the From address, see
+    /// [Self::with_identity_check]
+    pub(crate) fn identity_match(
+        &self,
+        header: &DKIMHeader,
+        email: &mailparse::ParsedMail,
+    ) -> Option<bool> {
+        if !self.identity_check {
+            return None;
+        }
+        let identity = header.identity().ok()??;
+        let (local_part, domain) = identity.rsplit_once('@')?;
+        if local_part.is_empty() {
+            return None;
+        }
+        let from = crate::header_from_address(email).ok()?;
+        let (from_local_part, from_domain) = from.rsplit_once('@')?;
+        Some(
+            local_part == from_local_part
+                && crate::normalize_domain(domain) == crate::normalize_domain(from_domain),
+        )
+    }
+
+    /// Verify with bounded memory, for instance on embedded gateways or to get
+    /// predictable memory bounds in a zkVM: the body is canonicalized and
+    /// hashed in fixed-size chunks instead of being copied, and the
+    /// canonicalized headers of a signature are accumulated in a buffer of at
+    /// most `limit` bytes, allocated once. Signatures whose headers don't fit
+    /// fail with [DKIMError::MemoryLimitExceeded].
+    pub fn with_memory_limit(mut self, limit: usize) -> Self {
+        self.memory_limit = Some(limit);
+        self
+    }
+
+    pub(crate) fn memory_limit(&self) -> Option<usize> {
+        self.memory_limit
+    }
+
+    /// Specify an observer notified of the progress of the verification
+    pub fn with_observer(mut self, value: Arc<dyn VerificationObserver>) -> Self {
+        self.observer = Some(value);
+        self
+    }
+
+    pub(crate) fn observer(&self) -> Option<&Arc<dyn VerificationObserver>> {
+        self.observer.as_ref()
+    }
+
+    /// Whether all the signatures must be verified, rather than stopping at
+    /// the first passing one
+    pub(crate) fn requires_several_signatures(&self) -> bool {
+        self.min_passing_signatures > 1
+            || !self.required_algorithms.is_empty()
+            || self.selection == SignatureSelection::Best
+    }
+
+    /// Check the algorithms (`a=` tags) of the passing signatures against the
+    /// required signatures
+    pub(crate) fn check_passing_signatures(&self, algorithms: &[&str]) -> Result<(), DKIMError> {
+        if algorithms.len() < self.min_passing_signatures {
+            return Err(DKIMError::RequiredSignaturesMissing {
+                passing: algorithms.len(),
+                required: self.min_passing_signatures,
+                algorithm: None,
+            });
+        }
+        for required in &self.required_algorithms {
+            let passed = algorithms.iter().any(|algorithm| {
+                let algorithm = algorithm.to_lowercase();
+                algorithm == *required || algorithm.starts_with(&format!("{}-", required))
+            });
+            if !passed {
+                return Err(DKIMError::RequiredSignaturesMissing {
+                    passing: 0,
+                    required: 1,
+                    algorithm: Some(required.clone()),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Verification time as a Unix timestamp, if available
+    pub(crate) fn now(&self) -> Option<i64> {
+        if self.verification_time.is_some() {
+            return self.verification_time;
+        }
+        #[cfg(feature = "time")]
+        return Some(chrono::Utc::now().timestamp());
+        #[cfg(not(feature = "time"))]
+        None
+    }
+
+    /// Returns the age in seconds of the signature, based on its `t=` tag
+    pub(crate) fn signature_age(&self, header: &DKIMHeader) -> Option<i64> {
+        let timestamp = signature_timestamp(header).ok().flatten()?;
+        Some(self.now()?.saturating_sub(timestamp))
+    }
+
+    /// Check the signature against the policy. Returns the violations to
+    /// report as warnings, or the first violation rejecting the signature.
+    pub(crate) fn check(&self, header: &DKIMHeader) -> Result<Vec<DKIMError>, DKIMError> {
+        let mut warnings = vec![];
+
+        if let Some((required, action)) = &self.required_signed_headers {
+            let signed: Vec<&str> = header
+                .get_tag("h")
+                .unwrap_or_default()
+                .split(':')
+                .map(str::trim)
+                .collect();
+            for name in required {
+                if !signed
+                    .iter()
+                    .any(|signed| signed.eq_ignore_ascii_case(name))
+                {
+                    let violation = DKIMError::RequiredHeaderNotSigned {
+                        header: name.clone(),
+                    };
+                    apply(*action, violation, &mut warnings)?;
+                }
+            }
+        }
+
+        if self.max_signature_age.is_none() && self.max_future_skew.is_none() {
+            return Ok(warnings);
+        }
+        // Report invalid timestamps as syntax errors
+        signature_timestamp(header)?;
+        let age = match self.signature_age(header) {
+            Some(age) => age,
+            None => return Ok(warnings),
+        };
+
+        if let Some((max_age, action)) = self.max_signature_age {
+            let max_age = max_age.as_secs() as i64;
+            if age > max_age {
+                apply(
+                    action,
+                    DKIMError::SignatureTooOld { age, max_age },
+                    &mut warnings,
+                )?;
+            }
+        }
+
+        if let Some((max_skew, action)) = self.max_future_skew {
+            let max_skew = max_skew.as_secs() as i64;
+            if age < -max_skew {
+                let violation = DKIMError::SignatureTimestampInFuture {
+                    skew: -age,
+                    max_skew,
+                };
+                apply(action, violation, &mut warnings)?;
+            }
+        }
+
+        Ok(warnings)
+    }
+}
+
+fn apply(
+    action: PolicyAction,
+    violation: DKIMError,
+    warnings: &mut Vec<DKIMError>,
+) -> Result<(), DKIMError> {
+    match action {
+        PolicyAction::Reject => Err(violation),
+        PolicyAction::Flag => {
+            warnings.push(violation);
+            Ok(())
+        }
+    }
+}
+
+/// Returns the signature timestamp (`t=` tag)
+pub(crate) fn signature_timestamp(header: &DKIMHeader) -> Result<Option<i64>, DKIMError> {
+    header
+        .get_tag("t")
+        .map(|value| {
+            value
+                .parse::<i64>()
+                .map_err(|err| DKIMError::SignatureSyntaxError {
+                    tag: Some("t"),
+                    value: value.to_owned(),
+                    source: Some(ErrorSource::new(err)),
+                })
+        })
+        .transpose()
+}
+
+pub(crate) fn signature_expiration(header: &DKIMHeader) -> Result<Option<i64>, DKIMError> {
+    header
+        .get_tag("x")
+        .map(|value| {
+            value
+                .parse::<i64>()
+                .map_err(|err| DKIMError::SignatureSyntaxError {
+                    tag: Some("x"),
+                    value: value.to_owned(),
+                    source: Some(ErrorSource::new(err)),
+                })
+        })
+        .transpose()
+}
+
+/// Date of the topmost `Received` header, after its last semicolon
+fn received_time(email: &mailparse::ParsedMail) -> Option<i64> {
+    let received = email.headers.get_first_value("Received")?;
+    let (_, date) = received.rsplit_once(';')?;
+    mailparse::dateparse(date.trim()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header_with_time(timestamp: i64) -> DKIMHeader<'static> {
+        crate::validate_header(&format!(
+            "v=1; a=rsa-sha256; d=example.net; s=brisbane; h=From; bh=hash; b=hash; t={}",
+            timestamp
+        ))
+        .unwrap()
+        .into_owned()
+    }
+
+    #[test]
+    fn test_parent_domain_public_suffix() {
+        let policy = VerificationPolicy::new().with_parent_domain_signatures(true);
+        assert!(policy.accepts_signing_domain("victim.co.uk", "mail.victim.co.uk"));
+        assert!(!policy.accepts_signing_domain("co.uk", "victim.co.uk"));
+        assert!(!policy.accepts_signing_domain("uk", "victim.co.uk"));
+    }
+
+    #[test]
+    fn test_required_signed_headers() {
+        let header = crate::validate_header(
+            "v=1; a=rsa-sha256; d=example.net; s=brisbane; h=From : subject; bh=hash; b=hash",
+        )
+        .unwrap();
+        let required = ["Subject", "To", "Date"];
+
+        let policy = VerificationPolicy::new()
+            .with_required_signed_headers(&required[..1], PolicyAction::Reject);
+        assert_eq!(policy.check(&header), Ok(vec![]));
+
+        let policy =
+            VerificationPolicy::new().with_required_signed_headers(&required, PolicyAction::Flag);
+        assert_eq!(
+            policy.check(&header),
+            Ok(vec![
+                DKIMError::RequiredHeaderNotSigned {
+                    header: "To".to_owned()
+                },
+                DKIMError::RequiredHeaderNotSigned {
+                    header: "Date".to_owned()
+                }
+            ])
+        );
+
+        let policy =
+            VerificationPolicy::new().with_required_signed_headers(&required, PolicyAction::Reject);
+        assert_eq!(
+            policy.check(&header),
+            Err(DKIMError::RequiredHeaderNotSigned {
+                header: "To".to_owned()
+            })
+        );
+    }
+
+    #[test]
+    fn test_received_time() {
+        let raw_email = "Received: from a.example.com\r\n  by b.example.com; Fri, 11 Jul 2003 21:01:54 -0700\r\nReceived: from c.example.com by a.example.com; Fri, 11 Jul 2003 21:00:54 -0700\r\nFrom: joe@example.com\r\n\r\nHello\r\n";
+        let email = mailparse::parse_mail(raw_email.as_bytes()).unwrap();
+        let policy = VerificationPolicy::new().with_verification_time(1);
+        assert_eq!(policy.for_email(&email).now(), Some(1));
+        let policy = policy.with_received_time(true);
+        assert_eq!(policy.for_email(&email).now(), Some(1057982514));
+
+        let email = mailparse::parse_mail(b"From: joe@example.com\r\n\r\nHello\r\n").unwrap();
+        assert_eq!(policy.for_email(&email).now(), Some(1));
+    }
+
+    #[test]
+    fn test_identity_match() {
+        let policy = VerificationPolicy::new().with_identity_check(true);
+        let check = |identity: &str, from: &str| {
+            let header = crate::validate_header(&format!(
+                "v=1; a=rsa-sha256; d=example.com; s=s; h=From; bh=hash; b=hash; i={}",
+                identity
+            ))
+            .unwrap()
+            .into_owned();
+            let raw = format!("From: Joe <{}>\r\n\r\n", from);
+            let email = mailparse::parse_mail(raw.as_bytes()).unwrap();
+            policy.identity_match(&header, &email)
+        };
+
+        assert_eq!(check("joe@example.com", "joe@example.com"), Some(true));
+        assert_eq!(check("joe@Example.COM", "joe@example.com"), Some(true));
+        assert_eq!(check("Joe@example.com", "joe@example.com"), Some(false));
+        assert_eq!(
+            check("joe@mail.example.com", "joe@example.com"),
+            Some(false)
+        );
+        assert_eq!(check("@example.com", "joe@example.com"), None);
+        assert_eq!(check("joe=40example.com", "joe@example.com"), Some(true));
+    }
+
+    #[test]
+    fn test_default_policy() {
+        let header = header_with_time(0);
+        assert!(VerificationPolicy::new().check(&header).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_max_signature_age() {
+        let now = chrono::Utc::now().timestamp();
+        let day = Duration::from_secs(24 * 60 * 60);
+
+        let reject = VerificationPolicy::new().with_max_signature_age(day, PolicyAction::Reject);
+        assert!(reject.check(&header_with_time(now - 60)).is_ok());
+        assert!(matches!(
+            reject.check(&header_with_time(now - 2 * 24 * 60 * 60)),
+            Err(DKIMError::SignatureTooOld { max_age: 86400, .. })
+        ));
+
+        let flag = VerificationPolicy::new().with_max_signature_age(day, PolicyAction::Flag);
+        assert!(matches!(
+            flag.check(&header_with_time(now - 2 * 24 * 60 * 60))
+                .as_deref(),
+            Ok([DKIMError::SignatureTooOld { .. }])
+        ));
+    }
+
+    #[test]
+    fn test_max_future_skew() {
+        let now = 1_000_000_000;
+        let minute = Duration::from_secs(60);
+
+        let reject = VerificationPolicy::new()
+            .with_verification_time(now)
+            .with_max_future_skew(minute, PolicyAction::Reject);
+        assert!(reject.check(&header_with_time(now + 30)).is_ok());
+        assert_eq!(
+            reject.check(&header_with_time(now + 3600)),
+            Err(DKIMError::SignatureTimestampInFuture {
+                skew: 3600,
+                max_skew: 60
+            })
+        );
+
+        let flag = VerificationPolicy::new()
+            .with_verification_time(now)
+            .with_max_future_skew(minute, PolicyAction::Flag);
+        assert_eq!(
+            flag.check(&header_with_time(now + 3600)),
+            Ok(vec![DKIMError::SignatureTimestampInFuture {
+                skew: 3600,
+                max_skew: 60
+            }])
+        );
+    }
+
+    #[test]
+    fn test_check_passing_signatures() {
+        let policy = VerificationPolicy::new();
+        assert!(!policy.requires_several_signatures());
+
+        let policy = VerificationPolicy::new().with_min_passing_signatures(2);
+        assert!(policy.requires_several_signatures());
+        assert!(policy
+            .check_passing_signatures(&["rsa-sha256", "rsa-sha256"])
+            .is_ok());
+        assert_eq!(
+            policy.check_passing_signatures(&["rsa-sha256"]),
+            Err(DKIMError::RequiredSignaturesMissing {
+                passing: 1,
+                required: 2,
+                algorithm: None
+            })
+        );
+
+        let policy = VerificationPolicy::new()
+            .with_required_algorithm("ed25519")
+            .with_required_algorithm("RSA");
+        assert!(policy
+            .check_passing_signatures(&["ed25519-sha256", "rsa-sha256"])
+            .is_ok());
+        assert_eq!(
+            policy.check_passing_signatures(&["rsa-sha1"]),
+            Err(DKIMError::RequiredSignaturesMissing {
+                passing: 0,
+                required: 1,
+                algorithm: Some("ed25519".to_owned())
+            })
+        );
+        assert_eq!(
+            policy
+                .check_passing_signatures(&["rsa-sha1"])
+                .unwrap_err()
+                .to_string(),
+            "required signatures missing: no passing ed25519 signature"
+        );
+    }
+}
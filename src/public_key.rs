@@ -1,10 +1,7 @@
-#[cfg(feature = "dns")]
-use base64::{engine::general_purpose, Engine};
-#[cfg(feature = "dns")]
 use rsa::{pkcs1, pkcs8};
 #[cfg(feature = "dns")]
-use slog::{debug, warn};
-#[cfg(feature = "dns")]
+use slog::debug;
+use slog::warn;
 use std::collections::HashMap;
 #[cfg(feature = "dns")]
 use std::sync::Arc;
@@ -12,12 +9,51 @@ use std::sync::Arc;
 #[cfg(feature = "dns")]
 use crate::dns;
 #[cfg(feature = "dns")]
-use crate::{parser, DKIMError, DkimPublicKey, DNS_NAMESPACE};
+use crate::DNS_NAMESPACE;
+use crate::{bytes, parser, DKIMError, DkimPublicKey, ErrorSource};
 
-#[cfg(feature = "dns")]
 const RSA_KEY_TYPE: &str = "rsa";
-#[cfg(feature = "dns")]
 const ED25519_KEY_TYPE: &str = "ed25519";
+/// Minimum size of RSA keys recommended by RFC 8301 section 3.2
+#[cfg(feature = "dns")]
+const RECOMMENDED_RSA_KEY_SIZE: usize = 2048;
+
+/// Public key and flags of a DKIM key record
+#[derive(Debug, Clone)]
+pub struct KeyRecord {
+    pub key: DkimPublicKey,
+    /// Whether the domain is testing DKIM (`t=y` flag). Verifiers must treat
+    /// the signatures as if the message was unsigned for policy purposes.
+    pub testing: bool,
+    /// Notes for humans (`n=` tag), for instance to annotate a key rotation
+    pub notes: Option<String>,
+    /// All the tags of the record, in order, including the tags unknown to
+    /// this crate
+    pub tags: Vec<parser::Tag>,
+}
+
+/// Tags defined by RFC 6376 section 3.6.1
+const KNOWN_KEY_TAGS: &[&str] = &["v", "h", "k", "n", "p", "s", "t"];
+
+impl KeyRecord {
+    /// Record of a key provided out of band, without flags
+    pub(crate) fn new(key: DkimPublicKey) -> Self {
+        Self {
+            key,
+            testing: false,
+            notes: None,
+            tags: vec![],
+        }
+    }
+
+    /// Tags of the record not defined by RFC 6376, such as the tags of
+    /// extensions
+    pub fn unknown_tags(&self) -> impl Iterator<Item = &parser::Tag> {
+        self.tags
+            .iter()
+            .filter(|tag| !KNOWN_KEY_TAGS.contains(&tag.name.as_str()))
+    }
+}
 
 // https://datatracker.ietf.org/doc/html/rfc6376#section-6.1.2
 #[cfg(feature = "dns")]
@@ -27,20 +63,341 @@ pub async fn retrieve_public_key(
     domain: String,
     subdomain: String,
 ) -> Result<DkimPublicKey, DKIMError> {
-    let dns_name = format!("{}.{}.{}", subdomain, DNS_NAMESPACE, domain);
+    retrieve_key_record(logger, resolver, domain, subdomain)
+        .await
+        .map(|record| record.key)
+}
+
+/// Same as [retrieve_public_key] but also returns the flags of the record
+#[cfg(feature = "dns")]
+pub async fn retrieve_key_record(
+    logger: &slog::Logger,
+    resolver: Arc<dyn dns::Lookup>,
+    domain: String,
+    subdomain: String,
+) -> Result<KeyRecord, DKIMError> {
+    let dns_name = format!(
+        "{}.{}.{}",
+        subdomain,
+        DNS_NAMESPACE,
+        crate::normalize_domain(&domain)
+    );
     let res = resolver.lookup_txt(&dns_name).await?;
-    // TODO: Return multiple keys for when verifiying the signatures. During key
-    // rotation they are often multiple keys to consider.
-    let txt = res
-        .first()
-        .ok_or(DKIMError::NoKeyForSignature)?
-        .replace("\" \"", "");
+    // Key records must be unique for a selector, the result is undefined
+    // otherwise
+    // https://datatracker.ietf.org/doc/html/rfc6376#section-3.6.2.2
+    let key_records = join_txt_strings(&res)
+        .into_iter()
+        .filter(|record| is_key_record(record))
+        .collect::<std::collections::HashSet<_>>();
+    if key_records.len() > 1 {
+        return Err(DKIMError::DnsMultipleRecords { name: dns_name });
+    }
+    let txt = join_txt_records(&res).ok_or(DKIMError::NoKeyForSignature)?;
     debug!(logger, "DKIM TXT: {:?}", txt);
 
+    parse_key_record(logger, &txt)
+}
+
+/// Selectors commonly used by mail providers and signing software, probed by
+/// [discover_selectors]
+pub const COMMON_SELECTORS: &[&str] = &[
+    "default",
+    "dkim",
+    "mail",
+    "google",
+    "selector1",
+    "selector2",
+    "s1",
+    "s2",
+    "k1",
+    "k2",
+    "k3",
+    "mx",
+    "smtp",
+    "mandrill",
+    "mxvault",
+    "everlytickey1",
+    "everlytickey2",
+    "dkim1",
+    "dkim2",
+];
+
+/// Key record published for a selector, found by [discover_selectors]
+#[derive(Debug)]
+pub struct DiscoveredSelector {
+    pub selector: String,
+    /// The key record, or the reason why it couldn't be retrieved or parsed
+    pub record: Result<KeyRecord, DKIMError>,
+}
+
+/// Probes `selectors` of `domain`, for instance [COMMON_SELECTORS], and
+/// returns those for which a key record is published, in the order of
+/// `selectors`. The key type and size are available with
+/// [DkimPublicKey::key_type] and [DkimPublicKey::key_size].
+#[cfg(feature = "dns")]
+pub async fn discover_selectors<S: AsRef<str>>(
+    logger: &slog::Logger,
+    resolver: Arc<dyn dns::Lookup>,
+    domain: &str,
+    selectors: &[S],
+) -> Vec<DiscoveredSelector> {
+    let lookups = selectors.iter().map(|selector| {
+        let selector = selector.as_ref().to_owned();
+        let resolver = Arc::clone(&resolver);
+        async move {
+            let record =
+                retrieve_key_record(logger, resolver, domain.to_owned(), selector.clone()).await;
+            DiscoveredSelector { selector, record }
+        }
+    });
+    futures::future::join_all(lookups)
+        .await
+        .into_iter()
+        .filter(|found| !matches!(&found.record, Err(err) if err.is_key_missing()))
+        .collect()
+}
+
+/// Returns the key record from the TXT records returned by a lookup. Long
+/// records are split in strings of at most 255 bytes, which resolvers return
+/// either joined, quoted (`"v=DKIM1; p=MII" "BIj..."`) or as separate
+/// records. Continuation strings are recognized as they only contain base64
+/// characters and whitespace.
+// https://datatracker.ietf.org/doc/html/rfc6376#section-3.6.2.2
+#[cfg(feature = "dns")]
+fn join_txt_records(records: &[String]) -> Option<String> {
+    let mut joined = join_txt_strings(records);
+    let position = joined
+        .iter()
+        .position(|record| record.trim_start().starts_with("v=DKIM1"))
+        .or_else(|| joined.iter().position(|record| record.contains("p=")))
+        .unwrap_or(0);
+    (position < joined.len()).then(|| joined.swap_remove(position))
+}
+
+/// Whether a TXT record is a DKIM key record rather than an unrelated record
+/// published at the same name
+#[cfg(feature = "dns")]
+fn is_key_record(record: &str) -> bool {
+    record.trim_start().starts_with("v=DKIM1") || record.contains("p=")
+}
+
+/// Returns the TXT records returned by a lookup, with the continuation strings
+/// joined to the record they belong to
+#[cfg(feature = "dns")]
+fn join_txt_strings(records: &[String]) -> Vec<String> {
+    let mut joined: Vec<String> = vec![];
+    for record in records {
+        let record = unquote_txt(record);
+        let is_continuation = !record.trim_start().starts_with("v=")
+            && record
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "+/=".contains(c) || c.is_ascii_whitespace());
+        match joined.last_mut() {
+            Some(last) if is_continuation => last.push_str(&record),
+            _ => joined.push(record),
+        }
+    }
+    joined
+}
+
+/// Problem found by [check_published]
+#[derive(Debug, Clone, PartialEq)]
+pub enum PublicationIssue {
+    /// No key record is published for the selector
+    MissingRecord,
+    /// The key record can't be parsed
+    InvalidRecord(DKIMError),
+    /// The published key isn't the expected one
+    WrongKey,
+    /// The RSA key is smaller than the 2048 bits recommended by RFC 8301
+    WeakKey(usize),
+    /// The record has the testing flag (`t=y`), verifiers ignore failures
+    Testing,
+    /// The service types (`s=`) don't include email
+    WrongServiceType(String),
+    /// The acceptable hash algorithms (`h=`) don't include sha256
+    WrongHashAlgorithms(String),
+    /// Other key records are published for the selector, verifiers may use
+    /// any of them
+    ExtraRecord(String),
+}
+
+/// Result of [check_published]
+#[derive(Debug, Clone, PartialEq)]
+pub struct PublicationCheck {
+    /// Name of the TXT record
+    pub name: String,
+    /// Key records published for the selector
+    pub records: Vec<String>,
+    pub issues: Vec<PublicationIssue>,
+}
+
+impl PublicationCheck {
+    /// Whether the expected key is published without issues
+    pub fn is_ok(&self) -> bool {
+        self.issues.is_empty()
+    }
+}
+
+/// Checks that the key record of `selector` in `domain` publishes the
+/// `expected` key, for instance to validate DNS before enabling signing or
+/// to check that the key of a signer, see
+/// [crate::DkimPrivateKey::to_public_key],
+/// is still published after a key rotation. Errors are only returned when
+/// the lookup fails; problems with the published records are reported in
+/// the [PublicationCheck].
+#[cfg(feature = "dns")]
+pub async fn check_published(
+    logger: &slog::Logger,
+    resolver: Arc<dyn dns::Lookup>,
+    domain: &str,
+    selector: &str,
+    expected: &DkimPublicKey,
+) -> Result<PublicationCheck, DKIMError> {
+    check_record(logger, resolver, domain, selector, Some(expected)).await
+}
+
+/// Same as [check_published] without an expected key: the syntax, flags and
+/// key size of the published record are checked
+#[cfg(feature = "dns")]
+pub async fn check_key_record(
+    logger: &slog::Logger,
+    resolver: Arc<dyn dns::Lookup>,
+    domain: &str,
+    selector: &str,
+) -> Result<PublicationCheck, DKIMError> {
+    check_record(logger, resolver, domain, selector, None).await
+}
+
+#[cfg(feature = "dns")]
+async fn check_record(
+    logger: &slog::Logger,
+    resolver: Arc<dyn dns::Lookup>,
+    domain: &str,
+    selector: &str,
+    expected: Option<&DkimPublicKey>,
+) -> Result<PublicationCheck, DKIMError> {
+    let name = format!(
+        "{}.{}.{}",
+        selector,
+        DNS_NAMESPACE,
+        crate::normalize_domain(domain)
+    );
+    let records = match resolver.lookup_txt(&name).await {
+        Ok(records) => join_txt_strings(&records)
+            .into_iter()
+            .filter(|record| is_key_record(record))
+            .collect(),
+        Err(err) if err.is_key_missing() => vec![],
+        Err(err) => return Err(err),
+    };
+    let issues = publication_issues(logger, &records, expected);
+    Ok(PublicationCheck {
+        name,
+        records,
+        issues,
+    })
+}
+
+#[cfg(feature = "dns")]
+fn publication_issues(
+    logger: &slog::Logger,
+    records: &[String],
+    expected: Option<&DkimPublicKey>,
+) -> Vec<PublicationIssue> {
+    if records.is_empty() {
+        return vec![PublicationIssue::MissingRecord];
+    }
+
+    // The flags are checked on the record publishing the expected key, or
+    // else on the first one
+    let matching = expected.and_then(|expected| {
+        records.iter().position(
+            |record| matches!(parse_key_record(logger, record), Ok(record) if record.key == *expected),
+        )
+    });
+    let index = matching.unwrap_or(0);
+    let mut issues = vec![];
+    match parse_key_record(logger, &records[index]) {
+        Err(err) => issues.push(PublicationIssue::InvalidRecord(err)),
+        Ok(_) if expected.is_some() && matching.is_none() => {
+            issues.push(PublicationIssue::WrongKey)
+        }
+        Ok(record) => {
+            if let DkimPublicKey::Rsa(_) = record.key {
+                let key_size = record.key.key_size();
+                if key_size < RECOMMENDED_RSA_KEY_SIZE {
+                    issues.push(PublicationIssue::WeakKey(key_size));
+                }
+            }
+        }
+    }
+    if let Ok((_, tags)) = parser::tag_list(&records[index]) {
+        for tag in tags {
+            let mut values = tag.value.split(':').map(str::trim);
+            match tag.name.as_str() {
+                "t" if values.any(|flag| flag == "y") => issues.push(PublicationIssue::Testing),
+                "s" if !values.any(|service| service == "*" || service == "email") => {
+                    issues.push(PublicationIssue::WrongServiceType(tag.value))
+                }
+                "h" if !values.any(|hash| hash == "sha256") => {
+                    issues.push(PublicationIssue::WrongHashAlgorithms(tag.value))
+                }
+                _ => {}
+            }
+        }
+    }
+
+    issues.extend(
+        records
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| *i != index)
+            .map(|(_, record)| PublicationIssue::ExtraRecord(record.clone())),
+    );
+    issues
+}
+
+/// Concatenate the quoted strings of a TXT record, when it's presented in
+/// the zone file format
+#[cfg(feature = "dns")]
+fn unquote_txt(record: &str) -> String {
+    let record = record.trim();
+    if !record.starts_with('"') {
+        return record.replace("\" \"", "");
+    }
+
+    let mut out = String::with_capacity(record.len());
+    let mut quoted = false;
+    let mut chars = record.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => quoted = !quoted,
+            '\\' if quoted => out.extend(chars.next()),
+            c if quoted => out.push(c),
+            // Whitespace between the strings
+            _ => {}
+        }
+    }
+    out
+}
+
+/// Parse the public key from a DKIM key record (the content of the TXT DNS
+/// record), for instance to verify archived messages without DNS lookups
+pub fn parse_public_key(logger: &slog::Logger, txt: &str) -> Result<DkimPublicKey, DKIMError> {
+    parse_key_record(logger, txt).map(|record| record.key)
+}
+
+/// Same as [parse_public_key] but also returns the flags of the record
+pub fn parse_key_record(logger: &slog::Logger, txt: &str) -> Result<KeyRecord, DKIMError> {
     // Parse the tags inside the DKIM TXT DNS record
-    let (_, tags) = parser::tag_list(&txt).map_err(|err| {
+    let (_, tags) = parser::tag_list(txt).map_err(|err| {
         warn!(logger, "key syntax error: {}", err);
-        DKIMError::KeySyntaxError
+        DKIMError::KeySyntaxError {
+            reason: "invalid tag list".to_owned(),
+            source: None,
+        }
     })?;
 
     let mut tags_map = HashMap::new();
@@ -59,7 +416,9 @@ pub async fn retrieve_public_key(
     let key_type = match tags_map.get("k") {
         Some(v) => {
             if v.value != RSA_KEY_TYPE && v.value != ED25519_KEY_TYPE {
-                return Err(DKIMError::InappropriateKeyAlgorithm);
+                return Err(DKIMError::InappropriateKeyAlgorithm {
+                    key_type: v.value.clone(),
+                });
             }
             v.value.clone()
         }
@@ -67,37 +426,157 @@ pub async fn retrieve_public_key(
     };
 
     let tag = tags_map.get("p").ok_or(DKIMError::NoKeyForSignature)?;
-    let bytes = general_purpose::STANDARD
-        .decode(&tag.value)
-        .map_err(|err| {
-            DKIMError::KeyUnavailable(format!("failed to decode public key: {}", err))
-        })?;
+    let bytes = bytes::decode_base64(&tag.value).map_err(|err| DKIMError::KeySyntaxError {
+        reason: format!("failed to decode public key: {}", err),
+        source: Some(ErrorSource::new(err)),
+    })?;
     let key = if key_type == RSA_KEY_TYPE {
         DkimPublicKey::Rsa(
             pkcs8::DecodePublicKey::from_public_key_der(&bytes)
                 .or_else(|_| pkcs1::DecodeRsaPublicKey::from_pkcs1_der(&bytes))
-                .map_err(|err| {
-                    DKIMError::KeyUnavailable(format!("failed to parse public key: {}", err))
+                .map_err(|err| DKIMError::KeySyntaxError {
+                    reason: format!("failed to parse public key: {}", err),
+                    source: Some(ErrorSource::new(err)),
                 })?,
         )
     } else {
         DkimPublicKey::Ed25519(
             ed25519_dalek::VerifyingKey::from_bytes((&bytes as &[u8]).try_into().map_err(
-                |err| DKIMError::KeyUnavailable(format!("failed to convert public key: {}", err)),
+                |_| DKIMError::InvalidKeyLength {
+                    expected: ed25519_dalek::PUBLIC_KEY_LENGTH,
+                    actual: bytes.len(),
+                },
             )?)
-            .map_err(|err| {
-                DKIMError::KeyUnavailable(format!("failed to parse public key: {}", err))
+            .map_err(|err| DKIMError::KeySyntaxError {
+                reason: format!("failed to parse public key: {}", err),
+                source: Some(ErrorSource::new(err)),
             })?,
         )
     };
-    Ok(key)
+
+    // Flags are separated by colons, unknown flags are ignored
+    let testing = tags_map
+        .get("t")
+        .map(|tag| tag.value.split(':').any(|flag| flag.trim() == "y"))
+        .unwrap_or(false);
+
+    // Notes are informational, they're kept as is if they don't decode
+    let notes = tags_map.get("n").map(|tag| {
+        let notes = tag.raw_value.trim();
+        parser::decode_qp_section(notes)
+            .ok()
+            .and_then(|notes| String::from_utf8(notes).ok())
+            .unwrap_or_else(|| notes.to_owned())
+    });
+
+    Ok(KeyRecord {
+        key,
+        testing,
+        notes,
+        tags,
+    })
 }
 
-#[cfg(test)]
+#[cfg(all(test, feature = "dns"))]
 mod tests {
     use super::*;
+    use crate::DkimPrivateKey;
     use futures::future::BoxFuture;
 
+    #[test]
+    fn test_parse_key_record_testing() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let key = "p=11qYAYKxCrfVS/7TyWQHOg7hcvPapiMlrwIaaPcHURo=";
+
+        let record = parse_key_record(&logger, &format!("v=DKIM1; k=ed25519; {}", key)).unwrap();
+        assert!(!record.testing);
+        let record =
+            parse_key_record(&logger, &format!("v=DKIM1; k=ed25519; t=s:y; {}", key)).unwrap();
+        assert!(record.testing);
+        let record =
+            parse_key_record(&logger, &format!("v=DKIM1; k=ed25519; t=s; {}", key)).unwrap();
+        assert!(!record.testing);
+    }
+
+    #[test]
+    fn test_parse_key_record_unknown_tags() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let record = parse_key_record(
+            &logger,
+            "v=DKIM1; k=ed25519; x_rot=2024; p=11qYAYKxCrfVS/7TyWQHOg7hcvPapiMlrwIaaPcHURo=; s=email",
+        )
+        .unwrap();
+        assert_eq!(record.tags.len(), 5);
+        let unknown: Vec<(&str, &str)> = record
+            .unknown_tags()
+            .map(|tag| (tag.name.as_str(), tag.value.as_str()))
+            .collect();
+        assert_eq!(unknown, vec![("x_rot", "2024")]);
+    }
+
+    fn zone_file_strings(path: &str) -> Vec<String> {
+        let data = std::fs::read_to_string(path).unwrap();
+        data.split('"')
+            .skip(1)
+            .step_by(2)
+            .map(|s| s.to_owned())
+            .collect()
+    }
+
+    #[test]
+    fn test_join_txt_records() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+
+        for (path, key_size) in [
+            ("./test/keys/2022.txt", 2048),
+            ("./test/keys/4096.txt", 4096),
+        ] {
+            let strings = zone_file_strings(path);
+            let quoted = strings
+                .iter()
+                .map(|s| format!("\"{}\"", s))
+                .collect::<Vec<_>>();
+            let variants = vec![
+                // Strings joined by the resolver
+                vec![strings.concat()],
+                // Strings returned as separate records
+                strings.clone(),
+                // Quoted strings
+                vec![quoted.join(" ")],
+                vec![quoted.join("\t")],
+                // Interior whitespace
+                vec![strings.join(" ")],
+                vec![strings.join("\r\n ")],
+                // Unrelated record
+                [vec!["v=spf1 -all".to_owned()], strings.clone()].concat(),
+            ];
+            for records in variants {
+                let txt = join_txt_records(&records).unwrap();
+                let record = parse_key_record(&logger, &txt).unwrap();
+                assert_eq!(record.key.key_size(), key_size, "{:?}", records);
+            }
+        }
+
+        assert_eq!(join_txt_records(&[]), None);
+    }
+
+    #[test]
+    fn test_parse_key_record_notes() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let key = "p=11qYAYKxCrfVS/7TyWQHOg7hcvPapiMlrwIaaPcHURo=";
+
+        let record = parse_key_record(&logger, &format!("k=ed25519; {}", key)).unwrap();
+        assert_eq!(record.notes, None);
+        let record = parse_key_record(
+            &logger,
+            &format!("k=ed25519; n=retired after 2024-01-01; {}", key),
+        )
+        .unwrap();
+        assert_eq!(record.notes.as_deref(), Some("retired after 2024-01-01"));
+        let record = parse_key_record(&logger, &format!("k=ed25519; n=a=3Db c; {}", key)).unwrap();
+        assert_eq!(record.notes.as_deref(), Some("a=b c"));
+    }
+
     #[tokio::test]
     async fn test_retrieve_public_key() {
         struct TestResolver {}
@@ -152,6 +631,248 @@ mod tests {
         .unwrap();
     }
 
+    #[tokio::test]
+    async fn test_check_published_signer_key() {
+        use rsa::pkcs1::DecodeRsaPrivateKey;
+
+        struct TestResolver {}
+        impl dns::Lookup for TestResolver {
+            fn lookup_txt<'a>(
+                &'a self,
+                name: &'a str,
+            ) -> BoxFuture<'a, Result<Vec<String>, DKIMError>> {
+                let res = match name {
+                    "s2022._domainkey.example.com" => Ok(zone_file_strings("./test/keys/2022.txt")),
+                    "s4096._domainkey.example.com" => Ok(zone_file_strings("./test/keys/4096.txt")),
+                    _ => Err(DKIMError::NoKeyForSignature),
+                };
+                Box::pin(futures::future::ready(res))
+            }
+        }
+        let resolver: Arc<dyn dns::Lookup> = Arc::new(TestResolver {});
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let private_key = DkimPrivateKey::Rsa(
+            rsa::RsaPrivateKey::read_pkcs1_pem_file(std::path::Path::new(
+                "./test/keys/2022.private",
+            ))
+            .unwrap(),
+        );
+
+        let public_key = private_key.to_public_key();
+        let check = |selector: &'static str| {
+            let resolver = Arc::clone(&resolver);
+            let logger = &logger;
+            let public_key = &public_key;
+            async move {
+                check_published(logger, resolver, "example.com", selector, public_key)
+                    .await
+                    .unwrap()
+                    .issues
+            }
+        };
+        assert!(!check("s2022").await.contains(&PublicationIssue::WrongKey));
+        assert_eq!(check("s4096").await, vec![PublicationIssue::WrongKey]);
+        assert_eq!(
+            check("missing").await,
+            vec![PublicationIssue::MissingRecord]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_check_published() {
+        struct TestResolver {}
+        impl dns::Lookup for TestResolver {
+            fn lookup_txt<'a>(
+                &'a self,
+                name: &'a str,
+            ) -> BoxFuture<'a, Result<Vec<String>, DKIMError>> {
+                let ed = "p=11qYAYKxCrfVS/7TyWQHOg7hcvPapiMlrwIaaPcHURo=";
+                let other = "p=ZZqYAYKxCrfVS/7TyWQHOg7hcvPapiMlrwIaaPcHURo=";
+                let res = match name {
+                    "ok._domainkey.example.com" => Ok(vec![
+                        format!("v=DKIM1; k=ed25519; {}", ed),
+                        "v=spf1 -all".to_owned(),
+                    ]),
+                    "flags._domainkey.example.com" => Ok(vec![
+                        format!("v=DKIM1; k=ed25519; {}", other),
+                        format!("v=DKIM1; k=ed25519; t=y; s=tlsrpt; h=sha1; {}", ed),
+                    ]),
+                    "wrong._domainkey.example.com" => {
+                        Ok(vec![format!("v=DKIM1; k=ed25519; {}", other)])
+                    }
+                    "invalid._domainkey.example.com" => Ok(vec!["v=DKIM6; p=key".to_owned()]),
+                    "weak._domainkey.example.com" => Ok(vec!["v=DKIM1; p=MIGJAoGBALVI635dLK4cJJAH3Lx6upo3X/Lm1tQz3mezcWTA3BUBnyIsdnRf57aD5BtNmhPrYYDlWlzw3UgnKisIxktkk5+iMQMlFtAS10JB8L3YadXNJY+JBcbeSi5TgJe4WFzNgW95FWDAuSTRXSWZfA/8xjflbTLDx0euFZOM7C4T0GwLAgMBAAE=".to_owned()]),
+                    "error._domainkey.example.com" => {
+                        Err(DKIMError::DnsTimeout {
+                            name: name.to_owned(),
+                        })
+                    }
+                    _ => Err(DKIMError::NoKeyForSignature),
+                };
+                Box::pin(futures::future::ready(res))
+            }
+        }
+        let resolver: Arc<dyn dns::Lookup> = Arc::new(TestResolver {});
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let expected = parse_public_key(
+            &logger,
+            "k=ed25519; p=11qYAYKxCrfVS/7TyWQHOg7hcvPapiMlrwIaaPcHURo=",
+        )
+        .unwrap();
+
+        let check = |selector: &'static str| {
+            let resolver = Arc::clone(&resolver);
+            let logger = &logger;
+            let expected = &expected;
+            async move {
+                check_published(logger, resolver, "example.com", selector, expected)
+                    .await
+                    .map(|check| check.issues)
+            }
+        };
+        let ok = check_published(
+            &logger,
+            Arc::clone(&resolver),
+            "example.com",
+            "ok",
+            &expected,
+        )
+        .await
+        .unwrap();
+        assert!(ok.is_ok());
+        assert_eq!(ok.name, "ok._domainkey.example.com");
+        assert_eq!(ok.records.len(), 1);
+
+        assert_eq!(
+            check("flags").await.unwrap(),
+            vec![
+                PublicationIssue::Testing,
+                PublicationIssue::WrongServiceType("tlsrpt".to_owned()),
+                PublicationIssue::WrongHashAlgorithms("sha1".to_owned()),
+                PublicationIssue::ExtraRecord(
+                    "v=DKIM1; k=ed25519; p=ZZqYAYKxCrfVS/7TyWQHOg7hcvPapiMlrwIaaPcHURo=".to_owned()
+                ),
+            ]
+        );
+        assert_eq!(
+            check("wrong").await.unwrap(),
+            vec![PublicationIssue::WrongKey]
+        );
+        assert_eq!(
+            check("invalid").await.unwrap(),
+            vec![PublicationIssue::InvalidRecord(
+                DKIMError::KeyIncompatibleVersion
+            )]
+        );
+        assert_eq!(
+            check("missing").await.unwrap(),
+            vec![PublicationIssue::MissingRecord]
+        );
+        assert!(check("error").await.is_err());
+
+        let check_record = |selector: &'static str| {
+            let resolver = Arc::clone(&resolver);
+            let logger = &logger;
+            async move {
+                check_key_record(logger, resolver, "example.com", selector)
+                    .await
+                    .map(|check| check.issues)
+            }
+        };
+        assert_eq!(check_record("wrong").await.unwrap(), vec![]);
+        assert_eq!(
+            check_record("weak").await.unwrap(),
+            vec![PublicationIssue::WeakKey(1024)]
+        );
+        assert_eq!(
+            check_record("missing").await.unwrap(),
+            vec![PublicationIssue::MissingRecord]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_discover_selectors() {
+        struct TestResolver {}
+        impl dns::Lookup for TestResolver {
+            fn lookup_txt<'a>(
+                &'a self,
+                name: &'a str,
+            ) -> BoxFuture<'a, Result<Vec<String>, DKIMError>> {
+                let res = match name {
+                    "google._domainkey.example.com" => {
+                        Ok(zone_file_strings("./test/keys/2022.txt"))
+                    }
+                    "s1._domainkey.example.com" => Ok(vec![
+                        "k=ed25519; p=11qYAYKxCrfVS/7TyWQHOg7hcvPapiMlrwIaaPcHURo=".to_owned(),
+                    ]),
+                    "k1._domainkey.example.com" => Ok(vec!["v=DKIM1; p=".to_owned()]),
+                    _ => Err(DKIMError::NoKeyForSignature),
+                };
+                Box::pin(futures::future::ready(res))
+            }
+        }
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+
+        let found = discover_selectors(
+            &logger,
+            Arc::new(TestResolver {}),
+            "example.com",
+            COMMON_SELECTORS,
+        )
+        .await;
+        let summary: Vec<_> = found
+            .iter()
+            .map(|found| {
+                (
+                    found.selector.as_str(),
+                    found
+                        .record
+                        .as_ref()
+                        .map(|record| (record.key.key_type(), record.key.key_size())),
+                )
+            })
+            .collect();
+        assert_eq!(summary[0], ("google", Ok(("rsa", 2048))));
+        assert_eq!(summary[1], ("s1", Ok(("ed25519", 256))));
+        assert_eq!(summary[2].0, "k1");
+        assert!(summary[2].1.is_err());
+        assert_eq!(summary.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_retrieve_public_key_multiple_records() {
+        struct TestResolver {}
+        impl dns::Lookup for TestResolver {
+            fn lookup_txt<'a>(
+                &'a self,
+                _name: &'a str,
+            ) -> BoxFuture<'a, Result<Vec<String>, DKIMError>> {
+                let key = "v=DKIM1; k=ed25519; p=11qYAYKxCrfVS/7TyWQHOg7hcvPapiMlrwIaaPcHURo=";
+                let other = "v=DKIM1; k=ed25519; p=ZZqYAYKxCrfVS/7TyWQHOg7hcvPapiMlrwIaaPcHURo=";
+                Box::pin(futures::future::ready(Ok(vec![
+                    key.to_owned(),
+                    other.to_owned(),
+                ])))
+            }
+        }
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+
+        let err = retrieve_public_key(
+            &logger,
+            Arc::new(TestResolver {}),
+            "example.com".to_string(),
+            "dkim".to_string(),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(
+            err,
+            DKIMError::DnsMultipleRecords {
+                name: "dkim._domainkey.example.com".to_owned(),
+            }
+        );
+    }
+
     #[tokio::test]
     async fn test_retrieve_public_key_incompatible_version() {
         struct TestResolver {}
@@ -205,6 +926,11 @@ mod tests {
         )
         .await
         .unwrap_err();
-        assert_eq!(key, DKIMError::InappropriateKeyAlgorithm);
+        assert_eq!(
+            key,
+            DKIMError::InappropriateKeyAlgorithm {
+                key_type: "foo".to_owned()
+            }
+        );
     }
 }
@@ -0,0 +1,281 @@
+//! Public suffixes, the domains under which anyone can register a name (`com`,
+//! `co.uk`), so that a signature of such a domain can't vouch for every
+//! domain registered below it.
+//!
+//! Every top-level domain is a public suffix. The multi-label suffixes are the
+//! registry-operated second-level domains of the ICANN section of the Public
+//! Suffix List (<https://publicsuffix.org>) for the most common country code
+//! domains; the private section (hosting providers such as `github.io`) isn't
+//! included.
+
+const MULTI_LABEL_SUFFIXES: &[&str] = &[
+    // Argentina
+    "com.ar",
+    "edu.ar",
+    "gob.ar",
+    "gov.ar",
+    "int.ar",
+    "mil.ar",
+    "net.ar",
+    "org.ar",
+    // Australia
+    "asn.au",
+    "com.au",
+    "edu.au",
+    "gov.au",
+    "id.au",
+    "net.au",
+    "org.au",
+    // Austria
+    "ac.at",
+    "co.at",
+    "gv.at",
+    "or.at",
+    // Belgium
+    "ac.be",
+    // Brazil
+    "com.br",
+    "edu.br",
+    "gov.br",
+    "net.br",
+    "org.br",
+    // Canada
+    "ab.ca",
+    "bc.ca",
+    "gc.ca",
+    "mb.ca",
+    "nb.ca",
+    "nf.ca",
+    "nl.ca",
+    "ns.ca",
+    "nt.ca",
+    "nu.ca",
+    "on.ca",
+    "pe.ca",
+    "qc.ca",
+    "sk.ca",
+    "yk.ca",
+    // China
+    "ac.cn",
+    "com.cn",
+    "edu.cn",
+    "gov.cn",
+    "net.cn",
+    "org.cn",
+    // Colombia
+    "com.co",
+    "edu.co",
+    "gov.co",
+    "net.co",
+    "org.co",
+    // France
+    "asso.fr",
+    "com.fr",
+    "gouv.fr",
+    "nom.fr",
+    // Greece
+    "com.gr",
+    "edu.gr",
+    "gov.gr",
+    "net.gr",
+    "org.gr",
+    // Hong Kong
+    "com.hk",
+    "edu.hk",
+    "gov.hk",
+    "idv.hk",
+    "net.hk",
+    "org.hk",
+    // India
+    "ac.in",
+    "co.in",
+    "edu.in",
+    "firm.in",
+    "gen.in",
+    "gov.in",
+    "ind.in",
+    "net.in",
+    "org.in",
+    // Indonesia
+    "ac.id",
+    "co.id",
+    "go.id",
+    "or.id",
+    "web.id",
+    // Israel
+    "ac.il",
+    "co.il",
+    "gov.il",
+    "muni.il",
+    "net.il",
+    "org.il",
+    // Japan
+    "ac.jp",
+    "ad.jp",
+    "co.jp",
+    "ed.jp",
+    "go.jp",
+    "gr.jp",
+    "lg.jp",
+    "ne.jp",
+    "or.jp",
+    // Kenya
+    "ac.ke",
+    "co.ke",
+    "go.ke",
+    "or.ke",
+    // Malaysia
+    "com.my",
+    "edu.my",
+    "gov.my",
+    "net.my",
+    "org.my",
+    // Mexico
+    "com.mx",
+    "edu.mx",
+    "gob.mx",
+    "net.mx",
+    "org.mx",
+    // New Zealand
+    "ac.nz",
+    "co.nz",
+    "geek.nz",
+    "gen.nz",
+    "govt.nz",
+    "net.nz",
+    "org.nz",
+    "school.nz",
+    // Nigeria
+    "com.ng",
+    "edu.ng",
+    "gov.ng",
+    "net.ng",
+    "org.ng",
+    // Pakistan
+    "com.pk",
+    "edu.pk",
+    "gov.pk",
+    "net.pk",
+    "org.pk",
+    // Philippines
+    "com.ph",
+    "edu.ph",
+    "gov.ph",
+    "net.ph",
+    "org.ph",
+    // Poland
+    "com.pl",
+    "edu.pl",
+    "gov.pl",
+    "net.pl",
+    "org.pl",
+    // Portugal
+    "com.pt",
+    "edu.pt",
+    "gov.pt",
+    "org.pt",
+    // Russia
+    "com.ru",
+    "net.ru",
+    "org.ru",
+    "pp.ru",
+    // Saudi Arabia
+    "com.sa",
+    "edu.sa",
+    "gov.sa",
+    "net.sa",
+    "org.sa",
+    // Singapore
+    "com.sg",
+    "edu.sg",
+    "gov.sg",
+    "net.sg",
+    "org.sg",
+    "per.sg",
+    // South Africa
+    "ac.za",
+    "co.za",
+    "gov.za",
+    "net.za",
+    "org.za",
+    "web.za",
+    // South Korea
+    "ac.kr",
+    "co.kr",
+    "go.kr",
+    "ne.kr",
+    "or.kr",
+    "re.kr",
+    // Spain
+    "com.es",
+    "edu.es",
+    "gob.es",
+    "nom.es",
+    "org.es",
+    // Taiwan
+    "com.tw",
+    "edu.tw",
+    "gov.tw",
+    "idv.tw",
+    "net.tw",
+    "org.tw",
+    // Thailand
+    "ac.th",
+    "co.th",
+    "go.th",
+    "in.th",
+    "or.th",
+    // Turkey
+    "av.tr",
+    "bel.tr",
+    "com.tr",
+    "edu.tr",
+    "gen.tr",
+    "gov.tr",
+    "net.tr",
+    "org.tr",
+    // Ukraine
+    "com.ua",
+    "edu.ua",
+    "gov.ua",
+    "net.ua",
+    "org.ua",
+    // United Kingdom
+    "ac.uk",
+    "co.uk",
+    "gov.uk",
+    "ltd.uk",
+    "me.uk",
+    "net.uk",
+    "nhs.uk",
+    "org.uk",
+    "plc.uk",
+    "police.uk",
+    "sch.uk",
+    // Vietnam
+    "com.vn",
+    "edu.vn",
+    "gov.vn",
+    "net.vn",
+    "org.vn",
+];
+
+/// Whether `domain`, lowercase and without a trailing dot, is a public suffix
+pub(crate) fn is_public_suffix(domain: &str) -> bool {
+    !domain.contains('.') || MULTI_LABEL_SUFFIXES.contains(&domain)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_public_suffix() {
+        assert!(is_public_suffix("com"));
+        assert!(is_public_suffix("co.uk"));
+        assert!(is_public_suffix("com.au"));
+        assert!(!is_public_suffix("example.com"));
+        assert!(!is_public_suffix("victim.co.uk"));
+        assert!(!is_public_suffix("uk.example.com"));
+    }
+}
@@ -0,0 +1,218 @@
+//! Registry of the signers of many domains, for multi-tenant senders: the
+//! signers of a message are selected by the domain of its From header.
+
+use crate::{header_from_domain, normalize_domain, DKIMError, DKIMSigner};
+
+/// Domains a signer is registered for
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum DomainPattern {
+    /// The domain only
+    Exact(String),
+    /// The subdomains of the domain, at any depth
+    Subdomains(String),
+    /// The domain and its subdomains
+    Tree(String),
+    /// Any domain
+    Any,
+}
+
+impl DomainPattern {
+    pub(crate) fn parse(pattern: &str) -> Self {
+        // Patterns may be written as fully qualified domains
+        let pattern = normalize_domain(pattern.trim().trim_end_matches('.'));
+        match pattern.strip_prefix("*.") {
+            Some(domain) => DomainPattern::Subdomains(domain.to_owned()),
+            None if pattern == "*" => DomainPattern::Any,
+            None => DomainPattern::Exact(pattern),
+        }
+    }
+
+    /// Returns how specific the match of `domain` is, if it matches: longer
+    /// domains are more specific, and a domain more than its subdomains
+    pub(crate) fn matches(&self, domain: &str) -> Option<usize> {
+        let is_subdomain = |parent: &str| {
+            domain.len() > parent.len() + 1 && domain.ends_with(&format!(".{}", parent))
+        };
+        match self {
+            DomainPattern::Exact(d) | DomainPattern::Tree(d) if d == domain => {
+                Some(2 * d.len() + 2)
+            }
+            DomainPattern::Subdomains(d) | DomainPattern::Tree(d) if is_subdomain(d) => {
+                Some(2 * d.len() + 1)
+            }
+            DomainPattern::Any => Some(0),
+            _ => None,
+        }
+    }
+}
+
+/// Signers of many domains, selected by the From domain of the messages
+#[derive(Default)]
+pub struct SignerRegistry<'a> {
+    entries: Vec<(DomainPattern, DKIMSigner<'a>)>,
+}
+
+impl<'a> SignerRegistry<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register `signer` for the messages from the domains matching
+    /// `pattern`: `example.com` matches the domain only, `*.example.com` its
+    /// subdomains at any depth and `*` any domain, for a fallback signer.
+    ///
+    /// The most specific pattern matching the From domain of a message wins:
+    /// the domain itself, then its closest parent domain, then `*`. Several
+    /// signers registered with the same pattern all sign the message, for
+    /// instance with an RSA and an Ed25519 key.
+    pub fn with_signer(mut self, pattern: &str, signer: DKIMSigner<'a>) -> Self {
+        self.entries.push((DomainPattern::parse(pattern), signer));
+        self
+    }
+
+    /// Register `signer` for the messages from its signing domain and its
+    /// subdomains, which it can sign for with DMARC relaxed alignment
+    pub fn with_domain_signer(mut self, signer: DKIMSigner<'a>) -> Self {
+        let pattern = DomainPattern::Tree(normalize_domain(signer.signing_domain()));
+        self.entries.push((pattern, signer));
+        self
+    }
+
+    /// Signers of the messages from `domain`, in the order they were
+    /// registered. Empty if no pattern matches.
+    pub fn signers_for(&self, domain: &str) -> Vec<&DKIMSigner<'a>> {
+        let domain = normalize_domain(domain);
+        let matches: Vec<(usize, &DKIMSigner<'a>)> = self
+            .entries
+            .iter()
+            .filter_map(|(pattern, signer)| Some((pattern.matches(&domain)?, signer)))
+            .collect();
+        let Some(best) = matches.iter().map(|(specificity, _)| *specificity).max() else {
+            return vec![];
+        };
+        matches
+            .into_iter()
+            .filter(|(specificity, _)| *specificity == best)
+            .map(|(_, signer)| signer)
+            .collect()
+    }
+
+    /// Sign a message with the signers of its From domain. Returns one
+    /// `DKIM-Signature` header per signer, to add to the message.
+    pub fn sign<'b>(&self, email: &'b mailparse::ParsedMail<'b>) -> Result<Vec<String>, DKIMError> {
+        let domain = header_from_domain(email)?;
+        let signers = self.signers_for(&domain);
+        if signers.is_empty() {
+            return Err(DKIMError::NoSignerForDomain { domain });
+        }
+        signers
+            .into_iter()
+            .map(|signer| signer.sign(email))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{ed25519_private_key, rsa_private_key};
+    use crate::{DkimPrivateKey, SignerBuilder};
+
+    fn signer(domain: &str, selector: &str, private_key: DkimPrivateKey) -> DKIMSigner<'static> {
+        SignerBuilder::new()
+            .with_signed_headers(&["From", "Subject"])
+            .unwrap()
+            .with_private_key(private_key)
+            .with_selector(selector.to_owned())
+            .with_owned_logger(slog::Logger::root(slog::Discard, slog::o!()))
+            .with_signing_domain(domain.to_owned())
+            .build()
+            .unwrap()
+    }
+
+    fn selected(registry: &SignerRegistry, domain: &str) -> Vec<String> {
+        registry
+            .signers_for(domain)
+            .into_iter()
+            .map(|signer| format!("{}/{}", signer.signing_domain(), signer.selector()))
+            .collect()
+    }
+
+    #[test]
+    fn test_signers_for() {
+        let registry = SignerRegistry::new()
+            .with_signer("*", signer("esp.example", "esp", rsa_private_key()))
+            .with_domain_signer(signer("example.com", "rsa", rsa_private_key()))
+            .with_domain_signer(signer("example.com", "ed", ed25519_private_key()))
+            .with_signer(
+                "*.shop.example.com",
+                signer("shop.example.com", "s", rsa_private_key()),
+            )
+            .with_signer(
+                "Example.ORG.",
+                signer("example.org", "s", rsa_private_key()),
+            )
+            .with_signer(
+                "bücher.example",
+                signer("bücher.example", "s", rsa_private_key()),
+            );
+
+        assert_eq!(
+            selected(&registry, "example.com"),
+            vec!["example.com/rsa", "example.com/ed"]
+        );
+        assert_eq!(
+            selected(&registry, "news.EXAMPLE.com"),
+            vec!["example.com/rsa", "example.com/ed"]
+        );
+        // The closest parent domain wins
+        assert_eq!(
+            selected(&registry, "eu.shop.example.com"),
+            vec!["shop.example.com/s"]
+        );
+        assert_eq!(
+            selected(&registry, "shop.example.com"),
+            vec!["example.com/rsa", "example.com/ed"]
+        );
+        assert_eq!(selected(&registry, "example.org"), vec!["example.org/s"]);
+        assert_eq!(
+            selected(&registry, "sub.example.org"),
+            vec!["esp.example/esp"]
+        );
+        assert_eq!(
+            selected(&registry, "badexample.com"),
+            vec!["esp.example/esp"]
+        );
+        // Internationalized domains match in both forms
+        assert_eq!(
+            selected(&registry, "xn--bcher-kva.example"),
+            vec!["bücher.example/s"]
+        );
+    }
+
+    #[test]
+    fn test_sign() {
+        let registry = SignerRegistry::new().with_domain_signer(signer(
+            "example.com",
+            "rsa",
+            rsa_private_key(),
+        ));
+
+        let email = mailparse::parse_mail(
+            b"From: Joe <joe@news.example.com>\r\nSubject: hi\r\n\r\nHello\r\n",
+        )
+        .unwrap();
+        let headers = registry.sign(&email).unwrap();
+        assert_eq!(headers.len(), 1);
+        assert!(headers[0].contains("d=example.com"));
+
+        let email = mailparse::parse_mail(b"From: joe@example.net\r\nSubject: hi\r\n\r\nHello\r\n")
+            .unwrap();
+        assert_eq!(
+            registry.sign(&email).unwrap_err(),
+            DKIMError::NoSignerForDomain {
+                domain: "example.net".to_owned(),
+            }
+        );
+    }
+}
@@ -0,0 +1,687 @@
+//! Reports of verification results. [VerificationReport] aggregates results
+//! for operators auditing their mail flows, grouped by signing domain,
+//! selector and algorithm. [MessageReport] details the verification of a
+//! single message, for SIEM pipelines. The reports are serialized to JSON
+//! with the `serde` feature.
+
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+#[cfg(feature = "dns")]
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+#[cfg(feature = "dns")]
+use std::time::Instant;
+
+#[cfg(feature = "dns")]
+use futures::future::BoxFuture;
+
+#[cfg(feature = "dns")]
+use crate::{dns, SignatureScope, VerificationObserver, VerificationPolicy};
+use crate::{DKIMError, DKIMResult, Verdict};
+
+/// Version of the JSON schema of [MessageReport::to_json]
+pub const MESSAGE_REPORT_VERSION: u32 = 1;
+
+/// Key identifying the signatures aggregated together. The selector and the
+/// algorithm are empty when they are unknown, for instance for messages
+/// without a signature.
+#[derive(Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ReportKey {
+    pub domain: String,
+    pub selector: String,
+    pub algorithm: String,
+}
+
+/// Statistics of the signatures sharing a [ReportKey]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReportEntry {
+    pub total: usize,
+    pub pass: usize,
+    pub fail: usize,
+    pub neutral: usize,
+    /// Number of failures per error code, see [DKIMError::code]
+    pub failure_reasons: BTreeMap<&'static str, usize>,
+    /// Sizes in bits of the keys seen in passing signatures
+    pub key_sizes: BTreeSet<usize>,
+}
+
+impl ReportEntry {
+    /// Ratio of passing signatures, between 0 and 1
+    pub fn pass_rate(&self) -> f64 {
+        if self.total == 0 {
+            return 0.0;
+        }
+        self.pass as f64 / self.total as f64
+    }
+
+    /// Failure reasons, the most common first
+    pub fn common_failure_reasons(&self) -> Vec<(&'static str, usize)> {
+        let mut reasons: Vec<_> = self
+            .failure_reasons
+            .iter()
+            .map(|(code, count)| (*code, *count))
+            .collect();
+        reasons.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+        reasons
+    }
+}
+
+/// Aggregated report of verification results
+#[derive(Debug, Clone, Default)]
+pub struct VerificationReport {
+    entries: BTreeMap<ReportKey, ReportEntry>,
+}
+
+impl VerificationReport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a verification result to the report
+    pub fn add(&mut self, result: &DKIMResult) {
+        let key = ReportKey {
+            domain: result.domain_used(),
+            selector: result.selector().unwrap_or_default().to_owned(),
+            algorithm: result.algorithm().unwrap_or_default().to_owned(),
+        };
+        let entry = self.entries.entry(key).or_default();
+        entry.total += 1;
+        match result.verdict() {
+            Verdict::Pass => entry.pass += 1,
+            Verdict::Neutral => entry.neutral += 1,
+            _ => entry.fail += 1,
+        }
+        if let Some(err) = result.error() {
+            *entry.failure_reasons.entry(err.code()).or_default() += 1;
+        }
+        if let Some(key_size) = result.key_size() {
+            entry.key_sizes.insert(key_size);
+        }
+    }
+
+    /// Add a message which couldn't be verified, for instance because it was
+    /// malformed. It's reported as a failure with an unknown domain.
+    pub fn add_error(&mut self, err: &DKIMError) {
+        let entry = self.entries.entry(ReportKey::default()).or_default();
+        entry.total += 1;
+        entry.fail += 1;
+        *entry.failure_reasons.entry(err.code()).or_default() += 1;
+    }
+
+    /// Iterate over the entries of the report, ordered by key
+    pub fn entries(&self) -> impl Iterator<Item = (&ReportKey, &ReportEntry)> {
+        self.entries.iter()
+    }
+
+    /// Serialize the report as a JSON array, one object per entry, with the
+    /// key and the statistics of the entry. The failure reasons are ordered
+    /// from the most common.
+    #[cfg(feature = "serde")]
+    pub fn to_json(&self) -> String {
+        #[derive(serde::Serialize)]
+        struct Entry<'a> {
+            #[serde(flatten)]
+            key: &'a ReportKey,
+            total: usize,
+            pass: usize,
+            fail: usize,
+            neutral: usize,
+            pass_rate: f64,
+            key_sizes: &'a BTreeSet<usize>,
+            #[serde(serialize_with = "serialize_pairs")]
+            failure_reasons: Vec<(&'static str, usize)>,
+        }
+
+        let entries: Vec<Entry> = self
+            .entries
+            .iter()
+            .map(|(key, entry)| Entry {
+                key,
+                total: entry.total,
+                pass: entry.pass,
+                fail: entry.fail,
+                neutral: entry.neutral,
+                pass_rate: entry.pass_rate(),
+                key_sizes: &entry.key_sizes,
+                failure_reasons: entry.common_failure_reasons(),
+            })
+            .collect();
+        serde_json::to_string(&entries).expect("reports serialize to JSON")
+    }
+
+    /// Serialize the report as CSV, with a header line. Key sizes are
+    /// separated by `;` and failure reasons are formatted as `code:count`.
+    pub fn to_csv(&self) -> String {
+        let mut out = String::from(
+            "domain,selector,algorithm,total,pass,fail,neutral,pass_rate,key_sizes,failure_reasons\r\n",
+        );
+        for (key, entry) in &self.entries {
+            let key_sizes: Vec<String> = entry.key_sizes.iter().map(|v| v.to_string()).collect();
+            let reasons: Vec<String> = entry
+                .common_failure_reasons()
+                .iter()
+                .map(|(code, count)| format!("{}:{}", code, count))
+                .collect();
+            write!(
+                out,
+                "{},{},{},{},{},{},{},{:.4},{},{}\r\n",
+                csv_field(&key.domain),
+                csv_field(&key.selector),
+                csv_field(&key.algorithm),
+                entry.total,
+                entry.pass,
+                entry.fail,
+                entry.neutral,
+                entry.pass_rate(),
+                key_sizes.join(";"),
+                reasons.join(";"),
+            )
+            .expect("writing to a String should not fail");
+        }
+        out
+    }
+}
+
+impl Extend<Result<DKIMResult, DKIMError>> for VerificationReport {
+    fn extend<I: IntoIterator<Item = Result<DKIMResult, DKIMError>>>(&mut self, iter: I) {
+        for result in iter {
+            match result {
+                Ok(result) => self.add(&result),
+                Err(err) => self.add_error(&err),
+            }
+        }
+    }
+}
+
+impl FromIterator<Result<DKIMResult, DKIMError>> for VerificationReport {
+    fn from_iter<I: IntoIterator<Item = Result<DKIMResult, DKIMError>>>(iter: I) -> Self {
+        let mut report = Self::new();
+        report.extend(iter);
+        report
+    }
+}
+
+/// DNS query made while verifying a signature
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct DnsQueryReport {
+    pub name: String,
+    /// TXT records returned
+    pub records: Vec<String>,
+    pub error: Option<String>,
+    #[cfg_attr(
+        feature = "serde",
+        serde(rename = "duration_ms", serialize_with = "serialize_millis")
+    )]
+    pub duration: Duration,
+}
+
+/// Verification of a signature of the From domain
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct SignatureReport {
+    /// Index of the signature among the signatures of the message, see
+    /// [DKIMResult::signature_index]
+    pub index: Option<usize>,
+    pub domain: String,
+    /// Empty when the signature doesn't parse
+    pub selector: String,
+    /// `pass` or `fail`
+    pub result: &'static str,
+    /// See [DKIMError::code]
+    pub error_code: Option<&'static str>,
+    pub error: Option<String>,
+    #[cfg_attr(feature = "serde", serde(rename = "dns"))]
+    pub dns_queries: Vec<DnsQueryReport>,
+    #[cfg_attr(
+        feature = "serde",
+        serde(rename = "duration_ms", serialize_with = "serialize_millis")
+    )]
+    pub duration: Duration,
+}
+
+impl SignatureReport {
+    /// Report of the result of a signature, without the DNS queries
+    pub fn new(result: &DKIMResult) -> Self {
+        Self {
+            index: result.signature_index(),
+            domain: result.domain_used(),
+            selector: result.selector().unwrap_or_default().to_owned(),
+            result: result.summary(),
+            error_code: result.reason_code(),
+            error: result.error().map(|err| err.to_string()),
+            dns_queries: vec![],
+            duration: Duration::ZERO,
+        }
+    }
+}
+
+/// Detailed report of the verification of a message. Only the signatures of
+/// the From domain are reported, up to the first one which passes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct MessageReport {
+    pub from_domain: String,
+    /// `pass`, `fail` or `neutral`
+    pub result: &'static str,
+    /// The result followed by the reason of a failure, as displayed by
+    /// [DKIMResult]
+    pub detail: String,
+    /// See [DKIMResult::signature_index]
+    pub signature_index: Option<usize>,
+    /// See [DKIMResult::is_testing]
+    pub testing: bool,
+    /// See [DKIMResult::warnings]
+    pub warnings: Vec<String>,
+    pub signatures: Vec<SignatureReport>,
+    #[cfg_attr(
+        feature = "serde",
+        serde(rename = "duration_ms", serialize_with = "serialize_millis")
+    )]
+    pub duration: Duration,
+}
+
+impl MessageReport {
+    /// Report of a verification result, without the details of the
+    /// signatures
+    pub fn new(from_domain: &str, result: &DKIMResult, duration: Duration) -> Self {
+        Self {
+            from_domain: from_domain.to_owned(),
+            result: result.summary(),
+            detail: result.to_string(),
+            signature_index: result.signature_index(),
+            testing: result.is_testing(),
+            warnings: result.warnings().iter().map(|w| w.to_string()).collect(),
+            signatures: vec![],
+            duration,
+        }
+    }
+
+    /// Serialize the report as JSON. The schema is stable within a
+    /// [MESSAGE_REPORT_VERSION]; durations are in milliseconds:
+    ///
+    /// ```text
+    /// {
+    ///   "version": 1,
+    ///   "from_domain": string,
+    ///   "result": "pass" | "fail" | "neutral",
+    ///   "detail": string,
+    ///   "signature_index": number | null,
+    ///   "testing": boolean,
+    ///   "warnings": [string],
+    ///   "signatures": [{
+    ///     "index": number | null,
+    ///     "domain": string,
+    ///     "selector": string,
+    ///     "result": "pass" | "fail",
+    ///     "error_code": string | null,
+    ///     "error": string | null,
+    ///     "dns": [{
+    ///       "name": string,
+    ///       "records": [string],
+    ///       "error": string | null,
+    ///       "duration_ms": number
+    ///     }],
+    ///     "duration_ms": number
+    ///   }],
+    ///   "duration_ms": number
+    /// }
+    /// ```
+    #[cfg(feature = "serde")]
+    pub fn to_json(&self) -> String {
+        #[derive(serde::Serialize)]
+        struct Versioned<'a> {
+            version: u32,
+            #[serde(flatten)]
+            report: &'a MessageReport,
+        }
+
+        serde_json::to_string(&Versioned {
+            version: MESSAGE_REPORT_VERSION,
+            report: self,
+        })
+        .expect("reports serialize to JSON")
+    }
+}
+
+/// Verify a message like [crate::verify_email_with_resolver_and_policy] and
+/// report the details of the verification
+#[cfg(feature = "dns")]
+pub async fn verify_with_report<'a>(
+    logger: &slog::Logger,
+    from_domain: &str,
+    email: &'a mailparse::ParsedMail<'a>,
+    resolver: Arc<dyn dns::Lookup>,
+    policy: &VerificationPolicy,
+) -> Result<MessageReport, DKIMError> {
+    let start = Instant::now();
+    let recorder = Arc::new(Recorder {
+        inner: policy.observer().cloned(),
+        signatures: Mutex::new(vec![]),
+    });
+    let policy = policy
+        .clone()
+        .with_observer(Arc::clone(&recorder) as Arc<dyn VerificationObserver>);
+    let resolver = Arc::new(RecordingLookup {
+        inner: resolver,
+        recorder: Arc::clone(&recorder),
+    });
+
+    let results = crate::verify_signatures_with_resolver(
+        logger,
+        SignatureScope::Author(from_domain),
+        email,
+        resolver,
+        &policy,
+        None,
+    )
+    .await;
+
+    // The signatures with a selector are the ones whose verification was
+    // started, recorded in the same order
+    let mut recorded = std::mem::take(&mut *recorder.signatures.lock().unwrap()).into_iter();
+    let signatures = results
+        .iter()
+        .map(|result| {
+            let mut signature = SignatureReport::new(result);
+            if result.selector().is_some() {
+                if let Some((recorded, _)) = recorded.next() {
+                    signature.dns_queries = recorded.dns_queries;
+                    signature.duration = recorded.duration;
+                }
+            }
+            signature
+        })
+        .collect();
+
+    let result = crate::author_result(&policy, from_domain, results);
+    let mut report = MessageReport::new(from_domain, &result, start.elapsed());
+    report.signatures = signatures;
+    Ok(report)
+}
+
+/// Observer recording the DNS queries and the duration of the verification
+/// of the signatures, forwarding the events to the observer of the policy if
+/// any. The results of the signatures are taken from the verification.
+#[cfg(feature = "dns")]
+struct Recorder {
+    inner: Option<Arc<dyn VerificationObserver>>,
+    // Signatures with the time their verification started
+    signatures: Mutex<Vec<(RecordedSignature, Instant)>>,
+}
+
+#[cfg(feature = "dns")]
+#[derive(Default)]
+struct RecordedSignature {
+    dns_queries: Vec<DnsQueryReport>,
+    duration: Duration,
+}
+
+#[cfg(feature = "dns")]
+impl Recorder {
+    fn finish(&self) {
+        if let Some((signature, start)) = self.signatures.lock().unwrap().last_mut() {
+            signature.duration = start.elapsed();
+        }
+    }
+}
+
+#[cfg(feature = "dns")]
+impl VerificationObserver for Recorder {
+    fn signature_started(&self, domain: &str, selector: &str) {
+        self.signatures
+            .lock()
+            .unwrap()
+            .push((RecordedSignature::default(), Instant::now()));
+        if let Some(inner) = &self.inner {
+            inner.signature_started(domain, selector);
+        }
+    }
+
+    fn dns_lookup(&self, name: &str, latency: Duration, result: Result<(), &DKIMError>) {
+        if let Some(inner) = &self.inner {
+            inner.dns_lookup(name, latency, result);
+        }
+    }
+
+    fn signature_passed(&self, domain: &str, selector: &str) {
+        self.finish();
+        if let Some(inner) = &self.inner {
+            inner.signature_passed(domain, selector);
+        }
+    }
+
+    fn signature_failed(&self, domain: &str, selector: &str, reason: &DKIMError) {
+        self.finish();
+        if let Some(inner) = &self.inner {
+            inner.signature_failed(domain, selector, reason);
+        }
+    }
+}
+
+/// Lookup recording the records returned to the signature being verified
+#[cfg(feature = "dns")]
+struct RecordingLookup {
+    inner: Arc<dyn dns::Lookup>,
+    recorder: Arc<Recorder>,
+}
+
+#[cfg(feature = "dns")]
+impl dns::Lookup for RecordingLookup {
+    fn lookup_txt<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<Vec<String>, DKIMError>> {
+        Box::pin(async move {
+            let start = Instant::now();
+            let res = self.inner.lookup_txt(name).await;
+            let query = DnsQueryReport {
+                name: name.to_owned(),
+                records: res.as_ref().cloned().unwrap_or_default(),
+                error: res.as_ref().err().map(|err| err.to_string()),
+                duration: start.elapsed(),
+            };
+            if let Some((signature, _)) = self.recorder.signatures.lock().unwrap().last_mut() {
+                signature.dns_queries.push(query);
+            }
+            res
+        })
+    }
+}
+
+#[cfg(feature = "serde")]
+fn serialize_millis<S: serde::Serializer>(
+    duration: &Duration,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64(duration.as_secs_f64() * 1000.0)
+}
+
+#[cfg(feature = "serde")]
+fn serialize_pairs<S: serde::Serializer>(
+    pairs: &[(&'static str, usize)],
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_map(pairs.iter().copied())
+}
+
+fn csv_field(value: &str) -> Cow<'_, str> {
+    if value.contains([',', '"', '\r', '\n']) {
+        Cow::Owned(format!("\"{}\"", value.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::canonicalization::Type;
+
+    fn results() -> Vec<Result<DKIMResult, DKIMError>> {
+        let pass = |key_size| {
+            DKIMResult::pass("example.com".to_owned(), Type::Relaxed, Type::Simple)
+                .with_signature("s1", "rsa-sha256")
+                .with_key_size(key_size)
+        };
+        vec![
+            Ok(pass(2048)),
+            Ok(pass(1024)),
+            Ok(
+                DKIMResult::fail(DKIMError::BodyHashDidNotVerify, "example.com".to_owned())
+                    .with_signature("s1", "rsa-sha256"),
+            ),
+            Ok(DKIMResult::neutral("example.org".to_owned())),
+            Err(DKIMError::MalformedEmail {
+                reason: "missing From header",
+                source: None,
+            }),
+        ]
+    }
+
+    #[test]
+    fn test_aggregate() {
+        let report: VerificationReport = results().into_iter().collect();
+        let entries: Vec<_> = report.entries().collect();
+        assert_eq!(entries.len(), 3);
+
+        let (key, entry) = entries[1];
+        assert_eq!(key.domain, "example.com");
+        assert_eq!(key.selector, "s1");
+        assert_eq!(key.algorithm, "rsa-sha256");
+        assert_eq!((entry.total, entry.pass, entry.fail), (3, 2, 1));
+        assert!((entry.pass_rate() - 2.0 / 3.0).abs() < f64::EPSILON);
+        assert_eq!(
+            entry.common_failure_reasons(),
+            vec![("DKIM_BODY_HASH_DID_NOT_VERIFY", 1)]
+        );
+        assert_eq!(
+            entry.key_sizes.iter().collect::<Vec<_>>(),
+            vec![&1024, &2048]
+        );
+
+        assert_eq!(entries[0].0.domain, "");
+        assert_eq!(entries[0].1.fail, 1);
+        assert_eq!(entries[2].1.neutral, 1);
+    }
+
+    #[test]
+    fn test_to_csv() {
+        let report: VerificationReport = results().into_iter().take(3).collect();
+        assert_eq!(
+            report.to_csv(),
+            "domain,selector,algorithm,total,pass,fail,neutral,pass_rate,key_sizes,failure_reasons\r\n\
+             example.com,s1,rsa-sha256,3,2,1,0,0.6667,1024;2048,DKIM_BODY_HASH_DID_NOT_VERIFY:1\r\n"
+        );
+        assert_eq!(csv_field("a,b"), "\"a,b\"");
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_to_json() {
+        let report: VerificationReport = results().into_iter().take(3).collect();
+        assert_eq!(
+            report.to_json(),
+            r#"[{"domain":"example.com","selector":"s1","algorithm":"rsa-sha256","total":3,"pass":2,"fail":1,"neutral":0,"pass_rate":0.6666666666666666,"key_sizes":[1024,2048],"failure_reasons":{"DKIM_BODY_HASH_DID_NOT_VERIFY":1}}]"#
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_message_report_json() {
+        let result = DKIMResult::fail(DKIMError::BodyHashDidNotVerify, "example.com".to_owned());
+        let mut report = MessageReport::new("example.com", &result, Duration::from_micros(1500));
+        report.signatures.push(SignatureReport {
+            index: Some(0),
+            domain: "example.com".to_owned(),
+            selector: "s1".to_owned(),
+            result: "fail",
+            error_code: Some("DKIM_BODY_HASH_DID_NOT_VERIFY"),
+            error: Some("body hash did not verify".to_owned()),
+            dns_queries: vec![DnsQueryReport {
+                name: "s1._domainkey.example.com".to_owned(),
+                records: vec!["v=DKIM1; p=key".to_owned()],
+                error: None,
+                duration: Duration::from_millis(1),
+            }],
+            duration: Duration::from_millis(1),
+        });
+        assert_eq!(
+            report.to_json(),
+            r#"{"version":1,"from_domain":"example.com","result":"fail","detail":"fail (body hash did not verify)","signature_index":null,"testing":false,"warnings":[],"signatures":[{"index":0,"domain":"example.com","selector":"s1","result":"fail","error_code":"DKIM_BODY_HASH_DID_NOT_VERIFY","error":"body hash did not verify","dns":[{"name":"s1._domainkey.example.com","records":["v=DKIM1; p=key"],"error":null,"duration_ms":1.0}],"duration_ms":1.0}],"duration_ms":1.5}"#
+        );
+    }
+
+    #[cfg(feature = "dns")]
+    #[tokio::test]
+    async fn test_verify_with_report() {
+        struct Records;
+        impl dns::Lookup for Records {
+            fn lookup_txt<'a>(
+                &'a self,
+                name: &'a str,
+            ) -> BoxFuture<'a, Result<Vec<String>, DKIMError>> {
+                let res = match name {
+                    "brisbane._domainkey.football.example.com" => Ok(vec![
+                        "v=DKIM1; k=ed25519; p=11qYAYKxCrfVS/7TyWQHOg7hcvPapiMlrwIaaPcHURo="
+                            .to_owned(),
+                    ]),
+                    _ => Err(DKIMError::NoKeyForSignature),
+                };
+                Box::pin(futures::future::ready(res))
+            }
+        }
+
+        let raw_email = r#"DKIM-Signature: v=1; a=ed25519-sha256; c=relaxed/relaxed;
+ d=football.example.com; i=@football.example.com;
+ q=dns/txt; s=brisbane; t=1528637909; h=from : to :
+ subject : date : message-id : from : subject : date;
+ bh=2jUSOH9NhtVGCQWNr9BrIAPreKQjO6Sn7XIkfJVOzv8=;
+ b=/gCrinpcQOoIfuHNQIbq4pgh9kyIK3AQUdt9OdqQehSwhEIug4D11Bus
+ Fa3bT3FY5OsU7ZbnKELq+eXdp1Q1Dw==
+From: Joe SixPack <joe@football.example.com>
+To: Suzie Q <suzie@shopping.example.net>
+Subject: Is dinner ready?
+Date: Fri, 11 Jul 2003 21:00:37 -0700 (PDT)
+Message-ID: <20030712040037.46341.5F8J@football.example.com>
+
+Hi.
+
+We lost the game.  Are you hungry yet?
+
+Joe."#
+            .replace('\n', "\r\n");
+        let email = mailparse::parse_mail(raw_email.as_bytes()).unwrap();
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+
+        let report = verify_with_report(
+            &logger,
+            "football.example.com",
+            &email,
+            Arc::new(Records),
+            &VerificationPolicy::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(report.result, "pass");
+        assert_eq!(report.signature_index, Some(0));
+        assert_eq!(report.signatures.len(), 1);
+        let signature = &report.signatures[0];
+        assert_eq!(
+            (
+                signature.index,
+                signature.selector.as_str(),
+                signature.result
+            ),
+            (Some(0), "brisbane", "pass")
+        );
+        assert_eq!(signature.dns_queries.len(), 1);
+        assert_eq!(
+            signature.dns_queries[0].name,
+            "brisbane._domainkey.football.example.com"
+        );
+        #[cfg(feature = "serde")]
+        assert!(report
+            .to_json()
+            .starts_with(r#"{"version":1,"from_domain":"football.example.com","result":"pass""#));
+    }
+}
@@ -1,13 +1,58 @@
-use crate::{canonicalization, DKIMError};
+use std::ops::Range;
+
+use crate::{canonicalization, Commitment, DKIMError};
+
+/// Verdict of the DKIM verification of a message
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum Verdict {
+    /// A signature of the domain verified
+    Pass,
+    /// The signatures of the domain failed to verify, see
+    /// [DKIMResult::reason_code]
+    Fail,
+    /// The message has no signature of the domain
+    Neutral,
+}
+
+impl Verdict {
+    /// Returns the verdict as in the `Authentication-Results` header
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Verdict::Pass => "pass",
+            Verdict::Fail => "fail",
+            Verdict::Neutral => "neutral",
+        }
+    }
+}
+
+impl std::fmt::Display for Verdict {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
 
 #[derive(Clone)]
 /// Result of the DKIM verification
 pub struct DKIMResult {
-    value: &'static str,
+    verdict: Verdict,
     error: Option<DKIMError>,
     domain_used: String,
     header_canonicalization_type: Option<canonicalization::Type>,
     body_canonicalization_type: Option<canonicalization::Type>,
+    warnings: Vec<DKIMError>,
+    signature_age: Option<i64>,
+    selector: Option<String>,
+    algorithm: Option<String>,
+    key_size: Option<usize>,
+    testing: bool,
+    key_notes: Option<String>,
+    signature_index: Option<usize>,
+    signature_header: Option<String>,
+    selection_reason: Option<String>,
+    identity_match: Option<bool>,
+    commitment: Option<Commitment>,
+    unsigned_body: Option<Range<usize>>,
 }
 impl DKIMResult {
     /// Constructs a `pass` result
@@ -17,34 +62,135 @@ impl DKIMResult {
         body_canonicalization_type: canonicalization::Type,
     ) -> Self {
         DKIMResult {
-            value: "pass",
+            verdict: Verdict::Pass,
             error: None,
             domain_used,
             header_canonicalization_type: Some(header_canonicalization_type),
             body_canonicalization_type: Some(body_canonicalization_type),
+            warnings: vec![],
+            signature_age: None,
+            selector: None,
+            algorithm: None,
+            key_size: None,
+            testing: false,
+            key_notes: None,
+            signature_index: None,
+            signature_header: None,
+            selection_reason: None,
+            identity_match: None,
+            commitment: None,
+            unsigned_body: None,
         }
     }
     /// Constructs a `neutral` result
     pub fn neutral(domain_used: String) -> Self {
         DKIMResult {
-            value: "neutral",
+            verdict: Verdict::Neutral,
             error: None,
             domain_used,
             header_canonicalization_type: None,
             body_canonicalization_type: None,
+            warnings: vec![],
+            signature_age: None,
+            selector: None,
+            algorithm: None,
+            key_size: None,
+            testing: false,
+            key_notes: None,
+            signature_index: None,
+            signature_header: None,
+            selection_reason: None,
+            identity_match: None,
+            commitment: None,
+            unsigned_body: None,
+        }
+    }
+    /// Constructs a `pass` result for a signature which doesn't use the DKIM
+    /// canonicalizations
+    #[cfg(feature = "domainkeys")]
+    pub(crate) fn pass_without_canonicalization(domain_used: String) -> Self {
+        DKIMResult {
+            verdict: Verdict::Pass,
+            ..Self::neutral(domain_used)
         }
     }
     /// Constructs a `fail` result with a reason
     pub fn fail(reason: DKIMError, domain_used: String) -> Self {
         DKIMResult {
-            value: "fail",
+            verdict: Verdict::Fail,
             error: Some(reason),
             domain_used,
             header_canonicalization_type: None,
             body_canonicalization_type: None,
+            warnings: vec![],
+            signature_age: None,
+            selector: None,
+            algorithm: None,
+            key_size: None,
+            testing: false,
+            key_notes: None,
+            signature_index: None,
+            signature_header: None,
+            selection_reason: None,
+            identity_match: None,
+            commitment: None,
+            unsigned_body: None,
         }
     }
 
+    pub(crate) fn with_warnings(mut self, warnings: Vec<DKIMError>) -> Self {
+        self.warnings = warnings;
+        self
+    }
+
+    pub(crate) fn with_signature_age(mut self, signature_age: Option<i64>) -> Self {
+        self.signature_age = signature_age;
+        self
+    }
+
+    pub(crate) fn with_signature(mut self, selector: &str, algorithm: &str) -> Self {
+        self.selector = Some(selector.to_owned());
+        self.algorithm = Some(algorithm.to_owned());
+        self
+    }
+
+    pub(crate) fn with_signature_header(mut self, index: usize, value: &str) -> Self {
+        self.signature_index = Some(index);
+        self.signature_header = Some(value.to_owned());
+        self
+    }
+
+    pub(crate) fn with_key_size(mut self, key_size: usize) -> Self {
+        self.key_size = Some(key_size);
+        self
+    }
+
+    pub(crate) fn with_key_record(mut self, testing: bool, notes: Option<String>) -> Self {
+        self.testing = testing;
+        self.key_notes = notes;
+        self
+    }
+
+    pub(crate) fn with_identity_match(mut self, identity_match: Option<bool>) -> Self {
+        self.identity_match = identity_match;
+        self
+    }
+
+    pub(crate) fn with_commitment(mut self, commitment: Commitment) -> Self {
+        self.commitment = Some(commitment);
+        self
+    }
+
+    pub(crate) fn with_unsigned_body(mut self, unsigned_body: Option<Range<usize>>) -> Self {
+        self.unsigned_body = unsigned_body;
+        self
+    }
+
+    pub(crate) fn with_selection_reason(mut self, reason: String) -> Self {
+        self.selection_reason = Some(reason);
+        self
+    }
+
     pub fn error(&self) -> Option<DKIMError> {
         self.error.clone()
     }
@@ -54,9 +200,25 @@ impl DKIMResult {
         self.domain_used.to_lowercase()
     }
 
+    /// Returns the verdict of the verification
+    pub fn verdict(&self) -> Verdict {
+        self.verdict
+    }
+
     /// Returns the verification result as a summary: fail, neutral or pass.
     pub fn summary(&self) -> &'static str {
-        self.value
+        self.verdict.as_str()
+    }
+
+    /// Returns the stable code of the reason of the failure, see
+    /// [DKIMError::code]
+    pub fn reason_code(&self) -> Option<&'static str> {
+        self.error.as_ref().map(DKIMError::code)
+    }
+
+    /// Returns the stable codes of the warnings, see [DKIMResult::warnings]
+    pub fn warning_codes(&self) -> Vec<&'static str> {
+        self.warnings.iter().map(DKIMError::code).collect()
     }
 
     /// Returns the header canocalization type
@@ -69,13 +231,109 @@ impl DKIMResult {
         self.body_canonicalization_type.clone()
     }
 
+    /// Returns the policy violations that didn't prevent the verification
+    /// from passing. See [crate::PolicyAction::Flag].
+    pub fn warnings(&self) -> &[DKIMError] {
+        &self.warnings
+    }
+
+    /// Returns the age in seconds of the signature used to pass the DKIM
+    /// verification: the verification time minus its `t=` tag. Negative if
+    /// the signature is dated in the future, `None` if the signature has no
+    /// `t=` tag or the current time isn't known.
+    pub fn signature_age(&self) -> Option<i64> {
+        self.signature_age
+    }
+
+    /// Returns the selector (`s=` tag) of the signature used to pass the DKIM
+    /// verification, or of the last signature that failed
+    pub fn selector(&self) -> Option<&str> {
+        self.selector.as_deref()
+    }
+
+    /// Returns the algorithm (`a=` tag) of the signature used to pass the DKIM
+    /// verification, or of the last signature that failed
+    pub fn algorithm(&self) -> Option<&str> {
+        self.algorithm.as_deref()
+    }
+
+    /// Returns the index, among the `DKIM-Signature` headers of the email, of
+    /// the signature used to pass the DKIM verification, or of the last
+    /// signature that failed
+    pub fn signature_index(&self) -> Option<usize> {
+        self.signature_index
+    }
+
+    /// Returns the raw value of the `DKIM-Signature` header designated by
+    /// [DKIMResult::signature_index], for instance to quote it in the
+    /// `header.b` property of Authentication-Results
+    pub fn signature_header(&self) -> Option<&str> {
+        self.signature_header.as_deref()
+    }
+
+    /// Returns the size in bits of the key used to pass the DKIM verification
+    pub fn key_size(&self) -> Option<usize> {
+        self.key_size
+    }
+
+    /// Returns whether the key record of the signing domain has the testing
+    /// flag (`t=y`). Per RFC 6376 the message should then be treated as if it
+    /// was unsigned for policy purposes, whatever the result.
+    pub fn is_testing(&self) -> bool {
+        self.testing
+    }
+
+    /// Returns the notes (`n=` tag) of the key record of the signing domain,
+    /// which administrators use to annotate rotations and deprecations
+    pub fn key_notes(&self) -> Option<&str> {
+        self.key_notes.as_deref()
+    }
+
+    /// Returns why the signature was chosen among the passing signatures,
+    /// with [crate::SignatureSelection::Best]
+    pub fn selection_reason(&self) -> Option<&str> {
+        self.selection_reason.as_deref()
+    }
+
+    /// Returns whether the identity of the signature (`i=` tag) is the From
+    /// address, when checked with
+    /// [crate::VerificationPolicy::with_identity_check] and `i=` has a
+    /// local-part
+    pub fn identity_matches_from(&self) -> Option<bool> {
+        self.identity_match
+    }
+
+    /// Returns the commitment to the message verified by the signature that
+    /// passed, see [Commitment]
+    pub fn commitment(&self) -> Option<&Commitment> {
+        self.commitment.as_ref()
+    }
+
+    /// Range of the body, after the empty line separating it from the
+    /// headers, which isn't covered by the body length limit (`l=` tag) of
+    /// the passing signature: the content appended after the message was
+    /// signed. `None` when the whole body is signed.
+    pub fn unsigned_body(&self) -> Option<Range<usize>> {
+        self.unsigned_body.clone()
+    }
+
     /// Similar to `summary` but with detail on fail. Typically used for the
     /// `Authentication-Results` header.
+    #[deprecated(
+        note = "match on `verdict` and `reason_code` instead, or use `to_string` to display the result"
+    )]
     pub fn with_detail(&self) -> String {
-        if let Some(err) = self.error() {
-            format!("{} ({})", self.value, err)
-        } else {
-            self.value.to_owned()
+        self.to_string()
+    }
+}
+
+/// Displays the verdict, followed by the reason of the failure, as in
+/// `fail (signature did not verify)`
+impl std::fmt::Display for DKIMResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.error {
+            Some(err) => write!(f, "{} ({})", self.verdict, err),
+            None => write!(f, "{}", self.verdict),
         }
     }
 }
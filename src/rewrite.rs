@@ -0,0 +1,246 @@
+//! Utilities to rewrite raw messages while preserving their bytes
+
+use std::ops::Range;
+
+use crate::header::HEADER;
+
+/// Signature related headers commonly removed by forwarders and re-signers
+pub const SIGNATURE_HEADERS: &[&str] = &[
+    HEADER,
+    "ARC-Seal",
+    "ARC-Message-Signature",
+    "ARC-Authentication-Results",
+];
+
+/// Raw header field of a message, including its continuation lines and line
+/// endings
+pub(crate) struct RawHeaderField<'a> {
+    pub(crate) name: &'a str,
+    pub(crate) value: &'a [u8],
+    pub(crate) range: Range<usize>,
+}
+
+/// Split the header section of a raw message into header fields. Returns the
+/// fields and the offset where the header section ends (the offset of the
+/// empty line separating the headers and the body, or the length of the
+/// message if there is no body).
+pub(crate) fn header_fields(raw: &[u8]) -> (Vec<RawHeaderField<'_>>, usize) {
+    let mut ranges: Vec<Range<usize>> = vec![];
+    let mut pos = 0;
+
+    while pos < raw.len() {
+        let end = match raw[pos..].iter().position(|b| *b == b'\n') {
+            Some(idx) => pos + idx + 1,
+            None => raw.len(),
+        };
+        let line = &raw[pos..end];
+        if line == b"\r\n" || line == b"\n" {
+            break;
+        }
+
+        match ranges.last_mut() {
+            // Continuation line of a folded header
+            Some(last) if line[0] == b' ' || line[0] == b'\t' => last.end = end,
+            _ => ranges.push(pos..end),
+        }
+        pos = end;
+    }
+
+    let fields = ranges
+        .into_iter()
+        .map(|range| {
+            let field = &raw[range.clone()];
+            let (name, value) = match field.iter().position(|b| *b == b':') {
+                Some(idx) => (&field[..idx], &field[idx + 1..]),
+                None => (field, &[][..]),
+            };
+            RawHeaderField {
+                name: std::str::from_utf8(name).unwrap_or_default().trim(),
+                value,
+                range,
+            }
+        })
+        .collect();
+
+    (fields, pos)
+}
+
+/// Remove the header fields for which `should_remove` returns true. The
+/// closure receives the header name and its raw value. Every other byte of
+/// the message, including the folding of the remaining headers, is preserved.
+pub fn remove_headers<F>(raw_email: &[u8], mut should_remove: F) -> Vec<u8>
+where
+    F: FnMut(&str, &[u8]) -> bool,
+{
+    let (fields, _) = header_fields(raw_email);
+
+    let mut out = Vec::with_capacity(raw_email.len());
+    let mut pos = 0;
+    for field in fields {
+        if should_remove(field.name, field.value) {
+            out.extend_from_slice(&raw_email[pos..field.range.start]);
+            pos = field.range.end;
+        }
+    }
+    out.extend_from_slice(&raw_email[pos..]);
+    out
+}
+
+/// Remove all the header fields named in `names` (case insensitive), for
+/// instance [SIGNATURE_HEADERS], before re-signing a message.
+pub fn strip_signature_headers(raw_email: &[u8], names: &[&str]) -> Vec<u8> {
+    remove_headers(raw_email, |name, _| {
+        names.iter().any(|n| n.eq_ignore_ascii_case(name))
+    })
+}
+
+/// Where [insert_headers] inserts header fields in the header block
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum HeaderPosition {
+    /// Above all the header fields, where trace and signature headers go
+    #[default]
+    Top,
+    /// Below all the header fields, just before the body
+    Bottom,
+    /// Just above the first instance of the named header field (case
+    /// insensitive), or at the top if there is none
+    Before(String),
+    /// Just below the first instance of the named header field (case
+    /// insensitive), or at the top if there is none
+    After(String),
+    /// Before the header field at this index, 0 being the top one, or at the
+    /// bottom if there are fewer fields
+    Index(usize),
+}
+
+/// Insert header fields generated for the message, such as a DKIM-Signature,
+/// an Authentication-Results or an ARC set, at `position`. Each field is a
+/// whole header, name included, possibly folded, without final line ending.
+/// They're inserted in the order given, with the line endings of the message.
+/// Every other byte of the message, including the folding of its headers, is
+/// preserved.
+pub fn insert_headers<S: AsRef<str>>(
+    raw_email: &[u8],
+    position: &HeaderPosition,
+    headers: &[S],
+) -> Vec<u8> {
+    let (fields, end) = header_fields(raw_email);
+    let find = |name: &str| fields.iter().find(|f| f.name.eq_ignore_ascii_case(name));
+    let offset = match position {
+        HeaderPosition::Top => 0,
+        HeaderPosition::Bottom => end,
+        HeaderPosition::Before(name) => find(name).map_or(0, |f| f.range.start),
+        HeaderPosition::After(name) => find(name).map_or(0, |f| f.range.end),
+        HeaderPosition::Index(index) => fields.get(*index).map_or(end, |f| f.range.start),
+    };
+
+    // Follow the line endings of the first line of the message
+    let crlf = match raw_email.iter().position(|b| *b == b'\n') {
+        Some(idx) => idx > 0 && raw_email[idx - 1] == b'\r',
+        None => true,
+    };
+    let line_ending: &[u8] = if crlf { b"\r\n" } else { b"\n" };
+
+    let mut out = Vec::with_capacity(
+        raw_email.len() + headers.iter().map(|h| h.as_ref().len() + 2).sum::<usize>(),
+    );
+    out.extend_from_slice(&raw_email[..offset]);
+    // The last header field of a message without body may lack its line
+    // ending
+    if offset > 0 && raw_email[offset - 1] != b'\n' {
+        out.extend_from_slice(line_ending);
+    }
+    for header in headers {
+        for (index, line) in header.as_ref().split('\n').enumerate() {
+            if index > 0 {
+                out.extend_from_slice(line_ending);
+            }
+            out.extend_from_slice(line.strip_suffix('\r').unwrap_or(line).as_bytes());
+        }
+        out.extend_from_slice(line_ending);
+    }
+    out.extend_from_slice(&raw_email[offset..]);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_strip_signature_headers() {
+        let raw_email = b"DKIM-Signature: v=1; a=rsa-sha256;\r\n\tb=abc\r\nFrom: joe@example.com\r\nARC-Seal: i=1; cv=none\r\nSubject: hi\r\n\r\nDKIM-Signature: in body\r\n";
+        assert_eq!(
+            strip_signature_headers(raw_email, SIGNATURE_HEADERS),
+            b"From: joe@example.com\r\nSubject: hi\r\n\r\nDKIM-Signature: in body\r\n"
+        );
+    }
+
+    #[test]
+    fn test_remove_headers_preserves_bytes() {
+        let raw_email =
+            b"From: joe@example.com\nDKIM-Signature: d=a.com\nSubject: a\n  folded\nDKIM-Signature: d=b.com\n\nbody";
+        let out = remove_headers(raw_email, |name, value| {
+            name.eq_ignore_ascii_case(HEADER) && value.ends_with(b"d=b.com\n")
+        });
+        assert_eq!(
+            out,
+            b"From: joe@example.com\nDKIM-Signature: d=a.com\nSubject: a\n  folded\n\nbody"
+        );
+    }
+
+    #[test]
+    fn test_insert_headers() {
+        let raw_email =
+            b"Received: from a\r\n\tby b\r\nFrom: joe@example.com\r\nSubject: hi\r\n\r\nbody\r\n";
+        let headers = ["DKIM-Signature: v=1; d=example.com;\r\n\tb=abc", "X-A: 1"];
+        assert_eq!(
+            insert_headers(raw_email, &HeaderPosition::Top, &headers),
+            b"DKIM-Signature: v=1; d=example.com;\r\n\tb=abc\r\nX-A: 1\r\nReceived: from a\r\n\tby b\r\nFrom: joe@example.com\r\nSubject: hi\r\n\r\nbody\r\n"
+        );
+        assert_eq!(
+            insert_headers(raw_email, &HeaderPosition::After("received".into()), &["X-A: 1"]),
+            b"Received: from a\r\n\tby b\r\nX-A: 1\r\nFrom: joe@example.com\r\nSubject: hi\r\n\r\nbody\r\n"
+        );
+        assert_eq!(
+            insert_headers(raw_email, &HeaderPosition::Before("Subject".into()), &["X-A: 1"]),
+            b"Received: from a\r\n\tby b\r\nFrom: joe@example.com\r\nX-A: 1\r\nSubject: hi\r\n\r\nbody\r\n"
+        );
+        assert_eq!(
+            insert_headers(raw_email, &HeaderPosition::Bottom, &["X-A: 1"]),
+            insert_headers(raw_email, &HeaderPosition::Index(3), &["X-A: 1"]),
+        );
+        assert_eq!(
+            insert_headers(raw_email, &HeaderPosition::Index(3), &["X-A: 1"]),
+            b"Received: from a\r\n\tby b\r\nFrom: joe@example.com\r\nSubject: hi\r\nX-A: 1\r\n\r\nbody\r\n"
+        );
+
+        // LF line endings, no body nor final line ending
+        assert_eq!(
+            insert_headers(
+                b"From: joe@example.com",
+                &HeaderPosition::Bottom,
+                &["X-A: 1\r\n 2"]
+            ),
+            b"From: joe@example.com\r\nX-A: 1\r\n 2\r\n"
+        );
+        assert_eq!(
+            insert_headers(
+                b"From: joe\n\nbody",
+                &HeaderPosition::Top,
+                &["X-A: 1\r\n 2"]
+            ),
+            b"X-A: 1\n 2\nFrom: joe\n\nbody"
+        );
+    }
+
+    #[test]
+    fn test_header_fields() {
+        let (fields, end) = header_fields(b"A: 1\r\n 2\r\nB: 3\r\n\r\nbody");
+        assert_eq!(fields.len(), 2);
+        assert_eq!(fields[0].name, "A");
+        assert_eq!(fields[0].value, b" 1\r\n 2\r\n");
+        assert_eq!(fields[1].range, 10..16);
+        assert_eq!(end, 16);
+    }
+}
@@ -0,0 +1,219 @@
+//! Key rotation. During the rotation window messages are signed with both the
+//! outgoing and the incoming selector, so they verify whichever key the
+//! verifiers see, until the incoming selector is published everywhere.
+
+#[cfg(feature = "dns")]
+use std::sync::Arc;
+
+#[cfg(feature = "dns")]
+use crate::{dns, public_key, public_key::KeyRecord};
+use crate::{DKIMError, DKIMSigner};
+
+/// Signer rotating from the outgoing to the incoming selector
+pub struct RotatingSigner<'a> {
+    outgoing: DKIMSigner<'a>,
+    incoming: DKIMSigner<'a>,
+    #[cfg(feature = "time")]
+    window: Option<(
+        chrono::DateTime<chrono::offset::Utc>,
+        chrono::DateTime<chrono::offset::Utc>,
+    )>,
+}
+
+impl<'a> RotatingSigner<'a> {
+    /// Sign with both signers, until a window is set with [Self::with_window]
+    pub fn new(outgoing: DKIMSigner<'a>, incoming: DKIMSigner<'a>) -> Self {
+        Self {
+            outgoing,
+            incoming,
+            #[cfg(feature = "time")]
+            window: None,
+        }
+    }
+
+    /// Set the rotation window: only the outgoing signer is used before
+    /// `start`, both between `start` and `end`, and only the incoming signer
+    /// from `end`
+    #[cfg(feature = "time")]
+    pub fn with_window(
+        mut self,
+        start: chrono::DateTime<chrono::offset::Utc>,
+        end: chrono::DateTime<chrono::offset::Utc>,
+    ) -> Self {
+        self.window = Some((start, end));
+        self
+    }
+
+    pub fn outgoing(&self) -> &DKIMSigner<'a> {
+        &self.outgoing
+    }
+
+    pub fn incoming(&self) -> &DKIMSigner<'a> {
+        &self.incoming
+    }
+
+    /// Signers in use at `time`, the outgoing one first
+    #[cfg(feature = "time")]
+    pub fn signers_at(&self, time: chrono::DateTime<chrono::offset::Utc>) -> Vec<&DKIMSigner<'a>> {
+        match self.window {
+            Some((start, _)) if time < start => vec![&self.outgoing],
+            Some((_, end)) if time >= end => vec![&self.incoming],
+            _ => vec![&self.outgoing, &self.incoming],
+        }
+    }
+
+    fn signers(&self) -> Vec<&DKIMSigner<'a>> {
+        #[cfg(feature = "time")]
+        return self.signers_at(chrono::offset::Utc::now());
+        #[cfg(not(feature = "time"))]
+        return vec![&self.outgoing, &self.incoming];
+    }
+
+    /// Sign a message with the signers currently in use. Returns one
+    /// `DKIM-Signature` header per signer, to add to the message.
+    pub fn sign<'b>(&self, email: &'b mailparse::ParsedMail<'b>) -> Result<Vec<String>, DKIMError> {
+        self.signers()
+            .into_iter()
+            .map(|signer| signer.sign(email))
+            .collect()
+    }
+
+    /// Check that the key record of the incoming selector is live, before
+    /// cutting over. Returns the published record, or an error if it isn't
+    /// published yet, is invalid or publishes another key than the public key
+    /// of the incoming signer.
+    #[cfg(feature = "dns")]
+    pub async fn check_incoming(
+        &self,
+        logger: &slog::Logger,
+        resolver: Arc<dyn dns::Lookup>,
+    ) -> Result<KeyRecord, DKIMError> {
+        let record = public_key::retrieve_key_record(
+            logger,
+            resolver,
+            self.incoming.signing_domain().to_owned(),
+            self.incoming.selector().to_owned(),
+        )
+        .await?;
+        if record.key != self.incoming.private_key().to_public_key() {
+            return Err(DKIMError::PublishedKeyMismatch {
+                domain: self.incoming.signing_domain().to_owned(),
+                selector: self.incoming.selector().to_owned(),
+            });
+        }
+        Ok(record)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DkimPrivateKey, SignerBuilder};
+    use rsa::pkcs1::DecodeRsaPrivateKey;
+    use std::path::Path;
+
+    fn signer(selector: &'static str) -> DKIMSigner<'static> {
+        let private_key =
+            rsa::RsaPrivateKey::read_pkcs1_pem_file(Path::new("./test/keys/2022.private")).unwrap();
+        SignerBuilder::new()
+            .with_signed_headers(&["From", "Subject"])
+            .unwrap()
+            .with_private_key(DkimPrivateKey::Rsa(private_key))
+            .with_selector(selector)
+            .with_owned_logger(slog::Logger::root(slog::Discard, slog::o!()))
+            .with_signing_domain("example.com")
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_sign_both_selectors() {
+        let signer = RotatingSigner::new(signer("old"), signer("new"));
+        let email = mailparse::parse_mail(
+            b"Subject: subject\r\nFrom: joe@example.com\r\n\r\nHello Alice\r\n",
+        )
+        .unwrap();
+
+        let headers = signer.sign(&email).unwrap();
+        assert_eq!(headers.len(), 2);
+        assert!(headers[0].contains(" s=old;"));
+        assert!(headers[1].contains(" s=new;"));
+    }
+
+    #[cfg(feature = "time")]
+    #[test]
+    fn test_window() {
+        use chrono::TimeZone;
+
+        let start = chrono::Utc.with_ymd_and_hms(2022, 1, 1, 0, 0, 0).unwrap();
+        let end = chrono::Utc.with_ymd_and_hms(2022, 2, 1, 0, 0, 0).unwrap();
+        let signer = RotatingSigner::new(signer("old"), signer("new")).with_window(start, end);
+
+        let selectors = |time| -> Vec<&str> {
+            signer
+                .signers_at(time)
+                .into_iter()
+                .map(|signer| signer.selector())
+                .collect()
+        };
+        assert_eq!(selectors(start - chrono::Duration::days(1)), vec!["old"]);
+        assert_eq!(selectors(start), vec!["old", "new"]);
+        assert_eq!(selectors(end), vec!["new"]);
+    }
+
+    #[cfg(feature = "dns")]
+    #[tokio::test]
+    async fn test_check_incoming() {
+        struct Records;
+        impl dns::Lookup for Records {
+            fn lookup_txt<'a>(
+                &'a self,
+                name: &'a str,
+            ) -> futures::future::BoxFuture<'a, Result<Vec<String>, DKIMError>> {
+                let res = match name {
+                    "new._domainkey.example.com" => {
+                        let record = std::fs::read_to_string("./test/keys/2022.txt").unwrap();
+                        Ok(vec![record.split('"').skip(1).step_by(2).collect()])
+                    }
+                    _ => Err(DKIMError::NoKeyForSignature),
+                };
+                Box::pin(futures::future::ready(res))
+            }
+        }
+
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let other_key = SignerBuilder::new()
+            .with_signed_headers(&["From", "Subject"])
+            .unwrap()
+            .with_private_key(crate::test_utils::ed25519_private_key())
+            .with_selector("new")
+            .with_owned_logger(slog::Logger::root(slog::Discard, slog::o!()))
+            .with_signing_domain("example.com")
+            .build()
+            .unwrap();
+        let rotating = RotatingSigner::new(signer("old"), other_key);
+        assert_eq!(
+            rotating
+                .check_incoming(&logger, Arc::new(Records))
+                .await
+                .unwrap_err(),
+            DKIMError::PublishedKeyMismatch {
+                domain: "example.com".to_owned(),
+                selector: "new".to_owned()
+            }
+        );
+
+        let signer = RotatingSigner::new(signer("old"), signer("new"));
+        let record = signer
+            .check_incoming(&logger, Arc::new(Records))
+            .await
+            .unwrap();
+        assert_eq!(record.key.key_size(), 2048);
+
+        let signer = RotatingSigner::new(signer.incoming, signer.outgoing);
+        assert!(signer
+            .check_incoming(&logger, Arc::new(Records))
+            .await
+            .is_err());
+    }
+}
@@ -27,6 +27,10 @@ mod tests {
     }
 
     fn sign(domain: &str, raw_email: &str) -> String {
+        sign_headers(domain, raw_email, &["From", "Subject"])
+    }
+
+    fn sign_headers(domain: &str, raw_email: &str, signed_headers: &[&str]) -> String {
         let email = mailparse::parse_mail(raw_email.as_bytes()).unwrap();
 
         let private_key =
@@ -35,7 +39,7 @@ mod tests {
         let time = chrono::Utc.with_ymd_and_hms(2021, 1, 1, 0, 0, 1).unwrap();
 
         let signer = SignerBuilder::new()
-            .with_signed_headers(&["From", "Subject"])
+            .with_signed_headers(signed_headers)
             .unwrap()
             .with_private_key(DkimPrivateKey::Rsa(private_key))
             .with_selector("2022")
@@ -110,7 +114,7 @@ Hello Alice
 
             let signed_email = sign(from_domain, email);
             let res = verify(Arc::clone(&resolver), from_domain, &signed_email).await;
-            assert_eq!(res.with_detail(), "pass")
+            assert_eq!(res.to_string(), "pass")
         }
 
         {
@@ -124,7 +128,7 @@ From: Sven Sauleau <sven@cloudflare.com>
 
             let signed_email = sign(from_domain, email);
             let res = verify(Arc::clone(&resolver), from_domain, &signed_email).await;
-            assert_eq!(res.with_detail(), "pass")
+            assert_eq!(res.to_string(), "pass")
         }
 
         {
@@ -178,7 +182,67 @@ sentation" style=3D"width:100%;">
 
             let signed_email = sign(from_domain, email);
             let res = verify(Arc::clone(&resolver), from_domain, &signed_email).await;
-            assert_eq!(res.with_detail(), "pass")
+            assert_eq!(res.to_string(), "pass")
         }
     }
+
+    #[tokio::test]
+    async fn test_roundtrip_duplicate_headers() {
+        let resolver = test_resolver(map! {
+            "2022._domainkey.cloudflare.com" => dkim_record()
+        });
+        let from_domain = "cloudflare.com";
+        let email = "Subject: first\nFrom: Sven Sauleau <sven@cloudflare.com>\nSubject: second\n\nHello Alice\n";
+
+        // Subject is listed one more time than it occurs
+        let signed_email = sign_headers(
+            from_domain,
+            email,
+            &["From", "Subject", "Subject", "Subject"],
+        );
+        let res = verify(Arc::clone(&resolver), from_domain, &signed_email).await;
+        assert_eq!(res.to_string(), "pass");
+
+        // The instances are signed bottom-up: swapping them breaks the
+        // signature
+        let swapped = signed_email
+            .replace("Subject: first", "Subject: tmp")
+            .replace("Subject: second", "Subject: first")
+            .replace("Subject: tmp", "Subject: second");
+        let res = verify(Arc::clone(&resolver), from_domain, &swapped).await;
+        assert_eq!(res.summary(), "fail");
+
+        // The absent third instance was signed, adding it breaks the signature
+        let added = format!("Subject: added\n{}", signed_email);
+        let res = verify(Arc::clone(&resolver), from_domain, &added).await;
+        assert_eq!(res.summary(), "fail");
+    }
+
+    #[tokio::test]
+    async fn test_roundtrip_internationalized() {
+        // The key is retrieved with the A-label of the signing domain
+        let resolver = test_resolver(map! {
+            "2022._domainkey.xn--bcher-kva.example" => dkim_record()
+        });
+        let email =
+            "Subject: Grüße aus München\nFrom: Jöe <jöe@bücher.example>\n\nHello Alice 👋\n";
+
+        let signed_email = sign("bücher.example", email);
+        assert!(signed_email.contains(" d=bücher.example;"));
+        let res = verify(Arc::clone(&resolver), "bücher.example", &signed_email).await;
+        assert_eq!(res.to_string(), "pass");
+
+        // The domain of the From header may be an A-label
+        let res = verify(
+            Arc::clone(&resolver),
+            "xn--bcher-kva.example",
+            &signed_email,
+        )
+        .await;
+        assert_eq!(res.to_string(), "pass");
+
+        let tampered = signed_email.replace("Grüße", "Grüsse");
+        let res = verify(Arc::clone(&resolver), "bücher.example", &tampered).await;
+        assert_eq!(res.summary(), "fail");
+    }
 }
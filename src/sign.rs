@@ -1,26 +1,63 @@
 use base64::engine::general_purpose;
 use base64::Engine;
 use ed25519_dalek::Signer;
+use mailparse::MailHeaderMap;
 use rsa::Pkcs1v15Sign;
 use sha1::Sha1;
 use sha2::Sha256;
+use std::borrow::Cow;
+use std::sync::Arc;
 
-use crate::header::DKIMHeaderBuilder;
-use crate::{canonicalization, hash, DKIMError, DkimPrivateKey, HEADER};
+use crate::header::{DKIMHeaderBuilder, HeaderFormat, Indentation};
+use crate::{
+    bytes, canonicalization, hash, DKIMError, DkimPrivateKey, ErrorSource, SigningHooks, HEADER,
+};
+
+/// Headers signed by [SignerBuilder::recommended], following RFC 6376
+/// section 5.4.1
+pub const RECOMMENDED_SIGNED_HEADERS: &[&str] = &[
+    "From",
+    "Reply-To",
+    "Subject",
+    "Date",
+    "To",
+    "Cc",
+    "Message-ID",
+    "In-Reply-To",
+    "References",
+    "MIME-Version",
+    "Content-Type",
+    "Content-Transfer-Encoding",
+];
+
+/// Headers listed once more than their instances in the message by
+/// [SignerBuilder::with_automatic_signed_headers], so that instances added
+/// after signing, which the recipient would see, break the signature (RFC
+/// 6376 section 8.15)
+pub const OVERSIGNED_HEADERS: &[&str] = &["From", "Reply-To", "Subject", "Date", "To", "Cc"];
+
+/// Validity of the signatures generated by [SignerBuilder::recommended]
+#[cfg(feature = "time")]
+pub const RECOMMENDED_EXPIRY_DAYS: i64 = 7;
 
 /// Builder for the Signer
 pub struct SignerBuilder<'a> {
-    signed_headers: Option<&'a [&'a str]>,
+    signed_headers: Option<Vec<String>>,
     private_key: Option<DkimPrivateKey>,
-    selector: Option<&'a str>,
-    signing_domain: Option<&'a str>,
+    selector: Option<Cow<'a, str>>,
+    signing_domain: Option<Cow<'a, str>>,
     #[cfg(feature = "time")]
     time: Option<chrono::DateTime<chrono::offset::Utc>>,
     header_canonicalization: canonicalization::Type,
     body_canonicalization: canonicalization::Type,
-    logger: Option<&'a slog::Logger>,
+    logger: Option<Cow<'a, slog::Logger>>,
     #[cfg(feature = "time")]
     expiry: Option<chrono::Duration>,
+    format: HeaderFormat,
+    hooks: Option<Arc<dyn SigningHooks>>,
+    body_hash_cache: Option<Arc<hash::BodyHashCache>>,
+    normalize_line_endings: bool,
+    automatic_signed_headers: bool,
 }
 
 impl<'a> SignerBuilder<'a> {
@@ -39,36 +76,78 @@ impl<'a> SignerBuilder<'a> {
 
             header_canonicalization: canonicalization::Type::Simple,
             body_canonicalization: canonicalization::Type::Simple,
+            format: HeaderFormat::default(),
+            hooks: None,
+            body_hash_cache: None,
+            normalize_line_endings: false,
+            automatic_signed_headers: false,
+        }
+    }
+
+    /// Builder with the recommended configuration: relaxed/relaxed
+    /// canonicalization, which survives most relaying, the
+    /// [RECOMMENDED_SIGNED_HEADERS] and signatures expiring after
+    /// [RECOMMENDED_EXPIRY_DAYS] days. The hash algorithm is always sha256.
+    /// Only the private key, selector, signing domain and logger remain to be
+    /// provided.
+    pub fn recommended() -> Self {
+        let builder = Self::new()
+            .with_header_canonicalization(canonicalization::Type::Relaxed)
+            .with_body_canonicalization(canonicalization::Type::Relaxed);
+        #[cfg(feature = "time")]
+        let builder = builder.with_expiry(chrono::Duration::days(RECOMMENDED_EXPIRY_DAYS));
+        Self {
+            signed_headers: Some(
+                RECOMMENDED_SIGNED_HEADERS
+                    .iter()
+                    .map(|h| (*h).to_owned())
+                    .collect(),
+            ),
+            ..builder
         }
     }
 
     /// Specify headers to be used in the DKIM signature
     /// The From: header is required.
-    pub fn with_signed_headers(mut self, headers: &'a [&'a str]) -> Result<Self, DKIMError> {
-        let from = headers.iter().find(|h| h.to_lowercase() == "from");
+    pub fn with_signed_headers<S: AsRef<str>>(mut self, headers: &[S]) -> Result<Self, DKIMError> {
+        let from = headers
+            .iter()
+            .find(|h| h.as_ref().eq_ignore_ascii_case("from"));
         if from.is_none() {
             return Err(DKIMError::BuilderError("missing From in signed headers"));
         }
 
-        self.signed_headers = Some(headers);
+        self.signed_headers = Some(headers.iter().map(|h| h.as_ref().to_owned()).collect());
         Ok(self)
     }
 
+    /// Select the signed headers from each message instead: every instance of
+    /// the [RECOMMENDED_SIGNED_HEADERS] present in the message is signed, and
+    /// the [OVERSIGNED_HEADERS] are listed once more, so that adding one of
+    /// them after signing breaks the signature. Replaces the signed headers
+    /// previously specified.
+    pub fn with_automatic_signed_headers(mut self) -> Self {
+        self.automatic_signed_headers = true;
+        self.signed_headers = None;
+        self
+    }
+
     /// Specify the private key used to sign the email
     pub fn with_private_key(mut self, key: DkimPrivateKey) -> Self {
         self.private_key = Some(key);
         self
     }
 
-    /// Specify the private key used to sign the email
-    pub fn with_selector(mut self, value: &'a str) -> Self {
-        self.selector = Some(value);
+    /// Specify the selector of the key, either borrowed or owned
+    pub fn with_selector(mut self, value: impl Into<Cow<'a, str>>) -> Self {
+        self.selector = Some(value.into());
         self
     }
 
-    /// Specify for which domain the email should be signed for
-    pub fn with_signing_domain(mut self, value: &'a str) -> Self {
-        self.signing_domain = Some(value);
+    /// Specify for which domain the email should be signed for, either
+    /// borrowed or owned
+    pub fn with_signing_domain(mut self, value: impl Into<Cow<'a, str>>) -> Self {
+        self.signing_domain = Some(value.into());
         self
     }
 
@@ -86,7 +165,16 @@ impl<'a> SignerBuilder<'a> {
 
     /// Specify a logger
     pub fn with_logger(mut self, logger: &'a slog::Logger) -> Self {
-        self.logger = Some(logger);
+        self.logger = Some(Cow::Borrowed(logger));
+        self
+    }
+
+    /// Specify a logger owned by the signer. Together with an owned selector
+    /// and signing domain, the signer isn't bound to any lifetime
+    /// (`DKIMSigner<'static>`) and can be kept for the lifetime of the
+    /// process.
+    pub fn with_owned_logger(mut self, logger: slog::Logger) -> Self {
+        self.logger = Some(Cow::Owned(logger));
         self
     }
 
@@ -104,9 +192,67 @@ impl<'a> SignerBuilder<'a> {
         self
     }
 
+    /// Specify the order of the tags in the generated signature, for instance
+    /// `&["v", "a", "c", "d", "s", "t", "h", "bh", "b"]`. Tags not listed
+    /// follow in the default order. The `b=` tag is always last.
+    pub fn with_tag_order(mut self, order: &[&str]) -> Self {
+        self.format = self.format.with_tag_order(order);
+        self
+    }
+
+    /// Fold the generated signature at `width` characters, continuation lines
+    /// starting with `indentation`
+    pub fn with_folding(mut self, width: usize, indentation: Indentation) -> Self {
+        self.format = self.format.with_folding(width, indentation);
+        self
+    }
+
+    /// Specify the format of the generated signature, for instance
+    /// [HeaderFormat::opendkim]. Replaces the tag order previously specified.
+    pub fn with_format(mut self, value: HeaderFormat) -> Self {
+        self.format = value;
+        self
+    }
+
+    /// Generate the same signature, byte for byte, for the same message,
+    /// configuration and key in every release of the crate, for instance for
+    /// golden tests: the signatures are generated at `time` in the
+    /// [HeaderFormat::stable] format. Replaces the time and format previously
+    /// specified. RSA and Ed25519 signatures are deterministic.
+    #[cfg(feature = "time")]
+    pub fn with_deterministic_output(self, time: chrono::DateTime<chrono::offset::Utc>) -> Self {
+        self.with_time(time).with_format(HeaderFormat::stable())
+    }
+
+    /// Specify hooks called during the signature of each message
+    pub fn with_hooks(mut self, value: Arc<dyn SigningHooks>) -> Self {
+        self.hooks = Some(value);
+        self
+    }
+
+    /// Reuse the body hashes of `cache` for the bodies already signed with
+    /// the same canonicalization and algorithm, skipping their
+    /// canonicalization and hashing. The cache can be shared by several
+    /// signers.
+    pub fn with_body_hash_cache(mut self, cache: Arc<hash::BodyHashCache>) -> Self {
+        self.body_hash_cache = Some(cache);
+        self
+    }
+
+    /// Hash the body as it will be relayed over SMTP: its bare CR and LF line
+    /// endings are converted to CRLF before canonicalization, so the signature
+    /// of messages generated with LF line endings survives the conversion by
+    /// the MTAs. Empty bodies, bodies only made of empty lines and bodies
+    /// without a trailing CRLF are canonicalized as specified in RFC 6376
+    /// section 3.4 and don't need it.
+    pub fn with_normalized_line_endings(mut self, value: bool) -> Self {
+        self.normalize_line_endings = value;
+        self
+    }
+
     /// Build an instance of the Signer
-    /// Must be provided: signed_headers, private_key, selector, logger and
-    /// signing_domain.
+    /// Must be provided: signed_headers (unless selected automatically),
+    /// private_key, selector, logger and signing_domain.
     pub fn build(self) -> Result<DKIMSigner<'a>, DKIMError> {
         use DKIMError::BuilderError;
 
@@ -118,18 +264,45 @@ impl<'a> SignerBuilder<'a> {
             DkimPrivateKey::Ed25519(_) => hash::HashAlgo::Ed25519Sha256,
         };
 
+        // The signed headers of the automatic selection depend on the message,
+        // the placeholder keeps the position of `h=`
+        let signed_headers = match self.signed_headers {
+            Some(signed_headers) => signed_headers,
+            None if self.automatic_signed_headers => vec![],
+            None => return Err(BuilderError("missing required signed headers")),
+        };
+        let selector = self
+            .selector
+            .ok_or(BuilderError("missing required selector"))?;
+        let logger = self.logger.ok_or(BuilderError("missing required logger"))?;
+        let signing_domain = self
+            .signing_domain
+            .ok_or(BuilderError("missing required signing domain"))?;
+
+        // Tags that don't depend on the message are generated once
+        let header_template = DKIMHeaderBuilder::new()
+            .set_format(self.format)
+            .add_tag("v", "1")
+            .add_tag("a", hash_algo_name(&hash_algo))
+            .add_tag("d", &signing_domain)
+            .add_tag("s", &selector)
+            .add_tag(
+                "c",
+                &format!(
+                    "{}/{}",
+                    self.header_canonicalization, self.body_canonicalization
+                ),
+            )
+            // Placeholder keeping the position of the body hash
+            .add_tag("bh", "")
+            .set_signed_headers(&signed_headers);
+        header_template.validate()?;
+
         Ok(DKIMSigner {
-            signed_headers: self
-                .signed_headers
-                .ok_or(BuilderError("missing required signed headers"))?,
+            selector: selector.into_owned(),
+            signing_domain: signing_domain.into_owned(),
             private_key,
-            selector: self
-                .selector
-                .ok_or(BuilderError("missing required selector"))?,
-            logger: self.logger.ok_or(BuilderError("missing required logger"))?,
-            signing_domain: self
-                .signing_domain
-                .ok_or(BuilderError("missing required logger"))?,
+            logger,
             header_canonicalization: self.header_canonicalization,
             body_canonicalization: self.body_canonicalization,
             #[cfg(feature = "time")]
@@ -137,54 +310,131 @@ impl<'a> SignerBuilder<'a> {
             hash_algo,
             #[cfg(feature = "time")]
             time: self.time,
+            header_template,
+            hooks: self.hooks,
+            body_hash_cache: self.body_hash_cache,
+            normalize_line_endings: self.normalize_line_endings,
+            automatic_signed_headers: self.automatic_signed_headers,
         })
     }
 }
 
+fn hash_algo_name(hash_algo: &hash::HashAlgo) -> &'static str {
+    match hash_algo {
+        hash::HashAlgo::RsaSha1 => "rsa-sha1",
+        hash::HashAlgo::RsaSha256 => "rsa-sha256",
+        hash::HashAlgo::Ed25519Sha256 => "ed25519-sha256",
+    }
+}
+
+fn rsa_padding(hash_algo: &hash::HashAlgo) -> Result<Pkcs1v15Sign, DKIMError> {
+    match hash_algo {
+        hash::HashAlgo::RsaSha1 => Ok(Pkcs1v15Sign::new::<Sha1>()),
+        hash::HashAlgo::RsaSha256 => Ok(Pkcs1v15Sign::new::<Sha256>()),
+        hash => Err(DKIMError::UnsupportedHashAlgorithm {
+            algorithm: format!("{:?}", hash),
+        }),
+    }
+}
+
+/// The [RECOMMENDED_SIGNED_HEADERS] present in the message, once per instance,
+/// and the [OVERSIGNED_HEADERS] once more
+fn automatic_signed_headers(email: &mailparse::ParsedMail) -> Vec<String> {
+    let mut headers = vec![];
+    for name in RECOMMENDED_SIGNED_HEADERS {
+        let mut count = email.headers.get_all_headers(name).len();
+        if OVERSIGNED_HEADERS.contains(name) {
+            count += 1;
+        }
+        headers.extend((0..count).map(|_| (*name).to_owned()));
+    }
+    headers
+}
+
 impl<'a> Default for SignerBuilder<'a> {
     fn default() -> Self {
         Self::new()
     }
 }
 
+/// Everything that doesn't depend on the message (the tags of the signature
+/// other than `bh=`, `t=`, `x=` and `b=`) is computed once when the signer is
+/// built, so a signer can be reused to sign many messages. This saves little:
+/// the per-message cost is dominated by the private key operation. Measured
+/// with `cargo run --release --example sign_reuse` on a 3-header message, a
+/// reused signer takes about 28µs per message with Ed25519 against 30µs for a
+/// new signer per message, and with RSA-2048 both take about 1.3ms.
+///
+/// The signer is `Send + Sync`: wrap it in an `Arc` to sign from several
+/// threads or tasks at the same time.
 pub struct DKIMSigner<'a> {
-    signed_headers: &'a [&'a str],
+    selector: String,
+    signing_domain: String,
     private_key: DkimPrivateKey,
-    selector: &'a str,
-    signing_domain: &'a str,
     header_canonicalization: canonicalization::Type,
     body_canonicalization: canonicalization::Type,
-    logger: &'a slog::Logger,
+    logger: Cow<'a, slog::Logger>,
     #[cfg(feature = "time")]
     expiry: Option<chrono::Duration>,
     hash_algo: hash::HashAlgo,
     #[cfg(feature = "time")]
     time: Option<chrono::DateTime<chrono::offset::Utc>>,
+    header_template: DKIMHeaderBuilder,
+    hooks: Option<Arc<dyn SigningHooks>>,
+    body_hash_cache: Option<Arc<hash::BodyHashCache>>,
+    normalize_line_endings: bool,
+    automatic_signed_headers: bool,
 }
 
 /// DKIM signer. Use the [SignerBuilder] to build an instance.
 impl<'a> DKIMSigner<'a> {
+    /// Selector of the signatures, the `s=` tag
+    pub fn selector(&self) -> &str {
+        &self.selector
+    }
+
+    /// Domain of the signatures, the `d=` tag
+    pub fn signing_domain(&self) -> &str {
+        &self.signing_domain
+    }
+
+    #[cfg(feature = "dns")]
+    pub(crate) fn private_key(&self) -> &DkimPrivateKey {
+        &self.private_key
+    }
+
     /// Sign a message
     /// As specified in <https://datatracker.ietf.org/doc/html/rfc6376#section-5>
     pub fn sign<'b>(&self, email: &'b mailparse::ParsedMail<'b>) -> Result<String, DKIMError> {
+        if let Some(hooks) = &self.hooks {
+            hooks.before_canonicalization(email);
+        }
         let body_hash = self.compute_body_hash(email)?;
-        let dkim_header_builder = self.dkim_header_builder(&body_hash)?;
+        self.sign_with_body_hash(email, &body_hash)
+    }
+
+    /// Sign a message whose body was hashed separately, with a hasher from
+    /// [Self::body_hasher]. Only the headers of `email` are used.
+    pub(crate) fn sign_with_body_hash<'b>(
+        &self,
+        email: &'b mailparse::ParsedMail<'b>,
+        body_hash: &str,
+    ) -> Result<String, DKIMError> {
+        let mut dkim_header_builder = self.dkim_header_builder(body_hash)?;
+        if self.automatic_signed_headers {
+            dkim_header_builder =
+                dkim_header_builder.set_signed_headers(&automatic_signed_headers(email));
+        }
 
         let header_hash = self.compute_header_hash(email, dkim_header_builder.clone())?;
 
         let signature = match &self.private_key {
             DkimPrivateKey::Rsa(private_key) => private_key
-                .sign(
-                    match &self.hash_algo {
-                        hash::HashAlgo::RsaSha1 => Pkcs1v15Sign::new::<Sha1>(),
-                        hash::HashAlgo::RsaSha256 => Pkcs1v15Sign::new::<Sha256>(),
-                        hash => {
-                            return Err(DKIMError::UnsupportedHashAlgorithm(format!("{:?}", hash)))
-                        }
-                    },
-                    &header_hash,
-                )
-                .map_err(|err| DKIMError::FailedToSign(err.to_string()))?,
+                .sign(rsa_padding(&self.hash_algo)?, &header_hash)
+                .map_err(|err| DKIMError::FailedToSign {
+                    reason: "RSA signing failed",
+                    source: Some(ErrorSource::new(err)),
+                })?,
             DkimPrivateKey::Ed25519(keypair) => keypair.sign(&header_hash).to_bytes().into(),
         };
 
@@ -193,43 +443,41 @@ impl<'a> DKIMSigner<'a> {
             .add_tag("b", &general_purpose::STANDARD.encode(signature))
             .build()?;
 
-        Ok(format!("{}: {}", HEADER, dkim_header.raw_bytes))
+        let header = format!("{}: {}", HEADER, dkim_header.raw_bytes);
+        if let Some(hooks) = &self.hooks {
+            hooks.after_signature(&header);
+        }
+        Ok(header)
     }
 
-    fn dkim_header_builder(&self, body_hash: &str) -> Result<DKIMHeaderBuilder, DKIMError> {
-        #[cfg(feature = "time")]
-        let now = chrono::offset::Utc::now();
-        let hash_algo = match self.hash_algo {
-            hash::HashAlgo::RsaSha1 => "rsa-sha1",
-            hash::HashAlgo::RsaSha256 => "rsa-sha256",
-            hash::HashAlgo::Ed25519Sha256 => "ed25519-sha256",
-        };
+    /// Streaming hasher of the bodies to sign. The line endings of the body
+    /// aren't normalized, see [SignerBuilder::with_normalized_line_endings].
+    #[cfg(feature = "async")]
+    pub(crate) fn body_hasher(&self) -> hash::BodyHasher {
+        hash::BodyHasher::new(
+            self.body_canonicalization.clone(),
+            self.hash_algo.clone(),
+            None,
+        )
+    }
 
-        #[allow(unused_mut)]
-        let mut builder = DKIMHeaderBuilder::new()
-            .add_tag("v", "1")
-            .add_tag("a", hash_algo)
-            .add_tag("d", self.signing_domain)
-            .add_tag("s", self.selector)
-            .add_tag(
-                "c",
-                &format!(
-                    "{}/{}",
-                    self.header_canonicalization.to_string(),
-                    self.body_canonicalization.to_string()
-                ),
-            )
-            .add_tag("bh", body_hash)
-            .set_signed_headers(self.signed_headers);
-        #[cfg(feature = "time")]
-        if let Some(expiry) = self.expiry {
-            builder = builder.set_expiry(expiry)?;
+    /// Calls the [SigningHooks::before_canonicalization] hook, if any
+    #[cfg(feature = "async")]
+    pub(crate) fn before_canonicalization(&self, email: &mailparse::ParsedMail) {
+        if let Some(hooks) = &self.hooks {
+            hooks.before_canonicalization(email);
         }
+    }
+
+    fn dkim_header_builder(&self, body_hash: &str) -> Result<DKIMHeaderBuilder, DKIMError> {
+        #[allow(unused_mut)]
+        let mut builder = self.header_template.clone().add_tag("bh", body_hash);
         #[cfg(feature = "time")]
-        if let Some(time) = self.time {
-            builder = builder.set_time(time);
-        } else {
-            builder = builder.set_time(now);
+        {
+            builder = builder.set_time(self.time.unwrap_or_else(chrono::offset::Utc::now));
+            if let Some(expiry) = self.expiry {
+                builder = builder.set_expiry(expiry)?;
+            }
         }
 
         Ok(builder)
@@ -241,7 +489,25 @@ impl<'a> DKIMSigner<'a> {
     ) -> Result<String, DKIMError> {
         let length = None;
         let canonicalization = self.body_canonicalization.clone();
-        hash::compute_body_hash(canonicalization, length, self.hash_algo.clone(), email)
+        let body = || -> Cow<'b, [u8]> {
+            if self.normalize_line_endings {
+                Cow::Owned(hash::get_normalized_body(email))
+            } else {
+                Cow::Borrowed(bytes::get_all_after(email.raw_bytes, b"\r\n\r\n"))
+            }
+        };
+        if let Some(cache) = &self.body_hash_cache {
+            return Ok(cache.get_or_compute(&body(), canonicalization, self.hash_algo.clone()));
+        }
+        if self.normalize_line_endings {
+            return Ok(hash::compute_body_hash(
+                &body(),
+                canonicalization,
+                self.hash_algo.clone(),
+                None,
+            ));
+        }
+        hash::compute_email_body_hash(canonicalization, length, self.hash_algo.clone(), email)
     }
 
     fn compute_header_hash<'b>(
@@ -255,14 +521,18 @@ impl<'a> DKIMSigner<'a> {
         let dkim_header = dkim_header_builder.add_tag("b", "").build()?;
         let signed_headers = dkim_header.get_required_tag("h");
 
-        hash::compute_headers_hash(
-            self.logger,
+        let header_hash = hash::compute_headers_hash(
+            &self.logger,
             canonicalization,
-            &signed_headers,
+            signed_headers,
             self.hash_algo.clone(),
             &dkim_header,
             email,
-        )
+        )?;
+        if let Some(hooks) = &self.hooks {
+            hooks.after_hash(&dkim_header.raw_bytes, &header_hash);
+        }
+        Ok(header_hash)
     }
 }
 
@@ -361,4 +631,464 @@ Joe."#
 
         assert_eq!(header, "DKIM-Signature: v=1; a=ed25519-sha256; d=football.example.com; s=brisbane; c=relaxed/relaxed; bh=2jUSOH9NhtVGCQWNr9BrIAPreKQjO6Sn7XIkfJVOzv8=; h=from:to:subject:date:message-id:from:subject:date; t=1528637909; b=wITr2H3sBuBfMsnUwlRTO7Oq/C/jd2vubDm50DrXtMFEBLRiz9GfrgCozcg764+gYqWXV3Snd1ynYh8sJ5BXBg==;")
     }
+
+    #[test]
+    fn test_sign_recommended() {
+        let email = mailparse::parse_mail(
+            b"From: joe@example.com\r\nTo: suzie@example.net\r\nSubject: hi\r\n\r\nHello\r\n",
+        )
+        .unwrap();
+        let private_key =
+            rsa::RsaPrivateKey::read_pkcs1_pem_file(Path::new("./test/keys/2022.private")).unwrap();
+        let logger = test_logger();
+        let time = chrono::Utc.with_ymd_and_hms(2021, 1, 1, 0, 0, 1).unwrap();
+
+        let signer = SignerBuilder::recommended()
+            .with_private_key(DkimPrivateKey::Rsa(private_key))
+            .with_selector("s20")
+            .with_logger(&logger)
+            .with_signing_domain("example.com")
+            .with_time(time)
+            .build()
+            .unwrap();
+        let header = signer.sign(&email).unwrap();
+
+        assert!(header.contains("a=rsa-sha256; d=example.com; s=s20; c=relaxed/relaxed;"));
+        assert!(header.contains("h=from:reply-to:subject:date:to:cc:message-id:in-reply-to:references:mime-version:content-type:content-transfer-encoding;"));
+        assert!(header.contains("t=1609459201; x=1610064001;"));
+    }
+
+    #[test]
+    fn test_sign_automatic_signed_headers() {
+        let raw_email = "From: joe@example.com\r\nTo: suzie@example.net\r\nReferences: <a@example.com>\r\nReferences: <b@example.com>\r\nSubject: hi\r\n\r\nHello\r\n";
+        let email = mailparse::parse_mail(raw_email.as_bytes()).unwrap();
+        let private_key =
+            rsa::RsaPrivateKey::read_pkcs1_pem_file(Path::new("./test/keys/2022.private")).unwrap();
+        let public_key = rsa::RsaPublicKey::from(&private_key);
+        let logger = test_logger();
+
+        let signer = SignerBuilder::recommended()
+            .with_automatic_signed_headers()
+            .with_private_key(DkimPrivateKey::Rsa(private_key))
+            .with_selector("s20")
+            .with_logger(&logger)
+            .with_signing_domain("example.com")
+            .build()
+            .unwrap();
+        let header = signer.sign(&email).unwrap();
+        assert!(header.contains(
+            " h=from:from:reply-to:subject:subject:date:to:to:cc:references:references;"
+        ));
+
+        let verify = |raw_email: &str| {
+            let email = mailparse::parse_mail(raw_email.as_bytes()).unwrap();
+            crate::verify_email_with_key(
+                &logger,
+                "example.com",
+                &email,
+                crate::DkimPublicKey::Rsa(public_key.clone()),
+            )
+            .unwrap()
+            .summary()
+            .to_owned()
+        };
+        let signed = format!("{}\r\n{}", header, raw_email);
+        assert_eq!(verify(&signed), "pass");
+        // Oversigned headers can't be added
+        assert_eq!(
+            verify(&format!("Cc: mallory@example.net\r\n{}", signed)),
+            "fail"
+        );
+        assert_eq!(
+            verify(&signed.replace("Subject: hi\r\n", "Subject: hi\r\nSubject: win\r\n")),
+            "fail"
+        );
+    }
+
+    #[test]
+    fn test_sign_deterministic() {
+        use crate::test_utils;
+
+        let email = mailparse::parse_mail(test_utils::SAMPLE_MESSAGE.as_bytes()).unwrap();
+        let logger = test_logger();
+        let time = chrono::Utc.with_ymd_and_hms(2021, 1, 1, 0, 0, 1).unwrap();
+        let sign = |private_key: DkimPrivateKey, selector: &str| {
+            SignerBuilder::recommended()
+                .with_deterministic_output(time)
+                .with_private_key(private_key)
+                .with_selector(selector)
+                .with_logger(&logger)
+                .with_signing_domain(test_utils::DOMAIN)
+                .build()
+                .unwrap()
+                .sign(&email)
+                .unwrap()
+        };
+
+        // The output must never change: these headers are part of the API
+        let header = sign(test_utils::rsa_private_key(), test_utils::RSA_SELECTOR);
+        assert_eq!(
+            header,
+            "DKIM-Signature: v=1; a=rsa-sha256; c=relaxed/relaxed; d=example.com; s=2022;\r\n\
+             \tt=1609459201; x=1610064001;\r\n\
+             \th=from:reply-to:subject:date:to:cc:message-id:in-reply-to:references:\r\n\
+             \tmime-version:content-type:content-transfer-encoding;\r\n\
+             \tbh=2jUSOH9NhtVGCQWNr9BrIAPreKQjO6Sn7XIkfJVOzv8=;\r\n\
+             \tb=Y54mrw8ZAv76/ap5/Y/e+y9vHFUgwv71QS/6NOlnHO20XnqFgrmVrTVk4L9kX/Wh1zcv9Q8S1UU\r\n\
+             \tosA7e36vxZQCa5G4mUEwqvgBODTlC9+CNzMjmv911TOhHMNVkNh6q9LRlJ/gv5cLoT+OOA3vuZmth\r\n\
+             \tFzzL5kNTfqsEesOKhOtjMp/W8BDRR9dvupILPCRXjy/fdrOAM+hL7bwmPq885GSkeg6WXYXOErj3d\r\n\
+             \tEDyiNxoLqXftwIARxJHaCkzzlDQM2fL0z/0X9oPSY3gQY/V2QoOJsb4BEQaahJ64SZTQ0T1UaZYI4\r\n\
+             \tVsyQaZzNK8wObKr4mZmEu3w+dmA7pizPqWRA==;"
+        );
+        let header = sign(
+            test_utils::ed25519_private_key(),
+            test_utils::ED25519_SELECTOR,
+        );
+        assert_eq!(
+            header,
+            "DKIM-Signature: v=1; a=ed25519-sha256; c=relaxed/relaxed; d=example.com; s=ed;\r\n\
+             \tt=1609459201; x=1610064001;\r\n\
+             \th=from:reply-to:subject:date:to:cc:message-id:in-reply-to:references:\r\n\
+             \tmime-version:content-type:content-transfer-encoding;\r\n\
+             \tbh=2jUSOH9NhtVGCQWNr9BrIAPreKQjO6Sn7XIkfJVOzv8=;\r\n\
+             \tb=DYIY6Vcuf7qSRBf3N8q+FTtQaCw6s5IoXakbwfDNKpUKhsSkg9HSJkThhJeDnm/od8uqBrZK8bM\r\n\
+             \tgtLFyHff4AA==;"
+        );
+    }
+
+    #[test]
+    fn test_sign_tag_order() {
+        let email = mailparse::parse_mail(
+            b"Subject: subject\r\nFrom: joe@example.com\r\n\r\nHello Alice\r\n",
+        )
+        .unwrap();
+
+        let private_key =
+            rsa::RsaPrivateKey::read_pkcs1_pem_file(Path::new("./test/keys/2022.private")).unwrap();
+        let logger = test_logger();
+        let time = chrono::Utc.with_ymd_and_hms(2021, 1, 1, 0, 0, 1).unwrap();
+
+        let signer = SignerBuilder::new()
+            .with_signed_headers(&["From", "Subject"])
+            .unwrap()
+            .with_private_key(DkimPrivateKey::Rsa(private_key))
+            .with_selector("s20")
+            .with_logger(&logger)
+            .with_signing_domain("example.com")
+            .with_time(time)
+            .with_tag_order(&["b", "v", "a", "c", "d", "s", "t", "h"])
+            .build()
+            .unwrap();
+        let header = signer.sign(&email).unwrap();
+
+        assert!(header.starts_with("DKIM-Signature: v=1; a=rsa-sha256; c=simple/simple; d=example.com; s=s20; t=1609459201; h=from:subject; bh="));
+        let (_, signature) = header.rsplit_once("; ").unwrap();
+        assert!(signature.starts_with("b="));
+    }
+
+    #[test]
+    fn test_sign_folded_format_verifies() {
+        let raw_email = "From: joe@example.com\r\nTo: suzie@example.net\r\nSubject: hi\r\n\r\nHello  Suzie \r\n";
+        let email = mailparse::parse_mail(raw_email.as_bytes()).unwrap();
+
+        let private_key =
+            rsa::RsaPrivateKey::read_pkcs1_pem_file(Path::new("./test/keys/2022.private")).unwrap();
+        let public_key = rsa::RsaPublicKey::from(&private_key);
+        let logger = test_logger();
+
+        for (format, canonicalization) in [
+            (HeaderFormat::opendkim(), canonicalization::Type::Simple),
+            (HeaderFormat::opendkim(), canonicalization::Type::Relaxed),
+            (HeaderFormat::rspamd(), canonicalization::Type::Simple),
+            (HeaderFormat::rspamd(), canonicalization::Type::Relaxed),
+            (
+                HeaderFormat::new().with_folding(60, Indentation::Spaces(4)),
+                canonicalization::Type::Simple,
+            ),
+        ] {
+            let signer = SignerBuilder::new()
+                .with_signed_headers(&["From", "To", "Subject"])
+                .unwrap()
+                .with_private_key(DkimPrivateKey::Rsa(private_key.clone()))
+                .with_selector("s20")
+                .with_logger(&logger)
+                .with_signing_domain("example.com")
+                .with_header_canonicalization(canonicalization.clone())
+                .with_body_canonicalization(canonicalization)
+                .with_format(format)
+                .build()
+                .unwrap();
+            let header = signer.sign(&email).unwrap();
+            assert!(header.contains("\r\n\tb=") || header.contains("\r\n    b="));
+
+            let signed = format!("{}\r\n{}", header, raw_email);
+            let email = mailparse::parse_mail(signed.as_bytes()).unwrap();
+            let result = crate::verify_email_with_key(
+                &logger,
+                "example.com",
+                &email,
+                crate::DkimPublicKey::Rsa(public_key.clone()),
+            )
+            .unwrap();
+            assert_eq!(result.summary(), "pass");
+        }
+    }
+
+    #[test]
+    fn test_sign_normalized_line_endings() {
+        let private_key =
+            rsa::RsaPrivateKey::read_pkcs1_pem_file(Path::new("./test/keys/2022.private")).unwrap();
+        let logger = test_logger();
+        let time = chrono::Utc.with_ymd_and_hms(2021, 1, 1, 0, 0, 1).unwrap();
+        let signer = SignerBuilder::new()
+            .with_signed_headers(&["From"])
+            .unwrap()
+            .with_private_key(DkimPrivateKey::Rsa(private_key))
+            .with_selector("s20")
+            .with_logger(&logger)
+            .with_signing_domain("example.com")
+            .with_time(time)
+            .with_normalized_line_endings(true)
+            .build()
+            .unwrap();
+
+        let sign = |raw: &str| signer.sign(&mailparse::parse_mail(raw.as_bytes()).unwrap());
+        let crlf = sign("From: joe@example.com\r\n\r\nHello\r\nBye\r\n").unwrap();
+        assert_eq!(sign("From: joe@example.com\n\nHello\nBye\n").unwrap(), crlf);
+        // A missing trailing CRLF is added by the canonicalization
+        assert_eq!(sign("From: joe@example.com\n\nHello\nBye").unwrap(), crlf);
+
+        let empty = sign("From: joe@example.com\r\n\r\n").unwrap();
+        assert_eq!(sign("From: joe@example.com\n\n\n\n").unwrap(), empty);
+        assert_eq!(sign("From: joe@example.com\r\n").unwrap(), empty);
+    }
+
+    #[test]
+    fn test_sign_reuse() {
+        let private_key =
+            rsa::RsaPrivateKey::read_pkcs1_pem_file(Path::new("./test/keys/2022.private")).unwrap();
+        let logger = test_logger();
+        let time = chrono::Utc.with_ymd_and_hms(2021, 1, 1, 0, 0, 1).unwrap();
+        let build_signer = || {
+            SignerBuilder::new()
+                .with_signed_headers(&["From", "Subject"])
+                .unwrap()
+                .with_private_key(DkimPrivateKey::Rsa(private_key.clone()))
+                .with_selector("s20")
+                .with_logger(&logger)
+                .with_signing_domain("example.com")
+                .with_time(time)
+                .with_expiry(chrono::Duration::hours(3))
+                .build()
+                .unwrap()
+        };
+
+        let signer = build_signer();
+        for body in ["Hello Alice\r\n", "Hello Bob\r\n"] {
+            let raw_email = format!("Subject: subject\r\nFrom: joe@example.com\r\n\r\n{}", body);
+            let email = mailparse::parse_mail(raw_email.as_bytes()).unwrap();
+
+            let header = signer.sign(&email).unwrap();
+            assert!(header.contains("; t=1609459201; x=1609470001; b="));
+            assert_eq!(header, build_signer().sign(&email).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_sign_body_hash_cache() {
+        let private_key =
+            rsa::RsaPrivateKey::read_pkcs1_pem_file(Path::new("./test/keys/2022.private")).unwrap();
+        let logger = test_logger();
+        let time = chrono::Utc.with_ymd_and_hms(2021, 1, 1, 0, 0, 1).unwrap();
+        let cache = Arc::new(hash::BodyHashCache::new(2));
+        let build_signer = |cache: Option<Arc<hash::BodyHashCache>>| {
+            let builder = SignerBuilder::recommended()
+                .with_private_key(DkimPrivateKey::Rsa(private_key.clone()))
+                .with_selector("s20")
+                .with_logger(&logger)
+                .with_signing_domain("example.com")
+                .with_time(time);
+            match cache {
+                Some(cache) => builder.with_body_hash_cache(cache),
+                None => builder,
+            }
+            .build()
+            .unwrap()
+        };
+
+        let signer = build_signer(Some(Arc::clone(&cache)));
+        for (to, body) in [
+            ("alice", "Hello\r\n"),
+            ("bob", "Hello\r\n"),
+            ("carol", "Bye\r\n"),
+            ("dave", "Hello\r\n"),
+            ("erin", "Hello again\r\n"),
+        ] {
+            let raw_email = format!(
+                "Subject: subject\r\nFrom: joe@example.com\r\nTo: {}@example.net\r\n\r\n{}",
+                to, body
+            );
+            let email = mailparse::parse_mail(raw_email.as_bytes()).unwrap();
+            assert_eq!(
+                signer.sign(&email).unwrap(),
+                build_signer(None).sign(&email).unwrap()
+            );
+        }
+        // "Bye" was evicted
+        assert_eq!(cache.len(), 2);
+
+        // A changed body misses the cache, even with the same length
+        let tampered =
+            mailparse::parse_mail(b"Subject: subject\r\nFrom: joe@example.com\r\n\r\nHellO\r\n")
+                .unwrap();
+        assert_eq!(
+            signer.sign(&tampered).unwrap(),
+            build_signer(None).sign(&tampered).unwrap()
+        );
+        assert_eq!(cache.len(), 2);
+    }
+
+    #[test]
+    fn test_sign_hooks() {
+        use std::sync::Mutex;
+
+        #[derive(Default)]
+        struct RecordingHooks {
+            events: Mutex<Vec<String>>,
+        }
+        impl SigningHooks for RecordingHooks {
+            fn before_canonicalization(&self, email: &mailparse::ParsedMail) {
+                let event = format!("before {}", email.headers.len());
+                self.events.lock().unwrap().push(event);
+            }
+            fn after_hash(&self, signed_header: &str, hash: &[u8]) {
+                assert!(signed_header.trim_end_matches(';').ends_with(" b="));
+                let event = format!("hash {}", hash.len());
+                self.events.lock().unwrap().push(event);
+            }
+            fn after_signature(&self, header: &str) {
+                let event = format!("signature {}", header.starts_with("DKIM-Signature: "));
+                self.events.lock().unwrap().push(event);
+            }
+        }
+
+        let private_key =
+            rsa::RsaPrivateKey::read_pkcs1_pem_file(Path::new("./test/keys/2022.private")).unwrap();
+        let logger = test_logger();
+        let hooks = Arc::new(RecordingHooks::default());
+        let signer = SignerBuilder::new()
+            .with_signed_headers(&["From", "Subject"])
+            .unwrap()
+            .with_private_key(DkimPrivateKey::Rsa(private_key))
+            .with_selector("s20")
+            .with_logger(&logger)
+            .with_signing_domain("example.com")
+            .with_hooks(hooks.clone())
+            .build()
+            .unwrap();
+
+        let raw_email = "Subject: subject\r\nFrom: joe@example.com\r\n\r\nHello Alice\r\n";
+        let email = mailparse::parse_mail(raw_email.as_bytes()).unwrap();
+        signer.sign(&email).unwrap();
+        assert_eq!(
+            *hooks.events.lock().unwrap(),
+            vec!["before 2", "hash 32", "signature true"]
+        );
+    }
+
+    #[test]
+    fn test_sign_static_signer() {
+        fn build_signer(domain: String, selector: String) -> DKIMSigner<'static> {
+            let private_key =
+                rsa::RsaPrivateKey::read_pkcs1_pem_file(Path::new("./test/keys/2022.private"))
+                    .unwrap();
+            let signed_headers = vec!["From".to_owned(), "Subject".to_owned()];
+            SignerBuilder::new()
+                .with_signed_headers(&signed_headers)
+                .unwrap()
+                .with_private_key(DkimPrivateKey::Rsa(private_key))
+                .with_selector(selector)
+                .with_owned_logger(test_logger())
+                .with_signing_domain(domain)
+                .build()
+                .unwrap()
+        }
+
+        let signer = build_signer("example.com".to_owned(), "s20".to_owned());
+        let raw_email = "Subject: subject\r\nFrom: joe@example.com\r\n\r\nHello Alice\r\n";
+        let email = mailparse::parse_mail(raw_email.as_bytes()).unwrap();
+        let header = signer.sign(&email).unwrap();
+        assert!(header.contains("d=example.com; s=s20;"));
+        assert!(header.contains("h=from:subject;"));
+    }
+
+    #[test]
+    fn test_sign_builder_invalid_tags() {
+        let build = |selector: &str, domain: Option<&str>| {
+            let private_key =
+                rsa::RsaPrivateKey::read_pkcs1_pem_file(Path::new("./test/keys/2022.private"))
+                    .unwrap();
+            let builder = SignerBuilder::new()
+                .with_signed_headers(&["From", "Subject"])
+                .unwrap()
+                .with_private_key(DkimPrivateKey::Rsa(private_key))
+                .with_selector(selector.to_owned())
+                .with_owned_logger(test_logger());
+            match domain {
+                Some(domain) => builder.with_signing_domain(domain.to_owned()),
+                None => builder,
+            }
+            .build()
+            .err()
+        };
+
+        assert_eq!(build("s20", Some("example.com")), None);
+        assert_eq!(
+            build("s20; x=1", Some("example.com")),
+            Some(DKIMError::BuilderError("invalid character in tag value"))
+        );
+        assert_eq!(
+            build("s20", None),
+            Some(DKIMError::BuilderError("missing required signing domain"))
+        );
+    }
+
+    #[test]
+    fn test_signer_send_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<DKIMSigner<'static>>();
+        assert_send_sync::<DKIMSigner<'_>>();
+        assert_send_sync::<DkimPrivateKey>();
+
+        let private_key =
+            rsa::RsaPrivateKey::read_pkcs1_pem_file(Path::new("./test/keys/2022.private")).unwrap();
+        let signer = Arc::new(
+            SignerBuilder::new()
+                .with_signed_headers(&["From", "Subject"])
+                .unwrap()
+                .with_private_key(DkimPrivateKey::Rsa(private_key))
+                .with_selector("s20")
+                .with_owned_logger(test_logger())
+                .with_signing_domain("example.com")
+                .build()
+                .unwrap(),
+        );
+
+        let handles: Vec<_> = (0..4)
+            .map(|i| {
+                let signer = Arc::clone(&signer);
+                std::thread::spawn(move || {
+                    let raw_email = format!(
+                        "Subject: subject\r\nFrom: joe@example.com\r\n\r\nHello {}\r\n",
+                        i
+                    );
+                    let email = mailparse::parse_mail(raw_email.as_bytes()).unwrap();
+                    signer.sign(&email).unwrap()
+                })
+            })
+            .collect();
+        for handle in handles {
+            assert!(handle.join().unwrap().starts_with("DKIM-Signature: "));
+        }
+    }
 }
@@ -0,0 +1,325 @@
+//! Signing policy: rules deciding, from the attributes of each message (From
+//! domain, stream and size), whether to sign it, with which keys, which
+//! headers and which canonicalization. The rules are plain data, loadable
+//! from a configuration file with the `serde` feature.
+
+use crate::registry::{DomainPattern, SignerRegistry};
+use crate::{header_from_domain, parser, DKIMError, DKIMSigner, DkimPrivateKey, SignerBuilder};
+
+/// Whether the messages matching a rule are signed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Deserialize),
+    serde(rename_all = "lowercase")
+)]
+pub enum SigningAction {
+    #[default]
+    Sign,
+    Skip,
+}
+
+/// Rule of a [SigningConfig]. The criteria left empty match any message.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize), serde(default))]
+pub struct SigningRule {
+    /// Patterns of the From domains, as in [SignerRegistry::with_signer]
+    pub domains: Vec<String>,
+    /// Streams of the messages, such as `transactional` or `marketing`
+    pub streams: Vec<String>,
+    /// Minimum size of the messages, in bytes
+    pub min_size: Option<usize>,
+    /// Maximum size of the messages, in bytes
+    pub max_size: Option<usize>,
+    pub action: SigningAction,
+    /// Selectors of the keys to sign with. By default, all the keys of the
+    /// From domain sign.
+    pub selectors: Vec<String>,
+    /// Headers to sign. By default they're selected from each message, see
+    /// [SignerBuilder::with_automatic_signed_headers].
+    pub signed_headers: Option<Vec<String>>,
+    /// Canonicalization, as in the `c=` tag. `relaxed/relaxed` by default.
+    pub canonicalization: Option<String>,
+}
+
+/// Rules of a [SigningPolicy]. The first rule matching a message applies;
+/// messages matching none aren't signed.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize), serde(default))]
+pub struct SigningConfig {
+    pub rules: Vec<SigningRule>,
+}
+
+/// Private key published at a selector of a domain
+#[derive(Debug, Clone)]
+pub struct SigningKey {
+    pub domain: String,
+    pub selector: String,
+    pub private_key: DkimPrivateKey,
+}
+
+/// Attributes of a message matched by the rules
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MessageAttributes<'a> {
+    /// Domain of the From header
+    pub from_domain: &'a str,
+    /// Stream of the message, as defined by the sender
+    pub stream: Option<&'a str>,
+    /// Size of the message, in bytes
+    pub size: usize,
+}
+
+struct CompiledRule {
+    rule: SigningRule,
+    domains: Vec<DomainPattern>,
+    /// `None` when the rule skips signing
+    signers: Option<SignerRegistry<'static>>,
+}
+
+impl CompiledRule {
+    fn matches(&self, attributes: &MessageAttributes) -> bool {
+        let rule = &self.rule;
+        (self.domains.is_empty()
+            || self
+                .domains
+                .iter()
+                .any(|pattern| pattern.matches(attributes.from_domain).is_some()))
+            && (rule.streams.is_empty()
+                || attributes
+                    .stream
+                    .is_some_and(|stream| rule.streams.iter().any(|s| s == stream)))
+            && rule.min_size.is_none_or(|min| attributes.size >= min)
+            && rule.max_size.is_none_or(|max| attributes.size <= max)
+    }
+}
+
+/// Signing rules and the signers they use, built once from a
+/// [SigningConfig] and the keys of the domains
+pub struct SigningPolicy {
+    rules: Vec<CompiledRule>,
+}
+
+impl SigningPolicy {
+    /// Build the signers of each rule with `keys`. Fails if a rule has an
+    /// invalid canonicalization or signed headers list.
+    pub fn new(
+        logger: &slog::Logger,
+        config: &SigningConfig,
+        keys: &[SigningKey],
+    ) -> Result<Self, DKIMError> {
+        let rules = config
+            .rules
+            .iter()
+            .map(|rule| {
+                let signers = match rule.action {
+                    SigningAction::Sign => Some(build_signers(logger, rule, keys)?),
+                    SigningAction::Skip => None,
+                };
+                Ok(CompiledRule {
+                    rule: rule.clone(),
+                    domains: rule
+                        .domains
+                        .iter()
+                        .map(|d| DomainPattern::parse(d))
+                        .collect(),
+                    signers,
+                })
+            })
+            .collect::<Result<_, DKIMError>>()?;
+        Ok(Self { rules })
+    }
+
+    /// The rule applying to a message, if any
+    pub fn rule_for(&self, attributes: &MessageAttributes) -> Option<&SigningRule> {
+        self.compiled_rule_for(attributes).map(|rule| &rule.rule)
+    }
+
+    /// Signers of a message: empty if it shouldn't be signed
+    pub fn signers_for(&self, attributes: &MessageAttributes) -> Vec<&DKIMSigner<'static>> {
+        match self
+            .compiled_rule_for(attributes)
+            .and_then(|rule| rule.signers.as_ref())
+        {
+            Some(signers) => signers.signers_for(attributes.from_domain),
+            None => vec![],
+        }
+    }
+
+    /// Sign a message of `stream` as decided by the rules. Returns one
+    /// `DKIM-Signature` header per signer, none if the message shouldn't be
+    /// signed, or [DKIMError::NoSignerForDomain] if no key of the rule
+    /// applying to the message can sign for its From domain.
+    pub fn sign<'b>(
+        &self,
+        email: &'b mailparse::ParsedMail<'b>,
+        stream: Option<&str>,
+    ) -> Result<Vec<String>, DKIMError> {
+        let from_domain = header_from_domain(email)?;
+        let attributes = MessageAttributes {
+            from_domain: &from_domain,
+            stream,
+            size: email.raw_bytes.len(),
+        };
+        match self
+            .compiled_rule_for(&attributes)
+            .and_then(|rule| rule.signers.as_ref())
+        {
+            Some(signers) => signers.sign(email),
+            None => Ok(vec![]),
+        }
+    }
+
+    fn compiled_rule_for(&self, attributes: &MessageAttributes) -> Option<&CompiledRule> {
+        self.rules.iter().find(|rule| rule.matches(attributes))
+    }
+}
+
+/// Signers of the keys selected by `rule`, configured as it specifies
+fn build_signers(
+    logger: &slog::Logger,
+    rule: &SigningRule,
+    keys: &[SigningKey],
+) -> Result<SignerRegistry<'static>, DKIMError> {
+    let (header_canonicalization, body_canonicalization) = parser::parse_canonicalization(Some(
+        rule.canonicalization
+            .as_deref()
+            .unwrap_or("relaxed/relaxed"),
+    ))?;
+
+    let mut registry = SignerRegistry::new();
+    for key in keys {
+        if !rule.selectors.is_empty() && !rule.selectors.contains(&key.selector) {
+            continue;
+        }
+        let builder = SignerBuilder::recommended();
+        let builder = match &rule.signed_headers {
+            Some(signed_headers) => builder.with_signed_headers(signed_headers)?,
+            None => builder.with_automatic_signed_headers(),
+        };
+        let signer = builder
+            .with_private_key(key.private_key.clone())
+            .with_selector(key.selector.clone())
+            .with_signing_domain(key.domain.clone())
+            .with_header_canonicalization(header_canonicalization.clone())
+            .with_body_canonicalization(body_canonicalization.clone())
+            .with_owned_logger(logger.clone())
+            .build()?;
+        registry = registry.with_domain_signer(signer);
+    }
+    Ok(registry)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{ed25519_private_key, rsa_private_key};
+
+    fn keys() -> Vec<SigningKey> {
+        vec![
+            SigningKey {
+                domain: "example.com".to_owned(),
+                selector: "rsa".to_owned(),
+                private_key: rsa_private_key(),
+            },
+            SigningKey {
+                domain: "example.com".to_owned(),
+                selector: "ed".to_owned(),
+                private_key: ed25519_private_key(),
+            },
+        ]
+    }
+
+    fn config() -> SigningConfig {
+        SigningConfig {
+            rules: vec![
+                SigningRule {
+                    min_size: Some(1000),
+                    action: SigningAction::Skip,
+                    ..Default::default()
+                },
+                SigningRule {
+                    domains: vec!["*.example.com".to_owned()],
+                    streams: vec!["marketing".to_owned()],
+                    selectors: vec!["rsa".to_owned()],
+                    signed_headers: Some(vec!["From".to_owned(), "Subject".to_owned()]),
+                    canonicalization: Some("relaxed/simple".to_owned()),
+                    ..Default::default()
+                },
+                SigningRule {
+                    domains: vec!["example.com".to_owned(), "*.example.com".to_owned()],
+                    ..Default::default()
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn test_signing_policy() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let policy = SigningPolicy::new(&logger, &config(), &keys()).unwrap();
+        let sign = |raw_email: &str, stream| {
+            let email = mailparse::parse_mail(raw_email.as_bytes()).unwrap();
+            policy.sign(&email, stream)
+        };
+
+        let raw_email = "From: joe@news.example.com\r\nSubject: hi\r\n\r\nHello\r\n";
+        let headers = sign(raw_email, Some("marketing")).unwrap();
+        assert_eq!(headers.len(), 1);
+        assert!(headers[0].contains("s=rsa; c=relaxed/simple;"));
+        assert!(headers[0].contains(" h=from:subject;"));
+
+        // Both keys, automatic signed headers
+        let headers = sign(raw_email, None).unwrap();
+        assert_eq!(headers.len(), 2);
+        assert!(headers[0].contains("s=rsa; c=relaxed/relaxed;"));
+        assert!(headers[0].contains(" h=from:from:reply-to:subject:subject:date:to:cc;"));
+        assert!(headers[1].contains("s=ed;"));
+
+        let large = format!("{}{}", raw_email, "a".repeat(1000));
+        assert_eq!(sign(&large, None).unwrap(), Vec::<String>::new());
+        let other = "From: joe@example.net\r\n\r\nHello\r\n";
+        assert_eq!(sign(other, None).unwrap(), Vec::<String>::new());
+
+        let attributes = MessageAttributes {
+            from_domain: "example.com",
+            stream: Some("marketing"),
+            size: 100,
+        };
+        assert_eq!(policy.rule_for(&attributes), Some(&config().rules[2]));
+        assert_eq!(policy.signers_for(&attributes).len(), 2);
+    }
+
+    #[test]
+    fn test_signing_policy_invalid() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let config = SigningConfig {
+            rules: vec![SigningRule {
+                canonicalization: Some("strict".to_owned()),
+                ..Default::default()
+            }],
+        };
+        assert!(SigningPolicy::new(&logger, &config, &keys()).is_err());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_signing_config_deserialize() {
+        let config: SigningConfig = serde_json::from_str(
+            r#"{
+                "rules": [
+                    {"min_size": 1000, "action": "skip"},
+                    {
+                        "domains": ["*.example.com"],
+                        "streams": ["marketing"],
+                        "selectors": ["rsa"],
+                        "signed_headers": ["From", "Subject"],
+                        "canonicalization": "relaxed/simple"
+                    },
+                    {"domains": ["example.com", "*.example.com"]}
+                ]
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(config, super::tests::config());
+    }
+}
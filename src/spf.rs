@@ -0,0 +1,1190 @@
+//! SPF checks (RFC 7208) of the client of an SMTP session, with the same
+//! [dns::Lookup] as the DKIM verification, so a single resolver and cache
+//! serves both when emitting Authentication-Results. Enabled by the `spf`
+//! feature.
+//!
+//! The `ptr` mechanism and the `%{p}` macro need reverse lookups, which
+//! [dns::Lookup] doesn't provide: `ptr` never matches, as when its lookup
+//! fails, and `%{p}` expands to `unknown`. Explanations (`exp=` modifier)
+//! aren't retrieved.
+
+use futures::future::BoxFuture;
+use slog::debug;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::Arc;
+
+use crate::dns;
+
+/// Maximum number of mechanisms and modifiers querying DNS (RFC 7208 section
+/// 4.6.4)
+const MAX_LOOKUPS: usize = 10;
+/// Maximum number of queries answered without records
+const MAX_VOID_LOOKUPS: usize = 2;
+/// Maximum number of mail exchangers of an `mx` mechanism
+const MAX_MX_NAMES: usize = 10;
+/// Maximum length of a domain name
+const MAX_DOMAIN_LEN: usize = 253;
+
+/// Verdict of the SPF check (RFC 7208 section 2.6)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum SpfVerdict {
+    /// The domain has no SPF record
+    None,
+    /// The domain makes no assertion about the client
+    Neutral,
+    /// The client is authorized
+    Pass,
+    /// The client isn't authorized
+    Fail,
+    /// The client probably isn't authorized
+    SoftFail,
+    /// A DNS error prevented the check, see [SpfResult::reason]
+    TempError,
+    /// The record of the domain is invalid, see [SpfResult::reason]
+    PermError,
+}
+
+impl SpfVerdict {
+    /// Returns the verdict as in the `Authentication-Results` header
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SpfVerdict::None => "none",
+            SpfVerdict::Neutral => "neutral",
+            SpfVerdict::Pass => "pass",
+            SpfVerdict::Fail => "fail",
+            SpfVerdict::SoftFail => "softfail",
+            SpfVerdict::TempError => "temperror",
+            SpfVerdict::PermError => "permerror",
+        }
+    }
+}
+
+impl fmt::Display for SpfVerdict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Identity checked by SPF
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SpfIdentity {
+    /// The envelope sender, given by the `MAIL FROM` command
+    MailFrom,
+    /// The domain given by the `HELO` or `EHLO` command
+    Helo,
+}
+
+impl SpfIdentity {
+    /// Returns the property of the `Authentication-Results` header carrying
+    /// the identity
+    pub fn property(&self) -> &'static str {
+        match self {
+            SpfIdentity::MailFrom => "smtp.mailfrom",
+            SpfIdentity::Helo => "smtp.helo",
+        }
+    }
+}
+
+/// Result of the SPF check
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SpfResult {
+    verdict: SpfVerdict,
+    identity: SpfIdentity,
+    domain: String,
+    mechanism: Option<String>,
+    reason: Option<String>,
+}
+
+impl SpfResult {
+    pub fn verdict(&self) -> SpfVerdict {
+        self.verdict
+    }
+
+    pub fn identity(&self) -> SpfIdentity {
+        self.identity
+    }
+
+    /// Returns the domain whose record was checked
+    pub fn domain(&self) -> &str {
+        &self.domain
+    }
+
+    /// Returns the mechanism which matched the client, as written in the
+    /// record, for instance `-all`
+    pub fn mechanism(&self) -> Option<&str> {
+        self.mechanism.as_deref()
+    }
+
+    /// Returns why the check ended with [SpfVerdict::TempError] or
+    /// [SpfVerdict::PermError]
+    pub fn reason(&self) -> Option<&str> {
+        self.reason.as_deref()
+    }
+}
+
+/// Displays the verdict, followed by the reason of an error, as in
+/// `permerror (too many DNS lookups)`
+impl fmt::Display for SpfResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.reason {
+            Some(reason) => write!(f, "{} ({})", self.verdict, reason),
+            None => write!(f, "{}", self.verdict),
+        }
+    }
+}
+
+/// Check whether the client at `ip` is authorized to send messages from the
+/// envelope sender `mail_from`. An empty sender (null reverse-path) is
+/// checked as `postmaster` at the `helo` domain, as RFC 7208 requires.
+pub async fn check_mail_from(
+    logger: &slog::Logger,
+    ip: IpAddr,
+    helo: &str,
+    mail_from: &str,
+    resolver: Arc<dyn dns::Lookup>,
+) -> SpfResult {
+    let mail_from = mail_from
+        .trim()
+        .trim_start_matches('<')
+        .trim_end_matches('>');
+    let sender = match mail_from.rsplit_once('@') {
+        Some(("", domain)) => format!("postmaster@{}", domain),
+        Some(_) => mail_from.to_owned(),
+        None if mail_from.is_empty() => format!("postmaster@{}", helo),
+        None => format!("postmaster@{}", mail_from),
+    };
+    check(logger, ip, helo, sender, SpfIdentity::MailFrom, resolver).await
+}
+
+/// Check whether the client at `ip` is authorized to use the `helo` domain
+pub async fn check_helo(
+    logger: &slog::Logger,
+    ip: IpAddr,
+    helo: &str,
+    resolver: Arc<dyn dns::Lookup>,
+) -> SpfResult {
+    let sender = format!("postmaster@{}", helo);
+    check(logger, ip, helo, sender, SpfIdentity::Helo, resolver).await
+}
+
+async fn check(
+    logger: &slog::Logger,
+    ip: IpAddr,
+    helo: &str,
+    sender: String,
+    identity: SpfIdentity,
+    resolver: Arc<dyn dns::Lookup>,
+) -> SpfResult {
+    // IPv4-mapped addresses are checked as IPv4 addresses
+    let ip = match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+        ip => ip,
+    };
+    let (_, domain) = sender.rsplit_once('@').expect("the sender has a domain");
+    let domain = dns::normalize_name(domain);
+    let mut checker = Checker {
+        logger,
+        resolver: resolver.as_ref(),
+        ip,
+        sender: &sender,
+        helo,
+        lookups: 0,
+        void_lookups: 0,
+    };
+    let (verdict, mechanism, reason) = match checker.check_host(domain.clone()).await {
+        Ok((verdict, mechanism)) => (verdict, mechanism, None),
+        Err(err) => (err.verdict, None, Some(err.reason)),
+    };
+    debug!(logger, "SPF {} for {}", verdict, domain);
+    SpfResult {
+        verdict,
+        identity,
+        domain,
+        mechanism,
+        reason,
+    }
+}
+
+/// Error ending the check
+#[derive(Debug)]
+struct Error {
+    verdict: SpfVerdict,
+    reason: String,
+}
+
+impl Error {
+    fn temp(reason: impl Into<String>) -> Self {
+        Self {
+            verdict: SpfVerdict::TempError,
+            reason: reason.into(),
+        }
+    }
+
+    fn perm(reason: impl Into<String>) -> Self {
+        Self {
+            verdict: SpfVerdict::PermError,
+            reason: reason.into(),
+        }
+    }
+}
+
+/// Verdict and matching mechanism of a record
+type Outcome = (SpfVerdict, Option<String>);
+
+struct Checker<'c> {
+    logger: &'c slog::Logger,
+    resolver: &'c dyn dns::Lookup,
+    ip: IpAddr,
+    sender: &'c str,
+    helo: &'c str,
+    lookups: usize,
+    void_lookups: usize,
+}
+
+impl Checker<'_> {
+    /// The `check_host()` function of RFC 7208, evaluating the record of
+    /// `domain`
+    fn check_host(&mut self, domain: String) -> BoxFuture<'_, Result<Outcome, Error>> {
+        Box::pin(async move {
+            if !is_valid_domain(&domain) {
+                return Ok((SpfVerdict::None, None));
+            }
+            let Some(record) = self.record(&domain).await? else {
+                return Ok((SpfVerdict::None, None));
+            };
+            for directive in &record.directives {
+                if self.matches(&directive.mechanism, &domain).await? {
+                    return Ok((directive.qualifier, Some(directive.text.clone())));
+                }
+            }
+            if let Some(redirect) = &record.redirect {
+                self.count_lookup()?;
+                let target = self.expand_domain(redirect, &domain)?;
+                return match self.check_host(target).await? {
+                    (SpfVerdict::None, _) => Err(Error::perm(format!(
+                        "redirect of {} to a domain without SPF record",
+                        domain
+                    ))),
+                    outcome => Ok(outcome),
+                };
+            }
+            Ok((SpfVerdict::Neutral, None))
+        })
+    }
+
+    /// The SPF record of `domain`, if any
+    async fn record(&self, domain: &str) -> Result<Option<Record>, Error> {
+        let records = match self.resolver.lookup_txt(domain).await {
+            Ok(records) => records,
+            Err(err) if err.is_temporary() => return Err(Error::temp(err.to_string())),
+            Err(_) => return Ok(None),
+        };
+        let mut records = records.iter().filter(|record| is_spf_record(record));
+        let Some(record) = records.next() else {
+            return Ok(None);
+        };
+        if records.next().is_some() {
+            return Err(Error::perm(format!("multiple SPF records at {}", domain)));
+        }
+        Record::parse(record).map(Some)
+    }
+
+    async fn matches(&mut self, mechanism: &Mechanism, domain: &str) -> Result<bool, Error> {
+        match mechanism {
+            Mechanism::All => Ok(true),
+            Mechanism::Include(spec) => {
+                self.count_lookup()?;
+                let target = self.expand_domain(spec, domain)?;
+                match self.check_host(target.clone()).await? {
+                    (SpfVerdict::Pass, _) => Ok(true),
+                    (SpfVerdict::None, _) => Err(Error::perm(format!(
+                        "include of {} without SPF record",
+                        target
+                    ))),
+                    _ => Ok(false),
+                }
+            }
+            Mechanism::A(spec, cidr) => {
+                self.count_lookup()?;
+                let target = self.target(spec.as_ref(), domain)?;
+                let addresses = self.addresses(&target).await?;
+                self.void_lookup(addresses.is_empty())?;
+                Ok(addresses.iter().any(|ip| cidr.contains(*ip, self.ip)))
+            }
+            Mechanism::Mx(spec, cidr) => {
+                self.count_lookup()?;
+                let target = self.target(spec.as_ref(), domain)?;
+                let exchanges = lookup(self.resolver.lookup_mx(&target)).await?;
+                self.void_lookup(exchanges.is_empty())?;
+                if exchanges.len() > MAX_MX_NAMES {
+                    return Err(Error::perm(format!("too many MX records at {}", target)));
+                }
+                for exchange in exchanges {
+                    let addresses = self.addresses(&exchange).await?;
+                    if addresses.iter().any(|ip| cidr.contains(*ip, self.ip)) {
+                        return Ok(true);
+                    }
+                }
+                Ok(false)
+            }
+            Mechanism::Ptr(_) => {
+                self.count_lookup()?;
+                debug!(self.logger, "SPF ptr mechanism not supported");
+                Ok(false)
+            }
+            Mechanism::Ip4(network, len) => Ok(match self.ip {
+                IpAddr::V4(ip) => prefix_matches(&network.octets(), &ip.octets(), *len),
+                IpAddr::V6(_) => false,
+            }),
+            Mechanism::Ip6(network, len) => Ok(match self.ip {
+                IpAddr::V6(ip) => prefix_matches(&network.octets(), &ip.octets(), *len),
+                IpAddr::V4(_) => false,
+            }),
+            Mechanism::Exists(spec) => {
+                self.count_lookup()?;
+                let target = self.expand_domain(spec, domain)?;
+                let addresses = lookup(self.resolver.lookup_a(&target)).await?;
+                self.void_lookup(addresses.is_empty())?;
+                Ok(!addresses.is_empty())
+            }
+        }
+    }
+
+    /// Domain of an `a` or `mx` mechanism: the current domain by default
+    fn target(&self, spec: Option<&MacroString>, domain: &str) -> Result<String, Error> {
+        match spec {
+            Some(spec) => self.expand_domain(spec, domain),
+            None => Ok(domain.to_owned()),
+        }
+    }
+
+    /// Addresses of `name` in the family of the client address
+    async fn addresses(&self, name: &str) -> Result<Vec<IpAddr>, Error> {
+        Ok(match self.ip {
+            IpAddr::V4(_) => lookup(self.resolver.lookup_a(name))
+                .await?
+                .into_iter()
+                .map(IpAddr::V4)
+                .collect(),
+            IpAddr::V6(_) => lookup(self.resolver.lookup_aaaa(name))
+                .await?
+                .into_iter()
+                .map(IpAddr::V6)
+                .collect(),
+        })
+    }
+
+    fn count_lookup(&mut self) -> Result<(), Error> {
+        self.lookups += 1;
+        if self.lookups > MAX_LOOKUPS {
+            return Err(Error::perm("too many DNS lookups"));
+        }
+        Ok(())
+    }
+
+    fn void_lookup(&mut self, void: bool) -> Result<(), Error> {
+        if void {
+            self.void_lookups += 1;
+            if self.void_lookups > MAX_VOID_LOOKUPS {
+                return Err(Error::perm("too many void DNS lookups"));
+            }
+        }
+        Ok(())
+    }
+
+    /// Expand the macros of a domain-spec (RFC 7208 section 7), keeping the
+    /// rightmost labels of a name too long
+    fn expand_domain(&self, spec: &MacroString, domain: &str) -> Result<String, Error> {
+        let mut expanded = String::new();
+        for token in &spec.0 {
+            match token {
+                Token::Literal(literal) => expanded.push_str(literal),
+                Token::Macro(m) => expanded.push_str(&self.expand_macro(m, domain)?),
+            }
+        }
+        let mut name = expanded.trim_end_matches('.');
+        while name.len() > MAX_DOMAIN_LEN {
+            name = match name.split_once('.') {
+                Some((_, rest)) => rest,
+                None => return Err(Error::perm(format!("invalid domain {}", expanded))),
+            };
+        }
+        Ok(name.to_owned())
+    }
+
+    fn expand_macro(&self, m: &Macro, domain: &str) -> Result<String, Error> {
+        let (local_part, sender_domain) = self.sender.rsplit_once('@').unwrap_or(("", ""));
+        let value = match m.letter {
+            's' => self.sender.to_owned(),
+            'l' => local_part.to_owned(),
+            'o' => sender_domain.to_owned(),
+            'd' => domain.to_owned(),
+            'i' => match self.ip {
+                IpAddr::V4(ip) => ip.to_string(),
+                IpAddr::V6(ip) => ip
+                    .octets()
+                    .iter()
+                    .flat_map(|byte| [byte >> 4, byte & 0xf])
+                    .map(|nibble| format!("{:x}", nibble))
+                    .collect::<Vec<_>>()
+                    .join("."),
+            },
+            'p' => "unknown".to_owned(),
+            'v' => match self.ip {
+                IpAddr::V4(_) => "in-addr".to_owned(),
+                IpAddr::V6(_) => "ip6".to_owned(),
+            },
+            'h' => self.helo.to_owned(),
+            letter => {
+                return Err(Error::perm(format!(
+                    "macro {} only allowed in explanations",
+                    letter
+                )))
+            }
+        };
+
+        let mut parts: Vec<&str> = value.split(|c: char| m.delimiters.contains(&c)).collect();
+        if m.reverse {
+            parts.reverse();
+        }
+        if let Some(keep) = m.keep {
+            parts.drain(..parts.len().saturating_sub(keep));
+        }
+        let value = parts.join(".");
+        Ok(if m.url_escape {
+            url_escape(&value)
+        } else {
+            value
+        })
+    }
+}
+
+/// Query result: temporary errors end the check, a name without records
+/// has none
+async fn lookup<T>(
+    query: BoxFuture<'_, Result<Vec<T>, crate::DKIMError>>,
+) -> Result<Vec<T>, Error> {
+    match query.await {
+        Ok(records) => Ok(records),
+        Err(err) if err.is_temporary() => Err(Error::temp(err.to_string())),
+        // Retrying wouldn't help, the resolver can't answer
+        Err(err @ crate::DKIMError::UnsupportedLookup { .. }) => Err(Error::perm(err.to_string())),
+        Err(_) => Ok(vec![]),
+    }
+}
+
+fn is_spf_record(record: &str) -> bool {
+    // The record comes from the sender domain, it may not be ASCII
+    let record = record.as_bytes();
+    record
+        .get(..6)
+        .is_some_and(|prefix| prefix.eq_ignore_ascii_case(b"v=spf1"))
+        && record.get(6).is_none_or(|b| *b == b' ')
+}
+
+/// Whether `domain` is a multi-label domain name, the only ones with an SPF
+/// record
+fn is_valid_domain(domain: &str) -> bool {
+    domain.len() <= MAX_DOMAIN_LEN
+        && domain.contains('.')
+        && domain
+            .split('.')
+            .all(|label| !label.is_empty() && label.len() <= 63)
+}
+
+/// Whether the first `len` bits of `a` and `b` are equal
+fn prefix_matches(a: &[u8], b: &[u8], len: u8) -> bool {
+    let len = len as usize;
+    let (bytes, bits) = (len / 8, len % 8);
+    if a[..bytes] != b[..bytes] {
+        return false;
+    }
+    bits == 0 || (a[bytes] ^ b[bytes]) >> (8 - bits) == 0
+}
+
+/// Percent-encode the characters outside the unreserved set (RFC 3986)
+fn url_escape(value: &str) -> String {
+    let mut escaped = String::new();
+    for byte in value.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+            escaped.push(byte as char);
+        } else {
+            escaped.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    escaped
+}
+
+/// Prefix lengths of the addresses of an `a` or `mx` mechanism
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct DualCidr {
+    ip4: u8,
+    ip6: u8,
+}
+
+impl DualCidr {
+    /// Whether `ip` is in the network of `address`
+    fn contains(&self, address: IpAddr, ip: IpAddr) -> bool {
+        match (address, ip) {
+            (IpAddr::V4(a), IpAddr::V4(b)) => prefix_matches(&a.octets(), &b.octets(), self.ip4),
+            (IpAddr::V6(a), IpAddr::V6(b)) => prefix_matches(&a.octets(), &b.octets(), self.ip6),
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Mechanism {
+    All,
+    Include(MacroString),
+    A(Option<MacroString>, DualCidr),
+    Mx(Option<MacroString>, DualCidr),
+    Ptr(Option<MacroString>),
+    Ip4(Ipv4Addr, u8),
+    Ip6(Ipv6Addr, u8),
+    Exists(MacroString),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Directive {
+    qualifier: SpfVerdict,
+    mechanism: Mechanism,
+    /// The directive as written in the record
+    text: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Record {
+    directives: Vec<Directive>,
+    redirect: Option<MacroString>,
+}
+
+impl Record {
+    fn parse(record: &str) -> Result<Self, Error> {
+        let mut directives = vec![];
+        let mut redirect = None;
+        let mut explanation = None;
+        for term in record[6..].split(' ').filter(|term| !term.is_empty()) {
+            let name_end = term.find([':', '/', '=']).unwrap_or(term.len());
+            if term[name_end..].starts_with('=') {
+                let (name, value) = (&term[..name_end], &term[name_end + 1..]);
+                if !is_modifier_name(name) {
+                    return Err(Error::perm(format!("invalid modifier {}", term)));
+                }
+                let value = MacroString::parse(value)?;
+                let slot = match name.to_ascii_lowercase().as_str() {
+                    "redirect" => &mut redirect,
+                    "exp" => &mut explanation,
+                    // Unknown modifiers are ignored
+                    _ => continue,
+                };
+                if slot.replace(value).is_some() {
+                    return Err(Error::perm(format!("duplicate {} modifier", name)));
+                }
+            } else {
+                directives.push(Directive::parse(term)?);
+            }
+        }
+        Ok(Self {
+            directives,
+            redirect,
+        })
+    }
+}
+
+fn is_modifier_name(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_alphabetic())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c))
+}
+
+impl Directive {
+    fn parse(term: &str) -> Result<Self, Error> {
+        let (qualifier, rest) = match term.as_bytes()[0] {
+            b'+' => (SpfVerdict::Pass, &term[1..]),
+            b'-' => (SpfVerdict::Fail, &term[1..]),
+            b'~' => (SpfVerdict::SoftFail, &term[1..]),
+            b'?' => (SpfVerdict::Neutral, &term[1..]),
+            _ => (SpfVerdict::Pass, term),
+        };
+        let name_end = rest.find([':', '/']).unwrap_or(rest.len());
+        let (name, args) = (&rest[..name_end], &rest[name_end..]);
+        let invalid = || Error::perm(format!("invalid mechanism {}", term));
+        let domain_spec = |args: &str| match args.strip_prefix(':') {
+            Some(spec) => MacroString::parse(spec).map(Some),
+            None if args.is_empty() => Ok(None),
+            None => Err(invalid()),
+        };
+        let required = |spec: Option<MacroString>| spec.ok_or_else(invalid);
+
+        let mechanism = match name.to_ascii_lowercase().as_str() {
+            "all" if args.is_empty() => Mechanism::All,
+            "include" => Mechanism::Include(required(domain_spec(args)?)?),
+            "a" => {
+                let (args, cidr) = parse_dual_cidr(args).ok_or_else(invalid)?;
+                Mechanism::A(domain_spec(args)?, cidr)
+            }
+            "mx" => {
+                let (args, cidr) = parse_dual_cidr(args).ok_or_else(invalid)?;
+                Mechanism::Mx(domain_spec(args)?, cidr)
+            }
+            "ptr" => Mechanism::Ptr(domain_spec(args)?),
+            "ip4" => {
+                let (address, len) = parse_network(args, 32).ok_or_else(invalid)?;
+                Mechanism::Ip4(address.parse().map_err(|_| invalid())?, len)
+            }
+            "ip6" => {
+                let (address, len) = parse_network(args, 128).ok_or_else(invalid)?;
+                Mechanism::Ip6(address.parse().map_err(|_| invalid())?, len)
+            }
+            "exists" => Mechanism::Exists(required(domain_spec(args)?)?),
+            _ => return Err(invalid()),
+        };
+        Ok(Self {
+            qualifier,
+            mechanism,
+            text: term.to_owned(),
+        })
+    }
+}
+
+/// Split the prefix lengths (`/24//64`) at the end of the arguments of an `a`
+/// or `mx` mechanism
+fn parse_dual_cidr(args: &str) -> Option<(&str, DualCidr)> {
+    let mut cidr = DualCidr { ip4: 32, ip6: 128 };
+    let mut args = args;
+    if let Some((rest, len)) = split_prefix_len(args, "//", 128) {
+        args = rest;
+        cidr.ip6 = len?;
+    }
+    if let Some((rest, len)) = split_prefix_len(args, "/", 32) {
+        args = rest;
+        cidr.ip4 = len?;
+    }
+    Some((args, cidr))
+}
+
+/// Split `{separator}{length}` at the end of `args`. The length is `None` if
+/// it's invalid.
+fn split_prefix_len<'a>(args: &'a str, separator: &str, max: u8) -> Option<(&'a str, Option<u8>)> {
+    let (rest, len) = args.rsplit_once(separator)?;
+    if len.is_empty() || !len.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    Some((rest, parse_prefix_len(len, max)))
+}
+
+/// Prefix length, without leading zeros
+fn parse_prefix_len(len: &str, max: u8) -> Option<u8> {
+    if len.len() > 1 && len.starts_with('0') {
+        return None;
+    }
+    len.parse().ok().filter(|len| *len <= max)
+}
+
+/// The address and prefix length of an `ip4` or `ip6` mechanism
+fn parse_network(args: &str, max: u8) -> Option<(&str, u8)> {
+    let network = args.strip_prefix(':')?;
+    match network.split_once('/') {
+        Some((address, len)) => Some((address, parse_prefix_len(len, max)?)),
+        None => Some((network, max)),
+    }
+}
+
+/// Domain-spec, with macros
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct MacroString(Vec<Token>);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Literal(String),
+    Macro(Macro),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Macro {
+    /// Lowercase letter of the macro
+    letter: char,
+    /// Number of rightmost parts to keep
+    keep: Option<usize>,
+    reverse: bool,
+    delimiters: Vec<char>,
+    url_escape: bool,
+}
+
+impl MacroString {
+    fn parse(value: &str) -> Result<Self, Error> {
+        let invalid = || Error::perm(format!("invalid macro string {}", value));
+        let mut tokens = vec![];
+        let mut literal = String::new();
+        let mut chars = value.chars();
+        while let Some(c) = chars.next() {
+            if c != '%' {
+                if !('!'..='~').contains(&c) {
+                    return Err(invalid());
+                }
+                literal.push(c);
+                continue;
+            }
+            match chars.next() {
+                Some('%') => literal.push('%'),
+                Some('_') => literal.push(' '),
+                Some('-') => literal.push_str("%20"),
+                Some('{') => {
+                    let rest = chars.as_str();
+                    let end = rest.find('}').ok_or_else(invalid)?;
+                    let m = Macro::parse(&rest[..end]).ok_or_else(invalid)?;
+                    chars = rest[end + 1..].chars();
+                    if !literal.is_empty() {
+                        tokens.push(Token::Literal(std::mem::take(&mut literal)));
+                    }
+                    tokens.push(Token::Macro(m));
+                }
+                _ => return Err(invalid()),
+            }
+        }
+        if !literal.is_empty() {
+            tokens.push(Token::Literal(literal));
+        }
+        Ok(Self(tokens))
+    }
+}
+
+impl Macro {
+    /// Parse the content of `%{...}`
+    fn parse(content: &str) -> Option<Self> {
+        let mut chars = content.chars();
+        let letter = chars.next()?;
+        if !"slodiphcrtv".contains(letter.to_ascii_lowercase()) {
+            return None;
+        }
+        let rest = chars.as_str();
+        let digits_end = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let keep = match &rest[..digits_end] {
+            "" => None,
+            digits => Some(digits.parse().ok().filter(|keep| *keep > 0)?),
+        };
+        let rest = &rest[digits_end..];
+        let (reverse, rest) = match rest.strip_prefix(['r', 'R']) {
+            Some(rest) => (true, rest),
+            None => (false, rest),
+        };
+        if !rest.chars().all(|c| ".-+,/_=".contains(c)) {
+            return None;
+        }
+        let delimiters = if rest.is_empty() {
+            vec!['.']
+        } else {
+            rest.chars().collect()
+        };
+        Some(Self {
+            letter: letter.to_ascii_lowercase(),
+            keep,
+            reverse,
+            delimiters,
+            url_escape: letter.is_ascii_uppercase(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DKIMError;
+    use std::collections::HashMap;
+
+    /// Lookup answering from records keyed by type and name
+    #[derive(Default)]
+    struct TestLookup {
+        txt: HashMap<String, Vec<String>>,
+        addresses: HashMap<String, Vec<IpAddr>>,
+        mx: HashMap<String, Vec<String>>,
+    }
+
+    impl TestLookup {
+        fn with_txt(mut self, name: &str, txt: &str) -> Self {
+            self.txt
+                .entry(name.to_owned())
+                .or_default()
+                .push(txt.to_owned());
+            self
+        }
+
+        fn with_ip(mut self, name: &str, ip: &str) -> Self {
+            self.addresses
+                .entry(name.to_owned())
+                .or_default()
+                .push(ip.parse().unwrap());
+            self
+        }
+
+        fn with_mx(mut self, name: &str, exchange: &str) -> Self {
+            self.mx
+                .entry(name.to_owned())
+                .or_default()
+                .push(exchange.to_owned());
+            self
+        }
+
+        fn answer<T: Clone + Send + 'static>(
+            name: &str,
+            records: Option<Vec<T>>,
+        ) -> BoxFuture<'static, Result<Vec<T>, DKIMError>> {
+            let res = match name {
+                "temperror.example" => Err(DKIMError::DnsServFail {
+                    name: name.to_owned(),
+                }),
+                _ => records.ok_or_else(|| DKIMError::DnsNxDomain {
+                    name: name.to_owned(),
+                }),
+            };
+            Box::pin(futures::future::ready(res))
+        }
+    }
+
+    impl dns::Lookup for TestLookup {
+        fn lookup_txt<'a>(
+            &'a self,
+            name: &'a str,
+        ) -> BoxFuture<'a, Result<Vec<String>, DKIMError>> {
+            Self::answer(name, self.txt.get(name).cloned())
+        }
+
+        fn lookup_a<'a>(
+            &'a self,
+            name: &'a str,
+        ) -> BoxFuture<'a, Result<Vec<Ipv4Addr>, DKIMError>> {
+            let addresses = self.addresses.get(name).map(|addresses| {
+                addresses
+                    .iter()
+                    .filter_map(|ip| match ip {
+                        IpAddr::V4(ip) => Some(*ip),
+                        IpAddr::V6(_) => None,
+                    })
+                    .collect()
+            });
+            Self::answer(name, addresses)
+        }
+
+        fn lookup_aaaa<'a>(
+            &'a self,
+            name: &'a str,
+        ) -> BoxFuture<'a, Result<Vec<Ipv6Addr>, DKIMError>> {
+            let addresses = self.addresses.get(name).map(|addresses| {
+                addresses
+                    .iter()
+                    .filter_map(|ip| match ip {
+                        IpAddr::V6(ip) => Some(*ip),
+                        IpAddr::V4(_) => None,
+                    })
+                    .collect()
+            });
+            Self::answer(name, addresses)
+        }
+
+        fn lookup_mx<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<Vec<String>, DKIMError>> {
+            Self::answer(name, self.mx.get(name).cloned())
+        }
+    }
+
+    fn resolver() -> Arc<dyn dns::Lookup> {
+        Arc::new(
+            TestLookup::default()
+                .with_txt(
+                    "example.com",
+                    "v=spf1 ip4:192.0.2.0/24 a/30 mx include:_spf.example.net ~all",
+                )
+                .with_txt("example.com", "google-site-verification=abc")
+                .with_ip("example.com", "198.51.100.4")
+                .with_mx("example.com", "mx.example.com")
+                .with_ip("mx.example.com", "203.0.113.10")
+                .with_ip("mx.example.com", "2001:db8::10")
+                .with_txt("_spf.example.net", "v=spf1 ip6:2001:db8:1::/48 -all")
+                .with_txt("example.org", "v=spf1 redirect=example.com")
+                .with_txt("helo.example.com", "v=spf1 a -all")
+                .with_ip("helo.example.com", "192.0.2.200")
+                .with_txt(
+                    "exists.example.com",
+                    "v=spf1 exists:%{ir}.%{l1r-}._spf.%{d} -all",
+                )
+                .with_ip("1.2.0.192.bob._spf.exists.example.com", "127.0.0.2")
+                .with_txt("nonascii.example.com", "v=spf\u{e9} ip4:192.0.2.0/24")
+                .with_txt("twice.example.com", "v=spf1 -all")
+                .with_txt("twice.example.com", "v=spf1 +all")
+                .with_txt("loop.example.com", "v=spf1 include:loop.example.com")
+                .with_txt("syntax.example.com", "v=spf1 ip4:192.0.2.1/33 -all")
+                .with_txt("temp.example.com", "v=spf1 include:temperror.example -all")
+                .with_txt(
+                    "void.example.com",
+                    "v=spf1 a:a.invalid.example a:b.invalid.example a:c.invalid.example",
+                ),
+        )
+    }
+
+    async fn check_ip(ip: &str, mail_from: &str) -> SpfResult {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let ip = ip.parse().unwrap();
+        check_mail_from(&logger, ip, "helo.example.com", mail_from, resolver()).await
+    }
+
+    #[tokio::test]
+    async fn test_check_mail_from() {
+        for (ip, mail_from, verdict, mechanism) in [
+            (
+                "192.0.2.1",
+                "joe@example.com",
+                SpfVerdict::Pass,
+                Some("ip4:192.0.2.0/24"),
+            ),
+            (
+                "::ffff:192.0.2.1",
+                "<joe@example.com>",
+                SpfVerdict::Pass,
+                Some("ip4:192.0.2.0/24"),
+            ),
+            (
+                "198.51.100.7",
+                "joe@example.com",
+                SpfVerdict::Pass,
+                Some("a/30"),
+            ),
+            (
+                "198.51.100.8",
+                "joe@example.com",
+                SpfVerdict::SoftFail,
+                Some("~all"),
+            ),
+            (
+                "203.0.113.10",
+                "joe@EXAMPLE.com",
+                SpfVerdict::Pass,
+                Some("mx"),
+            ),
+            (
+                "2001:db8::10",
+                "joe@example.com",
+                SpfVerdict::Pass,
+                Some("mx"),
+            ),
+            (
+                "2001:db8:1::1",
+                "joe@example.com",
+                SpfVerdict::Pass,
+                Some("include:_spf.example.net"),
+            ),
+            (
+                "2001:db8:2::1",
+                "joe@example.com",
+                SpfVerdict::SoftFail,
+                Some("~all"),
+            ),
+            (
+                "192.0.2.1",
+                "joe@example.org",
+                SpfVerdict::Pass,
+                Some("ip4:192.0.2.0/24"),
+            ),
+            ("192.0.2.200", "", SpfVerdict::Pass, Some("a")),
+            (
+                "192.0.2.1",
+                "bob@exists.example.com",
+                SpfVerdict::Pass,
+                Some("exists:%{ir}.%{l1r-}._spf.%{d}"),
+            ),
+            (
+                "192.0.2.1",
+                "alice@exists.example.com",
+                SpfVerdict::Fail,
+                Some("-all"),
+            ),
+            ("192.0.2.1", "joe@example.net", SpfVerdict::None, None),
+            ("192.0.2.1", "joe@localhost", SpfVerdict::None, None),
+            (
+                "192.0.2.1",
+                "joe@nonascii.example.com",
+                SpfVerdict::None,
+                None,
+            ),
+            (
+                "192.0.2.1",
+                "joe@twice.example.com",
+                SpfVerdict::PermError,
+                None,
+            ),
+            (
+                "192.0.2.1",
+                "joe@loop.example.com",
+                SpfVerdict::PermError,
+                None,
+            ),
+            (
+                "192.0.2.1",
+                "joe@syntax.example.com",
+                SpfVerdict::PermError,
+                None,
+            ),
+            (
+                "192.0.2.1",
+                "joe@temp.example.com",
+                SpfVerdict::TempError,
+                None,
+            ),
+            (
+                "192.0.2.1",
+                "joe@void.example.com",
+                SpfVerdict::PermError,
+                None,
+            ),
+        ] {
+            let result = check_ip(ip, mail_from).await;
+            assert_eq!(result.verdict(), verdict, "{} {}", ip, mail_from);
+            assert_eq!(result.mechanism(), mechanism, "{} {}", ip, mail_from);
+            assert_eq!(result.identity(), SpfIdentity::MailFrom);
+        }
+
+        let result = check_ip("192.0.2.1", "joe@loop.example.com").await;
+        assert_eq!(result.to_string(), "permerror (too many DNS lookups)");
+        assert_eq!(result.domain(), "loop.example.com");
+    }
+
+    #[tokio::test]
+    async fn test_check_unsupported_lookup() {
+        // The resolver only implements TXT lookups
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let resolver = Arc::new(
+            crate::test_utils::MockResolver::new().with_record("example.com", "v=spf1 mx -all"),
+        );
+        let ip = "192.0.2.1".parse().unwrap();
+        let result = check_mail_from(&logger, ip, "", "joe@example.com", resolver).await;
+        assert_eq!(result.verdict(), SpfVerdict::PermError);
+        assert_eq!(
+            result.reason(),
+            Some("unsupported DNS lookup: MX records of example.com")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_check_helo() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let ip = "192.0.2.1".parse().unwrap();
+        let result = check_helo(&logger, ip, "helo.example.com", resolver()).await;
+        assert_eq!(result.verdict(), SpfVerdict::Fail);
+        assert_eq!(result.identity(), SpfIdentity::Helo);
+
+        let header = crate::arc::ArcAuthenticationResultsBuilder::new()
+            .with_instance(1)
+            .with_authserv_id("mx.example.org")
+            .with_spf_result(&result)
+            .build()
+            .unwrap();
+        assert_eq!(
+            header,
+            "ARC-Authentication-Results: i=1; mx.example.org; spf=fail smtp.helo=helo.example.com"
+        );
+    }
+
+    #[test]
+    fn test_macro_expansion() {
+        // Examples of RFC 7208 section 7.4
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let resolver = TestLookup::default();
+        let mut checker = Checker {
+            logger: &logger,
+            resolver: &resolver,
+            ip: "192.0.2.3".parse().unwrap(),
+            sender: "strong-bad@email.example.com",
+            helo: "mx.example.org",
+            lookups: 0,
+            void_lookups: 0,
+        };
+        let expand = |checker: &Checker, spec: &str| {
+            let spec = MacroString::parse(spec).unwrap();
+            checker.expand_domain(&spec, "email.example.com").unwrap()
+        };
+        for (spec, expanded) in [
+            ("%{s}", "strong-bad@email.example.com"),
+            ("%{o}", "email.example.com"),
+            ("%{d4}", "email.example.com"),
+            ("%{d2}", "example.com"),
+            ("%{d1}", "com"),
+            ("%{dr}", "com.example.email"),
+            ("%{d2r}", "example.email"),
+            ("%{l}", "strong-bad"),
+            ("%{l-}", "strong.bad"),
+            ("%{lr-}", "bad.strong"),
+            ("%{l1r-}", "strong"),
+            (
+                "%{ir}.%{v}._spf.%{d2}",
+                "3.2.0.192.in-addr._spf.example.com",
+            ),
+            (
+                "%{lr-}.lp.%{ir}.%{v}._spf.%{d2}",
+                "bad.strong.lp.3.2.0.192.in-addr._spf.example.com",
+            ),
+            (
+                "%{d2}.trusted-domains.example.net",
+                "example.com.trusted-domains.example.net",
+            ),
+            ("%{h}.%%.%{p}", "mx.example.org.%.unknown"),
+            ("%{S}", "strong-bad%40email.example.com"),
+        ] {
+            assert_eq!(expand(&checker, spec), expanded, "{}", spec);
+        }
+
+        checker.ip = "2001:db8::cb01".parse().unwrap();
+        assert_eq!(
+            expand(&checker, "%{ir}.%{v}._spf.%{d2}"),
+            "1.0.b.c.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.ip6._spf.example.com"
+        );
+
+        for spec in ["%{x}", "%{d0}", "%{d", "%a", "%{d2r!}"] {
+            assert!(MacroString::parse(spec).is_err(), "{}", spec);
+        }
+        let spec = MacroString::parse("%{c}").unwrap();
+        assert!(checker.expand_domain(&spec, "example.com").is_err());
+    }
+
+    #[test]
+    fn test_parse_record() {
+        let record = Record::parse("v=spf1 -a:mail.%{d}/24//64 ?mx//48 ip6:2001:db8::/32 foo=bar exp=explain.%{d} redirect=_spf.example.com").unwrap();
+        let mechanisms: Vec<_> = record
+            .directives
+            .iter()
+            .map(|directive| (directive.qualifier, &directive.mechanism))
+            .collect();
+        assert!(matches!(
+            mechanisms[..],
+            [
+                (
+                    SpfVerdict::Fail,
+                    Mechanism::A(Some(_), DualCidr { ip4: 24, ip6: 64 })
+                ),
+                (
+                    SpfVerdict::Neutral,
+                    Mechanism::Mx(None, DualCidr { ip4: 32, ip6: 48 })
+                ),
+                (SpfVerdict::Pass, Mechanism::Ip6(_, 32)),
+            ]
+        ));
+        assert!(record.redirect.is_some());
+
+        for record in [
+            "v=spf1 ip4:192.0.2.1/024",
+            "v=spf1 a/33",
+            "v=spf1 include",
+            "v=spf1 all:example.com",
+            "v=spf1 foo:example.com",
+            "v=spf1 redirect=a.example redirect=b.example",
+            "v=spf1 1x=y",
+        ] {
+            assert!(Record::parse(record).is_err(), "{}", record);
+        }
+        assert!(is_spf_record("V=SPF1 -all"));
+        assert!(!is_spf_record("v=spf10 -all"));
+    }
+}
@@ -0,0 +1,279 @@
+//! Signing of messages streamed through an [AsyncWrite] sink, for proxies
+//! relaying messages without holding them in memory. Enabled by the `async`
+//! feature.
+//!
+//! The DKIM-Signature header comes before the message but depends on its
+//! whole body: the header block is kept in memory while the body is hashed as
+//! it is written and copied to a spool. When the writer is closed, the
+//! signature, the headers and the spooled body are written to the sink.
+
+use std::io::SeekFrom;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use futures::io::{AsyncRead, AsyncSeek, AsyncWrite, Cursor};
+
+use crate::bytes;
+use crate::hash::BodyHasher;
+use crate::{DKIMError, DKIMSigner, ErrorSource};
+
+/// Size of the chunks copied from the spool to the sink
+const COPY_CHUNK_SIZE: usize = 8192;
+
+/// Position in the message written to a [SigningWriter]
+enum State {
+    /// Buffering the header block
+    Headers,
+    /// Hashing and spooling the body
+    Body,
+    /// Writing the signature and the headers to the sink
+    WriteHead {
+        head: Vec<u8>,
+        written: usize,
+    },
+    /// Rewinding the spool
+    Rewind,
+    /// Copying the spooled body to the sink
+    CopyBody {
+        chunk: Box<[u8]>,
+        start: usize,
+        end: usize,
+    },
+    /// Closing the sink
+    Close,
+    Closed,
+}
+
+/// Writer signing the message written to it. The message, prefixed with its
+/// DKIM-Signature header, is written to the sink when the writer is closed:
+/// call [futures::io::AsyncWriteExt::close] once the whole message was
+/// written.
+///
+/// The message is expected in its SMTP form, with CRLF line endings: the
+/// [crate::SignerBuilder::with_normalized_line_endings] option doesn't apply.
+///
+/// By default the body is spooled in memory. For large messages, provide a
+/// spool backed by a file with [SigningWriter::with_spool].
+pub struct SigningWriter<'s, 'a, W, S = Cursor<Vec<u8>>> {
+    signer: &'s DKIMSigner<'a>,
+    sink: W,
+    spool: S,
+    headers: Vec<u8>,
+    hasher: Option<BodyHasher>,
+    state: State,
+}
+
+impl<'s, 'a, W> SigningWriter<'s, 'a, W>
+where
+    W: AsyncWrite + Unpin,
+{
+    /// New writer signing with `signer` and writing the signed message to
+    /// `sink`, spooling the body in memory
+    pub fn new(signer: &'s DKIMSigner<'a>, sink: W) -> Self {
+        Self::with_spool(signer, sink, Cursor::new(Vec::new()))
+    }
+}
+
+impl<'s, 'a, W, S> SigningWriter<'s, 'a, W, S>
+where
+    W: AsyncWrite + Unpin,
+    S: AsyncRead + AsyncWrite + AsyncSeek + Unpin,
+{
+    /// New writer signing with `signer` and writing the signed message to
+    /// `sink`. The body is spooled in `spool`, which should be empty.
+    pub fn with_spool(signer: &'s DKIMSigner<'a>, sink: W, spool: S) -> Self {
+        Self {
+            signer,
+            sink,
+            spool,
+            headers: vec![],
+            hasher: Some(signer.body_hasher()),
+            state: State::Headers,
+        }
+    }
+
+    /// Returns the sink, and the spool
+    pub fn into_inner(self) -> (W, S) {
+        (self.sink, self.spool)
+    }
+
+    /// Returns the DKIM-Signature header followed by the header block
+    fn sign(&mut self) -> Result<Vec<u8>, DKIMError> {
+        if bytes::find(&self.headers, b"\r\n\r\n").is_none() {
+            // Message without body
+            if !self.headers.ends_with(b"\r\n") {
+                self.headers.extend_from_slice(b"\r\n");
+            }
+            self.headers.extend_from_slice(b"\r\n");
+        }
+        let email =
+            mailparse::parse_mail(&self.headers).map_err(|err| DKIMError::MalformedEmail {
+                reason: "invalid message",
+                source: Some(ErrorSource::new(err)),
+            })?;
+        let hasher = self.hasher.take().ok_or_else(|| DKIMError::FailedToSign {
+            reason: "the message was already signed",
+            source: None,
+        })?;
+        self.signer.before_canonicalization(&email);
+        let body_hash = hasher.finish();
+        let header = self.signer.sign_with_body_hash(&email, &body_hash)?;
+
+        let mut head = Vec::with_capacity(header.len() + 2 + self.headers.len());
+        head.extend_from_slice(header.as_bytes());
+        head.extend_from_slice(b"\r\n");
+        head.extend_from_slice(&self.headers);
+        Ok(head)
+    }
+}
+
+impl<W, S> AsyncWrite for SigningWriter<'_, '_, W, S>
+where
+    W: AsyncWrite + Unpin,
+    S: AsyncRead + AsyncWrite + AsyncSeek + Unpin,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        match this.state {
+            State::Headers => {
+                // The end of the header block may straddle two writes
+                let start = this.headers.len().saturating_sub(3);
+                this.headers.extend_from_slice(buf);
+                if let Some(offset) = bytes::find(&this.headers[start..], b"\r\n\r\n") {
+                    let end = start + offset + 4;
+                    let body = this.headers.split_off(end);
+                    this.state = State::Body;
+                    // The bytes of the body are written to the spool on the
+                    // next writes
+                    return Poll::Ready(Ok(buf.len() - body.len()));
+                }
+                Poll::Ready(Ok(buf.len()))
+            }
+            State::Body => {
+                let written = ready!(Pin::new(&mut this.spool).poll_write(cx, buf))?;
+                if let Some(hasher) = this.hasher.as_mut() {
+                    hasher.update(&buf[..written]);
+                }
+                Poll::Ready(Ok(written))
+            }
+            _ => Poll::Ready(Err(std::io::Error::other("write after close"))),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        match this.state {
+            State::Headers => Poll::Ready(Ok(())),
+            State::Body => Pin::new(&mut this.spool).poll_flush(cx),
+            _ => Pin::new(&mut this.sink).poll_flush(cx),
+        }
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            match &mut this.state {
+                State::Headers | State::Body => {
+                    let head = this.sign().map_err(std::io::Error::other)?;
+                    this.state = State::WriteHead { head, written: 0 };
+                }
+                State::WriteHead { head, written } => {
+                    if *written == head.len() {
+                        this.state = State::Rewind;
+                        continue;
+                    }
+                    let n = ready!(Pin::new(&mut this.sink).poll_write(cx, &head[*written..]))?;
+                    if n == 0 {
+                        return Poll::Ready(Err(std::io::ErrorKind::WriteZero.into()));
+                    }
+                    *written += n;
+                }
+                State::Rewind => {
+                    ready!(Pin::new(&mut this.spool).poll_seek(cx, SeekFrom::Start(0)))?;
+                    this.state = State::CopyBody {
+                        chunk: vec![0; COPY_CHUNK_SIZE].into_boxed_slice(),
+                        start: 0,
+                        end: 0,
+                    };
+                }
+                State::CopyBody { chunk, start, end } => {
+                    if start == end {
+                        let n = ready!(Pin::new(&mut this.spool).poll_read(cx, chunk))?;
+                        if n == 0 {
+                            this.state = State::Close;
+                            continue;
+                        }
+                        *start = 0;
+                        *end = n;
+                    }
+                    let n = ready!(Pin::new(&mut this.sink).poll_write(cx, &chunk[*start..*end]))?;
+                    if n == 0 {
+                        return Poll::Ready(Err(std::io::ErrorKind::WriteZero.into()));
+                    }
+                    *start += n;
+                }
+                State::Close => {
+                    ready!(Pin::new(&mut this.sink).poll_close(cx))?;
+                    this.state = State::Closed;
+                }
+                State::Closed => return Poll::Ready(Ok(())),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{rsa_private_key, DOMAIN, RSA_SELECTOR, SAMPLE_MESSAGE};
+    use crate::{verify_email_with_key, SignerBuilder};
+    use futures::io::AsyncWriteExt;
+
+    #[test]
+    fn test_signing_writer() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let signer = SignerBuilder::recommended()
+            .with_private_key(rsa_private_key())
+            .with_selector(RSA_SELECTOR)
+            .with_signing_domain(DOMAIN)
+            .with_logger(&logger)
+            .build()
+            .unwrap();
+
+        // Small writes, the end of the header block straddling two of them
+        for chunk_size in [1, 7, SAMPLE_MESSAGE.len()] {
+            let mut writer = SigningWriter::new(&signer, vec![]);
+            futures::executor::block_on(async {
+                for chunk in SAMPLE_MESSAGE.as_bytes().chunks(chunk_size) {
+                    writer.write_all(chunk).await.unwrap();
+                }
+                writer.close().await.unwrap();
+            });
+            let (signed, _) = writer.into_inner();
+
+            let signed = String::from_utf8(signed).unwrap();
+            assert!(signed.starts_with("DKIM-Signature: "));
+            assert!(signed.ends_with(SAMPLE_MESSAGE));
+            let email = mailparse::parse_mail(signed.as_bytes()).unwrap();
+            let public_key = rsa_private_key().to_public_key();
+            let result = verify_email_with_key(&logger, DOMAIN, &email, public_key).unwrap();
+            assert_eq!(result.summary(), "pass", "{}", chunk_size);
+        }
+
+        // Message without body
+        let mut writer = SigningWriter::new(&signer, vec![]);
+        futures::executor::block_on(async {
+            writer.write_all(b"From: joe@example.com").await.unwrap();
+            writer.close().await.unwrap();
+        });
+        let (signed, _) = writer.into_inner();
+        let email = mailparse::parse_mail(&signed).unwrap();
+        let public_key = rsa_private_key().to_public_key();
+        let result = verify_email_with_key(&logger, DOMAIN, &email, public_key).unwrap();
+        assert_eq!(result.summary(), "pass");
+    }
+}
@@ -0,0 +1,226 @@
+//! Fixtures to test code using DKIM: resolvers answering from memory, the key
+//! pairs of this crate's tests and messages signed with them. Enabled by the
+//! `test-utils` feature.
+//!
+//! The private keys are published with this crate: never use them outside of
+//! tests.
+
+use base64::engine::general_purpose;
+use base64::Engine;
+#[cfg(feature = "dns")]
+use futures::future::BoxFuture;
+use rsa::pkcs1::DecodeRsaPrivateKey;
+#[cfg(feature = "dns")]
+use std::collections::HashMap;
+
+#[cfg(feature = "dns")]
+use crate::dns::{self, Lookup};
+#[cfg(feature = "dns")]
+use crate::DKIMError;
+use crate::{DkimPrivateKey, SignerBuilder};
+
+pub use crate::test_vectors::{
+    RFC6376_MESSAGE, RFC6376_RECORD, RFC8463_MESSAGE, RFC8463_RECORD, RFC8463_RSA_RECORD,
+};
+
+/// Domain of the test key pairs
+pub const DOMAIN: &str = "example.com";
+
+/// Selector of the RSA test key pair, see [rsa_private_key]
+pub const RSA_SELECTOR: &str = "2022";
+
+/// Selector of the Ed25519 test key pair, see [ed25519_private_key]
+pub const ED25519_SELECTOR: &str = "ed";
+
+/// The 2048 bits RSA private key of the tests, PKCS#1 PEM encoded
+pub const RSA_PRIVATE_KEY_PEM: &str = include_str!("../test/keys/2022.private");
+
+/// The Ed25519 private key of the tests, base64 encoded. It's the example key
+/// of RFC 8463.
+pub const ED25519_PRIVATE_KEY: &str = include_str!("../test/keys/ed.private");
+
+/// An unsigned message from [DOMAIN], to sign with [sign_message]
+pub const SAMPLE_MESSAGE: &str = "From: Joe SixPack <joe@example.com>\r
+To: Suzie Q <suzie@example.net>\r
+Subject: Is dinner ready?\r
+Date: Fri, 11 Jul 2003 21:00:37 -0700\r
+Message-ID: <20030712040037.46341.5F8J@example.com>\r
+\r
+Hi.\r
+\r
+We lost the game. Are you hungry yet?\r
+\r
+Joe.\r
+";
+
+/// The RSA private key of the tests, published as [RSA_SELECTOR] of [DOMAIN]
+/// by the [MockResolver]
+pub fn rsa_private_key() -> DkimPrivateKey {
+    let key =
+        rsa::RsaPrivateKey::from_pkcs1_pem(RSA_PRIVATE_KEY_PEM).expect("invalid RSA test key");
+    DkimPrivateKey::Rsa(key)
+}
+
+/// The Ed25519 private key of the tests, published as [ED25519_SELECTOR] of
+/// [DOMAIN] by the [MockResolver]
+pub fn ed25519_private_key() -> DkimPrivateKey {
+    let secret_key: [u8; 32] = general_purpose::STANDARD
+        .decode(ED25519_PRIVATE_KEY.trim())
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .expect("invalid Ed25519 test key");
+    DkimPrivateKey::Ed25519(ed25519_dalek::SigningKey::from_bytes(&secret_key))
+}
+
+/// Sign `raw_email` for [DOMAIN] with one of the test keys and `selector`,
+/// with the recommended configuration. Returns the message with the
+/// DKIM-Signature header prepended.
+pub fn sign_message(raw_email: &str, private_key: DkimPrivateKey, selector: &str) -> String {
+    let logger = slog::Logger::root(slog::Discard, slog::o!());
+    let signer = SignerBuilder::recommended()
+        .with_private_key(private_key)
+        .with_selector(selector)
+        .with_signing_domain(DOMAIN)
+        .with_logger(&logger)
+        .build()
+        .expect("failed to build the test signer");
+    let email = mailparse::parse_mail(raw_email.as_bytes()).expect("invalid test message");
+    let header = signer
+        .sign(&email)
+        .expect("failed to sign the test message");
+    format!("{}\r\n{}", header, raw_email)
+}
+
+/// Lookup answering with the TXT records it was given. Names without
+/// records don't exist.
+#[cfg(feature = "dns")]
+#[derive(Debug, Clone, Default)]
+pub struct StaticResolver {
+    records: HashMap<String, Vec<String>>,
+}
+
+#[cfg(feature = "dns")]
+impl StaticResolver {
+    /// New resolver without records
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a TXT record to `name`
+    pub fn with_record(mut self, name: &str, txt: &str) -> Self {
+        self.records
+            .entry(dns::normalize_name(name))
+            .or_default()
+            .push(txt.to_owned());
+        self
+    }
+
+    /// Add the key record of `private_key` to the selector `selector` of
+    /// `domain`
+    pub fn with_key(self, selector: &str, domain: &str, private_key: &DkimPrivateKey) -> Self {
+        let name = format!("{}._domainkey.{}", selector, domain);
+        self.with_record(&name, &private_key.to_public_key().to_key_record())
+    }
+}
+
+#[cfg(feature = "dns")]
+impl Lookup for StaticResolver {
+    fn lookup_txt<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<Vec<String>, DKIMError>> {
+        let res = self
+            .records
+            .get(&dns::normalize_name(name))
+            .cloned()
+            .ok_or_else(|| DKIMError::DnsNxDomain {
+                name: name.to_owned(),
+            });
+        Box::pin(futures::future::ready(res))
+    }
+}
+
+/// Lookup publishing the keys of the tests ([RSA_SELECTOR] and
+/// [ED25519_SELECTOR] of [DOMAIN]) and of the RFC examples ([RFC6376_RECORD],
+/// [RFC8463_RECORD] and [RFC8463_RSA_RECORD]). Other names don't exist.
+#[cfg(feature = "dns")]
+#[derive(Debug, Clone)]
+pub struct MockResolver {
+    inner: StaticResolver,
+}
+
+#[cfg(feature = "dns")]
+impl MockResolver {
+    pub fn new() -> Self {
+        let inner = StaticResolver::new()
+            .with_key(RSA_SELECTOR, DOMAIN, &rsa_private_key())
+            .with_key(ED25519_SELECTOR, DOMAIN, &ed25519_private_key())
+            .with_record("newengland._domainkey.example.com", RFC6376_RECORD)
+            .with_record("brisbane._domainkey.football.example.com", RFC8463_RECORD)
+            .with_record("test._domainkey.football.example.com", RFC8463_RSA_RECORD);
+        Self { inner }
+    }
+
+    /// Add a TXT record to `name`
+    pub fn with_record(self, name: &str, txt: &str) -> Self {
+        Self {
+            inner: self.inner.with_record(name, txt),
+        }
+    }
+}
+
+#[cfg(feature = "dns")]
+impl Default for MockResolver {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(feature = "dns")]
+impl Lookup for MockResolver {
+    fn lookup_txt<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<Vec<String>, DKIMError>> {
+        self.inner.lookup_txt(name)
+    }
+}
+
+#[cfg(all(test, feature = "dns"))]
+mod tests {
+    use super::*;
+    use crate::verify_email_with_resolver;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_fixtures_verify() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let resolver: Arc<dyn Lookup> = Arc::new(MockResolver::new());
+        for (raw_email, from_domain) in [
+            (
+                sign_message(SAMPLE_MESSAGE, rsa_private_key(), RSA_SELECTOR),
+                DOMAIN,
+            ),
+            (
+                sign_message(SAMPLE_MESSAGE, ed25519_private_key(), ED25519_SELECTOR),
+                DOMAIN,
+            ),
+            (RFC6376_MESSAGE.to_owned(), "example.com"),
+            (RFC8463_MESSAGE.to_owned(), "football.example.com"),
+        ] {
+            let email = mailparse::parse_mail(raw_email.as_bytes()).unwrap();
+            let result =
+                verify_email_with_resolver(&logger, from_domain, &email, Arc::clone(&resolver))
+                    .await
+                    .unwrap();
+            assert_eq!(result.summary(), "pass", "{}", from_domain);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_static_resolver() {
+        let resolver = StaticResolver::new().with_record("A.Example.com.", "v=DKIM1; p=");
+        assert_eq!(
+            resolver.lookup_txt("a.example.com").await.unwrap(),
+            vec!["v=DKIM1; p=".to_owned()]
+        );
+        assert!(matches!(
+            resolver.lookup_txt("b.example.com").await,
+            Err(DKIMError::DnsNxDomain { .. })
+        ));
+    }
+}
@@ -0,0 +1,192 @@
+//! The reference messages and keys of RFC 6376 and RFC 8463, to check the
+//! interoperability of DKIM implementations against the same corpus. Enabled
+//! by the `test-vectors` feature.
+
+use mailparse::MailHeaderMap;
+
+use crate::{
+    public_key, signature_value, validate_header, verify_email_header_with_key, DKIMError,
+    DKIMResult, ErrorSource, HEADER,
+};
+
+/// Record of the `newengland` selector of `example.com`, the 1024 bits RSA
+/// example key of RFC 6376
+pub const RFC6376_RECORD: &str = "v=DKIM1; p=MIGJAoGBALVI635dLK4cJJAH3Lx6upo3X/Lm1tQz3mezcWTA3BUBnyIsdnRf57aD5BtNmhPrYYDlWlzw3UgnKisIxktkk5+iMQMlFtAS10JB8L3YadXNJY+JBcbeSi5TgJe4WFzNgW95FWDAuSTRXSWZfA/8xjflbTLDx0euFZOM7C4T0GwLAgMBAAE=";
+
+/// Record of the `brisbane` selector of `football.example.com`, the Ed25519
+/// example key of RFC 8463
+pub const RFC8463_RECORD: &str =
+    "v=DKIM1; k=ed25519; p=11qYAYKxCrfVS/7TyWQHOg7hcvPapiMlrwIaaPcHURo=";
+
+/// The example message of RFC 6376 appendix A, signed with the `newengland`
+/// selector of `example.com` ([RFC6376_RECORD]). The signature printed in the
+/// RFC doesn't match its message (see the errata), this one was generated
+/// again with the key of appendix C.
+pub const RFC6376_MESSAGE: &str =
+    "DKIM-Signature: a=rsa-sha256; bh=2jUSOH9NhtVGCQWNr9BrIAPreKQjO6Sn7XIkfJVOzv8=;\r
+ c=simple/simple; d=example.com;\r
+ h=Received:From:To:Subject:Date:Message-ID; i=joe@football.example.com;\r
+ s=newengland; t=1615825284; v=1;\r
+ b=Xh4Ujb2wv5x54gXtulCiy4C0e+plRm6pZ4owF+kICpYzs/8WkTVIDBrzhJP0DAYCpnL62T0G\r
+ k+0OH8pi/yqETVjKtKk+peMnNvKkut0GeWZMTze0bfq3/JUK3Ln3jTzzpXxrgVnvBxeY9EZIL4g\r
+ s4wwFRRKz/1bksZGSjD8uuSU=\r
+Received: from client1.football.example.com  [192.0.2.1]\r
+      by submitserver.example.com with SUBMISSION;\r
+      Fri, 11 Jul 2003 21:01:54 -0700 (PDT)\r
+From: Joe SixPack <joe@football.example.com>\r
+To: Suzie Q <suzie@shopping.example.net>\r
+Subject: Is dinner ready?\r
+Date: Fri, 11 Jul 2003 21:00:37 -0700 (PDT)\r
+Message-ID: <20030712040037.46341.5F8J@football.example.com>\r
+\r
+Hi.\r
+\r
+We lost the game. Are you hungry yet?\r
+\r
+Joe.\r
+";
+
+/// Record of the `test` selector of `football.example.com`, the RSA example
+/// key of RFC 8463
+pub const RFC8463_RSA_RECORD: &str = "v=DKIM1; k=rsa; p=MIGfMA0GCSqGSIb3DQEBAQUAA4GNADCBiQKBgQDkHlOQoBTzWRiGs5V6NpP3idY6Wk08a5qhdR6wy5bdOKb2jLQiY/J16JYi0Qvx/byYzCNb3W91y3FutACDfzwQ/BC/e/8uBsCR+yz1Lxj+PL6lHvqMKrM3rG4hstT5QjvHO9PzoxZyVYLzBfO2EeC3Ip3G+2kryOTIKT+l/K4w3QIDAQAB";
+
+/// The Ed25519 private key of RFC 8463, base64 encoded
+pub const RFC8463_ED25519_PRIVATE_KEY: &str = "nWGxne/9WmC6hEr0kuwsxERJxWl7MmkZcDusAxyuf2A=";
+
+/// The example message of RFC 8463 appendix A, signed with the `brisbane`
+/// and `test` selectors of `football.example.com` ([RFC8463_RECORD] and
+/// [RFC8463_RSA_RECORD])
+pub const RFC8463_MESSAGE: &str = "DKIM-Signature: v=1; a=ed25519-sha256; c=relaxed/relaxed;\r
+ d=football.example.com; i=@football.example.com;\r
+ q=dns/txt; s=brisbane; t=1528637909; h=from : to :\r
+ subject : date : message-id : from : subject : date;\r
+ bh=2jUSOH9NhtVGCQWNr9BrIAPreKQjO6Sn7XIkfJVOzv8=;\r
+ b=/gCrinpcQOoIfuHNQIbq4pgh9kyIK3AQUdt9OdqQehSwhEIug4D11Bus\r
+ Fa3bT3FY5OsU7ZbnKELq+eXdp1Q1Dw==\r
+DKIM-Signature: v=1; a=rsa-sha256; c=relaxed/relaxed;\r
+ d=football.example.com; i=@football.example.com;\r
+ q=dns/txt; s=test; t=1528637909; h=from : to : subject :\r
+ date : message-id : from : subject : date;\r
+ bh=2jUSOH9NhtVGCQWNr9BrIAPreKQjO6Sn7XIkfJVOzv8=;\r
+ b=F45dVWDfMbQDGHJFlXUNB2HKfbCeLRyhDXgFpEL8GwpsRe0IeIixNTe3\r
+ DhCVlUrSjV4BwcVcOF6+FF3Zo9Rpo1tFOeS9mPYQTnGdaSGsgeefOsk2Jz\r
+ dA+L10TeYt9BgDfQNZtKdN1WO//KgIqXP7OdEFE4LjFYNcUxZQ4FADY+8=\r
+From: Joe SixPack <joe@football.example.com>\r
+To: Suzie Q <suzie@shopping.example.net>\r
+Subject: Is dinner ready?\r
+Date: Fri, 11 Jul 2003 21:00:37 -0700 (PDT)\r
+Message-ID: <20030712040037.46341.5F8J@football.example.com>\r
+\r
+Hi.\r
+\r
+We lost the game.  Are you hungry yet?\r
+\r
+Joe.";
+
+/// A signature of a reference message
+#[derive(Debug, Clone, Copy)]
+pub struct TestVector {
+    /// Identifier of the vector, for instance `rfc8463-ed25519`
+    pub name: &'static str,
+    /// The signed message, with CRLF line endings
+    pub message: &'static str,
+    /// Signing domain of the signature, the `d=` tag
+    pub domain: &'static str,
+    /// Selector of the signature, the `s=` tag
+    pub selector: &'static str,
+    /// Key record published for the selector
+    pub record: &'static str,
+    /// Private key of the record when published by the RFC, base64 encoded
+    /// for Ed25519
+    pub private_key: Option<&'static str>,
+}
+
+/// All the test vectors. They all verify.
+pub const VECTORS: &[TestVector] = &[
+    TestVector {
+        name: "rfc6376-rsa",
+        message: RFC6376_MESSAGE,
+        domain: "example.com",
+        selector: "newengland",
+        record: RFC6376_RECORD,
+        private_key: None,
+    },
+    TestVector {
+        name: "rfc8463-ed25519",
+        message: RFC8463_MESSAGE,
+        domain: "football.example.com",
+        selector: "brisbane",
+        record: RFC8463_RECORD,
+        private_key: Some(RFC8463_ED25519_PRIVATE_KEY),
+    },
+    TestVector {
+        name: "rfc8463-rsa",
+        message: RFC8463_MESSAGE,
+        domain: "football.example.com",
+        selector: "test",
+        record: RFC8463_RSA_RECORD,
+        private_key: None,
+    },
+];
+
+impl TestVector {
+    /// DNS name of the key record, for instance
+    /// `brisbane._domainkey.football.example.com`
+    pub fn record_name(&self) -> String {
+        format!("{}._domainkey.{}", self.selector, self.domain)
+    }
+
+    /// Verify the signature of the vector with this crate, using its record
+    /// and without applying any policy
+    pub fn verify(&self, logger: &slog::Logger) -> Result<DKIMResult, DKIMError> {
+        let email = mailparse::parse_mail(self.message.as_bytes()).map_err(|err| {
+            DKIMError::MalformedEmail {
+                reason: "invalid message",
+                source: Some(ErrorSource::new(err)),
+            }
+        })?;
+        let public_key = public_key::parse_public_key(logger, self.record)?;
+        for header in email.headers.get_all_headers(HEADER) {
+            let dkim_header = validate_header(signature_value(header)?)?;
+            if dkim_header.get_required_tag("d") == self.domain
+                && dkim_header.get_required_tag("s") == self.selector
+            {
+                return verify_email_header_with_key(
+                    logger,
+                    &dkim_header,
+                    &email,
+                    &public_key,
+                    None,
+                );
+            }
+        }
+        Err(DKIMError::RequiredSignaturesMissing {
+            passing: 0,
+            required: 1,
+            algorithm: None,
+        })
+    }
+}
+
+/// Verify every vector, see [TestVector::verify]
+pub fn verify_all(
+    logger: &slog::Logger,
+) -> Vec<(&'static TestVector, Result<DKIMResult, DKIMError>)> {
+    VECTORS
+        .iter()
+        .map(|vector| (vector, vector.verify(logger)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verify_all() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        for (vector, result) in verify_all(&logger) {
+            assert_eq!(result.unwrap().summary(), "pass", "{}", vector.name);
+        }
+    }
+}
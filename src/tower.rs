@@ -0,0 +1,150 @@
+//! Tower middleware signing outgoing messages, to add DKIM to existing
+//! tower-based SMTP or submission pipelines.
+
+use futures::future::{self, Either, Ready};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tower_layer::Layer;
+use tower_service::Service;
+
+use crate::{DKIMError, DKIMSigner, ErrorSource};
+
+/// Message which can be signed by the [SignService]
+pub trait SignableMessage {
+    /// Raw message, with CRLF line endings
+    fn raw_bytes(&self) -> &[u8];
+
+    /// Add a header line (including the trailing CRLF) at the top of the
+    /// message
+    fn prepend_header(&mut self, header: &str);
+}
+
+impl SignableMessage for Vec<u8> {
+    fn raw_bytes(&self) -> &[u8] {
+        self
+    }
+
+    fn prepend_header(&mut self, header: &str) {
+        self.splice(0..0, header.bytes());
+    }
+}
+
+/// Layer wrapping a service with a [SignService]
+#[derive(Clone)]
+pub struct SignLayer {
+    signer: Arc<DKIMSigner<'static>>,
+}
+
+impl SignLayer {
+    pub fn new(signer: Arc<DKIMSigner<'static>>) -> Self {
+        Self { signer }
+    }
+}
+
+impl<S> Layer<S> for SignLayer {
+    type Service = SignService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        SignService {
+            inner,
+            signer: Arc::clone(&self.signer),
+        }
+    }
+}
+
+/// Service adding a `DKIM-Signature` header to the messages before passing
+/// them to the inner service. Signing errors are converted into the error
+/// type of the inner service.
+#[derive(Clone)]
+pub struct SignService<S> {
+    inner: S,
+    signer: Arc<DKIMSigner<'static>>,
+}
+
+impl<S, M> Service<M> for SignService<S>
+where
+    S: Service<M>,
+    S::Error: From<DKIMError>,
+    M: SignableMessage,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Either<Ready<Result<S::Response, S::Error>>, S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut message: M) -> Self::Future {
+        let header = mailparse::parse_mail(message.raw_bytes())
+            .map_err(|err| DKIMError::MalformedEmail {
+                reason: "invalid message",
+                source: Some(ErrorSource::new(err)),
+            })
+            .and_then(|email| self.signer.sign(&email));
+        match header {
+            Ok(header) => {
+                message.prepend_header(&format!("{}\r\n", header));
+                Either::Right(self.inner.call(message))
+            }
+            Err(err) => Either::Left(future::ready(Err(err.into()))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DkimPrivateKey, SignerBuilder};
+    use rsa::pkcs1::DecodeRsaPrivateKey;
+    use std::path::Path;
+
+    #[derive(Debug, PartialEq)]
+    enum Error {
+        Dkim(DKIMError),
+    }
+    impl From<DKIMError> for Error {
+        fn from(err: DKIMError) -> Self {
+            Error::Dkim(err)
+        }
+    }
+
+    /// Inner service returning the message it received
+    struct Echo;
+    impl Service<Vec<u8>> for Echo {
+        type Response = Vec<u8>;
+        type Error = Error;
+        type Future = Ready<Result<Vec<u8>, Error>>;
+
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, message: Vec<u8>) -> Self::Future {
+            future::ready(Ok(message))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sign_service() {
+        let private_key =
+            rsa::RsaPrivateKey::read_pkcs1_pem_file(Path::new("./test/keys/2022.private")).unwrap();
+        let signer = SignerBuilder::new()
+            .with_signed_headers(&["From", "Subject"])
+            .unwrap()
+            .with_private_key(DkimPrivateKey::Rsa(private_key))
+            .with_selector("s20")
+            .with_owned_logger(slog::Logger::root(slog::Discard, slog::o!()))
+            .with_signing_domain("example.com")
+            .build()
+            .unwrap();
+        let mut service = SignLayer::new(Arc::new(signer)).layer(Echo);
+
+        let raw_email = b"Subject: subject\r\nFrom: joe@example.com\r\n\r\nHello Alice\r\n";
+        let signed = service.call(raw_email.to_vec()).await.unwrap();
+        let signed = String::from_utf8(signed).unwrap();
+        assert!(signed.starts_with("DKIM-Signature: v=1; a=rsa-sha256;"));
+        assert!(signed
+            .ends_with("\r\nSubject: subject\r\nFrom: joe@example.com\r\n\r\nHello Alice\r\n"));
+    }
+}